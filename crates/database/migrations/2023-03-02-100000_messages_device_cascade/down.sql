@@ -0,0 +1,3 @@
+alter table messages drop constraint messages_device_uid_fkey;
+alter table messages add constraint messages_device_uid_fkey
+    foreign key (device_uid) references devices(uid);
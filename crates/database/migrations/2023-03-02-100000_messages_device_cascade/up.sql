@@ -0,0 +1,6 @@
+-- Messages for devices that no longer exist can never be dequeued
+delete from messages where not exists (select from devices where devices.uid = messages.device_uid);
+
+alter table messages drop constraint messages_device_uid_fkey;
+alter table messages add constraint messages_device_uid_fkey
+    foreign key (device_uid) references devices(uid) on delete cascade;
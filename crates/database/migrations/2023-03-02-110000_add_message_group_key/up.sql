@@ -0,0 +1 @@
+alter table messages add column if not exists group_key varchar;
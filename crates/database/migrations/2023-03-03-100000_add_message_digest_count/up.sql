@@ -0,0 +1 @@
+alter table messages add column if not exists digest_count int;
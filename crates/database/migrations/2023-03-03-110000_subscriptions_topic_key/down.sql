@@ -0,0 +1,2 @@
+-- Original topic urls can't be restored, but they are not used anyway
+select 1;
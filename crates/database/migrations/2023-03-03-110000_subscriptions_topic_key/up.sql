@@ -0,0 +1,29 @@
+-- Subscriptions are stored with a canonical topic key (see `Topic::key`) instead of the topic url,
+-- so that the primary key (subscriber_address, topic) prevents duplicate subscriptions to a topic.
+create temporary table subscription_topic_keys as
+    select s.uid,
+           s.subscriber_address,
+           case
+               when o.subscription_uid is not null then 'orders'
+               else 'price_threshold/' || p.amount_asset_id || '/' || p.price_asset_id || '/' || encode(float8send(p.price_threshold), 'hex')
+           end as topic_key
+    from subscriptions s
+         left outer join topics_order_execution o on (o.subscription_uid = s.uid)
+         left outer join topics_price_threshold p on (p.subscription_uid = s.uid)
+    where o.subscription_uid is not null or p.subscription_uid is not null;
+
+-- Keep the latest of duplicate subscriptions
+delete from subscriptions where uid in (
+    select uid from (
+        select uid, row_number() over (partition by subscriber_address, topic_key order by uid desc) as n
+        from subscription_topic_keys
+    ) k
+    where n > 1
+);
+
+update subscriptions s
+    set topic = k.topic_key
+    from subscription_topic_keys k
+    where k.uid = s.uid;
+
+drop table subscription_topic_keys;
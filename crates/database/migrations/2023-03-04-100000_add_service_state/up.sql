@@ -0,0 +1,6 @@
+-- Small pieces of state the services persist across restarts, like the last processed stream position
+create table if not exists service_state (
+    key        varchar primary key,
+    value      varchar not null,
+    updated_at timestamptz not null default now()
+);
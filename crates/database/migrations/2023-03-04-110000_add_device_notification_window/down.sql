@@ -0,0 +1,3 @@
+alter table devices
+    drop column notifications_window_start,
+    drop column notifications_in_window;
@@ -0,0 +1,4 @@
+-- Fixed-window counter of notifications enqueued for a device, for the per-device cap
+alter table devices
+    add column notifications_window_start timestamptz,
+    add column notifications_in_window integer not null default 0;
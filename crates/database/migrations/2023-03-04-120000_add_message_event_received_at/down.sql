@@ -0,0 +1,2 @@
+alter table messages
+    drop column event_received_at;
@@ -0,0 +1,3 @@
+-- When the processor received the event the message is about, for end-to-end latency metrics
+alter table messages
+    add column event_received_at timestamptz;
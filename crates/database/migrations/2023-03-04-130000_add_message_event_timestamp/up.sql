@@ -0,0 +1,3 @@
+-- Timestamp of the event the message is about, for the date and time formatted at send time
+alter table messages
+    add column event_timestamp timestamptz;
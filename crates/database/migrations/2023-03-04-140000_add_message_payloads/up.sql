@@ -0,0 +1,10 @@
+-- Payloads of sent messages (with the device token redacted), stored for audit if enabled
+create table if not exists message_payloads (
+    uid         serial primary key,
+    message_uid int4 not null,
+    sent_at     timestamptz not null default now(),
+    payload     jsonb not null
+);
+
+create index if not exists message_payloads_message_uid_idx on message_payloads (message_uid);
+create index if not exists message_payloads_sent_at_idx on message_payloads (sent_at);
@@ -0,0 +1,7 @@
+drop trigger if exists topics_price_threshold_asset_uids on topics_price_threshold;
+drop function if exists topics_price_threshold_asset_uids();
+drop index if exists topics_price_threshold_asset_uids_idx;
+alter table topics_price_threshold
+    drop column if exists amount_asset_uid,
+    drop column if exists price_asset_uid;
+drop table if exists asset_ids;
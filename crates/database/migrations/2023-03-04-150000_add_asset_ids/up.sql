@@ -0,0 +1,40 @@
+-- Numeric ids of assets, so that price subscriptions are matched by integers
+-- rather than by base58 strings. The string ids stay in place and remain the source of truth.
+create table if not exists asset_ids (
+    uid serial primary key,
+    id  varchar not null unique
+);
+
+insert into asset_ids (id)
+    select amount_asset_id from topics_price_threshold
+    union
+    select price_asset_id from topics_price_threshold
+on conflict do nothing;
+
+alter table topics_price_threshold
+    add column if not exists amount_asset_uid int4 references asset_ids (uid),
+    add column if not exists price_asset_uid int4 references asset_ids (uid);
+
+update topics_price_threshold t set
+    amount_asset_uid = (select a.uid from asset_ids a where a.id = t.amount_asset_id),
+    price_asset_uid = (select a.uid from asset_ids a where a.id = t.price_asset_id);
+
+-- Numeric ids of new (or changed) topics are filled in here, so that every writer
+-- (including older API versions still running during a deploy) keeps them in sync
+create or replace function topics_price_threshold_asset_uids() returns trigger as $$
+begin
+    insert into asset_ids (id) values (new.amount_asset_id), (new.price_asset_id)
+        on conflict do nothing;
+    select uid into new.amount_asset_uid from asset_ids where id = new.amount_asset_id;
+    select uid into new.price_asset_uid from asset_ids where id = new.price_asset_id;
+    return new;
+end;
+$$ language plpgsql;
+
+drop trigger if exists topics_price_threshold_asset_uids on topics_price_threshold;
+create trigger topics_price_threshold_asset_uids
+    before insert or update of amount_asset_id, price_asset_id on topics_price_threshold
+    for each row execute procedure topics_price_threshold_asset_uids();
+
+create index if not exists topics_price_threshold_asset_uids_idx
+    on topics_price_threshold (amount_asset_uid, price_asset_uid, price_threshold);
@@ -0,0 +1,4 @@
+-- FCM message structure: notification and/or data (see `DeliveryStyle`),
+-- messages queued before are sent with both
+alter table messages
+    add column if not exists delivery_style varchar;
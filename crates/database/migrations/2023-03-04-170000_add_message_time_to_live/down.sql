@@ -0,0 +1,2 @@
+alter table messages
+    drop column time_to_live;
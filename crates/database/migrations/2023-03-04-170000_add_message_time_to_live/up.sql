@@ -0,0 +1,4 @@
+-- How long FCM keeps the message for an offline device, seconds,
+-- messages queued before are sent with the FCM default (4 weeks)
+alter table messages
+    add column if not exists time_to_live integer;
@@ -0,0 +1,10 @@
+drop index if exists message_payloads_fcm_message_id_idx;
+
+-- Keep the rows with only the id stored, which the payload column can't be null for
+update message_payloads
+set payload = jsonb_build_object('fcm_message_id', fcm_message_id)
+where payload is null;
+
+alter table message_payloads
+    alter column payload set not null,
+    drop column fcm_message_id;
@@ -0,0 +1,7 @@
+-- FCM message ids of sent messages, to correlate them with FCM diagnostics,
+-- stored along with the payload or on their own (if only the ids are stored)
+alter table message_payloads
+    add column if not exists fcm_message_id varchar,
+    alter column payload drop not null;
+
+create index if not exists message_payloads_fcm_message_id_idx on message_payloads (fcm_message_id);
@@ -0,0 +1,3 @@
+-- FCM priority of the message (see `MessagePriority`), messages queued before are sent as high
+alter table messages
+    add column if not exists priority varchar;
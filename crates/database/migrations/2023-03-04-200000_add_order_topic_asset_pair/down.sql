@@ -0,0 +1,8 @@
+-- Pair-scoped order subscriptions would turn into subscriptions to orders on any pair
+delete from subscriptions where uid in (
+    select subscription_uid from topics_order_execution where amount_asset_id is not null
+);
+
+alter table topics_order_execution
+    drop column amount_asset_id,
+    drop column price_asset_id;
@@ -0,0 +1,4 @@
+-- Order subscriptions scoped to an asset pair, subscriptions without one match orders on any pair
+alter table topics_order_execution
+    add column if not exists amount_asset_id varchar,
+    add column if not exists price_asset_id varchar;
@@ -0,0 +1,7 @@
+-- Directed subscriptions would be notified either way
+delete from subscriptions where uid in (
+    select subscription_uid from topics_price_threshold where direction <> 'any'
+);
+
+alter table topics_price_threshold
+    drop column direction;
@@ -0,0 +1,4 @@
+-- Which way the price has to move through the threshold (see `ThresholdDirection`),
+-- existing subscriptions are notified either way
+alter table topics_price_threshold
+    add column if not exists direction varchar not null default 'any';
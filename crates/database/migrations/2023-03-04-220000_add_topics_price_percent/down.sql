@@ -0,0 +1,4 @@
+-- Subscriptions without a topic are ignored, so they are removed along with the topics
+delete from subscriptions where uid in (select subscription_uid from topics_price_percent);
+
+drop table topics_price_percent;
@@ -0,0 +1,13 @@
+-- Price moving by a percentage (either way) from the price at subscription time
+create table if not exists topics_price_percent (
+    subscription_uid integer primary key,
+    amount_asset_id  varchar not null,
+    price_asset_id   varchar not null,
+    base_price       double precision not null,
+    percent          double precision not null,
+
+    foreign key (subscription_uid) references subscriptions (uid) on delete cascade
+);
+
+create index if not exists topics_price_percent_pair_idx
+    on topics_price_percent (amount_asset_id, price_asset_id);
@@ -0,0 +1,4 @@
+-- Platform of the app on the device (see `Platform`),
+-- devices registered before it was stored are treated as web
+alter table devices
+    add column if not exists platform varchar not null default 'web';
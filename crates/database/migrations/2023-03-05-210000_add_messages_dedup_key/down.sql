@@ -0,0 +1,4 @@
+drop index if exists messages_device_dedup_key_idx;
+
+alter table messages
+    drop column dedup_key;
@@ -0,0 +1,7 @@
+-- Key of the messages duplicating each other (see `MessageData::dedup_key`),
+-- not set for the messages queued before it was stored
+alter table messages
+    add column if not exists dedup_key varchar;
+
+create index if not exists messages_device_dedup_key_idx
+    on messages (device_uid, dedup_key) where dedup_key is not null;
@@ -0,0 +1,8 @@
+-- Events already processed, by their stable id in the source (see `EventWithFeedback::event_id`),
+-- so that an event read again is not notified about twice
+create table if not exists event_dedup (
+    event_id     varchar primary key,
+    processed_at timestamptz not null default now()
+);
+
+create index if not exists event_dedup_processed_at_idx on event_dedup (processed_at);
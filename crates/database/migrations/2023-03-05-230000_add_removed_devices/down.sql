@@ -0,0 +1,2 @@
+alter table subscribers drop column if exists last_device_removed_at;
+drop table if exists removed_devices;
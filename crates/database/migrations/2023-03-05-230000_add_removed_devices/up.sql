@@ -0,0 +1,15 @@
+-- Devices removed by the sender because FCM reported their token as invalid, recorded for
+-- support if enabled. The token itself is left out, like in the stored payloads.
+create table if not exists removed_devices (
+    uid                serial primary key,
+    device_uid         int4 not null,
+    subscriber_address varchar not null,
+    reason             varchar not null,
+    last_device        boolean not null,
+    removed_at         timestamptz not null default now()
+);
+
+create index if not exists removed_devices_subscriber_address_idx on removed_devices (subscriber_address);
+
+-- Subscribers whose last device was removed that way, to be re-engaged by other means
+alter table subscribers add column if not exists last_device_removed_at timestamptz;
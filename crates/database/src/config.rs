@@ -2,6 +2,7 @@
 
 use std::fmt;
 
+use model::secret::Secret;
 use serde::Deserialize;
 
 #[derive(Deserialize, Clone)]
@@ -11,7 +12,7 @@ pub struct Config {
     pub port: u16,
     pub database: String,
     pub user: String,
-    pub password: String,
+    pub password: Secret<String>,
 }
 
 fn default_pgport() -> u16 {
@@ -26,18 +27,35 @@ impl Config {
     pub fn database_url(&self) -> String {
         format!(
             "postgres://{}:{}@{}:{}/{}",
-            self.user, self.password, self.host, self.port, self.database
+            self.user,
+            self.password.expose(),
+            self.host,
+            self.port,
+            self.database
         )
     }
 }
 
 impl fmt::Debug for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Intentionally avoid printing password for security reasons
         write!(
             f,
-            "Postgres(server={}:{}; database={}; user={}; password=***)",
-            self.host, self.port, self.database, self.user
+            "Postgres(server={}:{}; database={}; user={}; password={:?})",
+            self.host, self.port, self.database, self.user, self.password
         )
     }
 }
+
+#[test]
+fn test_password() {
+    let secret = Secret::new("qwerty".to_string());
+    let config = Config {
+        host: "localhost".to_string(),
+        port: 5432,
+        database: "push".to_string(),
+        user: "user".to_string(),
+        password: secret,
+    };
+    assert!(!format!("{:?}", config).contains("qwerty"));
+    assert!(config.database_url().contains("qwerty"));
+}
@@ -1,8 +1,13 @@
-use diesel::{result::Error as DslError, AsChangeset, ExpressionMethods, QueryDsl};
+use chrono::{DateTime, Utc};
+use diesel::{
+    result::Error as DslError,
+    sql_types::{Integer, Timestamptz},
+    AsChangeset, ExpressionMethods, QueryDsl, QueryableByName,
+};
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
 use model::{
-    device::{Device, FcmUid, LocaleInfo},
+    device::{Device, FcmUid, LocaleInfo, Platform},
     waves::{Address, AsBase58String},
 };
 
@@ -11,10 +16,19 @@ use crate::{
     schema::{devices, subscribers},
 };
 
-#[derive(Clone)]
-pub struct Repo {}
+#[derive(Clone, Default)]
+pub struct Repo {
+    max_devices_per_address: Option<u32>,
+}
 
 impl Repo {
+    /// Repo which refuses to register more than `max` devices for an address
+    pub fn with_max_devices_per_address(max: u32) -> Self {
+        Repo {
+            max_devices_per_address: Some(max),
+        }
+    }
+
     pub async fn subscribers(
         &self,
         address: &Address,
@@ -26,36 +40,44 @@ impl Repo {
                 devices::fcm_uid,
                 devices::language,
                 devices::utc_offset_seconds,
+                devices::platform,
             ))
             .filter(devices::subscriber_address.eq(address.as_base58_string()))
             .order(devices::uid)
-            .load::<(i32, String, String, i32)>(conn)
+            .load::<(i32, String, String, i32, String)>(conn)
             .await?;
 
         let devices = rows
             .into_iter()
-            .map(|(device_uid, fcm_uid, lang, utc_offset_seconds)| Device {
-                device_uid,
-                fcm_uid,
-                address: address.clone(),
-                locale: LocaleInfo {
-                    lang,
-                    utc_offset_seconds,
+            .map(
+                |(device_uid, fcm_uid, lang, utc_offset_seconds, platform)| Device {
+                    device_uid,
+                    fcm_uid,
+                    address: address.clone(),
+                    locale: LocaleInfo {
+                        lang,
+                        utc_offset_seconds,
+                    },
+                    platform: Platform::parse(&platform).unwrap_or_default(),
                 },
-            })
+            )
             .collect();
 
         Ok(devices)
     }
 
+    /// Returns `false` if the device is already registered, which is not an error,
+    /// even if it was registered by a concurrent request in the meantime
     pub async fn register(
         &self,
         address: &Address,
         fcm_uid: &FcmUid,
         lang: &str,
         tz_offset: i32,
+        platform: Platform,
         conn: &mut AsyncPgConnection,
-    ) -> Result<(), Error> {
+    ) -> Result<bool, Error> {
+        let subscriber = address;
         let address = address.as_base58_string();
         let lang = lang.to_string();
 
@@ -64,20 +86,50 @@ impl Repo {
             devices::subscriber_address.eq(&address),
             devices::language.eq(lang),
             devices::utc_offset_seconds.eq(tz_offset),
+            devices::platform.eq(platform.as_str()),
         );
 
-        diesel::insert_into(subscribers::table)
-            .values(subscribers::address.eq(&address))
-            .on_conflict_do_nothing()
-            .execute(conn)
-            .await?;
+        // Create subscriber (if missing) and lock it, so that a concurrent `unregister`
+        // can't delete it before the device is inserted.
+        // If it was deleted after our insert but before the lock was acquired - just retry once.
+        let mut locked = false;
+        for _ in 0..2 {
+            diesel::insert_into(subscribers::table)
+                .values(subscribers::address.eq(&address))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
 
-        diesel::insert_into(devices::table)
+            if self.lock_subscriber(&address, conn).await?.is_some() {
+                locked = true;
+                break;
+            }
+        }
+        if !locked {
+            return Err(Error::SubscriberDeleted(subscriber.clone()));
+        }
+
+        // Checked under the subscriber lock, so that concurrent registrations
+        // can't all fit in the limit
+        if let Some(max) = self.max_devices_per_address {
+            let registered = devices::table
+                .select(devices::fcm_uid)
+                .filter(devices::subscriber_address.eq(&address))
+                .load::<String>(conn)
+                .await?;
+            if !registered.contains(fcm_uid) && registered.len() >= max as usize {
+                return Err(Error::DeviceLimitExceeded(subscriber.clone(), max));
+            }
+        }
+
+        let num_rows = diesel::insert_into(devices::table)
             .values(device)
+            .on_conflict((devices::subscriber_address, devices::fcm_uid))
+            .do_nothing()
             .execute(conn)
             .await?;
 
-        Ok(())
+        Ok(num_rows > 0)
     }
 
     pub async fn unregister(
@@ -88,6 +140,13 @@ impl Repo {
     ) -> Result<(), Error> {
         let address = address.as_base58_string();
 
+        // Concurrent unregisters (or registers) for the same address are serialized
+        // on the subscriber row, so that the "no devices left" check below is reliable.
+        if self.lock_subscriber(&address, conn).await?.is_none() {
+            // No subscriber - no devices
+            return Ok(());
+        }
+
         diesel::delete(
             devices::table
                 .filter(devices::subscriber_address.eq(&address))
@@ -111,6 +170,64 @@ impl Repo {
         Ok(())
     }
 
+    /// Lock the subscriber row until the end of the current transaction.
+    /// Returns `None` if there is no such subscriber.
+    async fn lock_subscriber(
+        &self,
+        address: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<String>, Error> {
+        let subscriber = subscribers::table
+            .select(subscribers::address)
+            .filter(subscribers::address.eq(address))
+            .for_update()
+            .first::<String>(conn)
+            .await;
+
+        optional(subscriber)
+    }
+
+    /// Count a notification for the device, returns the number of notifications in the current
+    /// window, including this one. A new window starts at `now` if the current one started
+    /// at or before `window_start`. The counter is updated atomically, so that concurrent
+    /// processors share it.
+    pub async fn count_notification(
+        &self,
+        device_uid: i32,
+        now: DateTime<Utc>,
+        window_start: DateTime<Utc>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<u32, Error> {
+        #[derive(QueryableByName)]
+        struct Row {
+            #[diesel(sql_type = Integer)]
+            notifications_in_window: i32,
+        }
+
+        let row = diesel::sql_query(
+            r#"
+                UPDATE devices SET
+                    notifications_window_start = CASE
+                        WHEN notifications_window_start IS NULL OR notifications_window_start <= $2 THEN $3
+                        ELSE notifications_window_start
+                    END,
+                    notifications_in_window = CASE
+                        WHEN notifications_window_start IS NULL OR notifications_window_start <= $2 THEN 1
+                        ELSE notifications_in_window + 1
+                    END
+                WHERE uid = $1
+                RETURNING notifications_in_window
+            "#,
+        )
+        .bind::<Integer, _>(device_uid)
+        .bind::<Timestamptz, _>(window_start)
+        .bind::<Timestamptz, _>(now)
+        .get_result::<Row>(conn)
+        .await?;
+
+        Ok(row.notifications_in_window as u32)
+    }
+
     pub async fn exists(
         &self,
         address: &Address,
@@ -127,28 +244,30 @@ impl Repo {
         Ok(optional(row_exists)?.is_some())
     }
 
+    /// FCM tokens of the devices registered for the address
+    pub async fn fcm_uids(
+        &self,
+        address: &Address,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<FcmUid>, Error> {
+        let fcm_uids = devices::table
+            .select(devices::fcm_uid)
+            .filter(devices::subscriber_address.eq(address.as_base58_string()))
+            .order(devices::uid)
+            .load::<FcmUid>(conn)
+            .await?;
+
+        Ok(fcm_uids)
+    }
+
     pub async fn update(
         &self,
         address: &Address,
         fcm_uid: &FcmUid,
-        language: Option<String>,
-        utc_offset_seconds: Option<i32>,
-        new_fcm_uid: Option<FcmUid>,
+        update: DeviceUpdate,
         conn: &mut AsyncPgConnection,
     ) -> Result<(), Error> {
-        #[derive(AsChangeset)]
-        #[diesel(table_name = devices)]
-        struct DeviceUpdate {
-            language: Option<String>,
-            utc_offset_seconds: Option<i32>,
-            fcm_uid: Option<FcmUid>,
-        }
-
-        let updates = DeviceUpdate {
-            language,
-            utc_offset_seconds,
-            fcm_uid: new_fcm_uid,
-        };
+        let updates = changeset(update);
 
         let address = address.as_base58_string();
 
@@ -165,6 +284,33 @@ impl Repo {
     }
 }
 
+/// Fields of a device to change, the ones which are not set are left as they are
+#[derive(Default)]
+pub struct DeviceUpdate {
+    pub language: Option<String>,
+    pub utc_offset_seconds: Option<i32>,
+    pub platform: Option<Platform>,
+    pub new_fcm_uid: Option<FcmUid>,
+}
+
+#[derive(AsChangeset)]
+#[diesel(table_name = devices)]
+struct DeviceChangeset {
+    language: Option<String>,
+    utc_offset_seconds: Option<i32>,
+    platform: Option<&'static str>,
+    fcm_uid: Option<FcmUid>,
+}
+
+fn changeset(update: DeviceUpdate) -> DeviceChangeset {
+    DeviceChangeset {
+        language: update.language,
+        utc_offset_seconds: update.utc_offset_seconds,
+        platform: update.platform.as_ref().map(Platform::as_str),
+        fcm_uid: update.new_fcm_uid,
+    }
+}
+
 fn optional<R>(query_result: Result<R, DslError>) -> Result<Option<R>, Error> {
     match query_result {
         Ok(r) => Ok(Some(r)),
@@ -172,3 +318,253 @@ fn optional<R>(query_result: Result<R, DslError>) -> Result<Option<R>, Error> {
         Err(e) => Err(e.into()),
     }
 }
+
+#[test]
+fn test_device_update() {
+    use diesel::{debug_query, pg::Pg};
+
+    let sql = |updates: DeviceChangeset| {
+        let query = diesel::update(devices::table).set(updates);
+        debug_query::<Pg, _>(&query).to_string()
+    };
+
+    // Only the platform is changed, the rest is kept
+    let updates = changeset(DeviceUpdate {
+        platform: Some(Platform::Ios),
+        ..Default::default()
+    });
+    assert_eq!(updates.platform, Some("ios"));
+    assert_eq!(
+        sql(updates),
+        r#"UPDATE "devices" SET "platform" = $1 -- binds: ["ios"]"#
+    );
+
+    let updates = changeset(DeviceUpdate {
+        language: Some("ru".to_string()),
+        utc_offset_seconds: Some(10800),
+        ..Default::default()
+    });
+    assert_eq!(updates.platform, None);
+    assert!(!sql(updates).contains("platform"));
+
+    let updates = changeset(DeviceUpdate {
+        language: None,
+        utc_offset_seconds: Some(0),
+        platform: Some(Platform::Android),
+        new_fcm_uid: Some("new".to_string()),
+    });
+    assert_eq!(
+        sql(updates),
+        r#"UPDATE "devices" SET "utc_offset_seconds" = $1, "platform" = $2, "fcm_uid" = $3 -- binds: [0, "android", "new"]"#
+    );
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_concurrent_unregister() {
+    use diesel_async::{scoped_futures::ScopedFutureExt as _, AsyncConnection};
+    use std::time::Duration;
+
+    let db = crate::testing::TestDb::new();
+    let repo = Repo::default();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let (token1, token2) = ("fcm_uid_1".to_string(), "fcm_uid_2".to_string());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        for fcm_uid in [&token1, &token2] {
+            let res = repo.register(&address, fcm_uid, "en", 0, Platform::Web, &mut conn);
+            assert!(res.await.unwrap());
+        }
+
+        // Both devices of the address are unregistered at once, the first transaction
+        // is not committed before the second one deletes its device. Neither would see
+        // the subscriber without devices, unless they are serialized.
+        let (mut conn1, mut conn2) = (db.connect_async().await, db.connect_async().await);
+        let first = conn1.transaction(|conn| {
+            async {
+                repo.unregister(&address, &token1, conn).await?;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, Error>(())
+            }
+            .scope_boxed()
+        });
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            conn2
+                .transaction(|conn| repo.unregister(&address, &token2, conn).scope_boxed())
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        first.unwrap();
+        second.unwrap();
+
+        let subscribers = subscribers::table
+            .select(subscribers::address)
+            .load::<String>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(subscribers, Vec::<String>::new());
+        assert!(repo.fcm_uids(&address, &mut conn).await.unwrap().is_empty());
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_concurrent_register() {
+    use diesel_async::{scoped_futures::ScopedFutureExt as _, AsyncConnection};
+    use std::time::Duration;
+
+    let db = crate::testing::TestDb::new();
+    let repo = Repo::default();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let fcm_uid = "fcm_uid".to_string();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // The same device is registered twice at once, the first transaction is not committed
+        // before the second one inserts the device
+        let (mut conn1, mut conn2) = (db.connect_async().await, db.connect_async().await);
+        let first = conn1.transaction(|conn| {
+            async {
+                let created = repo
+                    .register(&address, &fcm_uid, "en", 0, Platform::Web, conn)
+                    .await?;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, Error>(created)
+            }
+            .scope_boxed()
+        });
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            conn2
+                .transaction(|conn| {
+                    repo.register(&address, &fcm_uid, "en", 0, Platform::Web, conn)
+                        .scope_boxed()
+                })
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert!(first.unwrap());
+        assert!(!second.unwrap());
+
+        // A repeated registration finds the device in place too
+        let mut conn = db.connect_async().await;
+        let res = repo.register(&address, &fcm_uid, "en", 0, Platform::Web, &mut conn);
+        assert!(!res.await.unwrap());
+        assert_eq!(
+            repo.fcm_uids(&address, &mut conn).await.unwrap(),
+            vec![fcm_uid]
+        );
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_register_limit() {
+    use diesel_async::{scoped_futures::ScopedFutureExt as _, AsyncConnection};
+    use std::time::Duration;
+
+    let db = crate::testing::TestDb::new();
+    let repo = Repo::with_max_devices_per_address(2);
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let tokens = [1, 2, 3].map(|n| format!("fcm_uid_{}", n));
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let res = repo.register(&address, &tokens[0], "en", 0, Platform::Web, &mut conn);
+        assert!(res.await.unwrap());
+
+        // The last free slot is taken by two devices at once, the first transaction
+        // is not committed before the second one counts the devices
+        let (mut conn1, mut conn2) = (db.connect_async().await, db.connect_async().await);
+        let first = conn1.transaction(|conn| {
+            async {
+                let created = repo
+                    .register(&address, &tokens[1], "en", 0, Platform::Web, conn)
+                    .await?;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, Error>(created)
+            }
+            .scope_boxed()
+        });
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            conn2
+                .transaction(|conn| {
+                    repo.register(&address, &tokens[2], "en", 0, Platform::Web, conn)
+                        .scope_boxed()
+                })
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert!(first.unwrap());
+        assert!(matches!(second, Err(Error::DeviceLimitExceeded(_, 2))));
+
+        // A device already registered is still welcome
+        let res = repo.register(&address, &tokens[0], "en", 0, Platform::Web, &mut conn);
+        assert!(!res.await.unwrap());
+        let fcm_uids = repo.fcm_uids(&address, &mut conn).await.unwrap();
+        assert_eq!(fcm_uids.len(), 2);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_subscribers() {
+    let db = crate::testing::TestDb::new();
+    let repo = Repo::default();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let other = Address::from_string("3PAs2qSeUAfgqSKS8LpZPKGYEjJKcud9Djr").unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let devices = [
+            (&address, "fcm_uid_1", "en", 0, Platform::Web),
+            (&other, "fcm_uid_2", "en", 0, Platform::Web),
+            (&address, "fcm_uid_3", "ru", 10800, Platform::Ios),
+        ];
+        for (address, fcm_uid, lang, utc_offset_seconds, platform) in devices {
+            let fcm_uid = fcm_uid.to_string();
+            repo.register(
+                address,
+                &fcm_uid,
+                lang,
+                utc_offset_seconds,
+                platform,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+        }
+
+        // Only the devices of the address, in the order of registration
+        let subscribers = repo.subscribers(&address, &mut conn).await.unwrap();
+        let subscribers = subscribers
+            .iter()
+            .map(|device| {
+                assert_eq!(
+                    device.address.as_base58_string(),
+                    address.as_base58_string()
+                );
+                (
+                    device.fcm_uid.as_str(),
+                    device.locale.lang.as_str(),
+                    device.locale.utc_offset_seconds,
+                    device.platform,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subscribers,
+            vec![
+                ("fcm_uid_1", "en", 0, Platform::Web),
+                ("fcm_uid_3", "ru", 10800, Platform::Ios),
+            ]
+        );
+        assert_eq!(repo.subscribers(&other, &mut conn).await.unwrap().len(), 1);
+    });
+}
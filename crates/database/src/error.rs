@@ -15,6 +15,21 @@ pub enum Error {
     #[error("Database query returned a bad subscription mode: {0}")]
     BadTopicType(i32),
 
+    #[error("Database query returned a bad price threshold direction: {0}")]
+    BadThresholdDirection(String),
+
+    #[error("Database query affected {1} rows instead of {0}")]
+    UnexpectedRowCount(usize, usize),
+
+    #[error("Base price of the price change subscription is unknown: {0}")]
+    UnknownBasePrice(String),
+
     #[error("Subscriptions limit ({1}) exceeded for address {0:?}")]
     LimitExceeded(Address, u32),
+
+    #[error("Devices limit ({1}) exceeded for address {0:?}")]
+    DeviceLimitExceeded(Address, u32),
+
+    #[error("Subscriber {0:?} was deleted concurrently")]
+    SubscriberDeleted(Address),
 }
@@ -7,4 +7,7 @@ pub mod device;
 pub mod error;
 pub mod message;
 pub mod schema;
+pub mod state;
 pub mod subscription;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
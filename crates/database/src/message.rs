@@ -1,37 +1,402 @@
-use diesel::ExpressionMethods;
+use chrono::{DateTime, Utc};
+use diesel::{dsl::exists, pg::Pg, ExpressionMethods, Insertable, QueryDsl};
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
-use model::message::PreparedMessage;
+use model::message::{DeliveryStyle, LocalizedMessage, MessageData, PreparedMessage};
 
 use crate::{error::Error, schema::messages};
 
 /// Message queue in the database
 pub struct Queue {}
 
+/// Rows per `INSERT`: with 14 columns a row, well within the Postgres limit
+/// of 65535 bind parameters per statement
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Values of a queued message, the columns which are not set are left to their defaults
+#[derive(Insertable)]
+#[diesel(table_name = messages)]
+struct NewMessage {
+    device_uid: i32,
+    notification_title: String,
+    notification_body: String,
+    data: serde_json::Value,
+    collapse_key: Option<String>,
+    group_key: Option<String>,
+    event_received_at: Option<DateTime<Utc>>,
+    event_timestamp: Option<DateTime<Utc>>,
+    delivery_style: &'static str,
+    time_to_live: Option<i32>,
+    priority: &'static str,
+    dedup_key: Option<String>,
+    scheduled_for: Option<DateTime<Utc>>,
+    digest_count: Option<i32>,
+}
+
+impl NewMessage {
+    fn new(message: PreparedMessage, buffered_until: Option<DateTime<Utc>>) -> Self {
+        let dedup_key = message.data.as_ref().and_then(MessageData::dedup_key);
+        let time_to_live = message
+            .ttl
+            .map(|ttl| ttl.as_secs().min(i32::MAX as u64) as i32);
+        NewMessage {
+            device_uid: message.device.device_uid,
+            notification_title: message.message.notification_title,
+            notification_body: message.message.notification_body,
+            data: data_json(message.data, message.deep_link),
+            collapse_key: message.collapse_key,
+            group_key: message.group_key,
+            event_received_at: message.event_received_at.date_time_utc(),
+            event_timestamp: message.event_timestamp.date_time_utc(),
+            delivery_style: message.delivery_style.as_str(),
+            time_to_live,
+            priority: message.priority.as_str(),
+            dedup_key,
+            // A buffered message deferred further (say, by quiet hours) is still a pending digest
+            scheduled_for: buffered_until.max(message.scheduled_for),
+            digest_count: buffered_until.map(|_| 1),
+        }
+    }
+}
+
+/// A buffered digest message, which is not sent yet,
+/// so that more alerts can be merged into it
+#[derive(Debug)]
+pub struct PendingDigest {
+    pub uid: i32,
+    pub count: i32,
+}
+
+/// Contents of a buffered message which `count` alerts are merged into
+pub struct Digest {
+    pub count: i32,
+    pub message: LocalizedMessage,
+    pub data: MessageData,
+    pub deep_link: Option<String>,
+    pub delivery_style: DeliveryStyle,
+}
+
 impl Queue {
     pub async fn enqueue(
         &self,
         message: PreparedMessage,
         conn: &mut AsyncPgConnection,
     ) -> Result<(), Error> {
-        // This conversion can only fail due to a programming error
-        // (see `to_value` docs), so using unwrap is safe and no need
-        // to propagate error here
-        let data = serde_json::to_value(message.data).expect("serialize json");
-
-        let values = (
-            messages::device_uid.eq(message.device.device_uid),
-            messages::notification_title.eq(message.message.notification_title),
-            messages::notification_body.eq(message.message.notification_body),
-            messages::data.eq(data),
-            messages::collapse_key.eq(message.collapse_key),
-        );
-        let num_rows = diesel::insert_into(messages::table)
-            .values(values)
+        self.enqueue_batch(vec![message], conn).await
+    }
+
+    /// Enqueue the messages with as few `INSERT`s as possible
+    pub async fn enqueue_batch(
+        &self,
+        messages: Vec<PreparedMessage>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        let values = messages
+            .into_iter()
+            .map(|message| NewMessage::new(message, None))
+            .collect();
+        self.insert(values, conn).await
+    }
+
+    /// Enqueue a message which is held back until `until`, so that subsequent alerts
+    /// for the same device can be merged into it (see `merge_into_digest`)
+    pub async fn enqueue_buffered(
+        &self,
+        message: PreparedMessage,
+        until: DateTime<Utc>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        self.insert(vec![NewMessage::new(message, Some(until))], conn)
+            .await
+    }
+
+    /// Whether a message with the same dedup key (see `MessageData::dedup_key`) has been queued
+    /// for the device since `since` and is not sent yet (sent messages are removed from the queue)
+    pub async fn has_duplicate(
+        &self,
+        message: &PreparedMessage,
+        since: DateTime<Utc>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let dedup_key = match message.data.as_ref().and_then(MessageData::dedup_key) {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        let query = duplicates(message.device.device_uid, dedup_key, since).select(messages::uid);
+        let exists = diesel::select(exists(query)).get_result(conn).await?;
+        Ok(exists)
+    }
+
+    /// Inserted by `INSERT_CHUNK_SIZE` rows at a time
+    async fn insert(
+        &self,
+        mut values: Vec<NewMessage>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        while !values.is_empty() {
+            let rest = values.split_off(values.len().min(INSERT_CHUNK_SIZE));
+            let chunk = std::mem::replace(&mut values, rest);
+            let count = chunk.len();
+            let num_rows = diesel::insert_into(messages::table)
+                .values(chunk)
+                .execute(conn)
+                .await?;
+            if num_rows != count {
+                return Err(Error::UnexpectedRowCount(count, num_rows));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Buffered message of the device which is not sent yet, locked until the end of transaction
+    pub async fn pending_digest(
+        &self,
+        device_uid: i32,
+        now: DateTime<Utc>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<PendingDigest>, Error> {
+        let row = messages::table
+            .select((messages::uid, messages::digest_count))
+            .filter(messages::device_uid.eq(device_uid))
+            .filter(messages::digest_count.is_not_null())
+            .filter(messages::send_attempts_count.eq(0))
+            .filter(messages::scheduled_for.gt(now))
+            .order(messages::scheduled_for)
+            .for_update()
+            .first::<(i32, Option<i32>)>(conn)
+            .await;
+
+        match row {
+            Ok((uid, count)) => Ok(Some(PendingDigest {
+                uid,
+                count: count.unwrap_or(1),
+            })),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the contents of a buffered message with a digest.
+    /// A digest is not about a single pair, so it doesn't collapse with the alerts of one,
+    /// and it is not as urgent as a single alert.
+    pub async fn merge_into_digest(
+        &self,
+        digest_uid: i32,
+        digest: Digest,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        let Digest {
+            count,
+            message,
+            data,
+            deep_link,
+            delivery_style,
+        } = digest;
+        let priority = data.priority();
+        let data = data_json(Some(data), deep_link);
+
+        let num_rows = diesel::update(messages::table)
+            .filter(messages::uid.eq(digest_uid))
+            .set((
+                messages::notification_title.eq(message.notification_title),
+                messages::notification_body.eq(message.notification_body),
+                messages::data.eq(data),
+                messages::digest_count.eq(count),
+                messages::collapse_key.eq(None::<String>),
+                messages::dedup_key.eq(None::<String>),
+                messages::priority.eq(priority.as_str()),
+                messages::delivery_style.eq(delivery_style.as_str()),
+            ))
             .execute(conn)
             .await?;
-        debug_assert_eq!(num_rows, 1); //TODO return error?
+        debug_assert_eq!(num_rows, 1);
 
         Ok(())
     }
 }
+
+fn duplicates(
+    device_uid: i32,
+    dedup_key: String,
+    since: DateTime<Utc>,
+) -> messages::BoxedQuery<'static, Pg> {
+    messages::table
+        .filter(messages::device_uid.eq(device_uid))
+        .filter(messages::dedup_key.eq(dedup_key))
+        .filter(messages::created_at.gt(since))
+        .into_boxed()
+}
+
+/// The deep link is stored alongside the data fields, so that it ends up in the FCM `data` payload
+fn data_json(data: Option<MessageData>, deep_link: Option<String>) -> serde_json::Value {
+    // This conversion can only fail due to a programming error
+    // (see `to_value` docs), so using unwrap is safe and no need
+    // to propagate error here
+    let mut data = serde_json::to_value(data).expect("serialize json");
+    if let (Some(deep_link), Some(fields)) = (deep_link, data.as_object_mut()) {
+        fields.insert("deep_link".to_string(), deep_link.into());
+    }
+    data
+}
+
+#[test]
+fn test_data_json() {
+    use serde_json::json;
+
+    let data = || MessageData::Digest {
+        count: 2,
+        address: "1234567890".to_string(),
+    };
+    assert_eq!(
+        data_json(Some(data()), Some("waves://alerts".to_string())),
+        json!({"type": "digest", "count": 2, "address": "1234567890", "deep_link": "waves://alerts"})
+    );
+    assert_eq!(
+        data_json(Some(data()), None),
+        json!({"type": "digest", "count": 2, "address": "1234567890"})
+    );
+    assert_eq!(
+        data_json(None, Some("waves://alerts".to_string())),
+        json!(null)
+    );
+}
+
+#[cfg(test)]
+fn test_message(device_uid: i32, data: Option<MessageData>) -> PreparedMessage {
+    use model::{
+        device::{Device, LocaleInfo, Platform},
+        message::MessagePriority,
+        time::Timestamp,
+        waves::Address,
+    };
+
+    PreparedMessage {
+        device: Device {
+            device_uid,
+            address: Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap(),
+            fcm_uid: "fcm_uid".to_string(),
+            locale: LocaleInfo {
+                lang: "en".to_string(),
+                utc_offset_seconds: 0,
+            },
+            platform: Platform::Android,
+        },
+        message: LocalizedMessage {
+            notification_title: "title".to_string(),
+            notification_body: "body".to_string(),
+        },
+        data,
+        collapse_key: None,
+        group_key: None,
+        deep_link: None,
+        event_received_at: Timestamp::from_unix_timestamp_millis(1_700_000_000_000),
+        event_timestamp: Timestamp::from_unix_timestamp_millis(1_700_000_000_000),
+        delivery_style: DeliveryStyle::default(),
+        ttl: None,
+        priority: MessagePriority::High,
+        scheduled_for: None,
+    }
+}
+
+/// Device of a test address registered in the database, returns its uid
+#[cfg(test)]
+async fn test_device(conn: &mut AsyncPgConnection) -> i32 {
+    use model::{device::Platform, waves::Address};
+
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let devices = crate::device::Repo::default();
+    let fcm_uid = "fcm_uid".to_string();
+    let res = devices.register(&address, &fcm_uid, "en", 0, Platform::Android, conn);
+    res.await.unwrap();
+    devices.subscribers(&address, conn).await.unwrap()[0].device_uid
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_duplicates() {
+    use chrono::Duration;
+
+    let db = crate::testing::TestDb::new();
+    let message = |device_uid, order_id: &str| {
+        let data = MessageData::OrderExecuted {
+            amount_asset_id: "WAVES".to_string(),
+            price_asset_id: "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p".to_string(),
+            address: "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2".to_string(),
+            order_id: order_id.to_string(),
+        };
+        test_message(device_uid, Some(data))
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let device_uid = test_device(&mut conn).await;
+
+        let queue = Queue {};
+        let before = Utc::now() - Duration::seconds(60);
+        let order1 = message(device_uid, "order1");
+        assert!(!queue
+            .has_duplicate(&order1, before, &mut conn)
+            .await
+            .unwrap());
+
+        queue.enqueue(order1, &mut conn).await.unwrap();
+        let order1 = message(device_uid, "order1");
+        assert!(queue
+            .has_duplicate(&order1, before, &mut conn)
+            .await
+            .unwrap());
+
+        // Another order, or the message queued before the window
+        let order2 = message(device_uid, "order2");
+        assert!(!queue
+            .has_duplicate(&order2, before, &mut conn)
+            .await
+            .unwrap());
+        let later = Utc::now() + Duration::seconds(1);
+        assert!(!queue
+            .has_duplicate(&order1, later, &mut conn)
+            .await
+            .unwrap());
+    });
+}
+
+#[test]
+fn test_batch_insert() {
+    use diesel::debug_query;
+
+    let values = (1..=3)
+        .map(|device_uid| NewMessage::new(test_message(device_uid, None), None))
+        .collect::<Vec<_>>();
+    let query = diesel::insert_into(messages::table).values(values);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+
+    // A single statement with a row per message
+    assert_eq!(sql.matches("INSERT INTO").count(), 1);
+    assert_eq!(sql.matches("), (").count(), 2);
+    assert!(sql.contains(r#"-- binds: [1, "title", "body""#));
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_enqueue_batch() {
+    let db = crate::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let device_uid = test_device(&mut conn).await;
+
+        // More bind parameters than a single statement can take
+        let count = 5 * INSERT_CHUNK_SIZE + 1;
+        let batch = (0..count).map(|_| test_message(device_uid, None)).collect();
+        Queue {}.enqueue_batch(batch, &mut conn).await.unwrap();
+
+        let queued = messages::table
+            .count()
+            .get_result::<i64>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(queued, count as i64);
+    });
+}
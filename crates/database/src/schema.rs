@@ -1,5 +1,12 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    asset_ids (uid) {
+        uid -> Int4,
+        id -> Varchar,
+    }
+}
+
 diesel::table! {
     devices (subscriber_address, fcm_uid) {
         uid -> Int4,
@@ -9,6 +16,26 @@ diesel::table! {
         subscriber_address -> Varchar,
         language -> Varchar,
         utc_offset_seconds -> Int4,
+        notifications_window_start -> Nullable<Timestamptz>,
+        notifications_in_window -> Int4,
+        platform -> Varchar,
+    }
+}
+
+diesel::table! {
+    event_dedup (event_id) {
+        event_id -> Varchar,
+        processed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    message_payloads (uid) {
+        uid -> Int4,
+        message_uid -> Int4,
+        sent_at -> Timestamptz,
+        payload -> Nullable<Jsonb>,
+        fcm_message_id -> Nullable<Varchar>,
     }
 }
 
@@ -24,6 +51,33 @@ diesel::table! {
         notification_body -> Varchar,
         data -> Nullable<Jsonb>,
         collapse_key -> Nullable<Varchar>,
+        group_key -> Nullable<Varchar>,
+        digest_count -> Nullable<Int4>,
+        event_received_at -> Nullable<Timestamptz>,
+        event_timestamp -> Nullable<Timestamptz>,
+        delivery_style -> Nullable<Varchar>,
+        time_to_live -> Nullable<Int4>,
+        priority -> Nullable<Varchar>,
+        dedup_key -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    removed_devices (uid) {
+        uid -> Int4,
+        device_uid -> Int4,
+        subscriber_address -> Varchar,
+        reason -> Varchar,
+        last_device -> Bool,
+        removed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    service_state (key) {
+        key -> Varchar,
+        value -> Varchar,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -32,6 +86,7 @@ diesel::table! {
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
         address -> Varchar,
+        last_device_removed_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -43,12 +98,25 @@ diesel::table! {
         subscriber_address -> Varchar,
         topic -> Varchar,
         topic_type -> Int4,
+        label -> Nullable<Varchar>,
     }
 }
 
 diesel::table! {
     topics_order_execution (subscription_uid) {
         subscription_uid -> Int4,
+        amount_asset_id -> Nullable<Varchar>,
+        price_asset_id -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    topics_price_percent (subscription_uid) {
+        subscription_uid -> Int4,
+        amount_asset_id -> Varchar,
+        price_asset_id -> Varchar,
+        base_price -> Float8,
+        percent -> Float8,
     }
 }
 
@@ -58,6 +126,9 @@ diesel::table! {
         amount_asset_id -> Varchar,
         price_asset_id -> Varchar,
         price_threshold -> Float8,
+        amount_asset_uid -> Nullable<Int4>,
+        price_asset_uid -> Nullable<Int4>,
+        direction -> Varchar,
     }
 }
 
@@ -65,10 +136,16 @@ diesel::joinable!(devices -> subscribers (subscriber_address));
 diesel::joinable!(subscriptions -> subscribers (subscriber_address));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    asset_ids,
     devices,
+    event_dedup,
+    message_payloads,
     messages,
+    removed_devices,
+    service_state,
     subscribers,
     subscriptions,
     topics_order_execution,
+    topics_price_percent,
     topics_price_threshold,
 );
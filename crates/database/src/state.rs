@@ -0,0 +1,94 @@
+//! Persistent state of the services, like the last processed stream position
+//! or the events already processed
+
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::{
+    error::Error,
+    schema::{event_dedup, service_state},
+};
+
+/// Maintenance mode of the sender, `true` or `false`, overrides the sender config
+pub const SENDER_MAINTENANCE_KEY: &str = "sender_maintenance";
+
+#[derive(Clone)]
+pub struct Repo {}
+
+impl Repo {
+    pub async fn get(
+        &self,
+        key: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Option<String>, Error> {
+        let value = service_state::table
+            .select(service_state::value)
+            .filter(service_state::key.eq(key))
+            .first::<String>(conn)
+            .await
+            .optional()?;
+        Ok(value)
+    }
+
+    pub async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        diesel::insert_into(service_state::table)
+            .values((service_state::key.eq(key), service_state::value.eq(value)))
+            .on_conflict(service_state::key)
+            .do_update()
+            .set((
+                service_state::value.eq(value),
+                service_state::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Records the event as processed, returns `false` if it is recorded already.
+    /// To be called in the transaction enqueuing the messages of the event.
+    pub async fn mark_event_processed(
+        &self,
+        event_id: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let num_rows = diesel::insert_into(event_dedup::table)
+            .values(event_dedup::event_id.eq(event_id))
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+        Ok(num_rows > 0)
+    }
+
+    /// Deletes the ids of the events processed before the cutoff, which are not going to be read again
+    pub async fn delete_processed_events(
+        &self,
+        cutoff: DateTime<Utc>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<usize, Error> {
+        let count = diesel::delete(event_dedup::table)
+            .filter(event_dedup::processed_at.lt(cutoff))
+            .execute(conn)
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn is_event_processed(
+        &self,
+        event_id: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let processed = event_dedup::table
+            .select(event_dedup::event_id)
+            .filter(event_dedup::event_id.eq(event_id))
+            .first::<String>(conn)
+            .await
+            .optional()?;
+        Ok(processed.is_some())
+    }
+}
@@ -1,8 +1,15 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use chrono::{DateTime, Utc};
 use diesel::{
-    dsl::sql_query, ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl, Queryable,
+    dsl::{sql, sql_query},
+    sql_types::Bool,
+    upsert::excluded,
+    ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl, Queryable,
 };
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use itertools::{Either, Itertools};
@@ -11,13 +18,16 @@ use model::{
     asset::{Asset, AssetPair},
     event::Event,
     price::PriceRange,
-    topic::{PriceThreshold, SubscriptionMode, Topic},
+    topic::{PricePercentChange, PriceThreshold, SubscriptionMode, ThresholdDirection, Topic},
     waves::{Address, AsBase58String},
 };
 
 use crate::{
     error::Error,
-    schema::{subscribers, subscriptions, topics_order_execution, topics_price_threshold},
+    schema::{
+        asset_ids, subscribers, subscriptions, topics_order_execution, topics_price_percent,
+        topics_price_threshold,
+    },
 };
 
 #[derive(Debug)]
@@ -27,14 +37,34 @@ pub struct Subscription {
     pub created_at: DateTime<Utc>,
     pub mode: SubscriptionMode,
     pub topic: Topic,
+    pub label: Option<String>,
+}
+
+/// A page of subscriptions, ordered by uid
+#[derive(Debug)]
+pub struct SubscriptionsPage {
+    pub subscriptions: Vec<(Topic, SubscriptionMode, Option<String>)>,
+    /// Cursor to get the next page with, `None` if this page is the last one
+    pub next_cursor: Option<i32>,
+}
+
+/// The part of a subscription which can change without changing its uid,
+/// enough to tell whether the subscriptions of an address have changed
+#[derive(Queryable, Hash, PartialEq, Eq, Debug)]
+pub struct SubscriptionStamp {
+    pub uid: i32,
+    pub topic_type: i32,
+    pub label: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct SubscriptionRequest {
-    /// This field (and the corresponding database column) is not used anymore and can be safely deleted
+    /// This field is not used anymore and can be safely deleted
+    /// (the corresponding database column now holds the canonical topic key)
     pub topic_url: String,
     pub topic: Topic,
     pub mode: SubscriptionMode,
+    pub label: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -43,26 +73,161 @@ pub struct SubscribeConfig {
     pub max_subscriptions_per_address_total: u32,
 }
 
-#[derive(Clone)]
-pub struct Repo {}
+#[derive(Clone, Default)]
+pub struct Repo {
+    subscribed_pairs: Option<Arc<SubscribedPairs>>,
+    asset_uids: Option<Arc<AssetUids>>,
+    with_devices_only: bool,
+}
+
+/// Condition on the matched subscription: its subscriber has registered a device
+const SUBSCRIBER_HAS_DEVICE: &str = "(exists (select 1 from devices \
+     where devices.subscriber_address = subscriptions.subscriber_address))";
+
+/// Asset pairs (as `(amount_asset_id, price_asset_id)`) with at least one price subscription,
+/// kept in memory so that price events for pairs nobody subscribes to skip the database.
+///
+/// The set is reloaded once `refresh_interval` has passed since the last load, so pairs
+/// subscribed to by another service (the API) are picked up within that interval.
+/// A pair found in the set may have been unsubscribed meanwhile, that's why the subscriptions
+/// are still queried for it.
+struct SubscribedPairs {
+    refresh_interval: Duration,
+    loaded: Mutex<Option<LoadedPairs>>,
+}
+
+/// The pairs of `SubscribedPairs`, along with the moment they were loaded at
+type LoadedPairs = (Instant, HashSet<(String, String)>);
+
+impl SubscribedPairs {
+    fn new(refresh_interval: Duration) -> Self {
+        SubscribedPairs {
+            refresh_interval,
+            loaded: Mutex::new(None),
+        }
+    }
+
+    /// Whether the pair may have subscriptions, `None` if the set has to be (re)loaded first
+    fn lookup(&self, pair: &(String, String), now: Instant) -> Option<bool> {
+        match &*self.loaded.lock().expect("lock") {
+            Some((loaded_at, pairs)) if now.duration_since(*loaded_at) < self.refresh_interval => {
+                Some(pairs.contains(pair))
+            }
+            _ => None,
+        }
+    }
+
+    fn replace(&self, pairs: HashSet<(String, String)>, now: Instant) {
+        *self.loaded.lock().expect("lock") = Some((now, pairs));
+    }
+
+    fn insert(&self, pair: (String, String)) {
+        if let Some((_, pairs)) = &mut *self.loaded.lock().expect("lock") {
+            pairs.insert(pair);
+        }
+    }
+}
+
+/// Numeric ids of assets (the `asset_ids` table), which price subscriptions can be matched by
+/// instead of base58 strings. They are filled in by a database trigger on price topics.
+///
+/// An asset never changes its numeric id, so they are cached for good.
+/// Assets having none are not cached, as a new subscription can add one any time.
+#[derive(Default)]
+struct AssetUids {
+    cached: Mutex<HashMap<String, i32>>,
+}
+
+impl AssetUids {
+    fn cached(&self, id: &str) -> Option<i32> {
+        self.cached.lock().expect("lock").get(id).copied()
+    }
+
+    fn insert(&self, id: String, uid: i32) {
+        self.cached.lock().expect("lock").insert(id, uid);
+    }
+
+    /// Numeric id of the asset, `None` if no price subscription has ever referred to it
+    async fn uid(&self, id: String, conn: &mut AsyncPgConnection) -> Result<Option<i32>, Error> {
+        if let Some(uid) = self.cached(&id) {
+            return Ok(Some(uid));
+        }
+        let uid = asset_ids::table
+            .select(asset_ids::uid)
+            .filter(asset_ids::id.eq(&id))
+            .first::<i32>(conn)
+            .await;
+        match uid {
+            Ok(uid) => {
+                self.insert(id, uid);
+                Ok(Some(uid))
+            }
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
 
 impl Repo {
+    /// Repo which skips the subscriptions query for price events of asset pairs
+    /// nobody subscribes to, see `SubscribedPairs`
+    pub fn with_subscribed_pairs(refresh_interval: Duration) -> Self {
+        Repo {
+            subscribed_pairs: Some(Arc::new(SubscribedPairs::new(refresh_interval))),
+            ..Repo::default()
+        }
+    }
+
+    /// Match price subscriptions by numeric asset ids, see `AssetUids`
+    pub fn with_asset_uids(self) -> Self {
+        Repo {
+            asset_uids: Some(Arc::new(AssetUids::default())),
+            ..self
+        }
+    }
+
+    /// Match only the subscriptions of subscribers having a device, so that an event matching
+    /// lots of subscribers without any doesn't cost a devices query per subscription.
+    /// One-shot subscriptions of such subscribers are not completed then, as they don't match.
+    pub fn with_devices_only(self) -> Self {
+        Repo {
+            with_devices_only: true,
+            ..self
+        }
+    }
+
     pub async fn matching(
         &self,
         event: &Event,
         conn: &mut AsyncPgConnection,
     ) -> Result<Vec<Subscription>, Error> {
         match event {
-            Event::OrderExecuted { address, .. } => {
-                self.matching_order_subscriptions(address, conn).await
+            Event::OrderExecuted {
+                address,
+                asset_pair,
+                ..
+            } => {
+                self.matching_order_subscriptions(address, asset_pair, conn)
+                    .await
             }
             Event::PriceChanged {
                 asset_pair,
                 price_range,
                 ..
             } => {
-                self.matching_price_subscriptions(asset_pair, price_range, conn)
-                    .await
+                if !self.may_have_subscriptions(asset_pair, conn).await? {
+                    return Ok(vec![]);
+                }
+                let mut subscriptions = self
+                    .matching_price_subscriptions(asset_pair, price_range, conn)
+                    .await?;
+                subscriptions.extend(
+                    self.matching_percent_subscriptions(asset_pair, price_range, conn)
+                        .await?,
+                );
+                // Ordered by uid as a whole, which chunked processing resumes by
+                subscriptions.sort_by_key(|subscription| subscription.uid);
+                Ok(subscriptions)
             }
         }
     }
@@ -70,9 +235,10 @@ impl Repo {
     async fn matching_order_subscriptions(
         &self,
         address: &Address,
+        asset_pair: &AssetPair,
         conn: &mut AsyncPgConnection,
     ) -> Result<Vec<Subscription>, Error> {
-        let rows = topics_order_execution::table
+        let query = topics_order_execution::table
             .inner_join(
                 subscriptions::table
                     .on(topics_order_execution::subscription_uid.eq(subscriptions::uid)),
@@ -81,23 +247,46 @@ impl Repo {
                 subscriptions::uid,
                 subscriptions::created_at,
                 subscriptions::topic_type,
+                subscriptions::label,
+                topics_order_execution::amount_asset_id,
+                topics_order_execution::price_asset_id,
             ))
             .filter(subscriptions::subscriber_address.eq(address.as_base58_string()))
             .order(subscriptions::uid)
-            .load::<(i32, DateTime<Utc>, i32)>(conn)
+            .into_boxed();
+        let query = if self.with_devices_only {
+            query.filter(sql::<Bool>(SUBSCRIBER_HAS_DEVICE))
+        } else {
+            query
+        };
+        let rows = query
+            .load::<(
+                i32,
+                DateTime<Utc>,
+                i32,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+            )>(conn)
             .await?;
 
-        rows.into_iter()
-            .map(|(uid, created_at, topic_type)| {
-                Ok(Subscription {
-                    uid,
-                    subscriber: address.to_owned(),
-                    created_at,
-                    mode: topic_type_from_int(topic_type)?,
-                    topic: Topic::OrderFulfilled,
-                })
-            })
-            .collect()
+        // An address has only a few order subscriptions, so they are filtered by pair here
+        let mut subscriptions = Vec::with_capacity(rows.len());
+        for (uid, created_at, topic_type, label, amount_asset_id, price_asset_id) in rows {
+            let topic = match skip_bad_asset(uid, order_topic(amount_asset_id, price_asset_id))? {
+                Some(topic) if order_topic_matches(&topic, asset_pair) => topic,
+                _ => continue,
+            };
+            subscriptions.push(Subscription {
+                uid,
+                subscriber: address.to_owned(),
+                created_at,
+                mode: topic_type_from_int(topic_type)?,
+                topic,
+                label,
+            });
+        }
+        Ok(subscriptions)
     }
 
     async fn matching_price_subscriptions(
@@ -107,7 +296,7 @@ impl Repo {
         conn: &mut AsyncPgConnection,
     ) -> Result<Vec<Subscription>, Error> {
         let (price_low, price_high) = price_range.low_high();
-        let rows = topics_price_threshold::table
+        let query = topics_price_threshold::table
             .inner_join(
                 subscriptions::table
                     .on(topics_price_threshold::subscription_uid.eq(subscriptions::uid)),
@@ -117,37 +306,164 @@ impl Repo {
                 subscriptions::subscriber_address,
                 subscriptions::created_at,
                 subscriptions::topic_type,
+                subscriptions::label,
                 topics_price_threshold::price_threshold,
+                topics_price_threshold::direction,
             ))
-            .filter(topics_price_threshold::amount_asset_id.eq(asset_pair.amount_asset.id()))
-            .filter(topics_price_threshold::price_asset_id.eq(asset_pair.price_asset.id()))
             .filter(topics_price_threshold::price_threshold.between(price_low, price_high))
             .order(subscriptions::uid)
-            .load::<(i32, String, DateTime<Utc>, i32, f64)>(conn)
+            .into_boxed();
+        let query = if self.with_devices_only {
+            query.filter(sql::<Bool>(SUBSCRIBER_HAS_DEVICE))
+        } else {
+            query
+        };
+        let query = match &self.asset_uids {
+            Some(asset_uids) => {
+                let amount_asset_uid = asset_uids.uid(asset_pair.amount_asset.id(), conn).await?;
+                let price_asset_uid = asset_uids.uid(asset_pair.price_asset.id(), conn).await?;
+                let (amount_asset_uid, price_asset_uid) =
+                    match amount_asset_uid.zip(price_asset_uid) {
+                        Some(uids) => uids,
+                        // No subscription has ever referred to one of the assets
+                        None => return Ok(vec![]),
+                    };
+                query
+                    .filter(topics_price_threshold::amount_asset_uid.eq(amount_asset_uid))
+                    .filter(topics_price_threshold::price_asset_uid.eq(price_asset_uid))
+            }
+            None => query
+                .filter(topics_price_threshold::amount_asset_id.eq(asset_pair.amount_asset.id()))
+                .filter(topics_price_threshold::price_asset_id.eq(asset_pair.price_asset.id())),
+        };
+        let rows = query
+            .load::<(i32, String, DateTime<Utc>, i32, Option<String>, f64, String)>(conn)
             .await?;
 
-        rows.into_iter()
-            .filter(|&(_, _, _, _, threshold)| {
-                // Since we've used simple BETWEEN filter in SQL query,
-                // there can be extra rows that we need to filter properly.
-                price_range.contains(threshold)
-            })
-            .map(|(uid, address, created_at, topic_type, price_threshold)| {
-                let address =
-                    Address::from_string(&address).map_err(|_| Error::BadAddress(address))?;
-                Ok(Subscription {
-                    uid,
-                    subscriber: address,
-                    created_at,
-                    mode: topic_type_from_int(topic_type)?,
-                    topic: Topic::PriceThreshold(PriceThreshold {
-                        amount_asset: asset_pair.amount_asset.clone(),
-                        price_asset: asset_pair.price_asset.clone(),
-                        price_threshold,
-                    }),
-                })
-            })
-            .collect()
+        let mut subscriptions = Vec::with_capacity(rows.len());
+        for (uid, address, created_at, topic_type, label, price_threshold, direction) in rows {
+            let direction = threshold_direction(direction)?;
+            // Since we've used simple BETWEEN filter in SQL query,
+            // there can be extra rows that we need to filter properly.
+            if !price_range.crosses(price_threshold, direction) {
+                continue;
+            }
+            let address = Address::from_string(&address).map_err(|_| Error::BadAddress(address))?;
+            subscriptions.push(Subscription {
+                uid,
+                subscriber: address,
+                created_at,
+                mode: topic_type_from_int(topic_type)?,
+                topic: Topic::PriceThreshold(PriceThreshold {
+                    amount_asset: asset_pair.amount_asset.clone(),
+                    price_asset: asset_pair.price_asset.clone(),
+                    price_threshold,
+                    direction,
+                }),
+                label,
+            });
+        }
+        Ok(subscriptions)
+    }
+
+    /// Percent change thresholds depend on the base price of each subscription,
+    /// so all of them on the pair are loaded and matched here
+    async fn matching_percent_subscriptions(
+        &self,
+        asset_pair: &AssetPair,
+        price_range: &PriceRange,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<Subscription>, Error> {
+        let query = topics_price_percent::table
+            .inner_join(
+                subscriptions::table
+                    .on(topics_price_percent::subscription_uid.eq(subscriptions::uid)),
+            )
+            .select((
+                subscriptions::uid,
+                subscriptions::subscriber_address,
+                subscriptions::created_at,
+                subscriptions::topic_type,
+                subscriptions::label,
+                topics_price_percent::base_price,
+                topics_price_percent::percent,
+            ))
+            .filter(topics_price_percent::amount_asset_id.eq(asset_pair.amount_asset.id()))
+            .filter(topics_price_percent::price_asset_id.eq(asset_pair.price_asset.id()))
+            .order(subscriptions::uid)
+            .into_boxed();
+        let query = if self.with_devices_only {
+            query.filter(sql::<Bool>(SUBSCRIBER_HAS_DEVICE))
+        } else {
+            query
+        };
+        let rows = query
+            .load::<(i32, String, DateTime<Utc>, i32, Option<String>, f64, f64)>(conn)
+            .await?;
+
+        let mut subscriptions = Vec::with_capacity(rows.len());
+        for (uid, address, created_at, topic_type, label, base_price, percent) in rows {
+            let topic = PricePercentChange {
+                amount_asset: asset_pair.amount_asset.clone(),
+                price_asset: asset_pair.price_asset.clone(),
+                base_price: Some(base_price),
+                percent,
+            };
+            if !percent_change_matches(&topic, price_range) {
+                continue;
+            }
+            let address = Address::from_string(&address).map_err(|_| Error::BadAddress(address))?;
+            subscriptions.push(Subscription {
+                uid,
+                subscriber: address,
+                created_at,
+                mode: topic_type_from_int(topic_type)?,
+                topic: Topic::PricePercentChange(topic),
+                label,
+            });
+        }
+        Ok(subscriptions)
+    }
+
+    async fn may_have_subscriptions(
+        &self,
+        asset_pair: &AssetPair,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let subscribed_pairs = match &self.subscribed_pairs {
+            Some(subscribed_pairs) => subscribed_pairs,
+            None => return Ok(true),
+        };
+
+        let pair = (asset_pair.amount_asset.id(), asset_pair.price_asset.id());
+        if let Some(found) = subscribed_pairs.lookup(&pair, Instant::now()) {
+            return Ok(found);
+        }
+
+        let mut pairs = topics_price_threshold::table
+            .select((
+                topics_price_threshold::amount_asset_id,
+                topics_price_threshold::price_asset_id,
+            ))
+            .distinct()
+            .load::<(String, String)>(conn)
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        pairs.extend(
+            topics_price_percent::table
+                .select((
+                    topics_price_percent::amount_asset_id,
+                    topics_price_percent::price_asset_id,
+                ))
+                .distinct()
+                .load::<(String, String)>(conn)
+                .await?,
+        );
+        log::debug!("Loaded {} subscribed asset pairs", pairs.len());
+        let found = pairs.contains(&pair);
+        subscribed_pairs.replace(pairs, Instant::now());
+        Ok(found)
     }
 
     pub async fn complete_oneshot(
@@ -171,7 +487,16 @@ impl Repo {
         config: &SubscribeConfig,
         conn: &mut AsyncPgConnection,
     ) -> Result<(), Error> {
-        let existing_subscriptions = self.subscriptions(address, conn).await?;
+        // Percent changes are matched against the price at subscription time
+        for sub in &subscriptions {
+            if let Topic::PricePercentChange(t) = &sub.topic {
+                if t.base_price.is_none() {
+                    return Err(Error::UnknownBasePrice(sub.topic.key()));
+                }
+            }
+        }
+
+        let (existing_subscriptions, _) = self.subscriptions(address, None, None, conn).await?;
 
         // Check limits
         {
@@ -187,14 +512,14 @@ impl Repo {
 
             // Check per-pair limit on price subscriptions
             let subscriptions_per_pair = {
-                // Map of: asset pair -> set of prices
+                // Map of: asset pair -> set of price topic keys
                 let mut price_subs =
                     HashMap::<AssetPair, HashSet<_>>::with_capacity(new_subs_count);
 
                 // Topics of existing subscriptions
                 let existing_topics = existing_subscriptions
                     .iter()
-                    .map(|&(_, ref topic, _)| topic);
+                    .map(|&(_, ref topic, _, _)| topic);
 
                 // Topics of new subscriptions
                 let new_topics = subscriptions.iter().map(|sub| &sub.topic);
@@ -202,16 +527,19 @@ impl Repo {
                 // Topics of all subscriptions, old and new
                 let topics = existing_topics.chain(new_topics);
 
-                // Group by asset pair, collect all unique price thresholds
+                // Group by asset pair, collect all unique price thresholds and percent changes
                 for topic in topics {
-                    if let Topic::PriceThreshold(t) = topic {
-                        let pair = AssetPair {
-                            amount_asset: t.amount_asset.clone(),
-                            price_asset: t.price_asset.clone(),
-                        };
-                        let prices = price_subs.entry(pair).or_default();
-                        prices.insert(t.price_threshold.to_bits());
-                    }
+                    let (amount_asset, price_asset) = match topic {
+                        Topic::OrderFulfilled(_) => continue,
+                        Topic::PriceThreshold(t) => (&t.amount_asset, &t.price_asset),
+                        Topic::PricePercentChange(t) => (&t.amount_asset, &t.price_asset),
+                    };
+                    let pair = AssetPair {
+                        amount_asset: amount_asset.clone(),
+                        price_asset: price_asset.clone(),
+                    };
+                    let prices = price_subs.entry(pair).or_default();
+                    prices.insert(topic.key());
                 }
 
                 price_subs
@@ -231,33 +559,46 @@ impl Repo {
         }
 
         // Convert existing subscriptions to a map keyed by topic
-        let existing = HashMap::<Topic, (SubscriptionMode, i32)>::from_iter(
+        let existing = HashMap::<Topic, (SubscriptionMode, Option<String>, i32)>::from_iter(
             existing_subscriptions
                 .into_iter()
-                .map(|(uid, topic, mode)| (topic, (mode, uid))),
+                .map(|(uid, topic, mode, label)| (topic, (mode, label, uid))),
         );
 
         // We need to split the subscriptions into two categories:
-        //  1. Those that exists in database but with different subscription mode (need to update them).
+        //  1. Those that exists in database but with different subscription mode or label (need to update them).
         //  2. Not existing in the database (need to add them).
-        // Those existing in database with the same subscription mode can be safely ignored.
+        // Those existing in database with the same subscription mode and label can be safely ignored.
         let (to_update, to_add) = subscriptions
             .into_iter()
-            .filter(|sub| existing.get(&sub.topic).copied().map(|(mode, _)| mode) != Some(sub.mode))
+            .filter(|sub| {
+                let existing = existing.get(&sub.topic);
+                existing.map(|(mode, label, _)| (*mode, label)) != Some((sub.mode, &sub.label))
+            })
             .partition_map::<Vec<_>, Vec<_>, _, _, _>(|sub| match existing.get(&sub.topic) {
-                Some(&(_, uid)) => Either::Left((uid, sub)),
+                Some(&(_, _, uid)) => Either::Left((uid, sub)),
                 None => Either::Right(sub),
             });
 
         for (uid, sub) in to_update {
             log::debug!("Updating for {:?}: {:?}", address, sub);
             let count = diesel::update(subscriptions::table.filter(subscriptions::uid.eq(uid)))
-                .set(subscriptions::topic_type.eq(topic_type_to_int(sub.mode)))
+                .set((
+                    subscriptions::topic_type.eq(topic_type_to_int(sub.mode)),
+                    subscriptions::label.eq(&sub.label),
+                ))
                 .execute(conn)
                 .await?;
             log::debug!("Updated {} subscriptions for {:?}", count, address);
         }
 
+        // Same topic requested twice - the last one wins, just like with separate requests
+        let to_add = to_add
+            .into_iter()
+            .rev()
+            .unique_by(|sub| sub.topic.key())
+            .collect::<Vec<_>>();
+
         if !to_add.is_empty() {
             log::debug!("Creating subs for {:?}: {:?}", address, to_add);
 
@@ -276,13 +617,22 @@ impl Repo {
                 .map(|sub| {
                     (
                         subscriptions::subscriber_address.eq(&address),
-                        subscriptions::topic.eq(&sub.topic_url),
+                        subscriptions::topic.eq(sub.topic.key()),
                         subscriptions::topic_type.eq(topic_type_to_int(sub.mode)),
+                        subscriptions::label.eq(&sub.label),
                     )
                 })
                 .collect::<Vec<_>>();
+            // A concurrent request could have subscribed to the same topic meanwhile,
+            // in which case that subscription is updated instead of creating a duplicate
             let uids = diesel::insert_into(subscriptions::table)
                 .values(insert_rows)
+                .on_conflict((subscriptions::subscriber_address, subscriptions::topic))
+                .do_update()
+                .set((
+                    subscriptions::topic_type.eq(excluded(subscriptions::topic_type)),
+                    subscriptions::label.eq(excluded(subscriptions::label)),
+                ))
                 .returning(subscriptions::uid)
                 .get_results::<i32>(conn)
                 .await?;
@@ -290,23 +640,41 @@ impl Repo {
 
             // Subscriptions - topic data
             let subs = to_add.into_iter().map(|sub| sub.topic).zip(uids);
-            let (orders, prices) =
-                subs.partition_map::<Vec<_>, Vec<_>, _, _, _>(|(topic, uid)| match topic {
-                    Topic::OrderFulfilled => Either::Left((uid,)),
-                    Topic::PriceThreshold(t) => Either::Right((uid, t)),
-                });
+            let (mut orders, mut prices, mut percents) = (vec![], vec![], vec![]);
+            for (topic, uid) in subs {
+                match topic {
+                    Topic::OrderFulfilled(pair) => orders.push((uid, pair)),
+                    Topic::PriceThreshold(t) => prices.push((uid, t)),
+                    Topic::PricePercentChange(t) => percents.push((uid, t)),
+                }
+            }
             if !orders.is_empty() {
                 let insert_rows = orders
                     .into_iter()
-                    .map(|(uid,)| (topics_order_execution::subscription_uid.eq(uid),))
+                    .map(|(uid, pair)| {
+                        (
+                            topics_order_execution::subscription_uid.eq(uid),
+                            topics_order_execution::amount_asset_id
+                                .eq(pair.as_ref().map(|pair| pair.amount_asset.id())),
+                            topics_order_execution::price_asset_id
+                                .eq(pair.as_ref().map(|pair| pair.price_asset.id())),
+                        )
+                    })
                     .collect::<Vec<_>>();
 
                 diesel::insert_into(topics_order_execution::table)
                     .values(insert_rows)
+                    .on_conflict_do_nothing()
                     .execute(conn)
                     .await?;
             }
             if !prices.is_empty() {
+                if let Some(subscribed_pairs) = &self.subscribed_pairs {
+                    for (_, topic) in &prices {
+                        subscribed_pairs.insert((topic.amount_asset.id(), topic.price_asset.id()));
+                    }
+                }
+
                 let insert_rows = prices
                     .into_iter()
                     .map(|(uid, topic)| {
@@ -315,12 +683,41 @@ impl Repo {
                             topics_price_threshold::amount_asset_id.eq(topic.amount_asset.id()),
                             topics_price_threshold::price_asset_id.eq(topic.price_asset.id()),
                             topics_price_threshold::price_threshold.eq(topic.price_threshold),
+                            topics_price_threshold::direction.eq(topic.direction.as_str()),
                         )
                     })
                     .collect::<Vec<_>>();
 
                 diesel::insert_into(topics_price_threshold::table)
                     .values(insert_rows)
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+            }
+            if !percents.is_empty() {
+                if let Some(subscribed_pairs) = &self.subscribed_pairs {
+                    for (_, topic) in &percents {
+                        subscribed_pairs.insert((topic.amount_asset.id(), topic.price_asset.id()));
+                    }
+                }
+
+                let insert_rows = percents
+                    .into_iter()
+                    .map(|(uid, topic)| {
+                        (
+                            topics_price_percent::subscription_uid.eq(uid),
+                            topics_price_percent::amount_asset_id.eq(topic.amount_asset.id()),
+                            topics_price_percent::price_asset_id.eq(topic.price_asset.id()),
+                            topics_price_percent::base_price
+                                .eq(topic.base_price.expect("checked above")),
+                            topics_price_percent::percent.eq(topic.percent),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                diesel::insert_into(topics_price_percent::table)
+                    .values(insert_rows)
+                    .on_conflict_do_nothing()
                     .execute(conn)
                     .await?;
             }
@@ -341,13 +738,31 @@ impl Repo {
         let conditions = topics
             .iter()
             .map(|t| match t {
-                Topic::OrderFulfilled => format!("(o.subscription_uid IS NOT NULL)"),
+                Topic::OrderFulfilled(None) => {
+                    format!("(o.subscription_uid IS NOT NULL AND o.amount_asset_id IS NULL)")
+                }
+                Topic::OrderFulfilled(Some(pair)) => {
+                    format!(
+                        "(o.amount_asset_id = '{}' AND o.price_asset_id = '{}')",
+                        pair.amount_asset.id(),
+                        pair.price_asset.id(),
+                    )
+                }
                 Topic::PriceThreshold(t) => {
                     format!(
-                        "(p.amount_asset_id = '{}' AND p.price_asset_id = '{}' AND p.price_threshold = {})",
+                        "(p.amount_asset_id = '{}' AND p.price_asset_id = '{}' AND p.price_threshold = {} AND p.direction = '{}')",
                         t.amount_asset.id(),
                         t.price_asset.id(),
                         t.price_threshold,
+                        t.direction.as_str(),
+                    )
+                }
+                Topic::PricePercentChange(t) => {
+                    format!(
+                        "(c.amount_asset_id = '{}' AND c.price_asset_id = '{}' AND c.percent = {})",
+                        t.amount_asset.id(),
+                        t.price_asset.id(),
+                        t.percent,
                     )
                 }
             })
@@ -361,6 +776,7 @@ impl Repo {
                     FROM subscriptions s
                          LEFT OUTER JOIN topics_price_threshold p ON (p.subscription_uid = s.uid)
                          LEFT OUTER JOIN topics_order_execution o ON (o.subscription_uid = s.uid)
+                         LEFT OUTER JOIN topics_price_percent c ON (c.subscription_uid = s.uid)
                     WHERE (s.subscriber_address = '{}') AND ({})
                 )
             "#,
@@ -381,6 +797,45 @@ impl Repo {
         Ok(())
     }
 
+    /// Removes all the subscriptions scoped to the pair: price thresholds, percent changes
+    /// and pair orders. Subscriptions to orders on any pair (bare `push://orders`) are kept.
+    /// The pair is not removed from `SubscribedPairs`, which only costs a query until reloaded.
+    pub async fn unsubscribe_by_pair(
+        &self,
+        address: &Address,
+        pair: &AssetPair,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), Error> {
+        let address = address.as_base58_string();
+
+        // Safe from sql injections, because addresses and asset IDs are safe
+        let query = format!(
+            r#"
+                DELETE FROM subscriptions WHERE uid IN (
+                    SELECT s.uid
+                    FROM subscriptions s
+                         LEFT OUTER JOIN topics_price_threshold p ON (p.subscription_uid = s.uid)
+                         LEFT OUTER JOIN topics_order_execution o ON (o.subscription_uid = s.uid)
+                         LEFT OUTER JOIN topics_price_percent c ON (c.subscription_uid = s.uid)
+                    WHERE (s.subscriber_address = '{}') AND ({})
+                )
+            "#,
+            address,
+            pair_conditions(pair)
+        );
+
+        let count = sql_query(query).execute(conn).await?;
+
+        log::debug!(
+            "Deleted {} subscriptions to {:?} for {}",
+            count,
+            pair,
+            address
+        );
+
+        Ok(())
+    }
+
     pub async fn unsubscribe_all(
         &self,
         address: &Address,
@@ -403,24 +858,80 @@ impl Repo {
         &self,
         address: &Address,
         conn: &mut AsyncPgConnection,
-    ) -> Result<Vec<(Topic, SubscriptionMode)>, Error> {
-        let res = self
-            .subscriptions(address, conn)
-            .await?
+    ) -> Result<Vec<(Topic, SubscriptionMode, Option<String>)>, Error> {
+        let page = self.subscriptions_page(address, None, None, conn).await?;
+        debug_assert!(page.next_cursor.is_none());
+        Ok(page.subscriptions)
+    }
+
+    /// Stamps of all subscriptions of the address, ordered by uid.
+    /// Much cheaper than loading the subscriptions, as topics are not joined.
+    pub async fn subscription_stamps(
+        &self,
+        address: &Address,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<SubscriptionStamp>, Error> {
+        let stamps = subscriptions::table
+            .select((
+                subscriptions::uid,
+                subscriptions::topic_type,
+                subscriptions::label,
+            ))
+            .filter(subscriptions::subscriber_address.eq(address.as_base58_string()))
+            .order(subscriptions::uid)
+            .load::<SubscriptionStamp>(conn)
+            .await?;
+        Ok(stamps)
+    }
+
+    /// Subscriptions with uid greater than `cursor`, at most `limit` of them (all if not set).
+    ///
+    /// Uids only grow, so paging through with the returned cursor yields every subscription
+    /// exactly once, even if subscriptions are added or removed in between.
+    pub async fn subscriptions_page(
+        &self,
+        address: &Address,
+        cursor: Option<i32>,
+        limit: Option<u32>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<SubscriptionsPage, Error> {
+        let (subscriptions, next_cursor) = self.subscriptions(address, cursor, limit, conn).await?;
+        let subscriptions = subscriptions
             .into_iter()
-            .map(|(_, topic, mode)| (topic, mode))
+            .map(|(_, topic, mode, label)| (topic, mode, label))
             .collect();
-        Ok(res)
+        Ok(SubscriptionsPage {
+            subscriptions,
+            next_cursor,
+        })
+    }
+
+    /// Number of subscriptions listed on all the pages, the broken ones skipped there are not counted
+    pub async fn subscriptions_count(
+        &self,
+        address: &Address,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<usize, Error> {
+        let (subscriptions, _) = self.subscriptions(address, None, None, conn).await?;
+        Ok(subscriptions.len())
     }
 
     async fn subscriptions(
         &self,
         address: &Address,
+        after_uid: Option<i32>,
+        limit: Option<u32>,
         conn: &mut AsyncPgConnection,
-    ) -> Result<Vec<(i32, Topic, SubscriptionMode)>, Error> {
+    ) -> Result<
+        (
+            Vec<(i32, Topic, SubscriptionMode, Option<String>)>,
+            Option<i32>,
+        ),
+        Error,
+    > {
         let address = address.as_base58_string();
 
-        let query = subscriptions::table
+        let mut query = subscriptions::table
             .left_outer_join(
                 topics_order_execution::table
                     .on(topics_order_execution::subscription_uid.eq(subscriptions::uid)),
@@ -429,66 +940,649 @@ impl Repo {
                 topics_price_threshold::table
                     .on(topics_price_threshold::subscription_uid.eq(subscriptions::uid)),
             )
+            .left_outer_join(
+                topics_price_percent::table
+                    .on(topics_price_percent::subscription_uid.eq(subscriptions::uid)),
+            )
             .select((
                 subscriptions::uid,
                 subscriptions::topic_type,
+                subscriptions::label,
                 topics_order_execution::subscription_uid.nullable(),
+                topics_order_execution::amount_asset_id.nullable(),
+                topics_order_execution::price_asset_id.nullable(),
                 topics_price_threshold::subscription_uid.nullable(),
                 topics_price_threshold::amount_asset_id.nullable(),
                 topics_price_threshold::price_asset_id.nullable(),
                 topics_price_threshold::price_threshold.nullable(),
+                topics_price_threshold::direction.nullable(),
+                topics_price_percent::subscription_uid.nullable(),
+                topics_price_percent::amount_asset_id.nullable(),
+                topics_price_percent::price_asset_id.nullable(),
+                topics_price_percent::base_price.nullable(),
+                topics_price_percent::percent.nullable(),
             ))
             .filter(subscriptions::subscriber_address.eq(address))
-            .order(subscriptions::uid);
+            .order(subscriptions::uid)
+            .into_boxed();
+
+        if let Some(after_uid) = after_uid {
+            query = query.filter(subscriptions::uid.gt(after_uid));
+        }
+        if let Some(limit) = limit {
+            // One extra row to know whether there are more
+            query = query.limit(limit as i64 + 1);
+        }
 
         #[derive(Queryable)]
         struct Subscription {
             uid: i32,
             topic_type: i32,
+            label: Option<String>,
             order_subscription_uid: Option<i32>,
+            order_amount_asset_id: Option<String>,
+            order_price_asset_id: Option<String>,
             price_subscription_uid: Option<i32>,
             amount_asset_id: Option<String>,
             price_asset_id: Option<String>,
             price_threshold: Option<f64>,
+            direction: Option<String>,
+            percent_subscription_uid: Option<i32>,
+            percent_amount_asset_id: Option<String>,
+            percent_price_asset_id: Option<String>,
+            base_price: Option<f64>,
+            percent: Option<f64>,
         }
 
         let rows = query.load::<Subscription>(conn).await?;
+        let (rows, next_cursor) = take_page(rows, limit, |row| row.uid);
 
         let res = rows
             .into_iter()
             .map(|row| {
                 let uid = row.uid;
 
-                let topic = {
-                    if row.order_subscription_uid.is_some() {
-                        Topic::OrderFulfilled
+                let parse_asset =
+                    |id: String| Asset::from_id(&id).map_err(|()| Error::BadAsset(id));
+                let topic = (|| -> Result<Option<Topic>, Error> {
+                    let topic = if row.order_subscription_uid.is_some() {
+                        order_topic(row.order_amount_asset_id, row.order_price_asset_id)?
                     } else if row.price_subscription_uid.is_some() {
-                        let parse_asset =
-                            |id: String| Asset::from_id(&id).map_err(|()| Error::BadAsset(id));
                         // Unwraps below are safe because of the check `price_subscription_uid.is_some()`
                         // If it fails - database JOIN query is broken
                         Topic::PriceThreshold(PriceThreshold {
                             amount_asset: parse_asset(row.amount_asset_id.unwrap())?,
                             price_asset: parse_asset(row.price_asset_id.unwrap())?,
                             price_threshold: row.price_threshold.unwrap(),
+                            direction: threshold_direction(row.direction.unwrap())?,
+                        })
+                    } else if row.percent_subscription_uid.is_some() {
+                        // Same as above, checked by `percent_subscription_uid.is_some()`
+                        Topic::PricePercentChange(PricePercentChange {
+                            amount_asset: parse_asset(row.percent_amount_asset_id.unwrap())?,
+                            price_asset: parse_asset(row.percent_price_asset_id.unwrap())?,
+                            base_price: row.base_price,
+                            percent: row.percent.unwrap(),
                         })
                     } else {
                         log::warn!("Bad subscription {} (unknown type) - ignored", row.uid);
                         return Ok(None);
-                    }
+                    };
+                    Ok(Some(topic))
+                })();
+                let topic = match skip_bad_asset(uid, topic)?.flatten() {
+                    Some(topic) => topic,
+                    None => return Ok(None),
                 };
 
                 let mode = topic_type_from_int(row.topic_type)?;
 
-                Ok(Some((uid, topic, mode)))
+                Ok(Some((uid, topic, mode, row.label)))
             })
             .filter_map(Result::transpose)
             .collect::<Result<Vec<_>, Error>>()?;
 
-        Ok(res)
+        Ok((res, next_cursor))
+    }
+}
+
+/// Topic of an order subscription by its asset pair columns, which are either both set or not
+fn order_topic(
+    amount_asset_id: Option<String>,
+    price_asset_id: Option<String>,
+) -> Result<Topic, Error> {
+    let parse_asset = |id: String| Asset::from_id(&id).map_err(|()| Error::BadAsset(id));
+    let asset_pair = match amount_asset_id.zip(price_asset_id) {
+        Some((amount_asset_id, price_asset_id)) => Some(AssetPair {
+            amount_asset: parse_asset(amount_asset_id)?,
+            price_asset: parse_asset(price_asset_id)?,
+        }),
+        None => None,
+    };
+    Ok(Topic::OrderFulfilled(asset_pair))
+}
+
+/// Asset ids stored before they were validated (see `Asset::from_id`) may not parse,
+/// such subscriptions are skipped rather than failing all of the address or the event
+fn skip_bad_asset<T>(uid: i32, parsed: Result<T, Error>) -> Result<Option<T>, Error> {
+    match parsed {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(Error::BadAsset(id)) => {
+            log::warn!("Bad subscription {} (asset id {}) - ignored", uid, id);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether an order executed on the pair matches the order topic:
+/// one of the same pair (in the same direction), or one without a pair
+fn order_topic_matches(topic: &Topic, asset_pair: &AssetPair) -> bool {
+    match topic {
+        Topic::OrderFulfilled(None) => true,
+        Topic::OrderFulfilled(Some(pair)) => pair == asset_pair,
+        Topic::PriceThreshold(_) | Topic::PricePercentChange(_) => false,
+    }
+}
+
+/// Whether the price has moved by the percent from the base price within the range,
+/// reaching either of the thresholds
+fn percent_change_matches(topic: &PricePercentChange, price_range: &PriceRange) -> bool {
+    match topic.thresholds() {
+        Some((low, high)) => price_range.contains(low) || price_range.contains(high),
+        None => false,
+    }
+}
+
+#[test]
+fn test_percent_change_matching() {
+    let topic = |base_price| PricePercentChange {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+        base_price,
+        percent: 10.0,
+    };
+    // Thresholds are 90 and 110
+    let topic = topic(Some(100.0));
+    let range = |from: f64, to: f64| PriceRange::empty().extend(from).extend(to);
+
+    assert!(percent_change_matches(&topic, &range(105.0, 115.0)));
+    assert!(percent_change_matches(&topic, &range(95.0, 85.0)));
+    assert!(percent_change_matches(&topic, &range(80.0, 120.0)));
+    assert!(!percent_change_matches(&topic, &range(95.0, 105.0)));
+    assert!(!percent_change_matches(&topic, &range(111.0, 130.0)));
+    assert!(!percent_change_matches(&topic, &range(50.0, 89.0)));
+    assert!(!percent_change_matches(&topic, &PriceRange::empty()));
+
+    let unknown_base_price = PricePercentChange {
+        base_price: None,
+        ..topic
+    };
+    assert!(!percent_change_matches(
+        &unknown_base_price,
+        &range(0.0, 1000.0)
+    ));
+}
+
+#[test]
+fn test_order_topic_matching() {
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    let pair = |amount_asset: &str, price_asset: &str| AssetPair {
+        amount_asset: Asset::from_id(amount_asset).unwrap(),
+        price_asset: Asset::from_id(price_asset).unwrap(),
+    };
+    let waves_usdn = pair("WAVES", USDN);
+    let usdn_waves = pair(USDN, "WAVES");
+
+    // Bare `push://orders` matches orders on any pair
+    let any_pair = order_topic(None, None).unwrap();
+    assert_eq!(any_pair, Topic::OrderFulfilled(None));
+    assert!(order_topic_matches(&any_pair, &waves_usdn));
+    assert!(order_topic_matches(&any_pair, &usdn_waves));
+
+    // Pair-scoped topic matches orders on that pair only
+    let scoped = order_topic(Some("WAVES".to_string()), Some(USDN.to_string())).unwrap();
+    assert_eq!(scoped, Topic::OrderFulfilled(Some(waves_usdn.clone())));
+    assert!(order_topic_matches(&scoped, &waves_usdn));
+    assert!(!order_topic_matches(&scoped, &usdn_waves));
+
+    assert!(matches!(
+        order_topic(Some("!!!".to_string()), Some(USDN.to_string())),
+        Err(Error::BadAsset(_))
+    ));
+}
+
+/// Conditions of `unsubscribe_by_pair` on the joined topic tables
+fn pair_conditions(pair: &AssetPair) -> String {
+    let (amount_asset, price_asset) = (pair.amount_asset.id(), pair.price_asset.id());
+    ["p", "o", "c"]
+        .iter()
+        .map(|table| {
+            format!(
+                "({t}.amount_asset_id = '{}' AND {t}.price_asset_id = '{}')",
+                amount_asset,
+                price_asset,
+                t = table
+            )
+        })
+        .join(" OR ")
+}
+
+#[test]
+fn test_pair_conditions() {
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    let pair = AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id(USDN).unwrap(),
+    };
+    let conditions = pair_conditions(&pair);
+    assert_eq!(
+        conditions,
+        format!(
+            "(p.amount_asset_id = 'WAVES' AND p.price_asset_id = '{USDN}') \
+             OR (o.amount_asset_id = 'WAVES' AND o.price_asset_id = '{USDN}') \
+             OR (c.amount_asset_id = 'WAVES' AND c.price_asset_id = '{USDN}')"
+        )
+    );
+    // Neither the reversed pair, nor orders on any pair (null assets) match
+    assert!(!conditions.contains(&format!("amount_asset_id = '{USDN}'")));
+    assert!(!conditions.contains("IS NULL"));
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_order_subscriptions_by_pair() {
+    use model::{
+        order::{OrderExecution, OrderSide, OrderType},
+        time::Timestamp,
+    };
+
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+
+    let db = crate::testing::TestDb::new();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let pair = |amount_asset: &str, price_asset: &str| AssetPair {
+        amount_asset: Asset::from_id(amount_asset).unwrap(),
+        price_asset: Asset::from_id(price_asset).unwrap(),
+    };
+    let any_pair = || Topic::OrderFulfilled(None);
+    let waves_usdn = || Topic::OrderFulfilled(Some(pair("WAVES", USDN)));
+    let usdn_waves = || Topic::OrderFulfilled(Some(pair(USDN, "WAVES")));
+    let request = |topic: Topic| SubscriptionRequest {
+        topic_url: topic.key(),
+        topic,
+        mode: SubscriptionMode::Repeat,
+        label: None,
+    };
+    let event = |asset_pair| Event::OrderExecuted {
+        order_id: "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq".to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair,
+        execution: OrderExecution::Full,
+        address: address.clone(),
+        timestamp: Timestamp::now(),
+    };
+
+    // Subscriptions are not inserted in the order requested, so compared as sorted topic urls
+    fn keys(topics: impl IntoIterator<Item = Topic>) -> Vec<String> {
+        topics
+            .into_iter()
+            .map(|topic| topic.key())
+            .sorted()
+            .collect()
+    }
+
+    async fn matching(repo: &Repo, event: Event, conn: &mut AsyncPgConnection) -> Vec<String> {
+        let subscriptions = repo.matching(&event, conn).await.unwrap();
+        keys(subscriptions.into_iter().map(|s| s.topic))
+    }
+
+    async fn topics(repo: &Repo, address: &Address, conn: &mut AsyncPgConnection) -> Vec<String> {
+        let subscriptions = repo.subscriptions_by_address(address, conn).await.unwrap();
+        keys(subscriptions.into_iter().map(|(topic, ..)| topic))
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let repo = Repo::default();
+        let requests = vec![
+            request(any_pair()),
+            request(waves_usdn()),
+            request(usdn_waves()),
+        ];
+        repo.subscribe(&address, requests, &config, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            topics(&repo, &address, &mut conn).await,
+            keys([any_pair(), waves_usdn(), usdn_waves()])
+        );
+
+        // Orders on a pair match the subscriptions to that pair (in that direction) and to any
+        assert_eq!(
+            matching(&repo, event(pair("WAVES", USDN)), &mut conn).await,
+            keys([any_pair(), waves_usdn()])
+        );
+        assert_eq!(
+            matching(&repo, event(pair(USDN, "WAVES")), &mut conn).await,
+            keys([any_pair(), usdn_waves()])
+        );
+
+        // Unsubscribing from a pair keeps the subscription to any pair and vice versa
+        repo.unsubscribe(&address, vec![waves_usdn()], &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            topics(&repo, &address, &mut conn).await,
+            keys([any_pair(), usdn_waves()])
+        );
+        repo.unsubscribe(&address, vec![any_pair()], &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            topics(&repo, &address, &mut conn).await,
+            keys([usdn_waves()])
+        );
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_unsubscribe_by_pair() {
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    const BTC: &str = "8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS";
+
+    let db = crate::testing::TestDb::new();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let other = Address::from_string("3PAs2qSeUAfgqSKS8LpZPKGYEjJKcud9Djr").unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let pair = |amount_asset: &str, price_asset: &str| AssetPair {
+        amount_asset: Asset::from_id(amount_asset).unwrap(),
+        price_asset: Asset::from_id(price_asset).unwrap(),
+    };
+    let threshold = |amount_asset, price_asset| {
+        Topic::PriceThreshold(PriceThreshold {
+            amount_asset: Asset::from_id(amount_asset).unwrap(),
+            price_asset: Asset::from_id(price_asset).unwrap(),
+            price_threshold: 10.0,
+            direction: ThresholdDirection::Any,
+        })
+    };
+    let percent = |amount_asset, price_asset| {
+        Topic::PricePercentChange(PricePercentChange {
+            amount_asset: Asset::from_id(amount_asset).unwrap(),
+            price_asset: Asset::from_id(price_asset).unwrap(),
+            base_price: Some(10.0),
+            percent: 5.0,
+        })
+    };
+    // Every topic scoped to WAVES/USDN and its neighbours, which are to be kept
+    let topics = || {
+        vec![
+            threshold("WAVES", USDN),
+            percent("WAVES", USDN),
+            Topic::OrderFulfilled(Some(pair("WAVES", USDN))),
+            threshold(USDN, "WAVES"),
+            threshold(BTC, USDN),
+            Topic::OrderFulfilled(Some(pair(USDN, "WAVES"))),
+            Topic::OrderFulfilled(None),
+        ]
+    };
+    let kept = |topics: Vec<Topic>| topics.into_iter().skip(3).collect::<Vec<_>>();
+    let requests = |topics: Vec<Topic>| {
+        topics
+            .into_iter()
+            .map(|topic| SubscriptionRequest {
+                topic_url: topic.key(),
+                topic,
+                mode: SubscriptionMode::Repeat,
+                label: None,
+            })
+            .collect()
+    };
+
+    // Subscriptions are not inserted in the order requested, so compared as sorted topic urls
+    fn keys(topics: impl IntoIterator<Item = Topic>) -> Vec<String> {
+        topics
+            .into_iter()
+            .map(|topic| topic.key())
+            .sorted()
+            .collect()
+    }
+
+    async fn topics_of(
+        repo: &Repo,
+        address: &Address,
+        conn: &mut AsyncPgConnection,
+    ) -> Vec<String> {
+        let subscriptions = repo.subscriptions_by_address(address, conn).await.unwrap();
+        keys(subscriptions.into_iter().map(|(topic, ..)| topic))
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let repo = Repo::default();
+        for address in [&address, &other] {
+            repo.subscribe(address, requests(topics()), &config, &mut conn)
+                .await
+                .unwrap();
+        }
+
+        repo.unsubscribe_by_pair(&address, &pair("WAVES", USDN), &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            topics_of(&repo, &address, &mut conn).await,
+            keys(kept(topics()))
+        );
+        // Subscriptions of other addresses to the pair are kept
+        assert_eq!(topics_of(&repo, &other, &mut conn).await, keys(topics()));
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_bad_stored_asset_ids() {
+    use model::{
+        order::{OrderExecution, OrderSide, OrderType},
+        time::Timestamp,
+    };
+
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+
+    let db = crate::testing::TestDb::new();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let waves_usdn = || AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id(USDN).unwrap(),
+    };
+    let threshold = |price_threshold| {
+        Topic::PriceThreshold(PriceThreshold {
+            amount_asset: Asset::Waves,
+            price_asset: Asset::from_id(USDN).unwrap(),
+            price_threshold,
+            direction: ThresholdDirection::Any,
+        })
+    };
+    let requests = |topics: Vec<Topic>| {
+        topics
+            .into_iter()
+            .map(|topic| SubscriptionRequest {
+                topic_url: topic.key(),
+                topic,
+                mode: SubscriptionMode::Repeat,
+                label: None,
+            })
+            .collect()
+    };
+    let event = Event::OrderExecuted {
+        order_id: "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq".to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair: waves_usdn(),
+        execution: OrderExecution::Full,
+        address: address.clone(),
+        timestamp: Timestamp::now(),
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let repo = Repo::default();
+        let topics = vec![
+            Topic::OrderFulfilled(None),
+            Topic::OrderFulfilled(Some(waves_usdn())),
+            threshold(10.0),
+        ];
+        repo.subscribe(&address, requests(topics), &config, &mut conn)
+            .await
+            .unwrap();
+
+        // Stored before asset ids were checked to be 32 bytes long
+        let truncate = format!(
+            "update topics_order_execution set price_asset_id = '{}' where price_asset_id = '{}'",
+            &USDN[..USDN.len() / 2],
+            USDN
+        );
+        sql_query(truncate).execute(&mut conn).await.unwrap();
+
+        // The subscription is skipped, but the rest of the address still works
+        let topics = repo.subscriptions_by_address(&address, &mut conn).await;
+        let topics = topics.unwrap().into_iter().map(|(topic, ..)| topic.key());
+        assert_eq!(
+            topics.sorted().collect::<Vec<_>>(),
+            [Topic::OrderFulfilled(None), threshold(10.0)]
+                .map(|topic| topic.key())
+                .into_iter()
+                .sorted()
+                .collect::<Vec<_>>()
+        );
+        let matching = repo.matching(&event, &mut conn).await.unwrap();
+        assert_eq!(matching.len(), 1);
+        assert_eq!(matching[0].topic.key(), Topic::OrderFulfilled(None).key());
+        repo.subscribe(
+            &address,
+            requests(vec![threshold(20.0)]),
+            &config,
+            &mut conn,
+        )
+        .await
+        .unwrap();
+    });
+}
+
+/// Keep at most `limit` rows (of `limit + 1` queried),
+/// the next cursor is set only if some rows were left out.
+fn take_page<R>(
+    mut rows: Vec<R>,
+    limit: Option<u32>,
+    uid: impl Fn(&R) -> i32,
+) -> (Vec<R>, Option<i32>) {
+    match limit {
+        Some(limit) if rows.len() > limit as usize => {
+            rows.truncate(limit as usize);
+            let next_cursor = rows.last().map(uid);
+            (rows, next_cursor)
+        }
+        _ => (rows, None),
     }
 }
 
+#[test]
+fn test_take_page() {
+    // Simulates the paged query over a table of subscription uids
+    fn query(table: &[i32], after_uid: Option<i32>, limit: u32) -> (Vec<i32>, Option<i32>) {
+        let rows = table
+            .iter()
+            .copied()
+            .filter(|&uid| after_uid.map_or(true, |after| uid > after))
+            .take(limit as usize + 1)
+            .collect();
+        take_page(rows, Some(limit), |&uid| uid)
+    }
+
+    let mut table = vec![1, 2, 4, 5, 7];
+    let mut next_uid = 8;
+    let mut seen = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next_cursor) = query(&table, cursor, 2);
+        seen.extend(page);
+        // Concurrent changes between pages
+        if next_uid < 10 {
+            table.push(next_uid);
+            next_uid += 1;
+        }
+        table.retain(|&uid| uid != 1);
+        cursor = next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(seen, vec![1, 2, 4, 5, 7, 8, 9]);
+
+    // Exact fit - no next page
+    assert_eq!(query(&[1, 2], None, 2), (vec![1, 2], None));
+    // Not paged
+    assert_eq!(
+        take_page(vec![1, 2, 3], None, |&uid| uid),
+        (vec![1, 2, 3], None)
+    );
+}
+
+#[test]
+fn test_subscribed_pairs() {
+    let pair =
+        |amount_asset: &str, price_asset: &str| (amount_asset.to_string(), price_asset.to_string());
+    let subscribed = pair("WAVES", "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p");
+    let unsubscribed = pair("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p", "WAVES");
+    let new = pair("WAVES", "34N9YcEETLWn93qYQ64EsP1x89tSruJU44RrEMSXXEPJ");
+
+    let interval = Duration::from_secs(10);
+    let now = Instant::now();
+    let later = |secs| now + Duration::from_secs(secs);
+    let subscribed_pairs = SubscribedPairs::new(interval);
+
+    // Not loaded yet - query the database
+    assert_eq!(subscribed_pairs.lookup(&subscribed, now), None);
+    subscribed_pairs.insert(new.clone());
+    assert_eq!(subscribed_pairs.lookup(&new, now), None);
+
+    // Unsubscribed pairs skip the database, subscribed ones are queried
+    subscribed_pairs.replace(HashSet::from([subscribed.clone()]), now);
+    assert_eq!(subscribed_pairs.lookup(&subscribed, later(9)), Some(true));
+    assert_eq!(
+        subscribed_pairs.lookup(&unsubscribed, later(9)),
+        Some(false)
+    );
+
+    // Subscribed to in this process
+    assert_eq!(subscribed_pairs.lookup(&new, later(9)), Some(false));
+    subscribed_pairs.insert(new.clone());
+    assert_eq!(subscribed_pairs.lookup(&new, later(9)), Some(true));
+
+    // Reloaded after the refresh interval
+    assert_eq!(subscribed_pairs.lookup(&unsubscribed, later(10)), None);
+}
+
+fn threshold_direction(direction: String) -> Result<ThresholdDirection, Error> {
+    ThresholdDirection::parse(&direction).ok_or(Error::BadThresholdDirection(direction))
+}
+
 fn topic_type_from_int(mode: i32) -> Result<SubscriptionMode, Error> {
     match mode {
         0 => Ok(SubscriptionMode::Once),
@@ -504,6 +1598,204 @@ pub fn topic_type_to_int(mode: SubscriptionMode) -> i32 {
     }
 }
 
+#[test]
+#[ignore = "needs Postgres"]
+fn test_matching_by_asset_uids() {
+    use model::time::Timestamp;
+
+    const BTC: &str = "8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS";
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    const UNKNOWN: &str = "34N9YcEETLWn93qYQ64EsP1x89tSruJU44RrEMSXXEPJ";
+
+    let db = crate::testing::TestDb::new();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let asset = |id| match id {
+        "WAVES" => Asset::Waves,
+        id => Asset::from_id(id).unwrap(),
+    };
+    let request = |amount_asset, price_asset, price_threshold| {
+        let topic = Topic::PriceThreshold(PriceThreshold {
+            amount_asset: asset(amount_asset),
+            price_asset: asset(price_asset),
+            price_threshold,
+            direction: ThresholdDirection::Any,
+        });
+        SubscriptionRequest {
+            topic_url: topic.key(),
+            topic,
+            mode: SubscriptionMode::Repeat,
+            label: None,
+        }
+    };
+    // Every threshold is within the range of the event
+    let event = |amount_asset, price_asset| Event::PriceChanged {
+        asset_pair: AssetPair {
+            amount_asset: asset(amount_asset),
+            price_asset: asset(price_asset),
+        },
+        price_range: PriceRange::empty().extend(1.0).extend(10.0),
+        timestamp: Timestamp::now(),
+    };
+
+    async fn matching(repo: &Repo, event: Event, conn: &mut AsyncPgConnection) -> Vec<i32> {
+        let subscriptions = repo.matching(&event, conn).await.unwrap();
+        subscriptions.into_iter().map(|s| s.uid).collect()
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let requests = vec![
+            request("WAVES", USDN, 2.0),
+            request(USDN, "WAVES", 2.0),
+            request("WAVES", USDN, 3.0),
+            request(BTC, USDN, 4.0),
+            request(BTC, "WAVES", 5.0),
+        ];
+        Repo::default()
+            .subscribe(&address, requests, &config, &mut conn)
+            .await
+            .unwrap();
+
+        let by_ids = Repo::default();
+        let by_uids = Repo::default().with_asset_uids();
+        for (amount_asset, price_asset) in [
+            ("WAVES", USDN),
+            (USDN, "WAVES"),
+            (BTC, USDN),
+            (BTC, "WAVES"),
+            ("WAVES", BTC),
+            (UNKNOWN, USDN),
+            (USDN, UNKNOWN),
+        ] {
+            let expected = matching(&by_ids, event(amount_asset, price_asset), &mut conn).await;
+            let found = matching(&by_uids, event(amount_asset, price_asset), &mut conn).await;
+            assert_eq!(found, expected, "{}/{}", amount_asset, price_asset);
+            // Once more, with the uids cached
+            let found = matching(&by_uids, event(amount_asset, price_asset), &mut conn).await;
+            assert_eq!(found, expected, "{}/{}", amount_asset, price_asset);
+        }
+        assert_eq!(
+            matching(&by_uids, event("WAVES", USDN), &mut conn)
+                .await
+                .len(),
+            2
+        );
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_with_devices_only() {
+    use model::{
+        device::Platform,
+        order::{OrderExecution, OrderSide, OrderType},
+        time::Timestamp,
+    };
+
+    const WITH_DEVICE: &str = "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2";
+    const WITHOUT_DEVICE: &str = "3PAs2qSeUAfgqSKS8LpZPKGYEjJKcud9Djr";
+
+    let db = crate::testing::TestDb::new();
+    let address = |address| Address::from_string(address).unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let asset_pair = AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+    };
+    let request = |topic: Topic| SubscriptionRequest {
+        topic_url: topic.key(),
+        topic,
+        mode: SubscriptionMode::Repeat,
+        label: None,
+    };
+    let requests = || {
+        vec![
+            request(Topic::OrderFulfilled(None)),
+            request(Topic::PriceThreshold(PriceThreshold {
+                amount_asset: asset_pair.amount_asset.clone(),
+                price_asset: asset_pair.price_asset.clone(),
+                price_threshold: 2.0,
+                direction: ThresholdDirection::Any,
+            })),
+        ]
+    };
+    let order_event = |subscriber| Event::OrderExecuted {
+        order_id: "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq".to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair: asset_pair.clone(),
+        execution: OrderExecution::Full,
+        address: address(subscriber),
+        timestamp: Timestamp::now(),
+    };
+    let price_event = Event::PriceChanged {
+        asset_pair: asset_pair.clone(),
+        price_range: PriceRange::empty().extend(1.0).extend(10.0),
+        timestamp: Timestamp::now(),
+    };
+
+    async fn matching(repo: &Repo, event: &Event, conn: &mut AsyncPgConnection) -> Vec<String> {
+        let subscriptions = repo.matching(event, conn).await.unwrap();
+        subscriptions
+            .into_iter()
+            .map(|s| s.subscriber.as_base58_string())
+            .collect()
+    }
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        for subscriber in [WITH_DEVICE, WITHOUT_DEVICE] {
+            Repo::default()
+                .subscribe(&address(subscriber), requests(), &config, &mut conn)
+                .await
+                .unwrap();
+        }
+        crate::device::Repo::default()
+            .register(
+                &address(WITH_DEVICE),
+                &"token".to_string(),
+                "en",
+                0,
+                Platform::Web,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+
+        let all = Repo::default();
+        let devices_only = Repo::default().with_devices_only();
+
+        let event = order_event(WITH_DEVICE);
+        assert_eq!(matching(&all, &event, &mut conn).await, [WITH_DEVICE]);
+        assert_eq!(
+            matching(&devices_only, &event, &mut conn).await,
+            [WITH_DEVICE]
+        );
+
+        let event = order_event(WITHOUT_DEVICE);
+        assert_eq!(matching(&all, &event, &mut conn).await, [WITHOUT_DEVICE]);
+        assert!(matching(&devices_only, &event, &mut conn).await.is_empty());
+
+        assert_eq!(
+            matching(&all, &price_event, &mut conn).await,
+            [WITH_DEVICE, WITHOUT_DEVICE]
+        );
+        assert_eq!(
+            matching(&devices_only, &price_event, &mut conn).await,
+            [WITH_DEVICE]
+        );
+    });
+}
+
 #[test]
 fn test_topic_type_to_from_int() {
     let check = |m: i32| {
@@ -514,3 +1806,79 @@ fn test_topic_type_to_from_int() {
     check(0);
     check(1);
 }
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_concurrent_subscribe() {
+    use diesel_async::{scoped_futures::ScopedFutureExt as _, AsyncConnection};
+
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+
+    let db = crate::testing::TestDb::new();
+    let repo = Repo::default();
+    let address = Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap();
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 10,
+        max_subscriptions_per_address_total: 10,
+    };
+    let request = |label: &str| {
+        let topic = Topic::PriceThreshold(PriceThreshold {
+            amount_asset: Asset::Waves,
+            price_asset: Asset::from_id(USDN).unwrap(),
+            price_threshold: 2.5,
+            direction: ThresholdDirection::Any,
+        });
+        SubscriptionRequest {
+            topic_url: topic.key(),
+            topic,
+            mode: SubscriptionMode::Repeat,
+            label: Some(label.to_string()),
+        }
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // The same topic is subscribed to at once, the first transaction is not committed
+        // before the second one looks for existing subscriptions and finds none
+        let (mut conn1, mut conn2) = (db.connect_async().await, db.connect_async().await);
+        let first = conn1.transaction(|conn| {
+            async {
+                repo.subscribe(&address, vec![request("first")], &config, conn)
+                    .await?;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, Error>(())
+            }
+            .scope_boxed()
+        });
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            conn2
+                .transaction(|conn| {
+                    repo.subscribe(&address, vec![request("second")], &config, conn)
+                        .scope_boxed()
+                })
+                .await
+        };
+        let (first, second) = tokio::join!(first, second);
+        first.unwrap();
+        second.unwrap();
+
+        // A single subscription, updated by the request which came last
+        let mut conn = db.connect_async().await;
+        let (subscriptions, _) = repo
+            .subscriptions(&address, None, None, &mut conn)
+            .await
+            .unwrap();
+        let labels = subscriptions
+            .into_iter()
+            .map(|(_, _, _, label)| label)
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![Some("second".to_string())]);
+        let thresholds = topics_price_threshold::table
+            .select(topics_price_threshold::price_threshold)
+            .load::<f64>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(thresholds, vec![2.5]);
+    });
+}
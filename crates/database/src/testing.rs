@@ -0,0 +1,76 @@
+//! Throwaway databases for the tests which need a running Postgres.
+//!
+//! Such tests are `#[ignore]`d, so that a plain `cargo test` doesn't need a server.
+//! Run them with `cargo test -- --ignored`, with the `PG*` variables (see `Config`)
+//! pointing at a server where the user is allowed to create databases.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use diesel::{pg::PgConnection, Connection, RunQueryDsl};
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+use crate::config::Config;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// A new database with all the migrations applied, dropped along with this value
+pub struct TestDb {
+    pub config: Config,
+    server: Config,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let server = Config::load().expect("PG* variables of the test database server");
+        let database = format!(
+            "push_notifications_test_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+        diesel::sql_query(format!("CREATE DATABASE {}", database))
+            .execute(&mut establish(&server))
+            .expect("create test database");
+
+        let config = Config {
+            database,
+            ..server.clone()
+        };
+        establish(&config)
+            .run_pending_migrations(MIGRATIONS)
+            .expect("migrations");
+
+        TestDb { config, server }
+    }
+
+    pub fn connect(&self) -> PgConnection {
+        establish(&self.config)
+    }
+
+    pub async fn connect_async(&self) -> AsyncPgConnection {
+        AsyncPgConnection::establish(&self.config.database_url())
+            .await
+            .expect("connect to test database")
+    }
+}
+
+impl Default for TestDb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let query = format!("DROP DATABASE {} WITH (FORCE)", self.config.database);
+        if let Err(err) = diesel::sql_query(query).execute(&mut establish(&self.server)) {
+            log::warn!("Failed to drop {}: {}", self.config.database, err);
+        }
+    }
+}
+
+fn establish(config: &Config) -> PgConnection {
+    PgConnection::establish(&config.database_url()).expect("connect to test database server")
+}
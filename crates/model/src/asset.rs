@@ -1,5 +1,7 @@
 use std::fmt;
 
+use waves_rust::model::ByteString;
+
 use crate::waves::{AsBase58String, AssetId};
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -11,11 +13,17 @@ pub enum Asset {
 impl Asset {
     pub const WAVES_ASSET_ID: &str = "WAVES";
 
+    /// Issued asset ids are the base58 of a 32-byte transaction id
+    const ASSET_ID_LENGTH: usize = 32;
+
     pub fn from_id(id: &str) -> Result<Self, ()> {
         if id == Self::WAVES_ASSET_ID {
             Ok(Asset::Waves)
         } else {
             let asset_id = AssetId::from_string(id).map_err(|_| ())?;
+            if asset_id.bytes().len() != Self::ASSET_ID_LENGTH {
+                return Err(());
+            }
             Ok(Asset::IssuedAsset(asset_id))
         }
     }
@@ -40,6 +48,10 @@ impl fmt::Display for Asset {
     }
 }
 
+/// Asset pair in the matcher's trading direction.
+///
+/// Equality and hashing take the direction into account, so `WAVES/USDN` and `USDN/WAVES`
+/// are different keys. Use `canonical()` where the direction doesn't matter.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct AssetPair {
     pub amount_asset: Asset,
@@ -50,10 +62,112 @@ impl AssetPair {
     pub fn assets_as_ref(&self) -> (&Asset, &Asset) {
         (&self.amount_asset, &self.price_asset)
     }
+
+    /// The same pair with assets in a stable order (by asset id),
+    /// so that both directions of a pair map to the same key.
+    /// Not meant for pricing: the price of the canonical pair may be the reciprocal one.
+    pub fn canonical(&self) -> AssetPair {
+        if self.amount_asset.id() <= self.price_asset.id() {
+            self.clone()
+        } else {
+            AssetPair {
+                amount_asset: self.price_asset.clone(),
+                price_asset: self.amount_asset.clone(),
+            }
+        }
+    }
+
+    /// `amount_asset_id/price_asset_id`, for logging
+    pub fn display(&self) -> String {
+        format!("{}/{}", self.amount_asset, self.price_asset)
+    }
 }
 
 impl fmt::Debug for AssetPair {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.amount_asset, self.price_asset)
+        write!(f, "{}", self.display())
+    }
+}
+
+impl fmt::Display for AssetPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Asset, AssetPair};
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    const BTC: &str = "8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS";
+
+    fn pair(amount_asset: &str, price_asset: &str) -> AssetPair {
+        AssetPair {
+            amount_asset: Asset::from_id(amount_asset).unwrap(),
+            price_asset: Asset::from_id(price_asset).unwrap(),
+        }
+    }
+
+    fn hash(pair: &AssetPair) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        pair.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_from_id() {
+        assert_eq!(Asset::from_id("WAVES"), Ok(Asset::Waves));
+        assert_eq!(Asset::from_id(USDN).unwrap().id(), USDN);
+        assert_eq!(Asset::from_id(BTC).unwrap().id(), BTC);
+
+        // Too short, not base58 (`0`, `O`, `I`, `l` are not in the alphabet), wrong length
+        assert!(Asset::from_id("").is_err());
+        assert!(Asset::from_id("1234567qwe").is_err());
+        assert!(Asset::from_id("waves").is_err());
+        assert!(Asset::from_id("!!!").is_err());
+        assert!(Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad240").is_err());
+        assert!(Asset::from_id(&USDN[..USDN.len() / 2]).is_err());
+        assert!(Asset::from_id(&format!("{USDN}{USDN}")).is_err());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let direct = pair("WAVES", USDN);
+        let reversed = pair(USDN, "WAVES");
+        assert_ne!(direct, reversed);
+        assert_eq!(direct.canonical(), reversed.canonical());
+        assert_eq!(direct.canonical().canonical(), direct.canonical());
+
+        // Trading direction of the pair itself is not affected
+        assert_eq!(direct.amount_asset, Asset::Waves);
+        assert_eq!(reversed.amount_asset, Asset::from_id(USDN).unwrap());
+
+        let (a, b) = (pair(BTC, USDN), pair(USDN, BTC));
+        assert_eq!(a.canonical(), b.canonical());
+        assert_eq!(a.canonical().amount_asset, Asset::from_id(BTC).unwrap());
+    }
+
+    #[test]
+    fn test_hash() {
+        // Equal pairs hash equally, regardless of how they were obtained
+        assert_eq!(hash(&pair("WAVES", USDN)), hash(&pair("WAVES", USDN)));
+        assert_eq!(
+            hash(&pair(USDN, "WAVES").canonical()),
+            hash(&pair("WAVES", USDN).canonical())
+        );
+        assert_ne!(hash(&pair("WAVES", USDN)), hash(&pair(USDN, "WAVES")));
+    }
+
+    #[test]
+    fn test_display() {
+        let pair = pair("WAVES", USDN);
+        assert_eq!(pair.display(), format!("WAVES/{USDN}"));
+        assert_eq!(pair.to_string(), pair.display());
+        assert_eq!(format!("{:?}", pair), pair.display());
     }
 }
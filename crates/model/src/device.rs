@@ -8,6 +8,7 @@ pub struct Device {
     pub address: Address,
     pub fcm_uid: FcmUid,
     pub locale: LocaleInfo,
+    pub platform: Platform,
 }
 
 #[derive(Debug)]
@@ -17,3 +18,45 @@ pub struct LocaleInfo {
 }
 
 pub type Lang = String;
+
+/// Platform of the app on the device, which notifications are opened differently on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Platform {
+    Ios,
+    Android,
+    #[default]
+    Web,
+}
+
+/// Devices registered before the platform was stored (or with an unknown one) are web
+impl Platform {
+    /// Representation stored in the database and accepted by the API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Ios => "ios",
+            Platform::Android => "android",
+            Platform::Web => "web",
+        }
+    }
+
+    /// Case-insensitive, as the apps report it differently (like `iOS`)
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.to_ascii_lowercase();
+        [Platform::Ios, Platform::Android, Platform::Web]
+            .into_iter()
+            .find(|platform| platform.as_str() == s)
+    }
+}
+
+#[test]
+fn test_platform() {
+    for platform in [Platform::Ios, Platform::Android, Platform::Web] {
+        assert_eq!(Platform::parse(platform.as_str()), Some(platform));
+    }
+    assert_eq!(Platform::parse("iOS"), Some(Platform::Ios));
+    assert_eq!(Platform::parse("windows"), None);
+    assert_eq!(
+        Platform::parse("windows").unwrap_or_default(),
+        Platform::Web
+    );
+}
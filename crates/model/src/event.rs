@@ -9,6 +9,7 @@ use crate::{
 #[derive(Debug)]
 pub enum Event {
     OrderExecuted {
+        order_id: String,
         order_type: OrderType,
         side: OrderSide,
         asset_pair: AssetPair,
@@ -22,3 +23,19 @@ pub enum Event {
         timestamp: Timestamp,
     },
 }
+
+impl Event {
+    pub fn timestamp(&self) -> Timestamp {
+        match self {
+            Event::OrderExecuted { timestamp, .. } => *timestamp,
+            Event::PriceChanged { timestamp, .. } => *timestamp,
+        }
+    }
+
+    pub fn timestamp_mut(&mut self) -> &mut Timestamp {
+        match self {
+            Event::OrderExecuted { timestamp, .. } => timestamp,
+            Event::PriceChanged { timestamp, .. } => timestamp,
+        }
+    }
+}
@@ -6,6 +6,7 @@ pub mod event;
 pub mod message;
 pub mod order;
 pub mod price;
+pub mod secret;
 pub mod time;
 pub mod topic;
 pub mod waves;
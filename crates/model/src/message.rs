@@ -1,12 +1,17 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 use crate::{
     device::Device,
     order::{OrderExecution, OrderSide, OrderType},
     price::Price,
-    time::Timestamp,
+    time::{format_date_time, DateTimeUtc, Timestamp},
 };
 
+/// Placeholders left in localized messages if date and time are formatted at send time
+pub const DEFERRED_DATE: &str = "[%s:date]";
+pub const DEFERRED_TIME: &str = "[%s:time]";
+
 pub enum Message {
     OrderExecuted {
         order_type: OrderType,
@@ -15,12 +20,14 @@ pub enum Message {
         price_asset_ticker: String,
         execution: OrderExecution,
         timestamp: Timestamp,
+        label: Option<String>,
     },
     PriceThresholdReached {
         amount_asset_ticker: String,
         price_asset_ticker: String,
         threshold: Price, // decimals already applied
         timestamp: Timestamp,
+        label: Option<String>,
     },
 }
 
@@ -36,6 +43,99 @@ pub struct PreparedMessage {
     pub message: LocalizedMessage,
     pub data: Option<MessageData>, // JSON-serializable data
     pub collapse_key: Option<String>,
+    pub group_key: Option<String>,
+    pub deep_link: Option<String>,
+    /// When the event the message is about was received, for end-to-end latency metrics
+    pub event_received_at: Timestamp,
+    /// Timestamp of the event the message is about, to format date and time at send time
+    pub event_timestamp: Timestamp,
+    pub delivery_style: DeliveryStyle,
+    /// How long FCM keeps the message for an offline device, FCM default (4 weeks) if not set
+    pub ttl: Option<Duration>,
+    pub priority: MessagePriority,
+    /// Not to be sent before this time (like the end of the quiet hours of the device),
+    /// sent right away if not set
+    pub scheduled_for: Option<DateTimeUtc>,
+}
+
+/// FCM priority: high priority messages wake a sleeping device to be delivered right away,
+/// normal ones may be delayed to save the battery
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MessagePriority {
+    #[default]
+    High,
+    Normal,
+}
+
+impl MessagePriority {
+    /// Representation stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessagePriority::High => "high",
+            MessagePriority::Normal => "normal",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [MessagePriority::High, MessagePriority::Normal]
+            .into_iter()
+            .find(|priority| priority.as_str() == s)
+    }
+}
+
+/// What the FCM message carries: a notification (shown in the tray by the system)
+/// and/or data (passed to the app, which handles data-only messages in the background)
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStyle {
+    #[default]
+    NotificationAndData,
+    DataOnly,
+    NotificationOnly,
+}
+
+impl DeliveryStyle {
+    /// Representation stored in the database, the same as in configs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStyle::NotificationAndData => "notification_and_data",
+            DeliveryStyle::DataOnly => "data_only",
+            DeliveryStyle::NotificationOnly => "notification_only",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            DeliveryStyle::NotificationAndData,
+            DeliveryStyle::DataOnly,
+            DeliveryStyle::NotificationOnly,
+        ]
+        .into_iter()
+        .find(|style| style.as_str() == s)
+    }
+
+    pub fn has_notification(&self) -> bool {
+        *self != DeliveryStyle::DataOnly
+    }
+
+    pub fn has_data(&self) -> bool {
+        *self != DeliveryStyle::NotificationOnly
+    }
+}
+
+/// Fills in the date and time placeholders left at localization (see `DEFERRED_DATE`)
+pub fn fill_date_time(
+    text: &str,
+    timestamp: Timestamp,
+    utc_offset_seconds: i32,
+    lang: &str,
+) -> String {
+    if !text.contains(DEFERRED_DATE) && !text.contains(DEFERRED_TIME) {
+        return text.to_string();
+    }
+    let (date, time) = format_date_time(timestamp, utc_offset_seconds, lang);
+    text.replace(DEFERRED_DATE, &date)
+        .replace(DEFERRED_TIME, &time)
 }
 
 #[derive(Clone, Serialize, Debug)]
@@ -45,17 +145,323 @@ pub enum MessageData {
         amount_asset_id: String,
         price_asset_id: String,
         address: String,
+        /// Only to tell the orders apart, see `dedup_key`
+        #[serde(skip)]
+        order_id: String,
     },
     OrderExecuted {
         amount_asset_id: String,
         price_asset_id: String,
         address: String,
+        /// Only to tell the orders apart, see `dedup_key`
+        #[serde(skip)]
+        order_id: String,
     },
     PriceThresholdReached {
         amount_asset_id: String,
         price_asset_id: String,
         address: String,
     },
+    /// Several price alerts merged into a single notification
+    Digest { count: u32, address: String },
+}
+
+impl MessageData {
+    /// Key for grouping notifications of the same kind in the notification tray.
+    /// Unlike the collapse key, notifications in a group don't replace each other.
+    pub fn group_key(&self) -> &'static str {
+        match self {
+            MessageData::OrderPartiallyExecuted { .. } | MessageData::OrderExecuted { .. } => {
+                "orders"
+            }
+            MessageData::PriceThresholdReached { .. } | MessageData::Digest { .. } => {
+                "price_alerts"
+            }
+        }
+    }
+
+    /// Order executions and price alerts are time-sensitive, a digest is not
+    pub fn priority(&self) -> MessagePriority {
+        match self {
+            MessageData::OrderPartiallyExecuted { .. }
+            | MessageData::OrderExecuted { .. }
+            | MessageData::PriceThresholdReached { .. } => MessagePriority::High,
+            MessageData::Digest { .. } => MessagePriority::Normal,
+        }
+    }
+
+    /// Key for FCM to collapse notifications by: a newer price alert for the same pair replaces
+    /// the one not seen yet (if the device is offline, only the last one is delivered).
+    /// Other notifications don't collapse.
+    pub fn collapse_key(&self) -> Option<String> {
+        match self {
+            MessageData::PriceThresholdReached {
+                amount_asset_id,
+                price_asset_id,
+                ..
+            } => Some(price_collapse_key(amount_asset_id, price_asset_id)),
+            _ => None,
+        }
+    }
+
+    /// Key of the messages which duplicate each other if queued for the same device shortly
+    /// one after another, like alerts on a price oscillating around the threshold.
+    /// Orders are told apart by their ids, as different orders on a pair may well fill at once.
+    /// A digest is not about a single pair, so it has no key.
+    pub fn dedup_key(&self) -> Option<String> {
+        match self {
+            MessageData::OrderPartiallyExecuted { order_id, .. } => {
+                Some(format!("order_partially_executed:{}", order_id))
+            }
+            MessageData::OrderExecuted { order_id, .. } => {
+                Some(format!("order_executed:{}", order_id))
+            }
+            MessageData::PriceThresholdReached {
+                amount_asset_id,
+                price_asset_id,
+                ..
+            } => Some(format!(
+                "price_threshold_reached:{}/{}",
+                amount_asset_id, price_asset_id
+            )),
+            MessageData::Digest { .. } => None,
+        }
+    }
+
+    /// Deep link for the app to open on tap, made of a template like `waves://pair/{amount_asset}/{price_asset}`.
+    /// Supported placeholders are `{amount_asset}`, `{price_asset}` (asset ids) and `{address}`;
+    /// a digest has no pair, so its template can only refer to the address.
+    pub fn deep_link(&self, template: &str) -> String {
+        let (pair, address) = match self {
+            MessageData::OrderPartiallyExecuted {
+                amount_asset_id,
+                price_asset_id,
+                address,
+                ..
+            }
+            | MessageData::OrderExecuted {
+                amount_asset_id,
+                price_asset_id,
+                address,
+                ..
+            }
+            | MessageData::PriceThresholdReached {
+                amount_asset_id,
+                price_asset_id,
+                address,
+            } => (Some((amount_asset_id, price_asset_id)), address),
+            MessageData::Digest { address, .. } => (None, address),
+        };
+        let link = template.replace("{address}", address);
+        match pair {
+            Some((amount_asset_id, price_asset_id)) => link
+                .replace("{amount_asset}", amount_asset_id)
+                .replace("{price_asset}", price_asset_id),
+            None => link,
+        }
+    }
+}
+
+fn price_collapse_key(amount_asset_id: &str, price_asset_id: &str) -> String {
+    format!("price:{}/{}", amount_asset_id, price_asset_id)
+}
+
+#[test]
+fn test_collapse_key() {
+    let alert = |amount_asset_id: &str, price_asset_id: &str, address: &str| {
+        MessageData::PriceThresholdReached {
+            amount_asset_id: amount_asset_id.to_string(),
+            price_asset_id: price_asset_id.to_string(),
+            address: address.to_string(),
+        }
+    };
+    let key = alert("WAVES", "USDN", "address1").collapse_key();
+    assert_eq!(key.as_deref(), Some("price:WAVES/USDN"));
+    // Same pair
+    assert_eq!(alert("WAVES", "USDN", "address2").collapse_key(), key);
+    // Different pairs, including the reversed one
+    assert_ne!(alert("WAVES", "BTC", "address1").collapse_key(), key);
+    assert_ne!(alert("USDN", "WAVES", "address1").collapse_key(), key);
+
+    let order = MessageData::OrderExecuted {
+        amount_asset_id: "WAVES".to_string(),
+        price_asset_id: "USDN".to_string(),
+        address: "address1".to_string(),
+        order_id: "order1".to_string(),
+    };
+    assert_eq!(order.collapse_key(), None);
+    let digest = MessageData::Digest {
+        count: 2,
+        address: "address1".to_string(),
+    };
+    assert_eq!(digest.collapse_key(), None);
+}
+
+#[test]
+fn test_dedup_key() {
+    let alert = |amount_asset_id: &str, address: &str| MessageData::PriceThresholdReached {
+        amount_asset_id: amount_asset_id.to_string(),
+        price_asset_id: "USDN".to_string(),
+        address: address.to_string(),
+    };
+    let key = alert("WAVES", "address1").dedup_key();
+    assert_eq!(key.as_deref(), Some("price_threshold_reached:WAVES/USDN"));
+    // The device is compared separately
+    assert_eq!(alert("WAVES", "address2").dedup_key(), key);
+    assert_ne!(alert("BTC", "address1").dedup_key(), key);
+
+    let order = |order_id: &str| MessageData::OrderExecuted {
+        amount_asset_id: "WAVES".to_string(),
+        price_asset_id: "USDN".to_string(),
+        address: "address1".to_string(),
+        order_id: order_id.to_string(),
+    };
+    let key = order("order1").dedup_key();
+    assert_eq!(key.as_deref(), Some("order_executed:order1"));
+    // Another order on the same pair
+    assert_ne!(order("order2").dedup_key(), key);
+    let digest = MessageData::Digest {
+        count: 2,
+        address: "address1".to_string(),
+    };
+    assert_eq!(digest.dedup_key(), None);
+}
+
+#[test]
+fn test_priority() {
+    for priority in [MessagePriority::High, MessagePriority::Normal] {
+        assert_eq!(MessagePriority::parse(priority.as_str()), Some(priority));
+    }
+    assert_eq!(MessagePriority::parse("urgent"), None);
+
+    let alert = MessageData::PriceThresholdReached {
+        amount_asset_id: "WAVES".to_string(),
+        price_asset_id: "USDN".to_string(),
+        address: "address".to_string(),
+    };
+    assert_eq!(alert.priority(), MessagePriority::High);
+    let digest = MessageData::Digest {
+        count: 2,
+        address: "address".to_string(),
+    };
+    assert_eq!(digest.priority(), MessagePriority::Normal);
+}
+
+#[test]
+fn test_delivery_style() {
+    for style in [
+        DeliveryStyle::NotificationAndData,
+        DeliveryStyle::DataOnly,
+        DeliveryStyle::NotificationOnly,
+    ] {
+        assert_eq!(DeliveryStyle::parse(style.as_str()), Some(style));
+        let config_value = serde_json::Value::from(style.as_str());
+        assert_eq!(
+            serde_json::from_value::<DeliveryStyle>(config_value).unwrap(),
+            style
+        );
+    }
+    assert_eq!(DeliveryStyle::parse("silent"), None);
+    assert_eq!(DeliveryStyle::default(), DeliveryStyle::NotificationAndData);
+}
+
+#[test]
+fn test_fill_date_time() {
+    let timestamp = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    let text = "Filled at [%s:time] on [%s:date]";
+    assert_eq!(
+        fill_date_time(text, timestamp, 0, "xx"),
+        "Filled at 22:13:20 on 2023-11-14"
+    );
+    assert_eq!(
+        fill_date_time(text, timestamp, -5 * 3600, "xx"),
+        "Filled at 17:13:20 on 2023-11-14"
+    );
+    assert_eq!(
+        fill_date_time(text, timestamp, -5 * 3600, "en"),
+        "Filled at 5:13:20 PM on 11/14/2023"
+    );
+    assert_eq!(fill_date_time("Filled", timestamp, 0, "en"), "Filled");
+}
+
+#[test]
+fn test_group_key() {
+    let (amount_asset_id, price_asset_id, address) = (
+        "asset1".to_string(),
+        "asset2".to_string(),
+        "1234567890".to_string(),
+    );
+    let part = MessageData::OrderPartiallyExecuted {
+        amount_asset_id: amount_asset_id.clone(),
+        price_asset_id: price_asset_id.clone(),
+        address: address.clone(),
+        order_id: "order1".to_string(),
+    };
+    let full = MessageData::OrderExecuted {
+        amount_asset_id: amount_asset_id.clone(),
+        price_asset_id: price_asset_id.clone(),
+        address: address.clone(),
+        order_id: "order1".to_string(),
+    };
+    let price = MessageData::PriceThresholdReached {
+        amount_asset_id,
+        price_asset_id,
+        address,
+    };
+    assert_eq!(part.group_key(), "orders");
+    assert_eq!(full.group_key(), part.group_key());
+    assert_eq!(price.group_key(), "price_alerts");
+    let digest = MessageData::Digest {
+        count: 3,
+        address: "1234567890".to_string(),
+    };
+    assert_eq!(digest.group_key(), price.group_key());
+}
+
+#[test]
+fn test_deep_link() {
+    let pair_template = "waves://pair/{amount_asset}/{price_asset}?address={address}";
+    let order = MessageData::OrderExecuted {
+        amount_asset_id: "asset1".to_string(),
+        price_asset_id: "asset2".to_string(),
+        address: "1234567890".to_string(),
+        order_id: "order1".to_string(),
+    };
+    assert_eq!(
+        order.deep_link(pair_template),
+        "waves://pair/asset1/asset2?address=1234567890"
+    );
+    assert_eq!(order.deep_link("waves://orders"), "waves://orders");
+
+    let part = MessageData::OrderPartiallyExecuted {
+        amount_asset_id: "asset1".to_string(),
+        price_asset_id: "WAVES".to_string(),
+        address: "1234567890".to_string(),
+        order_id: "order1".to_string(),
+    };
+    assert_eq!(
+        part.deep_link("waves://orders/{amount_asset}/{price_asset}"),
+        "waves://orders/asset1/WAVES"
+    );
+
+    let price = MessageData::PriceThresholdReached {
+        amount_asset_id: "WAVES".to_string(),
+        price_asset_id: "asset2".to_string(),
+        address: "1234567890".to_string(),
+    };
+    assert_eq!(
+        price.deep_link(pair_template),
+        "waves://pair/WAVES/asset2?address=1234567890"
+    );
+
+    let digest = MessageData::Digest {
+        count: 3,
+        address: "1234567890".to_string(),
+    };
+    assert_eq!(
+        digest.deep_link("waves://alerts?address={address}"),
+        "waves://alerts?address=1234567890"
+    );
 }
 
 #[cfg(test)]
@@ -69,6 +475,7 @@ mod message_data_serialize_tests {
             amount_asset_id: "asset1".to_string(),
             price_asset_id: "asset2".to_string(),
             address: "1234567890".to_string(),
+            order_id: "order1".to_string(),
         };
         let expected_json = json! (
             {
@@ -88,6 +495,7 @@ mod message_data_serialize_tests {
             amount_asset_id: "asset1".to_string(),
             price_asset_id: "asset2".to_string(),
             address: "1234567890".to_string(),
+            order_id: "order1".to_string(),
         };
         let expected_json = json! (
             {
@@ -119,4 +527,21 @@ mod message_data_serialize_tests {
         let value = to_value(data).expect("serialize");
         assert_eq!(value, expected_json);
     }
+
+    #[test]
+    fn test_digest() {
+        let data = MessageData::Digest {
+            count: 3,
+            address: "1234567890".to_string(),
+        };
+        let expected_json = json! (
+            {
+                "type": "digest",
+                "count": 3,
+                "address": "1234567890",
+            }
+        );
+        let value = to_value(data).expect("serialize");
+        assert_eq!(value, expected_json);
+    }
 }
@@ -1,5 +1,7 @@
 use std::fmt;
 
+use crate::topic::ThresholdDirection;
+
 /// Price value as floating point, decimals applied
 pub type Price = f64;
 
@@ -28,6 +30,10 @@ impl fmt::Debug for PriceWithDecimals {
 pub struct PriceRange {
     low: Bound<Price>,
     high: Bound<Price>,
+    /// Price the range was moved from (the previous block price), if known
+    from: Option<Price>,
+    /// Price the range was left at (the block close), if known
+    to: Option<Price>,
 }
 
 #[derive(Clone, Copy, Default, PartialEq, Eq)]
@@ -82,6 +88,8 @@ impl PriceRange {
         PriceRange {
             low: Bound::None,
             high: Bound::None,
+            from: None,
+            to: None,
         }
     }
 
@@ -120,6 +128,44 @@ impl PriceRange {
         }
     }
 
+    /// Whether the price is within the range and was reached in the direction:
+    /// rising to it if the range was moved from a lower price, falling if from a higher one.
+    /// Within the range the price can cross it both ways, so it is also reached rising
+    /// if it went below and was left at or above it, and the other way round.
+    /// Unless it is known where the range was moved from, only `Any` direction is matched.
+    pub fn crosses(&self, price: Price, direction: ThresholdDirection) -> bool {
+        if !self.contains(price) {
+            return false;
+        }
+        let (low, high) = (self.low.value(), self.high.value());
+        match (direction, self.from) {
+            (ThresholdDirection::Any, _) => true,
+            (ThresholdDirection::Above, Some(from)) => {
+                from < price || (low < price && matches!(self.to, Some(to) if to >= price))
+            }
+            (ThresholdDirection::Below, Some(from)) => {
+                from > price || (high > price && matches!(self.to, Some(to) if to <= price))
+            }
+            (ThresholdDirection::Above | ThresholdDirection::Below, None) => false,
+        }
+    }
+
+    /// Set the price the range was moved from, see `crosses`.
+    pub fn moved_from(self, price: Price) -> Self {
+        PriceRange {
+            from: Some(price),
+            ..self
+        }
+    }
+
+    /// Set the price the range was left at, see `crosses`.
+    pub fn moved_to(self, price: Price) -> Self {
+        PriceRange {
+            to: Some(price),
+            ..self
+        }
+    }
+
     /// Extend the range by adding a price to it.
     pub fn extend(self, price: Price) -> Self {
         debug_assert!(self.low.value() <= self.high.value(), "low <= high");
@@ -135,6 +181,7 @@ impl PriceRange {
             } else {
                 self.high
             },
+            ..self
         }
     }
 
@@ -151,6 +198,7 @@ impl PriceRange {
             } else {
                 self.high
             },
+            ..self
         }
     }
 }
@@ -224,3 +272,56 @@ fn test_price_range_contains() {
     assert_eq!(p.contains(3.0), false);
     assert_eq!(p.contains(5.0), false);
 }
+
+#[test]
+fn test_price_range_crosses() {
+    use ThresholdDirection::{Above, Any, Below};
+
+    // Fell from 5 to 3 within the block
+    let p = PriceRange::empty()
+        .extend(3.0)
+        .extend(5.0)
+        .exclude_bound(5.0)
+        .moved_from(5.0);
+    assert!(p.crosses(4.0, Below));
+    assert!(p.crosses(4.0, Any));
+    assert!(!p.crosses(4.0, Above));
+    assert!(p.crosses(3.0, Below));
+    // Out of the range in any direction
+    assert!(!p.crosses(5.0, Below));
+    assert!(!p.crosses(2.0, Any));
+
+    // Rose from 3 to 5
+    let p = PriceRange::empty()
+        .extend(5.0)
+        .extend(3.0)
+        .exclude_bound(3.0)
+        .moved_from(3.0);
+    assert!(p.crosses(4.0, Above));
+    assert!(p.crosses(4.0, Any));
+    assert!(!p.crosses(4.0, Below));
+
+    // Fell from 5 to 3 and rose back to 6 within the block, so 4 is crossed both ways
+    let p = PriceRange::empty()
+        .extend(3.0)
+        .extend(6.0)
+        .extend(5.0)
+        .exclude_bound(5.0)
+        .moved_from(5.0)
+        .moved_to(6.0);
+    assert!(p.crosses(4.0, Above));
+    assert!(p.crosses(4.0, Below));
+    // Only rose through the prices above the previous one
+    assert!(p.crosses(5.5, Above));
+    assert!(!p.crosses(5.5, Below));
+    // Left at the low, so not reached rising again
+    let p = p.moved_to(3.0);
+    assert!(!p.crosses(4.0, Above));
+    assert!(p.crosses(4.0, Below));
+
+    // Unknown previous price
+    let p = PriceRange::empty().extend(3.0).extend(5.0);
+    assert!(p.crosses(4.0, Any));
+    assert!(!p.crosses(4.0, Above));
+    assert!(!p.crosses(4.0, Below));
+}
@@ -0,0 +1,36 @@
+//! Credentials in configs
+
+use std::fmt;
+
+use serde::Deserialize;
+
+/// A credential, which is never printed, even with `{:?}`.
+/// The value is only accessible explicitly, with `expose()`.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "****")
+    }
+}
+
+#[test]
+fn test_secret() {
+    let secret = Secret::new("qwerty".to_string());
+    assert_eq!(format!("{:?}", secret), "****");
+    assert_eq!(format!("{:#?}", secret), "****");
+    assert_eq!(format!("{:?}", Some(secret.clone())), "Some(****)");
+    assert_eq!(secret.expose(), "qwerty");
+}
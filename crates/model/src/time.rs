@@ -1,4 +1,7 @@
-use std::fmt;
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 
@@ -17,6 +20,29 @@ impl Timestamp {
         self.0
     }
 
+    pub fn now() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time before unix epoch");
+        Timestamp(now.as_millis() as i64)
+    }
+
+    /// Whether this timestamp is plausible relative to `now`:
+    /// not later than `now + max_future` and not earlier than `now - max_past` (if given).
+    pub fn is_valid(
+        &self,
+        now: Timestamp,
+        max_future: Duration,
+        max_past: Option<Duration>,
+    ) -> bool {
+        let not_too_late = self.0 <= now.0.saturating_add(max_future.as_millis() as i64);
+        let not_too_early = match max_past {
+            Some(max_past) => self.0 >= now.0.saturating_sub(max_past.as_millis() as i64),
+            None => true,
+        };
+        not_too_late && not_too_early
+    }
+
     pub fn date_time_utc(&self) -> Option<DateTimeUtc> {
         let unix_timestamp = self.unix_timestamp_millis();
         Utc.timestamp_millis_opt(unix_timestamp).earliest()
@@ -38,3 +64,85 @@ impl fmt::Debug for Timestamp {
         }
     }
 }
+
+/// Date and time as shown in notifications, in the timezone and the format of the device
+pub fn format_date_time(
+    timestamp: Timestamp,
+    utc_offset_seconds: i32,
+    lang: &str,
+) -> (String, String) {
+    if let Some(dt) = timestamp.date_time(utc_offset_seconds) {
+        let dt = dt.naive_local();
+        let (date_format, time_format) = date_time_format(lang);
+        let date = dt.date().format(date_format).to_string();
+        let time = dt.time().format(time_format).to_string();
+        (date, time)
+    } else {
+        ("?".to_string(), "?".to_string())
+    }
+}
+
+/// Date and time formats by language (the primary subtag of `lang`, region only matters
+/// for English), ISO 8601 for the languages not in the table
+fn date_time_format(lang: &str) -> (&'static str, &'static str) {
+    let lang = lang.to_ascii_lowercase();
+    let mut subtags = lang.split(|c| c == '-' || c == '_');
+    let primary = subtags.next().unwrap_or_default();
+    let region = subtags.next();
+    match (primary, region) {
+        ("en", None | Some("us")) => ("%m/%d/%Y", "%-I:%M:%S %p"),
+        ("en", Some(_)) => ("%d/%m/%Y", "%H:%M:%S"),
+        ("de" | "ru" | "uk" | "be" | "kk" | "pl" | "cs" | "fi" | "tr" | "nb" | "da", _) => {
+            ("%d.%m.%Y", "%H:%M:%S")
+        }
+        ("fr" | "es" | "it" | "pt" | "vi" | "id" | "el", _) => ("%d/%m/%Y", "%H:%M:%S"),
+        ("nl", _) => ("%d-%m-%Y", "%H:%M:%S"),
+        ("ja" | "zh", _) => ("%Y/%m/%d", "%H:%M:%S"),
+        ("ko", _) => ("%Y.%m.%d", "%H:%M:%S"),
+        _ => ("%Y-%m-%d", "%H:%M:%S"),
+    }
+}
+
+#[test]
+fn test_format_date_time() {
+    let timestamp = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    let format = |utc_offset_seconds, lang| {
+        let (date, time) = format_date_time(timestamp, utc_offset_seconds, lang);
+        format!("{} {}", date, time)
+    };
+
+    // Day/month order, 12h vs 24h
+    assert_eq!(format(0, "en"), "11/14/2023 10:13:20 PM");
+    assert_eq!(format(0, "en-US"), "11/14/2023 10:13:20 PM");
+    assert_eq!(format(0, "en_GB"), "14/11/2023 22:13:20");
+    assert_eq!(format(0, "de"), "14.11.2023 22:13:20");
+    assert_eq!(format(0, "pt-BR"), "14/11/2023 22:13:20");
+    assert_eq!(format(0, "ja"), "2023/11/14 22:13:20");
+
+    // In the device timezone
+    assert_eq!(format(3 * 3600, "en"), "11/15/2023 1:13:20 AM");
+    assert_eq!(format(3 * 3600, "ru"), "15.11.2023 01:13:20");
+
+    // Unknown language
+    assert_eq!(format(0, "xx"), "2023-11-14 22:13:20");
+    assert_eq!(format(-5 * 3600, ""), "2023-11-14 17:13:20");
+}
+
+#[test]
+fn test_is_valid() {
+    let now = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    let hour = Duration::from_secs(3600);
+    let ts = |offset_ms: i64| Timestamp::from_unix_timestamp_millis(now.0 + offset_ms);
+
+    assert!(now.is_valid(now, hour, None));
+    assert!(ts(3_600_000).is_valid(now, hour, None));
+    assert!(!ts(3_600_001).is_valid(now, hour, None));
+    assert!(ts(-1_000_000_000).is_valid(now, hour, None));
+    assert!(ts(-3_600_000).is_valid(now, hour, Some(hour)));
+    assert!(!ts(-3_600_001).is_valid(now, hour, Some(hour)));
+    assert!(Timestamp::from_unix_timestamp_millis(i64::MAX).is_valid(
+        Timestamp::from_unix_timestamp_millis(i64::MAX),
+        hour,
+        Some(hour)
+    ));
+}
@@ -1,6 +1,9 @@
 use std::hash::{Hash, Hasher};
 
-use crate::{asset::Asset, price::Price};
+use crate::{
+    asset::{Asset, AssetPair},
+    price::Price,
+};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum SubscriptionMode {
@@ -10,8 +13,10 @@ pub enum SubscriptionMode {
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Topic {
-    OrderFulfilled,
+    /// Executions of the subscriber's orders on the pair, or on any pair if not set
+    OrderFulfilled(Option<AssetPair>),
     PriceThreshold(PriceThreshold),
+    PricePercentChange(PricePercentChange),
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,6 +24,78 @@ pub struct PriceThreshold {
     pub amount_asset: Asset,
     pub price_asset: Asset,
     pub price_threshold: Price,
+    pub direction: ThresholdDirection,
+}
+
+/// Price moving by `percent` (either way) from the price at subscription time
+#[derive(Debug)]
+pub struct PricePercentChange {
+    pub amount_asset: Asset,
+    pub price_asset: Asset,
+    /// The latest known price when subscribed, `None` until the subscription is made.
+    /// Not a part of the topic identity, see `Topic::key`.
+    pub base_price: Option<Price>,
+    pub percent: f64,
+}
+
+impl PricePercentChange {
+    /// Prices `percent` below and above the base price, `None` if it is not known yet
+    pub fn thresholds(&self) -> Option<(Price, Price)> {
+        let base_price = self.base_price?;
+        let change = base_price * self.percent / 100.0;
+        Some((base_price - change, base_price + change))
+    }
+}
+
+impl PartialEq for PricePercentChange {
+    fn eq(&self, other: &Self) -> bool {
+        self.amount_asset == other.amount_asset
+            && self.price_asset == other.price_asset
+            && self.percent == other.percent
+    }
+}
+
+impl Eq for PricePercentChange {} // Same as for `PriceThreshold`
+
+impl Hash for PricePercentChange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.amount_asset.hash(state);
+        self.price_asset.hash(state);
+        self.percent.to_bits().hash(state);
+    }
+}
+
+/// Which way the price has to move through the threshold to notify the subscriber
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum ThresholdDirection {
+    /// Price rose to the threshold
+    Above,
+    /// Price fell to the threshold
+    Below,
+    /// Price reached the threshold either way
+    #[default]
+    Any,
+}
+
+impl ThresholdDirection {
+    /// Representation stored in the database and used in topic urls
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThresholdDirection::Above => "above",
+            ThresholdDirection::Below => "below",
+            ThresholdDirection::Any => "any",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            ThresholdDirection::Above,
+            ThresholdDirection::Below,
+            ThresholdDirection::Any,
+        ]
+        .into_iter()
+        .find(|direction| direction.as_str() == s)
+    }
 }
 
 impl Eq for PriceThreshold {} // Ignore the fact that Topic can contain `f64`
@@ -28,5 +105,101 @@ impl Hash for PriceThreshold { // Has to impl Hash manually because of `f64` ins
         self.amount_asset.hash(state);
         self.price_asset.hash(state);
         self.price_threshold.to_bits().hash(state);
+        self.direction.hash(state);
     }
 }
+
+impl Topic {
+    /// Canonical identity of the topic, regardless of how its url was written.
+    /// Stored with subscriptions to keep them unique per address.
+    pub fn key(&self) -> String {
+        match self {
+            Topic::OrderFulfilled(None) => "orders".to_string(),
+            Topic::OrderFulfilled(Some(pair)) => format!(
+                "orders/{}/{}",
+                pair.amount_asset.id(),
+                pair.price_asset.id()
+            ),
+            // Keys of topics without a direction are the same as before directions were added
+            Topic::PriceThreshold(t) => match t.direction {
+                ThresholdDirection::Any => format!(
+                    "price_threshold/{}/{}/{:016x}",
+                    t.amount_asset.id(),
+                    t.price_asset.id(),
+                    t.price_threshold.to_bits(),
+                ),
+                direction => format!(
+                    "price_threshold/{}/{}/{:016x}/{}",
+                    t.amount_asset.id(),
+                    t.price_asset.id(),
+                    t.price_threshold.to_bits(),
+                    direction.as_str(),
+                ),
+            },
+            Topic::PricePercentChange(t) => format!(
+                "price_percent/{}/{}/{:016x}",
+                t.amount_asset.id(),
+                t.price_asset.id(),
+                t.percent.to_bits(),
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_topic_key() {
+    let directed_price_threshold = |threshold, direction| {
+        Topic::PriceThreshold(PriceThreshold {
+            amount_asset: Asset::Waves,
+            price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+            price_threshold: threshold,
+            direction,
+        })
+    };
+    let price_threshold = |threshold| directed_price_threshold(threshold, ThresholdDirection::Any);
+    assert_eq!(Topic::OrderFulfilled(None).key(), "orders");
+    let pair = AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+    };
+    assert_eq!(
+        Topic::OrderFulfilled(Some(pair)).key(),
+        "orders/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p"
+    );
+    // Same as `encode(float8send(price_threshold), 'hex')` in Postgres
+    assert_eq!(
+        price_threshold(1.5).key(),
+        "price_threshold/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p/3ff8000000000000"
+    );
+    assert_eq!(price_threshold(1.5).key(), price_threshold(3.0 / 2.0).key());
+    assert_ne!(price_threshold(1.5).key(), price_threshold(1.25).key());
+    assert_eq!(
+        directed_price_threshold(1.5, ThresholdDirection::Below).key(),
+        "price_threshold/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p/3ff8000000000000/below"
+    );
+    assert_ne!(
+        directed_price_threshold(1.5, ThresholdDirection::Above).key(),
+        directed_price_threshold(1.5, ThresholdDirection::Below).key()
+    );
+}
+
+#[test]
+fn test_price_percent_change() {
+    let topic = |base_price| PricePercentChange {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+        base_price,
+        percent: 5.0,
+    };
+    assert_eq!(topic(Some(2.0)).thresholds(), Some((1.9, 2.1)));
+    assert_eq!(topic(None).thresholds(), None);
+
+    // Subscribed again later, at another price - still the same topic
+    assert_eq!(topic(Some(2.0)), topic(Some(3.0)));
+    let key = Topic::PricePercentChange(topic(Some(2.0))).key();
+    assert_eq!(key, Topic::PricePercentChange(topic(None)).key());
+    assert_eq!(
+        key,
+        "price_percent/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p/4014000000000000"
+    );
+}
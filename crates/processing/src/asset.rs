@@ -10,11 +10,15 @@ type Ticker = String;
 #[derive(Debug, Clone)]
 struct LocalAssetInfo {
     ticker: Option<Ticker>,
+    decimals: u8,
 }
 
 #[derive(Clone)]
 pub struct RemoteGateway {
     assets_client: HttpClient<AssetsService>,
+    /// Assets known without asking the service
+    #[cfg(test)]
+    known: std::collections::HashMap<Asset, LocalAssetInfo>,
 }
 
 pub type GatewayError = LoaderError<wavesexchange_apis::Error>;
@@ -23,7 +27,31 @@ impl RemoteGateway {
     pub fn new(asset_service_url: impl AsRef<str>) -> Self {
         let url = asset_service_url.as_ref();
         let assets_client = HttpClient::<AssetsService>::from_base_url(url);
-        RemoteGateway { assets_client }
+        RemoteGateway {
+            assets_client,
+            #[cfg(test)]
+            known: Default::default(),
+        }
+    }
+
+    /// Gateway knowing the tickers of the given assets (with 8 decimals),
+    /// so that the tests don't need the assets service
+    #[cfg(test)]
+    pub(crate) fn with_tickers(tickers: &[(Asset, &str)]) -> Self {
+        let known = tickers
+            .iter()
+            .map(|(asset, ticker)| {
+                let info = LocalAssetInfo {
+                    ticker: Some(ticker.to_string()),
+                    decimals: 8,
+                };
+                (asset.clone(), info)
+            })
+            .collect();
+        RemoteGateway {
+            known,
+            ..RemoteGateway::new("http://assets.test")
+        }
     }
 
     pub async fn preload(&self, assets: Vec<Asset>) -> Result<(), GatewayError> {
@@ -35,7 +63,15 @@ impl RemoteGateway {
         self.asset_info(asset).await.map(|a| a.ticker)
     }
 
+    pub async fn decimals(&self, asset: &Asset) -> Result<u8, GatewayError> {
+        self.asset_info(asset).await.map(|a| a.decimals)
+    }
+
     async fn asset_info(&self, asset: &Asset) -> Result<LocalAssetInfo, GatewayError> {
+        #[cfg(test)]
+        if let Some(info) = self.known.get(asset) {
+            return Ok(info.clone());
+        }
         self.load(asset.to_owned()).await
     }
 }
@@ -59,7 +95,10 @@ impl CachedLoader<Asset, LocalAssetInfo> for RemoteGateway {
             .into_iter()
             .zip(keys)
             .map(|(asset, asset_id)| match asset.data {
-                Some(AssetInfo::Full(a)) => LocalAssetInfo { ticker: a.ticker },
+                Some(AssetInfo::Full(a)) => LocalAssetInfo {
+                    ticker: a.ticker,
+                    decimals: a.precision as u8,
+                },
                 Some(AssetInfo::Brief(_)) => {
                     unreachable!("Broken API: Full info expected for asset {}", asset_id);
                 }
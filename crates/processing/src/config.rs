@@ -0,0 +1,256 @@
+//! Event processing config
+
+use model::message::{DeliveryStyle, MessageData};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProcessingConfig {
+    /// Event timestamps later than `now + this` are replaced with the current time
+    #[serde(default = "default_event_timestamp_max_future_sec")]
+    pub event_timestamp_max_future_sec: u32,
+
+    /// Event timestamps earlier than `now - this` are replaced with the current time.
+    /// Not checked if not set.
+    pub event_timestamp_max_past_sec: Option<u32>,
+
+    /// Group notifications of the same kind (orders, price alerts) in the notification tray
+    #[serde(default = "default_notification_grouping")]
+    pub notification_grouping: bool,
+
+    /// Price alerts for a device are held back for this long and merged into a single
+    /// digest notification if more alerts arrive meanwhile. Disabled if not set.
+    pub digest_window_sec: Option<u32>,
+
+    /// A message is not enqueued if one with the same dedup key (see `MessageData::dedup_key`)
+    /// was queued for the device within this long and is not sent yet. Disabled if not set.
+    pub dedup_window_sec: Option<u32>,
+
+    /// Notify about events involving assets without a ticker, referring to them by id.
+    /// If disabled, such events are skipped.
+    #[serde(default = "default_notify_unlisted_assets")]
+    pub notify_unlisted_assets: bool,
+
+    /// File listing asset pairs with muted price notifications (see `muted_pairs` module)
+    pub muted_pairs_file: Option<String>,
+
+    /// File listing the only asset pairs with price notifications, the rest are muted.
+    /// All pairs are allowed if not set.
+    pub allowed_pairs_file: Option<String>,
+
+    /// How often the muted and allowed pairs files are re-read
+    #[serde(default = "default_muted_pairs_reload_interval_sec")]
+    pub muted_pairs_reload_interval_sec: u32,
+
+    /// Deep link templates of order, price alert and digest notifications
+    /// (see `MessageData::deep_link`). No deep link is added if not set.
+    pub deep_link_order: Option<String>,
+    pub deep_link_price_alert: Option<String>,
+    pub deep_link_digest: Option<String>,
+
+    /// Delivery style of order, price alert and digest notifications (see `DeliveryStyle`)
+    #[serde(default)]
+    pub delivery_style_order: DeliveryStyle,
+    #[serde(default)]
+    pub delivery_style_price_alert: DeliveryStyle,
+    #[serde(default)]
+    pub delivery_style_digest: DeliveryStyle,
+
+    /// How long FCM keeps order and price alert (digest too) notifications for an offline
+    /// device: a price alert delivered hours late is of no use
+    #[serde(default = "default_ttl_order_sec")]
+    pub ttl_order_sec: u32,
+    #[serde(default = "default_ttl_price_alert_sec")]
+    pub ttl_price_alert_sec: u32,
+
+    /// Maximum number of notifications per device within the cap window,
+    /// the rest are dropped. Not limited if not set.
+    pub device_notification_cap: Option<u32>,
+
+    #[serde(default = "default_device_notification_cap_window_sec")]
+    pub device_notification_cap_window_sec: u32,
+
+    /// Hours of the day (0-23, local time of the device) during which notifications
+    /// are not delivered but deferred until the end hour. The hours may wrap around midnight
+    /// (like 22 to 8). Disabled unless both are set.
+    pub quiet_hours_start: Option<u8>,
+    pub quiet_hours_end: Option<u8>,
+
+    /// Price subscriptions only match price events timestamped later than
+    /// their creation time plus this, so that they don't fire on price movements
+    /// which predate them. Matched right away if not set.
+    pub new_subscription_grace_sec: Option<u32>,
+
+    /// Commit the messages of an event in chunks of this many subscriptions,
+    /// each in its own transaction, rather than all at once. Disabled if not set.
+    pub subscriptions_chunk_size: Option<u32>,
+
+    /// Match only the subscriptions of subscribers having a device registered
+    /// (see `subscription::Repo::with_devices_only`)
+    #[serde(default)]
+    pub skip_subscribers_without_devices: bool,
+
+    /// Matching subscriptions (along with their devices) logged individually per event
+    /// at debug level, the rest are only counted in a summary line
+    #[serde(default = "default_max_logged_subscriptions")]
+    pub max_logged_subscriptions: u32,
+
+    /// Languages to localize a notification in if the device language is not translated,
+    /// in order of preference
+    #[serde(default = "default_fallback_langs")]
+    pub fallback_langs: Vec<String>,
+
+    /// Ids of the processed events (see `EventWithFeedback::event_id`) are kept this long,
+    /// while the sources may read the events again, and purged afterwards
+    #[serde(default = "default_processed_event_retention_sec")]
+    pub processed_event_retention_sec: u32,
+}
+
+fn default_event_timestamp_max_future_sec() -> u32 {
+    3600
+}
+
+fn default_notification_grouping() -> bool {
+    true
+}
+
+fn default_notify_unlisted_assets() -> bool {
+    true
+}
+
+fn default_muted_pairs_reload_interval_sec() -> u32 {
+    60
+}
+
+fn default_ttl_order_sec() -> u32 {
+    86400
+}
+
+fn default_ttl_price_alert_sec() -> u32 {
+    3600
+}
+
+fn default_device_notification_cap_window_sec() -> u32 {
+    3600
+}
+
+fn default_max_logged_subscriptions() -> u32 {
+    100
+}
+
+fn default_fallback_langs() -> Vec<String> {
+    vec!["en".to_string()]
+}
+
+fn default_processed_event_retention_sec() -> u32 {
+    604800
+}
+
+impl ProcessingConfig {
+    pub fn load() -> Result<Self, envy::Error> {
+        Self::checked(envy::from_env::<ProcessingConfig>()?)
+    }
+
+    fn checked(config: ProcessingConfig) -> Result<Self, envy::Error> {
+        let hours = [config.quiet_hours_start, config.quiet_hours_end];
+        if hours.into_iter().flatten().any(|hour| hour > 23) {
+            return Err(envy::Error::Custom(
+                "quiet_hours_start and quiet_hours_end must be within 0-23".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn event_timestamp_max_future(&self) -> Duration {
+        Duration::from_secs(self.event_timestamp_max_future_sec as u64)
+    }
+
+    pub fn event_timestamp_max_past(&self) -> Option<Duration> {
+        self.event_timestamp_max_past_sec
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    pub fn muted_pairs_reload_interval(&self) -> Duration {
+        Duration::from_secs(self.muted_pairs_reload_interval_sec as u64)
+    }
+
+    pub fn device_notification_cap_window(&self) -> Duration {
+        Duration::from_secs(self.device_notification_cap_window_sec as u64)
+    }
+
+    pub fn new_subscription_grace(&self) -> Option<Duration> {
+        self.new_subscription_grace_sec
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    pub fn processed_event_retention(&self) -> Duration {
+        Duration::from_secs(self.processed_event_retention_sec as u64)
+    }
+
+    pub fn subscriptions_chunk_size(&self) -> Option<usize> {
+        self.subscriptions_chunk_size
+            .filter(|&size| size > 0)
+            .map(|size| size as usize)
+    }
+
+    pub fn delivery_style(&self, data: &MessageData) -> DeliveryStyle {
+        match data {
+            MessageData::OrderPartiallyExecuted { .. } | MessageData::OrderExecuted { .. } => {
+                self.delivery_style_order
+            }
+            MessageData::PriceThresholdReached { .. } => self.delivery_style_price_alert,
+            MessageData::Digest { .. } => self.delivery_style_digest,
+        }
+    }
+
+    pub fn ttl(&self, data: &MessageData) -> Duration {
+        let secs = match data {
+            MessageData::OrderPartiallyExecuted { .. } | MessageData::OrderExecuted { .. } => {
+                self.ttl_order_sec
+            }
+            MessageData::PriceThresholdReached { .. } | MessageData::Digest { .. } => {
+                self.ttl_price_alert_sec
+            }
+        };
+        Duration::from_secs(secs as u64)
+    }
+
+    pub fn deep_link(&self, data: &MessageData) -> Option<String> {
+        let template = match data {
+            MessageData::OrderPartiallyExecuted { .. } | MessageData::OrderExecuted { .. } => {
+                &self.deep_link_order
+            }
+            MessageData::PriceThresholdReached { .. } => &self.deep_link_price_alert,
+            MessageData::Digest { .. } => &self.deep_link_digest,
+        };
+        template.as_deref().map(|template| data.deep_link(template))
+    }
+
+    pub fn digest_window(&self) -> Option<Duration> {
+        self.digest_window_sec
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    pub fn dedup_window(&self) -> Option<Duration> {
+        self.dedup_window_sec
+            .map(|secs| Duration::from_secs(secs as u64))
+    }
+}
+
+#[test]
+fn test_checked() {
+    let load = |vars: &[(&str, &str)]| {
+        let vars = vars.iter().map(|&(k, v)| (k.to_string(), v.to_string()));
+        ProcessingConfig::checked(envy::from_iter::<_, ProcessingConfig>(vars).unwrap())
+    };
+
+    let config = load(&[("QUIET_HOURS_START", "22"), ("QUIET_HOURS_END", "0")]).unwrap();
+    assert_eq!(config.quiet_hours_start, Some(22));
+    assert_eq!(config.quiet_hours_end, Some(0));
+    assert!(load(&[("QUIET_HOURS_END", "23")]).is_ok());
+
+    let err = load(&[("QUIET_HOURS_START", "22"), ("QUIET_HOURS_END", "24")]);
+    assert!(matches!(err, Err(envy::Error::Custom(msg)) if msg.contains("quiet_hours")));
+    let err = load(&[("QUIET_HOURS_START", "30")]);
+    assert!(matches!(err, Err(envy::Error::Custom(_))));
+}
@@ -5,13 +5,23 @@ extern crate async_trait;
 
 extern crate wavesexchange_log as log;
 
+mod config;
 mod error;
 mod processing;
+mod stats;
+#[cfg(test)]
+mod testing;
 
 pub mod asset;
 pub mod localization;
+pub mod log_levels;
+pub mod metrics;
+pub mod muted_pairs;
+pub mod readiness;
 
 pub use crate::{
+    config::ProcessingConfig,
     error::Error,
-    processing::{EventWithFeedback, MessagePump},
+    processing::{Checkpoint, EventWithFeedback, MessagePump},
+    stats::Summary,
 };
@@ -1,34 +1,92 @@
 //! Lokalise API config
 
+use model::secret::Secret;
 use serde::Deserialize;
-use std::fmt;
+use std::time::Duration;
 
-#[derive(Deserialize, Clone)]
+use super::template::TemplateSyntax;
+
+#[derive(Deserialize, Clone, Debug)]
 pub struct LokaliseConfig {
-    pub token: String,
-    pub project_id: String,
+    pub token: Secret<String>,
+
+    /// Projects to take translations from. If a key is in several projects,
+    /// the translation from the later project is used.
+    #[serde(rename = "project_id")]
+    pub project_ids: Vec<String>,
 
     #[serde(default = "default_api_url")]
     pub api_url: String,
+
+    /// Format of the `pair` substitution, with `[%s:amountToken]` and `[%s:priceToken]` placeholders
+    #[serde(default = "default_pair_format")]
+    pub pair_format: String,
+
+    /// Look up localized asset tickers in Lokalise (keys like `assetTicker.WAVES`),
+    /// falling back to the ticker itself
+    #[serde(default)]
+    pub localize_tickers: bool,
+
+    /// Syntax of the translations, `legacy` (`[%s:key]`) or `icu`
+    #[serde(default)]
+    pub template_syntax: TemplateSyntax,
+
+    /// Keys already migrated to the ICU syntax, when the rest are still `legacy`
+    #[serde(default)]
+    pub icu_keys: Vec<String>,
+
+    /// Leave date and time to be formatted at send time, in the timezone the device has then
+    /// (which may have changed while the message was in the queue)
+    #[serde(default)]
+    pub defer_date_time: bool,
+
+    /// Format the numbers (like the price threshold) as per the device language,
+    /// with its digit grouping and decimal separator
+    #[serde(default)]
+    pub format_numbers: bool,
+
+    /// Platforms to take key names from, in order of preference
+    #[serde(default = "default_platforms")]
+    pub platforms: Vec<Platform>,
+
+    /// How often to reload the translations, in seconds, so that the changes made in Lokalise
+    /// are picked up without a restart. Not reloaded if not set
+    pub refresh_interval: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Web,
+    Other,
+    Android,
+    Ios,
 }
 
 fn default_api_url() -> String {
     "https://api.lokalise.com/api2".to_string()
 }
 
+pub(super) fn default_platforms() -> Vec<Platform> {
+    vec![
+        Platform::Web,
+        Platform::Other,
+        Platform::Android,
+        Platform::Ios,
+    ]
+}
+
+pub(super) fn default_pair_format() -> String {
+    "[%s:amountToken]/[%s:priceToken]".to_string()
+}
+
 impl LokaliseConfig {
     pub fn load() -> Result<Self, envy::Error> {
         Ok(envy::prefixed("LOKALISE_").from_env::<LokaliseConfig>()?)
     }
-}
 
-impl fmt::Debug for LokaliseConfig {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Intentionally avoid printing password for security reasons
-        f.debug_struct("LokaliseConfig")
-            .field("token", &"****")
-            .field("project_id", &self.project_id)
-            .field("api_url", &self.api_url)
-            .finish()
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        let interval = self.refresh_interval.filter(|&secs| secs > 0)?;
+        Some(Duration::from_secs(interval as u64))
     }
 }
@@ -37,6 +37,7 @@ impl RemoteGateway {
 }
 
 pub(super) mod dto {
+    use super::super::config::Platform;
     use serde::Deserialize;
 
     #[derive(Debug, Clone, Deserialize)]
@@ -66,6 +67,17 @@ pub(super) mod dto {
         pub other: String,
     }
 
+    impl PlatformStrings {
+        pub fn get(&self, platform: Platform) -> &str {
+            match platform {
+                Platform::Ios => &self.ios,
+                Platform::Android => &self.android,
+                Platform::Web => &self.web,
+                Platform::Other => &self.other,
+            }
+        }
+    }
+
     #[derive(Debug, Clone, Deserialize)]
     pub struct Translation {
         pub translation_id: i64,
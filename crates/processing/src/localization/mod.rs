@@ -2,8 +2,11 @@
 
 mod config;
 mod lokalise_gateway;
+mod number;
 mod repo;
 mod template;
 mod translations;
 
-pub use self::{config::LokaliseConfig, lokalise_gateway::GatewayError, repo::Repo};
+pub use self::{
+    config::LokaliseConfig, lokalise_gateway::GatewayError, repo::Repo, template::TemplateSyntax,
+};
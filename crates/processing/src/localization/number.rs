@@ -0,0 +1,81 @@
+//! Locale-aware number formatting: digit grouping and decimal separator per language
+
+/// Digit group separator and decimal separator
+struct Separators {
+    group: &'static str,
+    decimal: &'static str,
+}
+
+/// Separators by language (the primary subtag of `lang`, like `pt` of `pt-BR`),
+/// `None` for the languages not in the table
+fn separators(lang: &str) -> Option<Separators> {
+    let primary = lang
+        .split(|c| c == '-' || c == '_')
+        .next()
+        .unwrap_or_default();
+    let (group, decimal) = match primary.to_ascii_lowercase().as_str() {
+        "en" | "ja" | "ko" | "zh" | "th" | "he" | "hi" => (",", "."),
+        "de" | "es" | "it" | "nl" | "pt" | "id" | "tr" | "vi" | "da" => (".", ","),
+        "ru" | "uk" | "be" | "kk" | "fr" | "pl" | "cs" | "sk" | "fi" | "sv" | "nb" | "bg" => {
+            ("\u{a0}", ",")
+        }
+        _ => return None,
+    };
+    Some(Separators { group, decimal })
+}
+
+/// Formats the number as per the language, or as is (no grouping, dot as the decimal separator)
+/// if the language is unknown
+pub(super) fn format_number(value: f64, lang: &str) -> String {
+    let plain = value.to_string();
+    let separators = match separators(lang) {
+        Some(separators) => separators,
+        None => return plain,
+    };
+
+    let (sign, digits) = match plain.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", plain.as_str()),
+    };
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (digits, None),
+    };
+
+    let mut res = sign.to_string();
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            res.push_str(separators.group);
+        }
+        res.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        res.push_str(separators.decimal);
+        res.push_str(fraction);
+    }
+    res
+}
+
+#[test]
+fn test_format_number() {
+    // Comma vs dot vs space grouping, dot vs comma decimals
+    assert_eq!(format_number(1234567.25, "en"), "1,234,567.25");
+    assert_eq!(format_number(1234567.25, "de"), "1.234.567,25");
+    assert_eq!(format_number(1234567.25, "ru"), "1\u{a0}234\u{a0}567,25");
+    assert_eq!(format_number(1234567.25, "fr"), "1\u{a0}234\u{a0}567,25");
+
+    // Region subtags
+    assert_eq!(format_number(1234.5, "pt-BR"), "1.234,5");
+    assert_eq!(format_number(1234.5, "en_US"), "1,234.5");
+
+    // Small, whole and negative numbers
+    assert_eq!(format_number(2.5, "de"), "2,5");
+    assert_eq!(format_number(0.00012, "en"), "0.00012");
+    assert_eq!(format_number(123.0, "en"), "123");
+    assert_eq!(format_number(1000.0, "en"), "1,000");
+    assert_eq!(format_number(-12345.5, "ru"), "-12\u{a0}345,5");
+
+    // Neutral fallback
+    assert_eq!(format_number(1234567.25, "xx"), "1234567.25");
+    assert_eq!(format_number(1234567.25, ""), "1234567.25");
+}
@@ -1,13 +1,20 @@
 use model::{
     device::LocaleInfo,
-    message::{LocalizedMessage, Message},
+    message::{LocalizedMessage, Message, DEFERRED_DATE, DEFERRED_TIME},
     order::{OrderExecution, OrderSide},
-    time::Timestamp,
+    time::format_date_time,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
 };
-use std::collections::HashMap;
 
 use super::{
-    config::LokaliseConfig, lokalise_gateway::RemoteGateway, template::interpolate,
+    config::{LokaliseConfig, Platform},
+    lokalise_gateway::{GatewayError, RemoteGateway},
+    number::format_number,
+    template::{interpolate, TemplateSyntax},
     translations::TranslationMap,
 };
 
@@ -21,27 +28,56 @@ mod lokalise_keys {
     pub const PRICE_ALERT_MSG: &str = "priceAlertMessage";
     pub const BUY: &str = "buy";
     pub const SELL: &str = "sell";
+    pub const ALERTS_DIGEST_TITLE: &str = "alertsDigestTitle";
+    pub const ALERTS_DIGEST_MSG: &str = "alertsDigestMessage";
+    /// Prefix of optional keys with localized asset tickers, like `assetTicker.WAVES`
+    pub const ASSET_TICKER_PREFIX: &str = "assetTicker.";
 }
 
 pub struct Repo {
-    translations: TranslationMap,
+    /// Replaced as a whole when the translations are reloaded,
+    /// messages being localized keep the snapshot they started with
+    translations: Arc<RwLock<Arc<TranslationMap>>>,
+    pair_format: String,
+    localize_tickers: bool,
+    template_syntax: TemplateSyntax,
+    icu_keys: HashSet<String>,
+    defer_date_time: bool,
+    format_numbers: bool,
 }
 
 impl Repo {
     pub async fn new(config: LokaliseConfig) -> Result<Self, Error> {
-        let remote_gateway = RemoteGateway::new(&config.api_url, &config.token);
-        let keys = remote_gateway.keys_for_project(&config.project_id).await.map_err(Error::LocalizationApiError)?;
-        let translations = TranslationMap::build(keys);
-        if translations.is_complete() {
-            log::trace!("Lokalise translations: {:?}", translations);
-        } else {
-            log::warn!("Incomplete lokalise translations: {:?}", translations);
+        let remote_gateway = RemoteGateway::new(&config.api_url, config.token.expose());
+        let translations =
+            load_translations(&remote_gateway, &config.project_ids, &config.platforms)
+                .await
+                .map_err(Error::LocalizationApiError)?;
+        check_complete(&translations);
+        let translations = Arc::new(RwLock::new(Arc::new(translations)));
+        if let Some(interval) = config.refresh_interval() {
+            tokio::task::spawn(reload_periodically(
+                translations.clone(),
+                remote_gateway,
+                config.project_ids.clone(),
+                config.platforms.clone(),
+                interval,
+            ));
         }
-        Ok(Self { translations })
+        Ok(Self {
+            translations,
+            pair_format: config.pair_format,
+            localize_tickers: config.localize_tickers,
+            template_syntax: config.template_syntax,
+            icu_keys: config.icu_keys.into_iter().collect(),
+            defer_date_time: config.defer_date_time,
+            format_numbers: config.format_numbers,
+        })
     }
 
     pub fn localize(&self, message: &Message, locale: &LocaleInfo) -> Option<LocalizedMessage> {
-        let translate = |key| self.translations.translate(key, &locale.lang);
+        let translations = self.translations();
+        let translate = |key| translations.translate(key, &locale.lang);
 
         let title_key = match message {
             Message::OrderExecuted { .. } => lokalise_keys::ORDER_FILLED_TITLE,
@@ -79,13 +115,19 @@ impl Repo {
                 amount_asset_ticker,
                 price_asset_ticker,
                 ..
-            } => (amount_asset_ticker, price_asset_ticker),
+            } => (
+                self.ticker(&translations, amount_asset_ticker, &locale.lang),
+                self.ticker(&translations, price_asset_ticker, &locale.lang),
+            ),
         };
 
-        let pair = format!("{}/{}", amount_token, price_token);
+        let pair = format_pair(&self.pair_format, amount_token, price_token);
 
         let value = match message {
             Message::OrderExecuted { .. } => "".to_string(),
+            Message::PriceThresholdReached { threshold, .. } if self.format_numbers => {
+                format_number(*threshold, &locale.lang)
+            }
             Message::PriceThresholdReached { threshold, .. } => format!("{}", threshold),
         };
 
@@ -100,7 +142,17 @@ impl Repo {
         let (date, time) = match message {
             Message::OrderExecuted { timestamp, .. }
             | Message::PriceThresholdReached { timestamp, .. } => {
-                format_date_time(*timestamp, locale)
+                if self.defer_date_time {
+                    (DEFERRED_DATE.to_string(), DEFERRED_TIME.to_string())
+                } else {
+                    format_date_time(*timestamp, locale.utc_offset_seconds, &locale.lang)
+                }
+            }
+        };
+
+        let label = match message {
+            Message::OrderExecuted { label, .. } | Message::PriceThresholdReached { label, .. } => {
+                label.as_deref().unwrap_or_default()
             }
         };
 
@@ -117,23 +169,446 @@ impl Repo {
             ("ratio", &ratio),
             ("date", &date),
             ("time", &time),
+            ("label", label),
         ]);
 
         Some(LocalizedMessage {
-            notification_title: interpolate(title, &subst),
-            notification_body: interpolate(body, &subst),
+            notification_title: self.render(title_key, title, &subst, &locale.lang),
+            notification_body: self.render(body_key, body, &subst, &locale.lang),
         })
     }
+
+    /// Digest of several price alerts, like "3 of your alerts triggered".
+    /// The keys are only needed if digests are enabled, so they are allowed to be missing.
+    pub fn localize_digest(&self, count: u32, locale: &LocaleInfo) -> Option<LocalizedMessage> {
+        let translations = self.translations();
+        let translate = |key| translations.find(key, &locale.lang);
+        let title = translate(lokalise_keys::ALERTS_DIGEST_TITLE)?;
+        let body = translate(lokalise_keys::ALERTS_DIGEST_MSG)?;
+        let count = count.to_string();
+        let subst = HashMap::from([("", ""), ("count", count.as_str())]);
+        let render = |key, template| self.render(key, template, &subst, &locale.lang);
+        Some(LocalizedMessage {
+            notification_title: render(lokalise_keys::ALERTS_DIGEST_TITLE, title),
+            notification_body: render(lokalise_keys::ALERTS_DIGEST_MSG, body),
+        })
+    }
+
+    /// Current translations, the lock is only held to clone the pointer
+    fn translations(&self) -> Arc<TranslationMap> {
+        self.translations.read().expect("lock").clone()
+    }
+
+    /// Template syntax is chosen per key, so that translations can be migrated one by one.
+    /// Spaces left around empty values (like a missing label) are collapsed and trimmed.
+    fn render(&self, key: &str, template: &str, subst: &HashMap<&str, &str>, lang: &str) -> String {
+        let syntax = if self.icu_keys.contains(key) {
+            TemplateSyntax::Icu
+        } else {
+            self.template_syntax
+        };
+        collapse_spaces(&syntax.engine().render(template, subst, lang))
+    }
+
+    /// Localized asset ticker, if enabled and available for the language
+    fn ticker<'a>(&self, translations: &'a TranslationMap, ticker: &'a str, lang: &str) -> &'a str {
+        if !self.localize_tickers {
+            return ticker;
+        }
+        let key = format!("{}{}", lokalise_keys::ASSET_TICKER_PREFIX, ticker);
+        translations
+            .find(&key, lang)
+            .map(String::as_str)
+            .unwrap_or(ticker)
+    }
+}
+
+/// Translations of all the projects, later projects taking precedence
+async fn load_translations(
+    remote_gateway: &RemoteGateway,
+    project_ids: &[String],
+    platforms: &[Platform],
+) -> Result<TranslationMap, GatewayError> {
+    let mut translations = TranslationMap::default();
+    for project_id in project_ids {
+        let keys = remote_gateway.keys_for_project(project_id).await?;
+        log::debug!("Lokalise project {}: {} keys", project_id, keys.keys.len());
+        translations.merge(TranslationMap::build(keys, platforms));
+    }
+    Ok(translations)
 }
 
-fn format_date_time(timestamp: Timestamp, locale: &LocaleInfo) -> (String, String) {
-    if let Some(dt) = timestamp.date_time(locale.utc_offset_seconds) {
-        let dt = dt.naive_local();
-        //TODO Probably we gonna need the localized format of date and time (using `locale.lang` maybe)
-        let date = dt.date().format("%Y-%m-%d").to_string();
-        let time = dt.time().format("%H:%M:%S").to_string();
-        (date, time)
+fn check_complete(translations: &TranslationMap) {
+    let is_optional = |key: &str| key.starts_with(lokalise_keys::ASSET_TICKER_PREFIX);
+    if translations.is_complete(is_optional) {
+        log::trace!("Lokalise translations: {:?}", translations);
     } else {
-        ("?".to_string(), "?".to_string())
+        log::warn!("Incomplete lokalise translations: {:?}", translations);
+    }
+}
+
+/// Failed reloads keep the translations loaded last, until Lokalise is available again
+async fn reload_periodically(
+    current: Arc<RwLock<Arc<TranslationMap>>>,
+    remote_gateway: RemoteGateway,
+    project_ids: Vec<String>,
+    platforms: Vec<Platform>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match load_translations(&remote_gateway, &project_ids, &platforms).await {
+            Ok(translations) => {
+                check_complete(&translations);
+                log::info!("Lokalise translations reloaded");
+                *current.write().expect("lock") = Arc::new(translations);
+            }
+            Err(err) => log::warn!("Lokalise translations not reloaded: {}", err),
+        }
+    }
+}
+
+fn format_pair(format: &str, amount_token: &str, price_token: &str) -> String {
+    let subst = HashMap::from([("amountToken", amount_token), ("priceToken", price_token)]);
+    interpolate(format, &subst)
+}
+
+/// Collapses runs of spaces into one and trims the text
+fn collapse_spaces(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.trim().chars() {
+        if c == ' ' && res.ends_with(' ') {
+            continue;
+        }
+        res.push(c);
+    }
+    res
+}
+
+#[cfg(test)]
+impl Repo {
+    /// Repo with the given `(key, lang, translation)`s instead of the ones from Lokalise
+    pub(crate) fn with_translations(translations: &[(&str, &str, &str)]) -> Self {
+        let mut map = HashMap::<String, HashMap<String, String>>::new();
+        for &(key, lang, value) in translations {
+            map.entry(key.to_string())
+                .or_default()
+                .insert(lang.to_string(), value.to_string());
+        }
+        Repo {
+            translations: Arc::new(RwLock::new(Arc::new(TranslationMap::from_map(map)))),
+            pair_format: super::config::default_pair_format(),
+            localize_tickers: false,
+            template_syntax: TemplateSyntax::Legacy,
+            icu_keys: HashSet::new(),
+            defer_date_time: false,
+            format_numbers: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lokalise_keys, Repo};
+    use crate::localization::{template::TemplateSyntax, translations::TranslationMap};
+    use model::{device::LocaleInfo, message::Message, time::Timestamp};
+    use std::{collections::HashSet, sync::Arc};
+
+    fn repo(translations: &[(&str, &str, &str)]) -> Repo {
+        repo_with_tickers(translations, false)
+    }
+
+    fn translation_map(translations: &[(&str, &str, &str)]) -> Arc<TranslationMap> {
+        Repo::with_translations(translations).translations()
+    }
+
+    fn repo_with_tickers(translations: &[(&str, &str, &str)], localize_tickers: bool) -> Repo {
+        Repo {
+            localize_tickers,
+            ..Repo::with_translations(translations)
+        }
+    }
+
+    fn locale(lang: &str) -> LocaleInfo {
+        LocaleInfo {
+            lang: lang.to_string(),
+            utc_offset_seconds: 0,
+        }
+    }
+
+    fn price_message(label: Option<&str>) -> Message {
+        Message::PriceThresholdReached {
+            amount_asset_ticker: "WAVES".to_string(),
+            price_asset_ticker: "USDN".to_string(),
+            threshold: 2.5,
+            timestamp: Timestamp::from_unix_timestamp_millis(0),
+            label: label.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn test_localize_label() {
+        let repo = repo(&[
+            (
+                lokalise_keys::PRICE_ALERT_TITLE,
+                "en",
+                "Price alert [%s:label]",
+            ),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] reached [%s:value]",
+            ),
+        ]);
+
+        let msg = repo
+            .localize(&price_message(Some("Sell my WAVES bag")), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_title, "Price alert Sell my WAVES bag");
+        assert_eq!(msg.notification_body, "WAVES/USDN reached 2.5");
+
+        let msg = repo
+            .localize(&price_message(None), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_title, "Price alert");
+
+        // Keys missing in Lokalise
+        let msg = self::repo(&[]).localize(&price_message(None), &locale("en"));
+        assert!(msg.is_none());
+    }
+
+    #[test]
+    fn test_reloaded_translations() {
+        let repo = repo(&[
+            (lokalise_keys::PRICE_ALERT_TITLE, "en", "Price alert"),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] reached [%s:value]",
+            ),
+        ]);
+        let snapshot = repo.translations();
+
+        // As the reload task does it
+        *repo.translations.write().unwrap() = translation_map(&[
+            (lokalise_keys::PRICE_ALERT_TITLE, "en", "Price alert"),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] is at [%s:value] now",
+            ),
+        ]);
+
+        let msg = repo
+            .localize(&price_message(None), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_body, "WAVES/USDN is at 2.5 now");
+
+        // The snapshot taken before is left intact
+        let body = snapshot.translate(lokalise_keys::PRICE_ALERT_MSG, "en");
+        assert_eq!(
+            body.map(String::as_str),
+            Some("[%s:pair] reached [%s:value]")
+        );
+    }
+
+    #[test]
+    fn test_localize_tickers() {
+        let translations = [
+            (lokalise_keys::PRICE_ALERT_TITLE, "en", "Price alert"),
+            (lokalise_keys::PRICE_ALERT_TITLE, "ru", "Ценовой алерт"),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] reached [%s:value]",
+            ),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "ru",
+                "[%s:pair] достиг [%s:value]",
+            ),
+            ("assetTicker.WAVES", "ru", "ВЕЙВС"),
+        ];
+
+        // Localized where available, the ticker itself otherwise
+        let repo = repo_with_tickers(&translations, true);
+        let msg = repo
+            .localize(&price_message(None), &locale("ru"))
+            .expect("localized");
+        assert_eq!(msg.notification_body, "ВЕЙВС/USDN достиг 2.5");
+        let msg = repo
+            .localize(&price_message(None), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_body, "WAVES/USDN reached 2.5");
+
+        // Disabled
+        let repo = repo_with_tickers(&translations, false);
+        let msg = repo
+            .localize(&price_message(None), &locale("ru"))
+            .expect("localized");
+        assert_eq!(msg.notification_body, "WAVES/USDN достиг 2.5");
+    }
+
+    #[test]
+    fn test_localize_digest() {
+        let repo = repo(&[
+            (lokalise_keys::ALERTS_DIGEST_TITLE, "en", "Price alerts"),
+            (
+                lokalise_keys::ALERTS_DIGEST_MSG,
+                "en",
+                "[%s:count] of your alerts triggered",
+            ),
+        ]);
+
+        let msg = repo.localize_digest(3, &locale("en")).expect("localized");
+        assert_eq!(msg.notification_title, "Price alerts");
+        assert_eq!(msg.notification_body, "3 of your alerts triggered");
+
+        assert!(repo.localize_digest(3, &locale("ru")).is_none());
+        assert!(self::repo(&[]).localize_digest(3, &locale("en")).is_none());
+    }
+
+    #[test]
+    fn test_template_syntax_per_key() {
+        let mut repo = repo(&[
+            (
+                lokalise_keys::PRICE_ALERT_TITLE,
+                "en",
+                "Price alert [%s:label]",
+            ),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "{pair} reached {value}",
+            ),
+        ]);
+        repo.icu_keys = HashSet::from([lokalise_keys::PRICE_ALERT_MSG.to_string()]);
+
+        let msg = repo
+            .localize(&price_message(Some("WAVES")), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_title, "Price alert WAVES");
+        assert_eq!(msg.notification_body, "WAVES/USDN reached 2.5");
+
+        // Globally
+        repo.template_syntax = TemplateSyntax::Icu;
+        let msg = repo
+            .localize(&price_message(Some("WAVES")), &locale("en"))
+            .expect("localized");
+        assert_eq!(msg.notification_title, "Price alert [%s:label]");
     }
+
+    #[test]
+    fn test_format_numbers() {
+        let mut repo = repo(&[
+            (lokalise_keys::PRICE_ALERT_TITLE, "en", "Price alert"),
+            (lokalise_keys::PRICE_ALERT_TITLE, "de", "Preisalarm"),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] reached [%s:value]",
+            ),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "de",
+                "[%s:pair] hat [%s:value] erreicht",
+            ),
+        ]);
+        let message = Message::PriceThresholdReached {
+            amount_asset_ticker: "BTC".to_string(),
+            price_asset_ticker: "USDN".to_string(),
+            threshold: 23456.5,
+            timestamp: Timestamp::from_unix_timestamp_millis(0),
+            label: None,
+        };
+        let body = |repo: &Repo, lang| {
+            let msg = repo.localize(&message, &locale(lang)).expect("localized");
+            msg.notification_body
+        };
+
+        assert_eq!(body(&repo, "de"), "BTC/USDN hat 23456.5 erreicht");
+
+        repo.format_numbers = true;
+        assert_eq!(body(&repo, "en"), "BTC/USDN reached 23,456.5");
+        assert_eq!(body(&repo, "de"), "BTC/USDN hat 23.456,5 erreicht");
+    }
+
+    #[test]
+    fn test_deferred_date_time() {
+        use model::message::fill_date_time;
+
+        let mut repo = repo(&[
+            (lokalise_keys::PRICE_ALERT_TITLE, "en", "Price alert"),
+            (
+                lokalise_keys::PRICE_ALERT_MSG,
+                "en",
+                "[%s:pair] reached [%s:value] at [%s:time] on [%s:date]",
+            ),
+        ]);
+        let timestamp = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+        let message = Message::PriceThresholdReached {
+            amount_asset_ticker: "WAVES".to_string(),
+            price_asset_ticker: "USDN".to_string(),
+            threshold: 2.5,
+            timestamp,
+            label: None,
+        };
+        // The device timezone is changed after the message is enqueued
+        let enqueue_locale = locale("en");
+        let send_time_offset = 3 * 3600;
+
+        // Formatted at enqueue time
+        let msg = repo.localize(&message, &enqueue_locale).expect("localized");
+        assert_eq!(
+            msg.notification_body,
+            "WAVES/USDN reached 2.5 at 10:13:20 PM on 11/14/2023"
+        );
+
+        // Formatted at send time
+        repo.defer_date_time = true;
+        let msg = repo.localize(&message, &enqueue_locale).expect("localized");
+        assert_eq!(
+            msg.notification_body,
+            "WAVES/USDN reached 2.5 at [%s:time] on [%s:date]"
+        );
+        assert_eq!(
+            fill_date_time(&msg.notification_body, timestamp, send_time_offset, "en"),
+            "WAVES/USDN reached 2.5 at 1:13:20 AM on 11/15/2023"
+        );
+    }
+}
+
+#[test]
+fn test_format_pair() {
+    use super::config::default_pair_format;
+    assert_eq!(
+        format_pair(&default_pair_format(), "WAVES", "USDN"),
+        "WAVES/USDN"
+    );
+    assert_eq!(
+        format_pair("[%s:amountToken] / [%s:priceToken]", "WAVES", "USDN"),
+        "WAVES / USDN"
+    );
+    assert_eq!(
+        format_pair("[%s:amountToken]-[%s:priceToken]", "WAVES", "USDN"),
+        "WAVES-USDN"
+    );
+    assert_eq!(
+        format_pair("[%s:priceToken] per [%s:amountToken]", "WAVES", "USDN"),
+        "USDN per WAVES"
+    );
+}
+
+#[test]
+fn test_collapse_spaces() {
+    assert_eq!(collapse_spaces(""), "");
+    assert_eq!(collapse_spaces("Price alert "), "Price alert");
+    assert_eq!(
+        collapse_spaces(" Price alert  triggered "),
+        "Price alert triggered"
+    );
+    assert_eq!(
+        collapse_spaces("Price alert\n WAVES/USDN"),
+        "Price alert\n WAVES/USDN"
+    );
 }
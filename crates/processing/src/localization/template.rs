@@ -1,7 +1,54 @@
 use lazy_regex::{regex, Captures, Lazy, Regex};
+use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+/// Syntax of the translation templates
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateSyntax {
+    /// `[%s:key]` placeholders and `[%plural:key:form|form|...]` plural forms
+    #[default]
+    Legacy,
+    /// A subset of ICU MessageFormat: `{key}`, `{key, plural, ...}` and `{key, select, ...}`
+    Icu,
+}
+
+impl TemplateSyntax {
+    pub(super) fn engine(self) -> &'static dyn TemplateEngine {
+        match self {
+            TemplateSyntax::Legacy => &LegacyEngine,
+            TemplateSyntax::Icu => &IcuEngine,
+        }
+    }
+}
+
+pub(super) trait TemplateEngine: Send + Sync {
+    /// `lang` is the language of the template, which plural forms are chosen as per
+    fn render(&self, template: &str, subst: &HashMap<&str, &str>, lang: &str) -> String;
+}
+
+struct LegacyEngine;
+
+impl TemplateEngine for LegacyEngine {
+    fn render(&self, template: &str, subst: &HashMap<&str, &str>, lang: &str) -> String {
+        let template = interpolate_plurals(template, subst, plural_rule(lang));
+        interpolate(&template, subst)
+    }
+}
+
+/// Templates are rendered in a single pass, without compiling them to regexes or alike.
+///
+/// Plural categories are the CLDR ones of the language (as per `plural_rule`),
+/// plus exact matches like `=0`.
+struct IcuEngine;
+
+impl TemplateEngine for IcuEngine {
+    fn render(&self, template: &str, subst: &HashMap<&str, &str>, lang: &str) -> String {
+        render_icu(template, subst, None, plural_rule(lang))
+    }
+}
+
 pub(super) fn interpolate(s: &str, subst: &HashMap<&str, &str>) -> String {
     static RE: &Lazy<Regex> = regex!(r"\[%s:([a-zA-z]+)]");
     RE.replace_all(s, |caps: &Captures| {
@@ -14,6 +61,241 @@ pub(super) fn interpolate(s: &str, subst: &HashMap<&str, &str>) -> String {
     .to_string()
 }
 
+/// Plural rule of a language
+#[derive(Clone, Copy)]
+pub(super) struct PluralRule {
+    /// CLDR categories of the forms the language has, in the order of
+    /// `[%plural:key:form|form|...]`
+    categories: &'static [&'static str],
+    /// Index of the form to use for the number
+    form: fn(f64) -> usize,
+}
+
+impl PluralRule {
+    /// CLDR category of the number, fractions being `other` as in all the supported languages
+    fn category(&self, n: f64) -> &'static str {
+        if n.fract() != 0.0 {
+            return "other";
+        }
+        self.categories[(self.form)(n)]
+    }
+}
+
+/// Plural rule by language (the primary subtag of `lang`), English for the languages
+/// not in the table
+pub(super) fn plural_rule(lang: &str) -> PluralRule {
+    let primary = lang
+        .split(|c| c == '-' || c == '_')
+        .next()
+        .unwrap_or_default();
+    match primary.to_ascii_lowercase().as_str() {
+        "ru" | "uk" | "be" => PluralRule {
+            categories: &["one", "few", "many"],
+            form: plural_east_slavic,
+        },
+        _ => PluralRule {
+            categories: &["one", "other"],
+            form: plural_english,
+        },
+    }
+}
+
+/// `one|other`
+fn plural_english(n: f64) -> usize {
+    if n == 1.0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// `one|few|many`, fractions taking the `few` form as in "1,5 ордера"
+fn plural_east_slavic(n: f64) -> usize {
+    if n.fract() != 0.0 {
+        return 1;
+    }
+    let n = n.abs() as u64;
+    match (n % 10, n % 100) {
+        (1, m) if m != 11 => 0,
+        (2..=4, m) if !(12..=14).contains(&m) => 1,
+        _ => 2,
+    }
+}
+
+/// Replaces `[%plural:key:form|form|...]` with the form for the number substituted for `key`.
+/// If the value is missing or is not a number, the last form (like `other`) is used.
+fn interpolate_plurals(s: &str, subst: &HashMap<&str, &str>, rule: PluralRule) -> String {
+    static RE: &Lazy<Regex> = regex!(r"\[%plural:([a-zA-Z]+):([^\]]*)]");
+    RE.replace_all(s, |caps: &Captures| {
+        let key = caps.get(1).expect("regex capture").as_str();
+        let forms = caps.get(2).expect("regex capture").as_str();
+        let forms = forms.split('|').collect::<Vec<_>>();
+        let last = forms.len() - 1;
+        let index = match subst.get(key).and_then(|v| v.trim().parse::<f64>().ok()) {
+            Some(number) => (rule.form)(number).min(last),
+            None => last,
+        };
+        forms[index].to_string()
+    })
+    .to_string()
+}
+
+/// `number` is the value of the enclosing plural argument, which `#` stands for
+fn render_icu(
+    template: &str,
+    subst: &HashMap<&str, &str>,
+    number: Option<&str>,
+    rule: PluralRule,
+) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(|c: char| matches!(c, '{' | '#' | '\'')) {
+        res.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        match tail.as_bytes()[0] {
+            b'{' => match closing_brace(tail) {
+                Some(end) => {
+                    res.push_str(&render_argument(&tail[1..end], subst, rule));
+                    rest = &tail[end + 1..];
+                }
+                None => {
+                    // Unbalanced braces - keep the rest as is
+                    res.push_str(tail);
+                    rest = "";
+                }
+            },
+            b'#' => {
+                res.push_str(number.unwrap_or("#"));
+                rest = &tail[1..];
+            }
+            _ => {
+                let (literal, len) = quoted(tail);
+                res.push_str(literal);
+                rest = &tail[len..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Renders the contents of `{...}`
+fn render_argument(argument: &str, subst: &HashMap<&str, &str>, rule: PluralRule) -> String {
+    let mut parts = argument.splitn(3, ',');
+    let name = parts.next().unwrap_or_default().trim();
+    let value = match subst.get(name) {
+        Some(&value) => value,
+        None => return format!("<{}>", name),
+    };
+    let (kind, options) = match (parts.next().map(str::trim), parts.next()) {
+        (None, _) => return value.to_string(),
+        (Some(kind), Some(options)) if kind == "plural" || kind == "select" => (kind, options),
+        _ => return format!("{{{}}}", argument), // Unsupported
+    };
+    let options = match parse_options(options) {
+        Some(options) => options,
+        None => return format!("{{{}}}", argument),
+    };
+    let (message, number) = if kind == "plural" {
+        (select_plural(value, &options, rule), Some(value))
+    } else {
+        (select(value, &options), None)
+    };
+    message
+        .map(|message| render_icu(message, subst, number, rule))
+        .unwrap_or_default()
+}
+
+/// Parses `selector {message} selector {message} ...`
+fn parse_options(s: &str) -> Option<Vec<(&str, &str)>> {
+    let mut res = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let selector_end = rest.find(|c: char| c.is_whitespace() || c == '{')?;
+        if selector_end == 0 {
+            return None;
+        }
+        let selector = &rest[..selector_end];
+        let tail = rest[selector_end..].trim_start();
+        if !tail.starts_with('{') {
+            return None;
+        }
+        let end = closing_brace(tail)?;
+        res.push((selector, &tail[1..end]));
+        rest = tail[end + 1..].trim_start();
+    }
+    Some(res)
+}
+
+fn select_plural<'a>(
+    value: &str,
+    options: &[(&str, &'a str)],
+    rule: PluralRule,
+) -> Option<&'a str> {
+    let number = value.trim().parse::<f64>().ok();
+    let exact = options.iter().find(|&&(selector, _)| {
+        let exact_value = selector
+            .strip_prefix('=')
+            .and_then(|v| v.parse::<f64>().ok());
+        exact_value.is_some() && exact_value == number
+    });
+    let category = number.map_or("other", |n| rule.category(n));
+    exact
+        .or_else(|| options.iter().find(|&&(selector, _)| selector == category))
+        .or_else(|| options.iter().find(|&&(selector, _)| selector == "other"))
+        .map(|&(_, message)| message)
+}
+
+fn select<'a>(value: &str, options: &[(&str, &'a str)]) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|&&(selector, _)| selector == value)
+        .or_else(|| options.iter().find(|&&(selector, _)| selector == "other"))
+        .map(|&(_, message)| message)
+}
+
+/// Position of the brace closing the one `s` starts with
+fn closing_brace(s: &str) -> Option<usize> {
+    debug_assert!(s.starts_with('{'));
+    let bytes = s.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            b'\'' => {
+                let (_, len) = quoted(&s[i..]);
+                i += len;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// ICU quoting: `''` is an apostrophe, and an apostrophe before a special character
+/// starts a literal, which lasts until the next apostrophe.
+/// Returns the literal and the length of the quoted part of `s` (which starts with `'`).
+fn quoted(s: &str) -> (&str, usize) {
+    debug_assert!(s.starts_with('\''));
+    match s.as_bytes().get(1) {
+        Some(b'\'') => ("'", 2),
+        Some(b'{' | b'}' | b'#') => match s[1..].find('\'') {
+            Some(end) => (&s[1..end + 1], end + 2),
+            None => (&s[1..], s.len()),
+        },
+        _ => ("'", 1),
+    }
+}
+
 #[test]
 fn test_interpolate() {
     let subst = HashMap::from([("foo", "bar"), ("fee", "baz")]);
@@ -29,3 +311,123 @@ fn test_interpolate() {
     assert_eq!(&interpolate("[%s:unknown]", &subst), "<unknown>");
     assert_eq!(&interpolate("юникод [%s:foo] ок", &subst), "юникод bar ок");
 }
+
+#[test]
+fn test_interpolate_plurals() {
+    let render = |s: &str, subst: &[(&str, &str)], lang| {
+        let subst = subst.iter().copied().collect::<HashMap<_, _>>();
+        TemplateSyntax::Legacy.engine().render(s, &subst, lang)
+    };
+    let orders = "[%s:count] [%plural:count:order|orders] filled";
+    assert_eq!(render(orders, &[("count", "0")], "en"), "0 orders filled");
+    assert_eq!(render(orders, &[("count", "1")], "en"), "1 order filled");
+    assert_eq!(render(orders, &[("count", "2")], "en"), "2 orders filled");
+    assert_eq!(
+        render(orders, &[("count", "1.5")], "en"),
+        "1.5 orders filled"
+    );
+    assert_eq!(render(orders, &[("count", "21")], "en"), "21 orders filled");
+    assert_eq!(
+        render(
+            "[%plural:count:order|orders] [%s:foo]",
+            &[("count", "1"), ("foo", "bar")],
+            "en"
+        ),
+        "order bar"
+    );
+
+    // Missing or non-numeric count
+    assert_eq!(render(orders, &[], "en"), "<count> orders filled");
+    assert_eq!(
+        render(orders, &[("count", "many")], "en"),
+        "many orders filled"
+    );
+
+    // Language rules, unknown languages fall back to English
+    let orders = "[%s:count] [%plural:count:ордер|ордера|ордеров]";
+    let ru = |count| render(orders, &[("count", count)], "ru-RU");
+    assert_eq!(ru("1"), "1 ордер");
+    assert_eq!(ru("3"), "3 ордера");
+    assert_eq!(ru("5"), "5 ордеров");
+    assert_eq!(ru("11"), "11 ордеров");
+    assert_eq!(ru("21"), "21 ордер");
+    assert_eq!(ru("1.5"), "1.5 ордера");
+    assert_eq!(render(orders, &[("count", "5")], "xx"), "5 ордера");
+
+    // Fewer forms than the language has
+    let ru_two_forms = render("[%plural:count:ордер|ордеров]", &[("count", "5")], "ru");
+    assert_eq!(ru_two_forms, "ордеров");
+    assert_eq!(
+        render("[%plural:count:ордер]", &[("count", "5")], "ru"),
+        "ордер"
+    );
+}
+
+#[test]
+fn test_icu() {
+    let render_lang = |template: &str, subst: &[(&str, &str)], lang| {
+        let subst = subst.iter().copied().collect::<HashMap<_, _>>();
+        TemplateSyntax::Icu.engine().render(template, &subst, lang)
+    };
+    let render = |template: &str, subst: &[(&str, &str)]| render_lang(template, subst, "en");
+    let pair = [("pair", "WAVES/USDN"), ("value", "2.5")];
+    assert_eq!(render("", &pair), "");
+    assert_eq!(
+        render("{pair} reached {value}", &pair),
+        "WAVES/USDN reached 2.5"
+    );
+    assert_eq!(render("юникод {pair} ок", &pair), "юникод WAVES/USDN ок");
+    assert_eq!(render("{unknown}", &pair), "<unknown>");
+    assert_eq!(render("[%s:pair]", &pair), "[%s:pair]");
+
+    let alerts = "{count, plural, =0 {No alerts} one {# alert} other {# alerts}} triggered";
+    assert_eq!(render(alerts, &[("count", "0")]), "No alerts triggered");
+    assert_eq!(render(alerts, &[("count", "1")]), "1 alert triggered");
+    assert_eq!(render(alerts, &[("count", "3")]), "3 alerts triggered");
+    assert_eq!(render(alerts, &[("count", "1.5")]), "1.5 alerts triggered");
+
+    // Language rules
+    let alerts = "{count, plural, one {# оповещение} few {# оповещения} many {# оповещений} other {# оповещения}}";
+    let ru = |count| render_lang(alerts, &[("count", count)], "ru-RU");
+    assert_eq!(ru("1"), "1 оповещение");
+    assert_eq!(ru("2"), "2 оповещения");
+    assert_eq!(ru("5"), "5 оповещений");
+    assert_eq!(ru("11"), "11 оповещений");
+    assert_eq!(ru("21"), "21 оповещение");
+    assert_eq!(ru("1.5"), "1.5 оповещения");
+    assert_eq!(
+        render_lang(
+            "{count, plural, one {# ордер} other {# ордеров}}",
+            &[("count", "3")],
+            "ru"
+        ),
+        "3 ордеров"
+    );
+
+    let side = "{side, select, buy {Bought} sell {Sold} other {Traded}} {pair}";
+    let subst = |side| [("side", side), ("pair", "WAVES/USDN")];
+    assert_eq!(render(side, &subst("buy")), "Bought WAVES/USDN");
+    assert_eq!(render(side, &subst("sell")), "Sold WAVES/USDN");
+    assert_eq!(render(side, &subst("swap")), "Traded WAVES/USDN");
+
+    // Nested arguments and quoting
+    assert_eq!(
+        render(
+            "{count, plural, one {{pair}: # alert} other {{pair}: # alerts}}",
+            &[("count", "2"), ("pair", "WAVES/USDN")]
+        ),
+        "WAVES/USDN: 2 alerts"
+    );
+    assert_eq!(render("It''s '{pair}' #1", &pair), "It's {pair} #1");
+    assert_eq!(render("{pair", &pair), "{pair");
+}
+
+#[test]
+fn test_legacy() {
+    let subst = HashMap::from([("pair", "WAVES/USDN")]);
+    let engine = TemplateSyntax::Legacy.engine();
+    assert_eq!(
+        engine.render("[%s:pair] {pair}", &subst, "en"),
+        "WAVES/USDN {pair}"
+    );
+}
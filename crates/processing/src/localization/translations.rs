@@ -1,4 +1,7 @@
-use super::lokalise_gateway::dto::KeysResponse;
+use super::{
+    config::Platform,
+    lokalise_gateway::dto::{Key as KeyDto, KeysResponse},
+};
 use model::device::Lang;
 use std::{
     collections::{BTreeSet, HashMap},
@@ -9,13 +12,20 @@ pub(super) type Key = String;
 pub(super) type Value = String;
 pub(super) type ValuesMap = HashMap<Lang, Value>;
 
+#[derive(Default)]
 pub(super) struct TranslationMap(HashMap<Key, ValuesMap>);
 
 impl TranslationMap {
-    pub(super) fn build(keys: KeysResponse) -> Self {
+    pub(super) fn build(keys: KeysResponse, platforms: &[Platform]) -> Self {
         let mut translations = HashMap::<Key, ValuesMap>::new();
         for key in keys.keys {
-            let key_name = key.key_name.web;
+            let key_name = match key_name(&key, platforms) {
+                Some(name) => name,
+                None => {
+                    log::warn!("Lokalise key {} has no name - ignored", key.key_id);
+                    continue;
+                }
+            };
 
             if let Some(t) = key.translations {
                 for tr in t {
@@ -29,12 +39,41 @@ impl TranslationMap {
         TranslationMap(translations)
     }
 
-    pub(super) fn is_complete(&self) -> bool {
+    /// Merges translations of another project, which take precedence over the existing ones
+    pub(super) fn merge(&mut self, other: TranslationMap) {
+        let TranslationMap(translations) = self;
+        let TranslationMap(other) = other;
+        for (key, values) in other {
+            let existing = translations.entry(key.clone()).or_default();
+            for (lang, value) in values {
+                match existing.get(&lang) {
+                    Some(old) if *old != value => log::info!(
+                        "Lokalise key '{}' ({}) is overridden by a later project: '{}' -> '{}'",
+                        key,
+                        lang,
+                        old,
+                        value,
+                    ),
+                    _ => {}
+                }
+                existing.insert(lang, value);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn from_map(translations: HashMap<Key, ValuesMap>) -> Self {
+        TranslationMap(translations)
+    }
+
+    /// Checks that every key is translated to every language,
+    /// except for the optional keys, which may be translated to only some of them.
+    pub(super) fn is_complete(&self, is_optional: impl Fn(&str) -> bool) -> bool {
         let TranslationMap(translations) = self;
         let keys = self.keys();
         let langs = self.langs();
         for lang in &langs {
-            for key in &keys {
+            for key in keys.iter().filter(|&key| !is_optional(key)) {
                 let has_value = translations
                     .get(key)
                     .map(|values| values.get(lang))
@@ -63,10 +102,35 @@ impl TranslationMap {
             .collect()
     }
 
+    /// `None` if the key is not translated to the language, or is missing altogether
+    /// (say, added to the code but not yet to Lokalise)
     pub(super) fn translate(&self, key: &str, lang: &str) -> Option<&Value> {
         let TranslationMap(translations) = self;
-        translations[key].get(lang)
+        translations.get(key)?.get(lang)
+    }
+
+    /// Same as `translate`, for the keys which are expected to be missing at times
+    pub(super) fn find(&self, key: &str, lang: &str) -> Option<&Value> {
+        self.translate(key, lang)
+    }
+}
+
+/// Name of the key for the first of the `platforms` it has a name for
+fn key_name(key: &KeyDto, platforms: &[Platform]) -> Option<String> {
+    let (i, name) = platforms
+        .iter()
+        .map(|&platform| key.key_name.get(platform))
+        .enumerate()
+        .find(|(_, name)| !name.is_empty())?;
+    if i > 0 {
+        log::info!(
+            "Lokalise key '{}' has no {:?} name, using the {:?} one",
+            name,
+            platforms[0],
+            platforms[i],
+        );
     }
+    Some(name.to_string())
 }
 
 impl fmt::Debug for TranslationMap {
@@ -80,3 +144,116 @@ impl fmt::Debug for TranslationMap {
         )
     }
 }
+
+#[test]
+fn test_build_with_platform_fallback() {
+    use super::lokalise_gateway::dto::{PlatformStrings, Translation};
+
+    let key = |key_id, web: &str, android: &str, ios: &str, translation: &str| KeyDto {
+        key_id,
+        created_at: String::new(),
+        created_at_timestamp: 0,
+        key_name: PlatformStrings {
+            ios: ios.to_string(),
+            android: android.to_string(),
+            web: web.to_string(),
+            other: String::new(),
+        },
+        filenames: PlatformStrings {
+            ios: String::new(),
+            android: String::new(),
+            web: String::new(),
+            other: String::new(),
+        },
+        description: String::new(),
+        platforms: vec![],
+        tags: vec![],
+        translations: Some(vec![Translation {
+            translation_id: key_id,
+            key_id,
+            language_iso: "en".to_string(),
+            translation: translation.to_string(),
+            modified_by: 0,
+            modified_by_email: String::new(),
+            modified_at: String::new(),
+            modified_at_timestamp: 0,
+            is_reviewed: true,
+            is_unverified: false,
+            reviewed_by: 0,
+            task_id: None,
+        }]),
+    };
+    let keys = || KeysResponse {
+        project_id: "project".to_string(),
+        keys: vec![
+            key(1, "buy", "buy_android", "buy_ios", "Buy"),
+            key(2, "", "sell", "sell_ios", "Sell"),
+            key(3, "", "", "priceAlertTitle", "Price alert"),
+            key(4, "", "", "", "Nameless"),
+        ],
+    };
+
+    let map = TranslationMap::build(keys(), &super::config::default_platforms());
+    assert_eq!(map.find("buy", "en").map(String::as_str), Some("Buy"));
+    assert_eq!(map.find("sell", "en").map(String::as_str), Some("Sell"));
+    assert_eq!(
+        map.find("priceAlertTitle", "en").map(String::as_str),
+        Some("Price alert")
+    );
+    assert_eq!(map.keys().len(), 3);
+
+    let map = TranslationMap::build(keys(), &[Platform::Ios]);
+    assert_eq!(map.find("buy_ios", "en").map(String::as_str), Some("Buy"));
+    assert_eq!(map.find("buy", "en"), None);
+}
+
+#[test]
+fn test_merge() {
+    let project = |translations: &[(&str, &str, &str)]| {
+        let mut map = HashMap::<Key, ValuesMap>::new();
+        for &(key, lang, value) in translations {
+            map.entry(key.to_string())
+                .or_default()
+                .insert(lang.to_string(), value.to_string());
+        }
+        TranslationMap::from_map(map)
+    };
+    let common = project(&[
+        ("buy", "en", "Buy"),
+        ("buy", "ru", "Покупка"),
+        ("sell", "en", "Sell"),
+    ]);
+    let app = project(&[
+        ("buy", "en", "Bought"),
+        ("priceAlertTitle", "en", "Price alert"),
+    ]);
+
+    let mut merged = TranslationMap::default();
+    merged.merge(common);
+    merged.merge(app);
+
+    // The later project takes precedence, per language
+    assert_eq!(merged.find("buy", "en").map(String::as_str), Some("Bought"));
+    assert_eq!(
+        merged.find("buy", "ru").map(String::as_str),
+        Some("Покупка")
+    );
+    assert_eq!(merged.find("sell", "en").map(String::as_str), Some("Sell"));
+    assert_eq!(
+        merged.find("priceAlertTitle", "en").map(String::as_str),
+        Some("Price alert")
+    );
+    assert_eq!(merged.keys().len(), 3);
+}
+
+#[test]
+fn test_translate_missing() {
+    let map = TranslationMap::from_map(HashMap::from([(
+        "buy".to_string(),
+        HashMap::from([("en".to_string(), "Buy".to_string())]),
+    )]));
+    assert_eq!(map.translate("buy", "en").map(String::as_str), Some("Buy"));
+    assert_eq!(map.translate("buy", "ru"), None);
+    assert_eq!(map.translate("sell", "en"), None);
+    assert_eq!(TranslationMap::default().translate("buy", "en"), None);
+}
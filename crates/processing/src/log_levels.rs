@@ -0,0 +1,110 @@
+//! Per-module log level overrides.
+//!
+//! Variables like `LOG_LEVEL_source_orders=trace` are converted to `RUST_LOG` directives
+//! (`<crate>::source::orders=trace`), so that a single noisy module can be switched to trace
+//! without flooding the rest of the log.
+//!
+//! Underscores in the variable name separate module path segments. A directive applies to
+//! nested modules as well, so a module whose own name contains an underscore
+//! (like `source::orders::redis_stream`) is addressed through its parent.
+
+use thiserror::Error;
+
+const VAR_PREFIX: &str = "LOG_LEVEL_";
+const DEFAULT_LEVEL: &str = "info";
+const KNOWN_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Bad log level '{level}' for module '{module}'")]
+pub struct BadLogLevel {
+    module: String,
+    level: String,
+}
+
+/// Apply overrides from the environment to `RUST_LOG`.
+///
+/// Must be called before anything is logged, because the logger reads `RUST_LOG` only once.
+pub fn apply_overrides(crate_name: &str) -> Result<(), BadLogLevel> {
+    let overrides = parse_overrides(crate_name, std::env::vars())?;
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let base = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LEVEL.to_string());
+    let mut directives = vec![base];
+    directives.extend(
+        overrides
+            .into_iter()
+            .map(|(module, level)| format!("{}={}", module, level)),
+    );
+    std::env::set_var("RUST_LOG", directives.join(","));
+    Ok(())
+}
+
+/// Returns `(module path, level)` pairs, sorted by module path.
+fn parse_overrides(
+    crate_name: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<(String, String)>, BadLogLevel> {
+    let mut overrides = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let module = name.strip_prefix(VAR_PREFIX)?;
+            (!module.is_empty()).then(|| (module.to_string(), value))
+        })
+        .map(|(module, value)| {
+            let level = value.trim().to_ascii_lowercase();
+            if !KNOWN_LEVELS.contains(&level.as_str()) {
+                return Err(BadLogLevel {
+                    module,
+                    level: value,
+                });
+            }
+            let path = module.replace('_', "::");
+            Ok((format!("{}::{}", crate_name, path), level))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    overrides.sort();
+    Ok(overrides)
+}
+
+#[test]
+fn test_parse_overrides() {
+    let vars = |v: &[(&str, &str)]| {
+        v.iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let res = parse_overrides(
+        "processor_orders",
+        vars(&[
+            ("LOG_LEVEL_source_orders", "trace"),
+            ("RUST_LOG", "info"),
+            ("LOG_LEVEL_source", "DEBUG"),
+            ("LOG_LEVEL_", "trace"),
+            ("REDIS_HOSTNAME", "localhost"),
+        ]),
+    );
+    assert_eq!(
+        res,
+        Ok(vec![
+            ("processor_orders::source".to_string(), "debug".to_string()),
+            (
+                "processor_orders::source::orders".to_string(),
+                "trace".to_string()
+            ),
+        ])
+    );
+
+    let res = parse_overrides("processor_prices", vars(&[]));
+    assert_eq!(res, Ok(vec![]));
+
+    let res = parse_overrides("processor_prices", vars(&[("LOG_LEVEL_source", "loud")]));
+    assert_eq!(
+        res,
+        Err(BadLogLevel {
+            module: "source".to_string(),
+            level: "loud".to_string(),
+        })
+    );
+}
@@ -0,0 +1,121 @@
+//! Event processing metrics
+
+use lazy_static::lazy_static;
+use model::event::Event;
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts};
+use std::time::Duration;
+
+use crate::stats::EventStats;
+
+lazy_static! {
+    pub static ref EVENTS_RECEIVED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "events_received",
+            "Events received from the source, by kind"
+        ),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref SUBSCRIPTIONS_MATCHED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "subscriptions_matched",
+            "Subscriptions matching the events processed, by event kind"
+        ),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref MESSAGES_ENQUEUED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "messages_enqueued",
+            "Messages enqueued for sending (digests merged into count too), by event kind"
+        ),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref EVENT_PROCESSING_DURATION: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "event_processing_duration_seconds",
+            "Time to process an event, including the failed attempts, by event kind"
+        )
+        .buckets(vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0
+        ]),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref UNLISTED_ASSET_NOTIFICATIONS_SKIPPED: IntCounter = IntCounter::new(
+        "unlisted_asset_notifications_skipped",
+        "Notifications not sent because of assets without a ticker"
+    )
+    .unwrap();
+    pub static ref DEVICE_CAP_NOTIFICATIONS_DROPPED: IntCounter = IntCounter::new(
+        "device_cap_notifications_dropped",
+        "Notifications dropped because the device has reached its notification cap"
+    )
+    .unwrap();
+    pub static ref DUPLICATE_MESSAGES_SKIPPED: IntCounter = IntCounter::new(
+        "duplicate_messages_skipped",
+        "Messages not enqueued because a duplicate was queued for the device recently"
+    )
+    .unwrap();
+    pub static ref UNTRANSLATED_NOTIFICATIONS_SKIPPED: IntCounter = IntCounter::new(
+        "untranslated_notifications_skipped",
+        "Notifications not sent because neither the device language nor a fallback is translated"
+    )
+    .unwrap();
+    pub static ref MUTED_PAIR_EVENTS_SKIPPED: IntCounter = IntCounter::new(
+        "muted_pair_events_skipped",
+        "Price events not notified about because the asset pair is muted"
+    )
+    .unwrap();
+}
+
+/// Value of the `kind` label of the event metrics
+pub(crate) fn event_kind(event: &Event) -> &'static str {
+    match event {
+        Event::OrderExecuted { .. } => "order",
+        Event::PriceChanged { .. } => "price",
+    }
+}
+
+/// Subscriptions and messages are only counted if the event is processed successfully,
+/// otherwise its transaction is rolled back, and they are counted again on a retry
+pub(crate) fn record_processed<E>(kind: &str, result: &Result<EventStats, E>, duration: Duration) {
+    if let Ok(stats) = result {
+        SUBSCRIPTIONS_MATCHED
+            .with_label_values(&[kind])
+            .inc_by(stats.subscriptions_matched);
+        MESSAGES_ENQUEUED
+            .with_label_values(&[kind])
+            .inc_by(stats.messages_enqueued);
+    }
+    EVENT_PROCESSING_DURATION
+        .with_label_values(&[kind])
+        .observe(duration.as_secs_f64());
+}
+
+#[test]
+fn test_record_processed() {
+    let enqueued = || MESSAGES_ENQUEUED.with_label_values(&["price"]).get();
+    let observed = || {
+        EVENT_PROCESSING_DURATION
+            .with_label_values(&["price"])
+            .get_sample_count()
+    };
+    let (enqueued_before, observed_before) = (enqueued(), observed());
+
+    // A price event with two matching subscriptions
+    let stats = EventStats {
+        subscriptions_matched: 2,
+        messages_enqueued: 2,
+        oneshots_completed: 1,
+    };
+    record_processed::<()>("price", &Ok(stats), Duration::from_millis(20));
+    assert_eq!(enqueued(), enqueued_before + 2);
+    assert_eq!(observed(), observed_before + 1);
+
+    // Rolled back
+    record_processed("price", &Err(()), Duration::from_millis(20));
+    assert_eq!(enqueued(), enqueued_before + 2);
+    assert_eq!(observed(), observed_before + 2);
+}
@@ -0,0 +1,224 @@
+//! Price notifications muted per asset pair, e.g. during an incident (bad oracle, delisting).
+//!
+//! Muted pairs are read from a file with one `amount_asset_id/price_asset_id` pair per line
+//! (blank lines and `#` comments are ignored). Alternatively (or in addition), a file
+//! of the same format can list the only pairs to notify about, muting the rest.
+//! The files are re-read periodically, so pairs can be muted and unmuted without restarting
+//! the service. Subscriptions to muted pairs are left intact.
+
+use std::{collections::HashSet, sync::Arc, sync::RwLock, time::Duration};
+
+use model::{
+    asset::{Asset, AssetPair},
+    event::Event,
+};
+use thiserror::Error;
+
+use crate::config::ProcessingConfig;
+
+#[derive(Debug, Error)]
+pub enum MutedPairsError {
+    #[error("Failed to read muted pairs file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Bad muted asset pair '{0}'")]
+    BadPair(String),
+}
+
+#[derive(Default)]
+pub struct MutedPairs {
+    pairs: RwLock<HashSet<AssetPair>>,
+    /// If set, pairs not listed here are muted too
+    allowed: RwLock<Option<HashSet<AssetPair>>>,
+}
+
+impl MutedPairs {
+    /// Load muted and allowed pairs from the configured files (if any)
+    /// and keep reloading them in background
+    pub fn start(config: &ProcessingConfig) -> Result<Arc<Self>, MutedPairsError> {
+        let muted_pairs = Arc::new(MutedPairs::default());
+        let interval = config.muted_pairs_reload_interval();
+        if let Some(path) = &config.muted_pairs_file {
+            let pairs = read_file(path)?;
+            log::info!("Muted pairs loaded from {}: {:?}", path, pairs);
+            *muted_pairs.pairs.write().expect("lock") = pairs;
+            let reload =
+                muted_pairs
+                    .clone()
+                    .reload_periodically(path.clone(), interval, Self::replace);
+            tokio::task::spawn(reload);
+        }
+        if let Some(path) = &config.allowed_pairs_file {
+            let pairs = read_file(path)?;
+            log::info!("Allowed pairs loaded from {}: {:?}", path, pairs);
+            *muted_pairs.allowed.write().expect("lock") = Some(pairs);
+            let reload =
+                muted_pairs
+                    .clone()
+                    .reload_periodically(path.clone(), interval, Self::allow_only);
+            tokio::task::spawn(reload);
+        }
+        Ok(muted_pairs)
+    }
+
+    /// Whether notifications about the event are muted
+    pub fn mutes(&self, event: &Event) -> bool {
+        match event {
+            Event::PriceChanged { asset_pair, .. } => {
+                let allowed = match &*self.allowed.read().expect("lock") {
+                    Some(allowed) => allowed.contains(asset_pair),
+                    None => true,
+                };
+                !allowed || self.pairs.read().expect("lock").contains(asset_pair)
+            }
+            Event::OrderExecuted { .. } => false,
+        }
+    }
+
+    fn replace(&self, pairs: HashSet<AssetPair>) {
+        let mut current = self.pairs.write().expect("lock");
+        if *current != pairs {
+            log::info!("Muted pairs changed: {:?}", pairs);
+            *current = pairs;
+        }
+    }
+
+    fn allow_only(&self, pairs: HashSet<AssetPair>) {
+        let mut current = self.allowed.write().expect("lock");
+        if current.as_ref() != Some(&pairs) {
+            log::info!("Allowed pairs changed: {:?}", pairs);
+            *current = Some(pairs);
+        }
+    }
+
+    /// A broken file doesn't change anything: the last good list is kept until the file is fixed
+    async fn reload_periodically(
+        self: Arc<Self>,
+        path: String,
+        interval: Duration,
+        apply: fn(&Self, HashSet<AssetPair>),
+    ) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match read_file(&path) {
+                Ok(pairs) => apply(&self, pairs),
+                Err(err) => log::warn!("Pairs not reloaded from {}: {}", path, err),
+            }
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<HashSet<AssetPair>, MutedPairsError> {
+    let contents = std::fs::read_to_string(path)?;
+    parse(&contents)
+}
+
+fn parse(contents: &str) -> Result<HashSet<AssetPair>, MutedPairsError> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let bad_pair = || MutedPairsError::BadPair(line.to_string());
+            let (amount_asset, price_asset) = line.split_once('/').ok_or_else(bad_pair)?;
+            Ok(AssetPair {
+                amount_asset: Asset::from_id(amount_asset.trim()).map_err(|_| bad_pair())?,
+                price_asset: Asset::from_id(price_asset.trim()).map_err(|_| bad_pair())?,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+
+    let pairs = parse("").unwrap();
+    assert!(pairs.is_empty());
+
+    let pairs = parse(&format!(
+        "# Oracle incident\nWAVES/{USDN}\n\n  {USDN} / WAVES  # reversed\n"
+    ))
+    .unwrap();
+    let usdn = Asset::from_id(USDN).unwrap();
+    assert_eq!(
+        pairs,
+        HashSet::from([
+            AssetPair {
+                amount_asset: Asset::Waves,
+                price_asset: usdn.clone(),
+            },
+            AssetPair {
+                amount_asset: usdn,
+                price_asset: Asset::Waves,
+            },
+        ])
+    );
+
+    assert!(matches!(parse("WAVES"), Err(MutedPairsError::BadPair(_))));
+    assert!(matches!(
+        parse("WAVES/bad!"),
+        Err(MutedPairsError::BadPair(_))
+    ));
+}
+
+#[test]
+fn test_mutes() {
+    use model::{
+        order::{OrderExecution, OrderSide, OrderType},
+        price::PriceRange,
+        time::Timestamp,
+        waves::Address,
+    };
+
+    let muted_pair = AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+    };
+    let other_pair = AssetPair {
+        amount_asset: muted_pair.price_asset.clone(),
+        price_asset: Asset::Waves,
+    };
+    let price_changed = |asset_pair: &AssetPair| Event::PriceChanged {
+        asset_pair: asset_pair.clone(),
+        price_range: PriceRange::empty(),
+        timestamp: Timestamp::from_unix_timestamp_millis(0),
+    };
+
+    let muted_pairs = MutedPairs::default();
+    assert!(!muted_pairs.mutes(&price_changed(&muted_pair)));
+
+    muted_pairs.replace(HashSet::from([muted_pair.clone()]));
+    assert!(muted_pairs.mutes(&price_changed(&muted_pair)));
+    assert!(!muted_pairs.mutes(&price_changed(&other_pair)));
+
+    // Order notifications are not affected
+    let order_executed = Event::OrderExecuted {
+        order_id: "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq".to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair: muted_pair.clone(),
+        execution: OrderExecution::Full,
+        address: Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap(),
+        timestamp: Timestamp::from_unix_timestamp_millis(0),
+    };
+    assert!(!muted_pairs.mutes(&order_executed));
+
+    // Unmuted after reload
+    muted_pairs.replace(HashSet::new());
+    assert!(!muted_pairs.mutes(&price_changed(&muted_pair)));
+
+    // Pairs which are not allowed are muted, the muted ones stay muted even if allowed
+    muted_pairs.allow_only(HashSet::from([muted_pair.clone()]));
+    assert!(!muted_pairs.mutes(&price_changed(&muted_pair)));
+    assert!(muted_pairs.mutes(&price_changed(&other_pair)));
+    assert!(!muted_pairs.mutes(&order_executed));
+    muted_pairs.replace(HashSet::from([muted_pair.clone()]));
+    assert!(muted_pairs.mutes(&price_changed(&muted_pair)));
+
+    // Allowed after reload
+    muted_pairs.replace(HashSet::new());
+    muted_pairs.allow_only(HashSet::from([muted_pair.clone(), other_pair.clone()]));
+    assert!(!muted_pairs.mutes(&price_changed(&muted_pair)));
+    assert!(!muted_pairs.mutes(&price_changed(&other_pair)));
+}
@@ -1,5 +1,15 @@
-use crate::{asset, localization, error::Error};
-use database::{device, message, subscription};
+use crate::{
+    asset,
+    config::ProcessingConfig,
+    error::Error,
+    localization, metrics,
+    muted_pairs::MutedPairs,
+    stats::{EventStats, Summary},
+};
+use database::{
+    device, message, state,
+    subscription::{self, Subscription},
+};
 use diesel_async::{AsyncConnection, AsyncPgConnection};
 use model::{
     asset::Asset,
@@ -7,25 +17,54 @@ use model::{
     event::Event,
     message::{LocalizedMessage, Message, MessageData, PreparedMessage},
     order::OrderExecution,
+    time::{DateTimeUtc, Timestamp},
     topic::{SubscriptionMode, Topic},
     waves::AsBase58String,
 };
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, oneshot};
 
 use diesel_async::scoped_futures::ScopedFutureExt as _;
 
+#[cfg(test)]
+use crate::testing;
+
+/// The ids of the processed events past their retention are deleted at most this often
+const PROCESSED_EVENTS_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
 pub struct EventWithFeedback {
     pub event: Event,
+    /// When the source received the event
+    pub received_at: Timestamp,
+    pub checkpoint: Option<Checkpoint>,
+    /// Stable id of the event in its source (like the last trade of the pair for a price event).
+    /// An event with an id already processed is skipped, even if the source reads it again.
+    pub event_id: Option<String>,
     pub result_tx: oneshot::Sender<Result<(), Error>>,
 }
 
+/// Position of the event in its source, saved in the same transaction as the messages
+/// enqueued for the event, so that the source can skip the event if it is read again
+/// (say, after a crash before the source acknowledged it)
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub key: &'static str,
+    pub value: String,
+}
+
 pub struct MessagePump {
     subscriptions: subscription::Repo,
     assets: asset::RemoteGateway,
     devices: device::Repo,
     localizer: localization::Repo,
     messages: message::Queue,
+    state: state::Repo,
+    muted_pairs: Arc<MutedPairs>,
+    config: ProcessingConfig,
 }
 
 impl MessagePump {
@@ -35,6 +74,8 @@ impl MessagePump {
         devices: device::Repo,
         localizer: localization::Repo,
         messages: message::Queue,
+        muted_pairs: Arc<MutedPairs>,
+        config: ProcessingConfig,
     ) -> Self {
         MessagePump {
             subscriptions,
@@ -42,6 +83,9 @@ impl MessagePump {
             devices,
             localizer,
             messages,
+            state: state::Repo {},
+            muted_pairs,
+            config,
         }
     }
 
@@ -49,25 +93,223 @@ impl MessagePump {
         self: Arc<Self>,
         mut events: mpsc::Receiver<EventWithFeedback>,
         mut conn: AsyncPgConnection,
-    ) {
+    ) -> Summary {
         log::debug!("Starting event processing loop");
+        let mut summary = Summary::new();
+        let mut purged_at = None;
         while let Some(event) = events.recv().await {
-            let EventWithFeedback { event, result_tx } = event;
-            let this = self.clone();
-            let res = conn
+            self.process_event(event, &mut summary, &mut conn).await;
+            self.purge_processed_events(&mut purged_at, &mut conn).await;
+        }
+        log::info!("Event processing loop finished: {}", summary);
+        summary
+    }
+
+    /// Processes the event and sends the result to its source. A source which does not
+    /// await the result anymore fails neither the event nor the loop.
+    async fn process_event(
+        &self,
+        event: EventWithFeedback,
+        summary: &mut Summary,
+        conn: &mut AsyncPgConnection,
+    ) {
+        let EventWithFeedback {
+            event,
+            received_at,
+            checkpoint,
+            event_id,
+            result_tx,
+        } = event;
+        let kind = metrics::event_kind(&event);
+        metrics::EVENTS_RECEIVED.with_label_values(&[kind]).inc();
+        let started_at = Instant::now();
+        let res = match self.config.subscriptions_chunk_size() {
+            Some(chunk_size) => {
+                self.process_in_chunks(event, received_at, checkpoint, event_id, chunk_size, conn)
+                    .await
+            }
+            None => {
+                self.process_in_transaction(event, received_at, checkpoint, event_id, conn)
+                    .await
+            }
+        };
+        summary.record(&res);
+        metrics::record_processed(kind, &res, started_at.elapsed());
+        send_result(result_tx, res.map(|_| ()));
+    }
+
+    /// Deletes the ids of the events processed before the retention period, unless done
+    /// within `PROCESSED_EVENTS_PURGE_INTERVAL`. Errors are logged only, to be retried next time.
+    async fn purge_processed_events(
+        &self,
+        purged_at: &mut Option<Instant>,
+        conn: &mut AsyncPgConnection,
+    ) {
+        if matches!(purged_at, Some(at) if at.elapsed() < PROCESSED_EVENTS_PURGE_INTERVAL) {
+            return;
+        }
+        *purged_at = Some(Instant::now());
+        let retention = self.config.processed_event_retention();
+        let cutoff = Timestamp::from_unix_timestamp_millis(
+            Timestamp::now().unix_timestamp_millis() - retention.as_millis() as i64,
+        );
+        match self.state.delete_processed_events(utc(cutoff), conn).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Deleted {} processed event ids", count),
+            Err(err) => log::error!("Failed to delete processed event ids: {:?}", err),
+        }
+    }
+
+    /// Processes the event and saves the checkpoint in a single transaction,
+    /// along with the event id, so that the event is processed once
+    async fn process_in_transaction(
+        &self,
+        event: Event,
+        received_at: Timestamp,
+        checkpoint: Option<Checkpoint>,
+        event_id: Option<String>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<EventStats, Error> {
+        conn.transaction(|conn| {
+            async move {
+                // Asynchronously process this event within a database transaction
+                if let Some(event_id) = &event_id {
+                    if !self.state.mark_event_processed(event_id, conn).await? {
+                        log::info!("Event {} is already processed - skipped", event_id);
+                        return Ok(EventStats::default());
+                    }
+                }
+                let (event, subscriptions) = self.prepare_event(event, conn).await?;
+                let mut delivered = DeliveredDevices::for_event(&event);
+                let mut log_cap = LogCap::new(self.config.max_logged_subscriptions);
+                let stats = self
+                    .process_subscriptions(
+                        &event,
+                        received_at,
+                        subscriptions,
+                        &mut delivered,
+                        &mut log_cap,
+                        conn,
+                    )
+                    .await?;
+                log_cap.log_summary();
+                if let Some(checkpoint) = checkpoint {
+                    log::trace!("Saving checkpoint {:?}", checkpoint);
+                    self.state
+                        .set(checkpoint.key, &checkpoint.value, conn)
+                        .await?;
+                }
+                Ok(stats)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Processes the matching subscriptions in chunks, a transaction per chunk,
+    /// then saves the checkpoint.
+    ///
+    /// The progress is saved along with each chunk, so if the event is processed again
+    /// (after a failure, before the checkpoint is saved), the subscriptions of the chunks
+    /// already committed are skipped. Only the device deduplication of order events
+    /// (see `DeliveredDevices`) does not survive such a restart.
+    /// The event id is saved along with the checkpoint, once all the chunks are committed.
+    async fn process_in_chunks(
+        &self,
+        event: Event,
+        received_at: Timestamp,
+        checkpoint: Option<Checkpoint>,
+        event_id: Option<String>,
+        chunk_size: usize,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<EventStats, Error> {
+        if let Some(event_id) = &event_id {
+            if self.state.is_event_processed(event_id, conn).await? {
+                log::info!("Event {} is already processed - skipped", event_id);
+                return Ok(EventStats::default());
+            }
+        }
+        // Before the timestamp is sanitized, so that it is the same if the event is read again
+        let progress = ChunkProgress::for_event(&event);
+        let (event, subscriptions) = self.prepare_event(event, conn).await?;
+        let done_up_to = match self.state.get(progress.key, conn).await? {
+            Some(value) => progress.done_up_to(&value),
+            None => None,
+        };
+        if let Some(uid) = done_up_to {
+            log::info!("Resuming event processing after subscription {}", uid);
+        }
+
+        let mut stats = EventStats::default();
+        let mut delivered = DeliveredDevices::for_event(&event);
+        let mut log_cap = LogCap::new(self.config.max_logged_subscriptions);
+        let chunks = pending_chunks(subscriptions, |s| s.uid, done_up_to, chunk_size);
+        for chunk in chunks {
+            let last_uid = chunk.last().expect("non-empty chunk").uid;
+            let (event, progress) = (&event, &progress);
+            let (delivered, log_cap) = (&mut delivered, &mut log_cap);
+            let chunk_stats = conn
                 .transaction(|conn| {
                     async move {
-                        // Asynchronously process this event within a database transaction
-                        this.process_event(event, conn).await
+                        let stats = self
+                            .process_subscriptions(
+                                event,
+                                received_at,
+                                chunk,
+                                delivered,
+                                log_cap,
+                                conn,
+                            )
+                            .await?;
+                        log::trace!("Chunk committed up to subscription {}", last_uid);
+                        self.state
+                            .set(progress.key, &progress.value(last_uid), conn)
+                            .await?;
+                        Ok::<_, Error>(stats)
                     }
                     .scope_boxed()
                 })
-                .await;
-            result_tx.send(res).expect("ack");
+                .await?;
+            stats.subscriptions_matched += chunk_stats.subscriptions_matched;
+            stats.messages_enqueued += chunk_stats.messages_enqueued;
+            stats.oneshots_completed += chunk_stats.oneshots_completed;
+        }
+        log_cap.log_summary();
+
+        if let Some(checkpoint) = checkpoint {
+            log::trace!("Saving checkpoint {:?}", checkpoint);
+            self.state
+                .set(checkpoint.key, &checkpoint.value, conn)
+                .await?;
+        }
+        if let Some(event_id) = &event_id {
+            self.state.mark_event_processed(event_id, conn).await?;
         }
+        Ok(stats)
     }
 
-    async fn process_event(&self, event: Event, conn: &mut AsyncPgConnection) -> Result<(), Error> {
+    /// Sanitizes the event timestamp and finds the subscriptions matching the event
+    /// (none if the event is muted), ordered by uid
+    async fn prepare_event(
+        &self,
+        mut event: Event,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(Event, Vec<Subscription>), Error> {
+        let timestamp = event.timestamp();
+        let sanitized = sanitize_timestamp(timestamp, Timestamp::now(), &self.config);
+        if sanitized != timestamp {
+            log::warn!(
+                "Implausible event timestamp {:?} replaced with {:?}",
+                timestamp,
+                sanitized
+            );
+            *event.timestamp_mut() = sanitized;
+        }
+        if self.muted_pairs.mutes(&event) {
+            log::debug!("Event for a muted pair - skipped: {:?}", event);
+            metrics::MUTED_PAIR_EVENTS_SKIPPED.inc();
+            return Ok((event, vec![]));
+        }
         let subscriptions = self.subscriptions.matching(&event, conn).await?;
         if subscriptions.is_empty() {
             log::trace!("Event with no matching subscriptions: {:?}", event);
@@ -75,10 +317,48 @@ impl MessagePump {
             let n = subscriptions.len();
             log::debug!("Event with {} matching subscriptions: {:?}", n, event);
         }
+        Ok((event, subscriptions))
+    }
+
+    async fn process_subscriptions(
+        &self,
+        event: &Event,
+        received_at: Timestamp,
+        subscriptions: Vec<Subscription>,
+        delivered: &mut DeliveredDevices,
+        log_cap: &mut LogCap,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<EventStats, Error> {
+        let mut stats = EventStats {
+            subscriptions_matched: subscriptions.len() as u64,
+            ..EventStats::default()
+        };
+        // Messages which are not buffered are inserted all at once
+        let mut batch = Vec::new();
         for subscription in subscriptions {
-            log::debug!("  Subscription: {:?}", subscription);
+            let verbose = log_cap.allows();
+            if verbose {
+                log::debug!("  Subscription: {:?}", subscription);
+            }
+            if is_within_grace(event, &subscription, &self.config) {
+                // One-shot subscription stays active too
+                if verbose {
+                    log::debug!("  Subscription is newer than the price movement - skipped");
+                }
+                continue;
+            }
             let is_oneshot = subscription.mode == SubscriptionMode::Once;
-            let msg = self.make_message(&event, &subscription.topic).await?;
+            let msg = match self.make_message(event, &subscription).await? {
+                Some(msg) => msg,
+                None => {
+                    // One-shot subscription stays active until an event it can be notified about
+                    if verbose {
+                        log::debug!("  Event involves an unlisted asset - skipped");
+                    }
+                    metrics::UNLISTED_ASSET_NOTIFICATIONS_SKIPPED.inc();
+                    continue;
+                }
+            };
             let address = &subscription.subscriber;
             let devices = self.devices.subscribers(address, conn).await?;
             if devices.is_empty() {
@@ -88,35 +368,90 @@ impl MessagePump {
                 );
             }
             for device in devices {
-                log::debug!("    Device: {:?}", device);
-                let message = self.localize(&msg, &device.locale);
-                let meta = Self::make_metadata(&event, &device);
+                if verbose {
+                    log::debug!("    Device: {:?}", device);
+                }
+                if !delivered.first_delivery(device.device_uid) {
+                    if verbose {
+                        log::debug!("      Already notified about this event - skipped");
+                    }
+                    continue;
+                }
+                let message = match self.localize(&msg, &device.locale) {
+                    Some(message) => message,
+                    None => {
+                        log::warn!(
+                            "No translation for the {} language or the fallbacks {:?} - skipped",
+                            device.locale.lang,
+                            self.config.fallback_langs,
+                        );
+                        metrics::UNTRANSLATED_NOTIFICATIONS_SKIPPED.inc();
+                        continue;
+                    }
+                };
+                let meta = Self::make_metadata(event, &device);
+                let group_key = self
+                    .config
+                    .notification_grouping
+                    .then(|| meta.group_key().to_string());
+                let deep_link = self.config.deep_link(&meta);
+                let delivery_style = self.config.delivery_style(&meta);
+                let ttl = Some(self.config.ttl(&meta));
+                let collapse_key = meta.collapse_key();
+                let priority = meta.priority();
+                let offset = device.locale.utc_offset_seconds;
+                let scheduled_for = QuietHours::from_config(&self.config)
+                    .and_then(|quiet_hours| quiet_hours.deferred_until(Timestamp::now(), offset))
+                    .map(utc);
                 let prepared_message = PreparedMessage {
                     device,
                     message,
                     data: Some(meta),
-                    collapse_key: None,
+                    collapse_key,
+                    group_key,
+                    deep_link,
+                    event_received_at: received_at,
+                    event_timestamp: event.timestamp(),
+                    delivery_style,
+                    ttl,
+                    priority,
+                    scheduled_for,
                 };
-                log::debug!("      Message prepared: {:?}", prepared_message);
-                self.messages.enqueue(prepared_message, conn).await?;
+                if verbose {
+                    log::debug!("      Message prepared: {:?}", prepared_message);
+                }
+                if self.enqueue(prepared_message, &mut batch, conn).await? {
+                    stats.messages_enqueued += 1;
+                }
             }
             if is_oneshot {
-                log::debug!(
-                    "Removing completed one-shot subscription: {:?}",
-                    subscription
-                );
+                if verbose {
+                    log::debug!(
+                        "Removing completed one-shot subscription: {:?}",
+                        subscription
+                    );
+                }
                 self.subscriptions
                     .complete_oneshot(subscription, conn)
                     .await?;
+                stats.oneshots_completed += 1;
             }
         }
-        Ok(())
+        self.messages.enqueue_batch(batch, conn).await?;
+        Ok(stats)
     }
 
-    async fn make_message(&self, event: &Event, topic: &Topic) -> Result<Message, Error> {
-        let res = match (event, topic) {
+    /// Returns `None` if the event involves an unlisted asset which is not to be notified about
+    async fn make_message(
+        &self,
+        event: &Event,
+        subscription: &Subscription,
+    ) -> Result<Option<Message>, Error> {
+        let label = subscription.label.clone();
+        let res = match (event, &subscription.topic) {
             (
                 Event::OrderExecuted {
+                    order_id: _,
                     order_type,
                     side,
                     asset_pair: event_assets,
@@ -124,16 +459,22 @@ impl MessagePump {
                     address: _,
                     timestamp,
                 },
-                Topic::OrderFulfilled,
+                Topic::OrderFulfilled(_),
             ) => {
                 let (amount_asset, price_asset) = event_assets.assets_as_ref();
+                let (amount_asset_ticker, price_asset_ticker) =
+                    match self.pair_tickers(amount_asset, price_asset).await? {
+                        Some(tickers) => tickers,
+                        None => return Ok(None),
+                    };
                 Message::OrderExecuted {
                     order_type: *order_type,
                     side: *side,
-                    amount_asset_ticker: self.asset_ticker(amount_asset).await?,
-                    price_asset_ticker: self.asset_ticker(price_asset).await?,
+                    amount_asset_ticker,
+                    price_asset_ticker,
                     execution: *execution,
                     timestamp: *timestamp,
+                    label,
                 }
             }
             (
@@ -148,35 +489,73 @@ impl MessagePump {
                 debug_assert_eq!(event_assets.price_asset, topic.price_asset);
                 debug_assert!(price_range.contains(topic.price_threshold));
                 let (amount_asset, price_asset) = event_assets.assets_as_ref();
+                let (amount_asset_ticker, price_asset_ticker) =
+                    match self.pair_tickers(amount_asset, price_asset).await? {
+                        Some(tickers) => tickers,
+                        None => return Ok(None),
+                    };
                 Message::PriceThresholdReached {
-                    amount_asset_ticker: self.asset_ticker(amount_asset).await?,
-                    price_asset_ticker: self.asset_ticker(price_asset).await?,
+                    amount_asset_ticker,
+                    price_asset_ticker,
                     threshold: topic.price_threshold,
                     timestamp: *timestamp,
+                    label,
+                }
+            }
+            (
+                Event::PriceChanged {
+                    asset_pair: event_assets,
+                    price_range,
+                    timestamp,
+                },
+                Topic::PricePercentChange(topic),
+            ) => {
+                debug_assert_eq!(event_assets.amount_asset, topic.amount_asset);
+                debug_assert_eq!(event_assets.price_asset, topic.price_asset);
+                // Matched subscriptions have a base price, and the range contains a threshold
+                let (low, high) = topic.thresholds().expect("base price");
+                let threshold = if price_range.contains(low) { low } else { high };
+                debug_assert!(price_range.contains(threshold));
+                let (amount_asset, price_asset) = event_assets.assets_as_ref();
+                let (amount_asset_ticker, price_asset_ticker) =
+                    match self.pair_tickers(amount_asset, price_asset).await? {
+                        Some(tickers) => tickers,
+                        None => return Ok(None),
+                    };
+                Message::PriceThresholdReached {
+                    amount_asset_ticker,
+                    price_asset_ticker,
+                    threshold,
+                    timestamp: *timestamp,
+                    label,
                 }
             }
             (_, _) => unreachable!("unrecognized combination of subscription and event"),
         };
-        Ok(res)
+        Ok(Some(res))
     }
 
     fn make_metadata(event: &Event, device: &Device) -> MessageData {
         match event {
             Event::OrderExecuted {
+                order_id,
                 execution: OrderExecution::Full,
                 asset_pair,
                 ..
             } => MessageData::OrderExecuted {
+                order_id: order_id.clone(),
                 amount_asset_id: asset_pair.amount_asset.id(),
                 price_asset_id: asset_pair.price_asset.id(),
                 address: device.address.as_base58_string(),
             },
 
             Event::OrderExecuted {
+                order_id,
                 execution: OrderExecution::Partial { .. },
                 asset_pair,
                 ..
             } => MessageData::OrderPartiallyExecuted {
+                order_id: order_id.clone(),
                 amount_asset_id: asset_pair.amount_asset.id(),
                 price_asset_id: asset_pair.price_asset.id(),
                 address: device.address.as_base58_string(),
@@ -190,25 +569,1108 @@ impl MessagePump {
         }
     }
 
-    async fn asset_ticker(&self, asset: &Asset) -> Result<String, Error> {
-        let maybe_ticker = self.assets.ticker(asset).await.map_err(Error::AssetsApiError)?;
-        let ticker = maybe_ticker.unwrap_or_else(|| asset.id());
-        Ok(ticker)
+    /// Enqueue the message, or merge it into a pending digest if digests are enabled.
+    /// Messages which are not buffered for a digest are added to the batch to be inserted later.
+    /// Returns `false` if the message is dropped as a duplicate or because of the per-device cap.
+    async fn enqueue(
+        &self,
+        message: PreparedMessage,
+        batch: &mut Vec<PreparedMessage>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let now = Timestamp::now();
+        // Checked first, so that duplicates neither count towards the cap nor make a digest
+        if let Some(window) = self.config.dedup_window() {
+            let since = Timestamp::from_unix_timestamp_millis(
+                now.unix_timestamp_millis() - window.as_millis() as i64,
+            );
+            // The batch is not inserted yet, so the messages of this event are looked up here
+            let dedup_key = message.data.as_ref().and_then(MessageData::dedup_key);
+            let batched = dedup_key.is_some()
+                && batch.iter().any(|queued| {
+                    queued.device.device_uid == message.device.device_uid
+                        && queued.data.as_ref().and_then(MessageData::dedup_key) == dedup_key
+                });
+            if batched
+                || self
+                    .messages
+                    .has_duplicate(&message, utc(since), conn)
+                    .await?
+            {
+                log::debug!("      Duplicate of a message queued recently - dropped");
+                metrics::DUPLICATE_MESSAGES_SKIPPED.inc();
+                return Ok(false);
+            }
+        }
+        let is_alert = matches!(
+            message.data,
+            Some(MessageData::PriceThresholdReached { .. })
+        );
+        let window = match self.config.digest_window() {
+            Some(window) if is_alert => window,
+            _ => {
+                if !self
+                    .within_device_cap(message.device.device_uid, now, conn)
+                    .await?
+                {
+                    return Ok(false);
+                }
+                batch.push(message);
+                return Ok(true);
+            }
+        };
+
+        let device = &message.device;
+        let pending = self
+            .messages
+            .pending_digest(device.device_uid, utc(now), conn)
+            .await?;
+        if let Some(digest) = pending {
+            let count = digest.count + 1;
+            if let Some(localized) = self.localize_digest(count as u32, &device.locale) {
+                log::debug!("      Merged into digest #{} of {}", digest.uid, count);
+                let data = MessageData::Digest {
+                    count: count as u32,
+                    address: device.address.as_base58_string(),
+                };
+                let merged = message::Digest {
+                    count,
+                    message: localized,
+                    deep_link: self.config.deep_link(&data),
+                    delivery_style: self.config.delivery_style(&data),
+                    data,
+                };
+                self.messages
+                    .merge_into_digest(digest.uid, merged, conn)
+                    .await?;
+                return Ok(true);
+            }
+            log::warn!("No translation for alerts digest - the alert is sent separately");
+        }
+
+        // Merging into a digest doesn't add a notification, so only new messages are capped
+        if !self.within_device_cap(device.device_uid, now, conn).await? {
+            return Ok(false);
+        }
+        let until = Timestamp::from_unix_timestamp_millis(
+            now.unix_timestamp_millis() + window.as_millis() as i64,
+        );
+        self.messages
+            .enqueue_buffered(message, utc(until), conn)
+            .await?;
+        Ok(true)
     }
 
-    fn localize(&self, message: &Message, locale: &LocaleInfo) -> LocalizedMessage {
-        const FALLBACK_LANG: &str = "en";
-        let maybe_message = self.localizer.localize(message, locale);
-        if let Some(message) = maybe_message {
-            message
-        } else {
+    /// Counts a notification for the device, unless it has reached the cap
+    async fn within_device_cap(
+        &self,
+        device_uid: i32,
+        now: Timestamp,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<bool, Error> {
+        let cap = match DeviceCap::from_config(&self.config) {
+            Some(cap) => cap,
+            None => return Ok(true),
+        };
+        let count = self
+            .devices
+            .count_notification(device_uid, utc(now), utc(cap.window_start(now)), conn)
+            .await?;
+        let allowed = cap.allows(count);
+        if !allowed {
+            log::debug!(
+                "      Device notification cap reached ({}) - dropped",
+                count
+            );
+            metrics::DEVICE_CAP_NOTIFICATIONS_DROPPED.inc();
+        }
+        Ok(allowed)
+    }
+
+    fn localize_digest(&self, count: u32, locale: &LocaleInfo) -> Option<LocalizedMessage> {
+        with_fallback(locale, &self.config.fallback_langs, |locale| {
+            self.localizer.localize_digest(count, locale)
+        })
+    }
+
+    async fn pair_tickers(
+        &self,
+        amount_asset: &Asset,
+        price_asset: &Asset,
+    ) -> Result<Option<(String, String)>, Error> {
+        let amount_asset_ticker = self.asset_ticker(amount_asset).await?;
+        let price_asset_ticker = self.asset_ticker(price_asset).await?;
+        Ok(amount_asset_ticker.zip(price_asset_ticker))
+    }
+
+    async fn asset_ticker(&self, asset: &Asset) -> Result<Option<String>, Error> {
+        let maybe_ticker = self
+            .assets
+            .ticker(asset)
+            .await
+            .map_err(Error::AssetsApiError)?;
+        Ok(ticker_or_id(
+            asset,
+            maybe_ticker,
+            self.config.notify_unlisted_assets,
+        ))
+    }
+
+    fn localize(&self, message: &Message, locale: &LocaleInfo) -> Option<LocalizedMessage> {
+        with_fallback(locale, &self.config.fallback_langs, |locale| {
+            self.localizer.localize(message, locale)
+        })
+    }
+}
+
+/// Localized in the device language if possible, otherwise in the first of the fallback
+/// languages translated (keeping the device timezone)
+fn with_fallback<T>(
+    locale: &LocaleInfo,
+    fallback_langs: &[String],
+    localize: impl Fn(&LocaleInfo) -> Option<T>,
+) -> Option<T> {
+    localize(locale).or_else(|| {
+        fallback_langs.iter().find_map(|lang| {
             let fallback_locale = LocaleInfo {
-                lang: FALLBACK_LANG.to_string(),
+                lang: lang.clone(),
                 utc_offset_seconds: locale.utc_offset_seconds,
             };
-            self.localizer
-                .localize(message, &fallback_locale)
-                .expect("fallback translation")
+            localize(&fallback_locale)
+        })
+    })
+}
+
+/// Unlisted assets (having no ticker) are referred to by id, unless they are not to be notified about
+fn ticker_or_id(asset: &Asset, ticker: Option<String>, notify_unlisted: bool) -> Option<String> {
+    match ticker {
+        Some(ticker) => Some(ticker),
+        None if notify_unlisted => Some(asset.id()),
+        None => None,
+    }
+}
+
+/// Reports the processing result to the source of the event.
+///
+/// The source may have stopped waiting for the result (see its result timeout, or it is
+/// shutting down), which is not a reason to stop the event loop. The transaction is not
+/// rolled back then: the event is processed and its checkpoint is saved, so the source
+/// skips it if it reads the event again.
+fn send_result(result_tx: oneshot::Sender<Result<(), Error>>, res: Result<(), Error>) {
+    if result_tx.send(res).is_err() {
+        log::warn!("Event processing result is not awaited anymore");
+    }
+}
+
+fn utc(timestamp: Timestamp) -> DateTimeUtc {
+    timestamp.date_time_utc().expect("timestamp in range")
+}
+
+/// Maximum number of notifications per device within a time window,
+/// a safety valve against runaway (say, misconfigured) alerts
+struct DeviceCap {
+    max_notifications: u32,
+    window: Duration,
+}
+
+impl DeviceCap {
+    fn from_config(config: &ProcessingConfig) -> Option<Self> {
+        let max_notifications = config.device_notification_cap?;
+        Some(DeviceCap {
+            max_notifications,
+            window: config.device_notification_cap_window(),
+        })
+    }
+
+    /// A window started at or before this moment is over
+    fn window_start(&self, now: Timestamp) -> Timestamp {
+        Timestamp::from_unix_timestamp_millis(
+            now.unix_timestamp_millis() - self.window.as_millis() as i64,
+        )
+    }
+
+    /// `count` includes the notification in question
+    fn allows(&self, count: u32) -> bool {
+        count <= self.max_notifications
+    }
+}
+
+/// Hours of the day (local time of the device) during which notifications are not delivered
+/// but deferred until the end hour. Quiet hours may wrap around midnight (like 22 to 8).
+struct QuietHours {
+    start_hour: u8,
+    end_hour: u8,
+}
+
+impl QuietHours {
+    fn from_config(config: &ProcessingConfig) -> Option<Self> {
+        let start_hour = config.quiet_hours_start?;
+        let end_hour = config.quiet_hours_end?;
+        (start_hour != end_hour).then_some(QuietHours {
+            start_hour,
+            end_hour,
+        })
+    }
+
+    /// When to deliver a notification due `now` to a device with the UTC offset,
+    /// `None` if it is not within quiet hours there
+    fn deferred_until(&self, now: Timestamp, utc_offset_seconds: i32) -> Option<Timestamp> {
+        const HOUR_MILLIS: i64 = 3600 * 1000;
+        const DAY_MILLIS: i64 = 24 * HOUR_MILLIS;
+
+        let now = now.unix_timestamp_millis();
+        let local_time = (now + utc_offset_seconds as i64 * 1000).rem_euclid(DAY_MILLIS);
+        let start = self.start_hour as i64 * HOUR_MILLIS;
+        let end = self.end_hour as i64 * HOUR_MILLIS;
+        let is_quiet = if start < end {
+            start <= local_time && local_time < end
+        } else {
+            local_time >= start || local_time < end
+        };
+        is_quiet.then(|| {
+            let wait = (end - local_time).rem_euclid(DAY_MILLIS);
+            Timestamp::from_unix_timestamp_millis(now + wait)
+        })
+    }
+}
+
+/// Devices already notified about the event being processed.
+///
+/// An order event is delivered at most once per device, even if several order subscriptions
+/// of the same address match it (like the all-pairs `push://orders` and a pair-specific one).
+/// Price events are not deduplicated, because every crossed threshold deserves its own message.
+struct DeliveredDevices {
+    dedupe: bool,
+    device_uids: HashSet<i32>,
+}
+
+impl DeliveredDevices {
+    fn for_event(event: &Event) -> Self {
+        DeliveredDevices {
+            dedupe: matches!(event, Event::OrderExecuted { .. }),
+            device_uids: HashSet::new(),
+        }
+    }
+
+    fn first_delivery(&mut self, device_uid: i32) -> bool {
+        !self.dedupe || self.device_uids.insert(device_uid)
+    }
+}
+
+/// Limits the debug logging of the subscriptions matching an event (and their devices),
+/// which is overwhelming for events matching thousands of them
+struct LogCap {
+    max: u32,
+    logged: u32,
+    suppressed: u32,
+}
+
+impl LogCap {
+    fn new(max: u32) -> Self {
+        LogCap {
+            max,
+            logged: 0,
+            suppressed: 0,
         }
     }
+
+    /// Whether the next subscription is to be logged individually
+    fn allows(&mut self) -> bool {
+        if self.logged < self.max {
+            self.logged += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+
+    fn summary(&self) -> Option<String> {
+        (self.suppressed > 0).then(|| {
+            format!(
+                "  ...and {} more subscriptions (not logged, only {} are)",
+                self.suppressed, self.max
+            )
+        })
+    }
+
+    fn log_summary(&self) {
+        if let Some(summary) = self.summary() {
+            log::debug!("{}", summary);
+        }
+    }
+}
+
+/// Progress of an event processed in chunks (see `MessagePump::process_in_chunks`):
+/// the event fingerprint and the uid of the last subscription committed,
+/// stored in the service state under a key per event kind.
+///
+/// The fingerprint is made of the event fields explicitly (rather than hashed),
+/// so that it is the same for an event read again by any build of the service.
+struct ChunkProgress {
+    key: &'static str,
+    fingerprint: String,
+}
+
+impl ChunkProgress {
+    fn for_event(event: &Event) -> Self {
+        let (key, fingerprint) = match event {
+            Event::OrderExecuted {
+                asset_pair,
+                address,
+                timestamp,
+                ..
+            } => (
+                "order_event_progress",
+                format!(
+                    "{}/{}/{}/{}",
+                    address.as_base58_string(),
+                    asset_pair.amount_asset.id(),
+                    asset_pair.price_asset.id(),
+                    timestamp.unix_timestamp_millis()
+                ),
+            ),
+            Event::PriceChanged {
+                asset_pair,
+                price_range,
+                timestamp,
+            } => {
+                // Microblocks may share the timestamp of their block, but not the range
+                let (low, high) = match price_range.is_empty() {
+                    true => (0.0, 0.0),
+                    false => price_range.low_high(),
+                };
+                let fingerprint = format!(
+                    "{}/{}/{}/{}/{}",
+                    asset_pair.amount_asset.id(),
+                    asset_pair.price_asset.id(),
+                    timestamp.unix_timestamp_millis(),
+                    low,
+                    high
+                );
+                ("price_event_progress", fingerprint)
+            }
+        };
+        ChunkProgress { key, fingerprint }
+    }
+
+    fn value(&self, last_uid: i32) -> String {
+        format!("{}:{}", self.fingerprint, last_uid)
+    }
+
+    /// Uid of the last subscription committed, if the saved progress is of this very event
+    fn done_up_to(&self, value: &str) -> Option<i32> {
+        match value.rsplit_once(':') {
+            Some((fingerprint, uid)) if fingerprint == self.fingerprint => uid.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the items (ordered by uid) into chunks of `chunk_size`,
+/// skipping the ones up to `done_up_to`, which are already processed
+fn pending_chunks<T>(
+    items: Vec<T>,
+    uid: impl Fn(&T) -> i32,
+    done_up_to: Option<i32>,
+    chunk_size: usize,
+) -> Vec<Vec<T>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::with_capacity(chunk_size);
+    for item in items {
+        if matches!(done_up_to, Some(done) if uid(&item) <= done) {
+            continue;
+        }
+        chunk.push(item);
+        if chunk.len() == chunk_size {
+            chunks.push(std::mem::replace(
+                &mut chunk,
+                Vec::with_capacity(chunk_size),
+            ));
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Whether the event is too early for a (newly created) subscription to match it.
+/// Only price events are checked: order events are about orders placed by the subscriber,
+/// so they can't predate the subscription in a meaningful way.
+fn is_within_grace(event: &Event, subscription: &Subscription, config: &ProcessingConfig) -> bool {
+    let grace = match config.new_subscription_grace() {
+        Some(grace) => grace,
+        None => return false,
+    };
+    match event {
+        Event::PriceChanged { timestamp, .. } => {
+            let subscribed_at = subscription.created_at.timestamp_millis();
+            timestamp.unix_timestamp_millis() <= subscribed_at + grace.as_millis() as i64
+        }
+        Event::OrderExecuted { .. } => false,
+    }
+}
+
+/// Event timestamps can be off by several hours (and microblock ones are synthesized),
+/// so implausible values are replaced with the current time instead of being shown to users.
+fn sanitize_timestamp(
+    timestamp: Timestamp,
+    now: Timestamp,
+    config: &ProcessingConfig,
+) -> Timestamp {
+    let max_future = config.event_timestamp_max_future();
+    let max_past = config.event_timestamp_max_past();
+    if timestamp.is_valid(now, max_future, max_past) {
+        timestamp
+    } else {
+        now
+    }
+}
+
+#[test]
+fn test_sanitize_timestamp() {
+    let config = ProcessingConfig {
+        event_timestamp_max_past_sec: Some(86400),
+        ..testing::config()
+    };
+    let now = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    let ts = |offset_sec: i64| {
+        Timestamp::from_unix_timestamp_millis(now.unix_timestamp_millis() + offset_sec * 1000)
+    };
+
+    // Plausible timestamps are kept as is
+    assert_eq!(sanitize_timestamp(ts(-60), now, &config), ts(-60));
+    assert_eq!(sanitize_timestamp(ts(600), now, &config), ts(600));
+
+    // Far-future timestamp is clamped to now
+    assert_eq!(sanitize_timestamp(ts(365 * 86400), now, &config), now);
+
+    // Too old timestamp is replaced too, unless the check is disabled
+    assert_eq!(sanitize_timestamp(ts(-2 * 86400), now, &config), now);
+    let config = ProcessingConfig {
+        event_timestamp_max_past_sec: None,
+        ..config
+    };
+    assert_eq!(
+        sanitize_timestamp(ts(-2 * 86400), now, &config),
+        ts(-2 * 86400)
+    );
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_result_not_awaited() {
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        testing::subscribe_orders(None, &mut conn).await;
+        let pump = testing::pump(testing::config());
+        let mut summary = Summary::new();
+        let event = |order_id, result_tx| EventWithFeedback {
+            event: testing::order_event(order_id),
+            received_at: Timestamp::now(),
+            checkpoint: None,
+            event_id: None,
+            result_tx,
+        };
+
+        // The receiver is dropped, as by a source which is shutting down
+        let (result_tx, result_rx) = oneshot::channel();
+        drop(result_rx);
+        pump.process_event(event("order1", result_tx), &mut summary, &mut conn)
+            .await;
+
+        // The event is processed all the same, and so is the next one
+        let (result_tx, result_rx) = oneshot::channel();
+        pump.process_event(event("order2", result_tx), &mut summary, &mut conn)
+            .await;
+        assert!(matches!(result_rx.await, Ok(Ok(()))));
+        assert_eq!(summary.events_processed, 2);
+        assert_eq!(summary.errors, 0);
+        assert_eq!(testing::queued(&mut conn).await.len(), 2);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_subscriptions_matched_metric() {
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        testing::subscribe_orders(None, &mut conn).await;
+        testing::subscribe_orders(Some(testing::waves_usdn()), &mut conn).await;
+        let pump = Arc::new(testing::pump(testing::config()));
+        let matched = || {
+            metrics::SUBSCRIPTIONS_MATCHED
+                .with_label_values(&["order"])
+                .get()
+        };
+        let matched_before = matched();
+
+        let (events_tx, events_rx) = mpsc::channel(1);
+        let event_loop = tokio::spawn(pump.run_event_loop(events_rx, conn));
+        let (result_tx, result_rx) = oneshot::channel();
+        let event = EventWithFeedback {
+            event: testing::order_event("order1"),
+            received_at: Timestamp::now(),
+            checkpoint: None,
+            event_id: None,
+            result_tx,
+        };
+        assert!(events_tx.send(event).await.is_ok());
+        result_rx.await.unwrap().unwrap();
+        drop(events_tx);
+        let summary = event_loop.await.unwrap();
+
+        // Both subscriptions are counted, though the device is notified once
+        assert_eq!(summary.messages_enqueued, 1);
+        assert_eq!(matched(), matched_before + 2);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_delivered_devices() {
+    use crate::testing::queued;
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("phone", &mut conn).await;
+        testing::register_device("tablet", &mut conn).await;
+        testing::subscribe_orders(None, &mut conn).await;
+        testing::subscribe_orders(Some(testing::waves_usdn()), &mut conn).await;
+        testing::subscribe_price(2.0, &mut conn).await;
+        testing::subscribe_price(3.0, &mut conn).await;
+        let pump = testing::pump(testing::config());
+        let received_at = Timestamp::now();
+
+        // Order events: every device is notified once, though both subscriptions match
+        let stats = pump
+            .process_in_transaction(
+                testing::order_event("order1"),
+                received_at,
+                None,
+                None,
+                &mut conn,
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 2);
+
+        // Price events: a message per matched threshold
+        let event = testing::price_event(1.0, 4.0);
+        let stats = pump
+            .process_in_transaction(event, received_at, None, None, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 4);
+        let titles = queued(&mut conn)
+            .await
+            .into_iter()
+            .map(|(title, _)| title)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            titles,
+            [
+                "Order filled",
+                "Order filled",
+                "Price alert",
+                "Price alert",
+                "Price alert",
+                "Price alert"
+            ]
+        );
+    });
+}
+
+#[test]
+fn test_log_cap() {
+    let mut cap = LogCap::new(3);
+    let logged = (0..5).map(|_| cap.allows()).collect::<Vec<_>>();
+    assert_eq!(logged, vec![true, true, true, false, false]);
+    assert_eq!(
+        cap.summary().unwrap(),
+        "  ...and 2 more subscriptions (not logged, only 3 are)"
+    );
+
+    // No summary within the cap
+    let mut cap = LogCap::new(3);
+    assert!(cap.allows() && cap.allows() && cap.allows());
+    assert_eq!(cap.summary(), None);
+
+    // Only the summary
+    let mut cap = LogCap::new(0);
+    assert!(!cap.allows());
+    assert!(cap.summary().unwrap().contains("1 more"));
+}
+
+#[test]
+fn test_pending_chunks() {
+    let items = vec![1, 2, 4, 5, 7, 8, 9];
+
+    assert_eq!(
+        pending_chunks(items.clone(), |&uid| uid, None, 3),
+        vec![vec![1, 2, 4], vec![5, 7, 8], vec![9]]
+    );
+    assert_eq!(
+        pending_chunks(items.clone(), |&uid| uid, None, 7),
+        vec![items.clone()]
+    );
+    // Resumed after the first chunk, the uid of a completed one-shot may be gone by then
+    assert_eq!(
+        pending_chunks(vec![2, 5, 7, 8, 9], |&uid| uid, Some(4), 3),
+        vec![vec![5, 7, 8], vec![9]]
+    );
+    assert!(pending_chunks(items, |&uid| uid, Some(9), 3).is_empty());
+    assert!(pending_chunks(Vec::<i32>::new(), |&uid| uid, None, 3).is_empty());
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_chunks_recovery() {
+    use crate::testing::{price_event, queued};
+    use database::schema::subscriptions;
+    use diesel::QueryDsl;
+    use diesel_async::RunQueryDsl;
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        for threshold in [1.5, 2.0, 2.5, 3.0, 3.5] {
+            testing::subscribe_price(threshold, &mut conn).await;
+        }
+        let uids = subscriptions::table
+            .select(subscriptions::uid)
+            .order(subscriptions::uid)
+            .load::<i32>(&mut conn)
+            .await
+            .unwrap();
+        let pump = testing::pump(testing::config());
+        let state = state::Repo {};
+        let event = price_event(1.0, 4.0);
+        let timestamp = event.timestamp();
+        let progress = ChunkProgress::for_event(&event);
+
+        // Interrupted after the first chunk (of 2 subscriptions) had been committed
+        state
+            .set(progress.key, &progress.value(uids[1]), &mut conn)
+            .await
+            .unwrap();
+        let stats = pump
+            .process_in_chunks(event, Timestamp::now(), None, None, 2, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 3);
+        assert_eq!(queued(&mut conn).await.len(), 3);
+        assert_eq!(
+            state.get(progress.key, &mut conn).await.unwrap(),
+            Some(progress.value(uids[4]))
+        );
+
+        // Progress of another event (another microblock with the same timestamp) is ignored
+        let mut other = price_event(1.0, 5.0);
+        *other.timestamp_mut() = timestamp;
+        let stats = pump
+            .process_in_chunks(other, Timestamp::now(), None, None, 2, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 5);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_chunks_recovery_mixed_topics() {
+    use crate::testing::price_event;
+    use database::schema::{messages, subscriptions};
+    use diesel::QueryDsl;
+    use diesel_async::RunQueryDsl;
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        // Threshold and percent subscriptions interleaved, 1.5, 3.0, 2.5, 2.7 and 3.5 are reached
+        testing::subscribe_price(1.5, &mut conn).await;
+        testing::subscribe_percent(2.0, 50.0, &mut conn).await;
+        testing::subscribe_price(2.5, &mut conn).await;
+        testing::subscribe_percent(3.0, 10.0, &mut conn).await;
+        testing::subscribe_price(3.5, &mut conn).await;
+        let uids = subscriptions::table
+            .select(subscriptions::uid)
+            .order(subscriptions::uid)
+            .load::<i32>(&mut conn)
+            .await
+            .unwrap();
+        let pump = testing::pump(testing::config());
+        let state = state::Repo {};
+        let event = price_event(1.0, 4.0);
+        let progress = ChunkProgress::for_event(&event);
+
+        // Interrupted after the first chunk (a threshold and a percent subscription)
+        state
+            .set(progress.key, &progress.value(uids[1]), &mut conn)
+            .await
+            .unwrap();
+        let stats = pump
+            .process_in_chunks(event, Timestamp::now(), None, None, 2, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 3);
+        let bodies = messages::table
+            .select(messages::notification_body)
+            .order(messages::uid)
+            .load::<String>(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            bodies,
+            [
+                "WAVES/USDN reached 2.5",
+                "WAVES/USDN reached 2.7",
+                "WAVES/USDN reached 3.5"
+            ]
+        );
+        assert_eq!(
+            state.get(progress.key, &mut conn).await.unwrap(),
+            Some(progress.value(uids[4]))
+        );
+    });
+}
+
+#[test]
+fn test_ticker_or_id() {
+    let unlisted = Asset::from_id("8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS").unwrap();
+    let ticker = || Some("BTC".to_string());
+
+    // Listed asset is referred to by ticker either way
+    assert_eq!(ticker_or_id(&unlisted, ticker(), true), ticker());
+    assert_eq!(ticker_or_id(&unlisted, ticker(), false), ticker());
+
+    // Unlisted asset is referred to by id if allowed, otherwise the notification is suppressed
+    assert_eq!(
+        ticker_or_id(&unlisted, None, true),
+        Some("8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS".to_string())
+    );
+    assert_eq!(ticker_or_id(&unlisted, None, false), None);
+}
+
+#[test]
+fn test_with_fallback() {
+    let translated = ["de", "en-US"];
+    let localize = |locale: &LocaleInfo| {
+        let lang = locale.lang.as_str();
+        translated
+            .contains(&lang)
+            .then(|| (lang.to_string(), locale.utc_offset_seconds))
+    };
+    let locale = |lang: &str| LocaleInfo {
+        lang: lang.to_string(),
+        utc_offset_seconds: 3600,
+    };
+    let fallback_langs = vec!["en".to_string(), "en-US".to_string()];
+
+    // Device language
+    assert_eq!(
+        with_fallback(&locale("de"), &fallback_langs, localize),
+        Some(("de".to_string(), 3600))
+    );
+
+    // Falls through the chain to the first translated language, in the device timezone
+    assert_eq!(
+        with_fallback(&locale("ru"), &fallback_langs, localize),
+        Some(("en-US".to_string(), 3600))
+    );
+
+    // Nothing translated
+    assert_eq!(
+        with_fallback(&locale("ru"), &["fr".to_string()], localize),
+        None
+    );
+    assert_eq!(with_fallback(&locale("ru"), &[], localize), None);
+}
+
+#[test]
+fn test_device_cap() {
+    let cap = DeviceCap {
+        max_notifications: 3,
+        window: Duration::from_secs(3600),
+    };
+    let now = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    assert_eq!(
+        cap.window_start(now),
+        Timestamp::from_unix_timestamp_millis(1_700_000_000_000 - 3_600_000)
+    );
+
+    // Notifications beyond the cap within the window are suppressed
+    let allowed = (1..=5).map(|count| cap.allows(count)).collect::<Vec<_>>();
+    assert_eq!(allowed, vec![true, true, true, false, false]);
+
+    // Disabled unless configured
+    let config = testing::config();
+    assert!(DeviceCap::from_config(&config).is_none());
+    let config = ProcessingConfig {
+        device_notification_cap: Some(20),
+        ..config
+    };
+    let cap = DeviceCap::from_config(&config).unwrap();
+    assert_eq!(cap.max_notifications, 20);
+    assert_eq!(cap.window, Duration::from_secs(3600));
+}
+
+#[test]
+fn test_quiet_hours() {
+    // 2023-11-14 00:00:00 UTC
+    let midnight = 1_699_920_000_000;
+    let at = |hour: i64, minute: i64| {
+        Timestamp::from_unix_timestamp_millis(midnight + (hour * 60 + minute) * 60_000)
+    };
+
+    // Wrapping around midnight
+    let quiet_hours = QuietHours {
+        start_hour: 22,
+        end_hour: 8,
+    };
+    let utc_device = |now| quiet_hours.deferred_until(now, 0);
+    assert_eq!(utc_device(at(21, 59)), None);
+    assert_eq!(utc_device(at(22, 0)), Some(at(32, 0)));
+    assert_eq!(utc_device(at(23, 30)), Some(at(32, 0)));
+    assert_eq!(utc_device(at(7, 59)), Some(at(8, 0)));
+    assert_eq!(utc_device(at(8, 0)), None);
+
+    // Local time is ahead of UTC: 19:00 UTC is 22:00 there
+    let moscow_device = |now| quiet_hours.deferred_until(now, 3 * 3600);
+    assert_eq!(moscow_device(at(18, 59)), None);
+    assert_eq!(moscow_device(at(19, 0)), Some(at(29, 0)));
+    assert_eq!(moscow_device(at(4, 59)), Some(at(5, 0)));
+    assert_eq!(moscow_device(at(5, 0)), None);
+
+    // Local time is behind UTC: 03:00 UTC is 22:00 of the day before there
+    let new_york_device = |now| quiet_hours.deferred_until(now, -5 * 3600);
+    assert_eq!(new_york_device(at(2, 59)), None);
+    assert_eq!(new_york_device(at(3, 0)), Some(at(13, 0)));
+    assert_eq!(new_york_device(at(12, 59)), Some(at(13, 0)));
+    assert_eq!(new_york_device(at(13, 0)), None);
+
+    // Within a day
+    let quiet_hours = QuietHours {
+        start_hour: 1,
+        end_hour: 6,
+    };
+    assert_eq!(quiet_hours.deferred_until(at(0, 30), 0), None);
+    assert_eq!(quiet_hours.deferred_until(at(3, 0), 0), Some(at(6, 0)));
+    assert_eq!(quiet_hours.deferred_until(at(6, 0), 0), None);
+    assert_eq!(quiet_hours.deferred_until(at(3, 0), 5 * 3600), None);
+
+    // Disabled unless both hours are set and differ
+    let config = |start, end| ProcessingConfig {
+        quiet_hours_start: start,
+        quiet_hours_end: end,
+        ..testing::config()
+    };
+    assert!(QuietHours::from_config(&config(None, None)).is_none());
+    assert!(QuietHours::from_config(&config(Some(22), None)).is_none());
+    assert!(QuietHours::from_config(&config(Some(8), Some(8))).is_none());
+    let quiet_hours = QuietHours::from_config(&config(Some(22), Some(8))).unwrap();
+    assert_eq!((quiet_hours.start_hour, quiet_hours.end_hour), (22, 8));
+}
+
+#[test]
+fn test_is_within_grace() {
+    use model::{
+        asset::AssetPair,
+        price::PriceRange,
+        topic::{PriceThreshold, ThresholdDirection},
+        waves::Address,
+    };
+
+    let config = |grace_sec| ProcessingConfig {
+        new_subscription_grace_sec: grace_sec,
+        ..testing::config()
+    };
+    let asset_pair = AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+    };
+    let subscribed_at = 1_700_000_000_000;
+    let subscription = Subscription {
+        uid: 1,
+        subscriber: Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap(),
+        created_at: utc(Timestamp::from_unix_timestamp_millis(subscribed_at)),
+        mode: SubscriptionMode::Once,
+        topic: Topic::PriceThreshold(PriceThreshold {
+            amount_asset: asset_pair.amount_asset.clone(),
+            price_asset: asset_pair.price_asset.clone(),
+            price_threshold: 500.0,
+            direction: ThresholdDirection::Any,
+        }),
+        label: None,
+    };
+    let price_changed = |timestamp| Event::PriceChanged {
+        asset_pair: asset_pair.clone(),
+        price_range: PriceRange::empty(),
+        timestamp: Timestamp::from_unix_timestamp_millis(timestamp),
+    };
+
+    // Doesn't fire on price movement which predates the subscription
+    let config_no_grace = config(Some(0));
+    let is_within = |timestamp, config: &ProcessingConfig| {
+        is_within_grace(&price_changed(timestamp), &subscription, config)
+    };
+    assert!(is_within(subscribed_at - 1000, &config_no_grace));
+    assert!(is_within(subscribed_at, &config_no_grace));
+    assert!(!is_within(subscribed_at + 1, &config_no_grace));
+
+    let config_grace = config(Some(60));
+    assert!(is_within(subscribed_at + 60_000, &config_grace));
+    assert!(!is_within(subscribed_at + 60_001, &config_grace));
+
+    // Matched right away unless configured
+    assert!(!is_within(subscribed_at - 1000, &config(None)));
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_dedup_window() {
+    use crate::testing::{order_event, queued};
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        testing::subscribe_orders(None, &mut conn).await;
+        let pump = testing::pump(ProcessingConfig {
+            dedup_window_sec: Some(60),
+            ..testing::config()
+        });
+        let received_at = Timestamp::now();
+        let mut enqueued = Vec::new();
+        // The same order twice within the window, then another order on the same pair
+        for order_id in ["order1", "order1", "order2"] {
+            let stats = pump
+                .process_in_transaction(order_event(order_id), received_at, None, None, &mut conn)
+                .await
+                .unwrap();
+            enqueued.push(stats.messages_enqueued);
+        }
+        assert_eq!(enqueued, vec![1, 0, 1]);
+
+        // Alerts on the same pair within a single event are batched, but still duplicates
+        testing::subscribe_price(2.0, &mut conn).await;
+        testing::subscribe_price(3.0, &mut conn).await;
+        let event = testing::price_event(1.0, 4.0);
+        let stats = pump
+            .process_in_transaction(event, received_at, None, None, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 1);
+
+        assert_eq!(queued(&mut conn).await.len(), 3);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_event_processed_once() {
+    use crate::testing::{order_event, queued};
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        testing::subscribe_orders(None, &mut conn).await;
+        let pump = testing::pump(testing::config());
+        let received_at = Timestamp::now();
+        let event_id = |order_id| Some(format!("order:{}", order_id));
+
+        // The same event read twice, processed at once or in chunks
+        let mut enqueued = Vec::new();
+        for _ in 0..2 {
+            let event = order_event("order1");
+            let stats = pump
+                .process_in_transaction(event, received_at, None, event_id("order1"), &mut conn)
+                .await
+                .unwrap();
+            enqueued.push(stats.messages_enqueued);
+            let event = order_event("order2");
+            let stats = pump
+                .process_in_chunks(event, received_at, None, event_id("order2"), 10, &mut conn)
+                .await
+                .unwrap();
+            enqueued.push(stats.messages_enqueued);
+        }
+        assert_eq!(enqueued, vec![1, 1, 0, 0]);
+        assert_eq!(queued(&mut conn).await.len(), 2);
+
+        // Once purged, the id is not known anymore
+        let pump = testing::pump(ProcessingConfig {
+            processed_event_retention_sec: 0,
+            ..testing::config()
+        });
+        pump.purge_processed_events(&mut None, &mut conn).await;
+        let event = order_event("order1");
+        let stats = pump
+            .process_in_transaction(event, received_at, None, event_id("order1"), &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 1);
+    });
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_digest_window() {
+    use crate::testing::{price_event, queued};
+
+    let db = database::testing::TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        testing::register_device("fcm_uid", &mut conn).await;
+        testing::subscribe_price(2.0, &mut conn).await;
+        testing::subscribe_price(3.0, &mut conn).await;
+        let pump = testing::pump(ProcessingConfig {
+            digest_window_sec: Some(60),
+            ..testing::config()
+        });
+        let received_at = Timestamp::now();
+        let digest = |count| ("Price alerts".to_string(), Some(count));
+        let alert = || ("Price alert".to_string(), Some(1));
+
+        // Both alerts of the event make a single digest
+        let event = price_event(1.0, 4.0);
+        let stats = pump
+            .process_in_transaction(event, received_at, None, None, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stats.messages_enqueued, 2);
+        assert_eq!(queued(&mut conn).await, vec![digest(2)]);
+
+        // So does an alert of another event within the window
+        let event = price_event(4.0, 2.5);
+        pump.process_in_transaction(event, received_at, None, None, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(queued(&mut conn).await, vec![digest(3)]);
+
+        // Once the window has passed, the digest is sent as it is and an alert starts anew
+        testing::end_digest_window(&mut conn).await;
+        let event = price_event(2.5, 1.5);
+        pump.process_in_transaction(event, received_at, None, None, &mut conn)
+            .await
+            .unwrap();
+        assert_eq!(queued(&mut conn).await, vec![digest(3), alert()]);
+    });
 }
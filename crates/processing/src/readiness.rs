@@ -0,0 +1,62 @@
+//! Readiness of a processor service, reported by its `/readyz` endpoint.
+//!
+//! The service is not ready until it is initialized (event sources created, translations loaded)
+//! and the event loop is started.
+
+use std::{
+    future::{ready, Ready},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Service initialization is not finished")]
+pub struct NotReady;
+
+#[derive(Clone, Default)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn check(&self) -> Result<(), NotReady> {
+        if self.ready.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(NotReady)
+        }
+    }
+
+    /// Checker for `MetricsWarpBuilder::with_readyz_checker`
+    pub fn checker(&self) -> impl Fn() -> Ready<Result<(), NotReady>> + Clone + Send + Sync {
+        let readiness = self.clone();
+        move || ready(readiness.check())
+    }
+}
+
+#[test]
+fn test_readiness() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let readiness = Readiness::default();
+    // As the metrics server calls it, from another task
+    let checker = readiness.checker();
+    let check = || {
+        let checker = checker.clone();
+        rt.block_on(async move { tokio::spawn(checker()).await })
+            .unwrap()
+    };
+
+    assert!(check().is_err());
+    assert!(check().is_err());
+
+    readiness.set_ready();
+    assert!(check().is_ok());
+}
@@ -0,0 +1,103 @@
+//! Event processing statistics, reported when the event loop finishes
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Counters collected while processing a single event
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) struct EventStats {
+    /// Subscriptions processed, the ones of the chunks committed before a restart aside
+    pub subscriptions_matched: u64,
+    pub messages_enqueued: u64,
+    pub oneshots_completed: u64,
+}
+
+/// Totals accumulated by the event loop over its lifetime
+#[derive(Clone, Debug)]
+pub struct Summary {
+    started_at: Instant,
+    pub events_processed: u64,
+    pub messages_enqueued: u64,
+    pub oneshots_completed: u64,
+    pub errors: u64,
+}
+
+impl Summary {
+    pub(crate) fn new() -> Self {
+        Summary {
+            started_at: Instant::now(),
+            events_processed: 0,
+            messages_enqueued: 0,
+            oneshots_completed: 0,
+            errors: 0,
+        }
+    }
+
+    /// Account for the outcome of a single event.
+    /// Failed events are counted as errors only, because their transaction was rolled back.
+    pub(crate) fn record<E>(&mut self, result: &Result<EventStats, E>) {
+        match result {
+            Ok(stats) => {
+                self.events_processed += 1;
+                self.messages_enqueued += stats.messages_enqueued;
+                self.oneshots_completed += stats.oneshots_completed;
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events processed: {}, messages enqueued: {}, oneshots completed: {}, errors: {}, uptime: {:?}",
+            self.events_processed,
+            self.messages_enqueued,
+            self.oneshots_completed,
+            self.errors,
+            self.uptime(),
+        )
+    }
+}
+
+#[test]
+fn test_summary() {
+    let mut summary = Summary::new();
+    assert_eq!(
+        summary.to_string().split(", uptime").next().unwrap(),
+        "events processed: 0, messages enqueued: 0, oneshots completed: 0, errors: 0"
+    );
+
+    let events: [Result<EventStats, ()>; 4] = [
+        Ok(EventStats {
+            subscriptions_matched: 2,
+            messages_enqueued: 3,
+            oneshots_completed: 1,
+        }),
+        Ok(EventStats::default()),
+        Err(()),
+        Ok(EventStats {
+            subscriptions_matched: 2,
+            messages_enqueued: 2,
+            oneshots_completed: 0,
+        }),
+    ];
+    for event in &events {
+        summary.record(event);
+    }
+
+    assert_eq!(summary.events_processed, 3);
+    assert_eq!(summary.messages_enqueued, 5);
+    assert_eq!(summary.oneshots_completed, 1);
+    assert_eq!(summary.errors, 1);
+    assert!(summary.to_string().starts_with(
+        "events processed: 3, messages enqueued: 5, oneshots completed: 1, errors: 1, uptime: "
+    ));
+}
@@ -0,0 +1,193 @@
+//! Message pump over a throwaway database (see `database::testing`) for the tests
+//! of event processing. Asset tickers and translations are fixed, so no services are needed.
+
+use std::sync::Arc;
+
+use database::{
+    device, message,
+    schema::messages,
+    subscription::{self, SubscribeConfig, SubscriptionRequest},
+};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use model::{
+    asset::{Asset, AssetPair},
+    device::Platform,
+    event::Event,
+    order::{OrderExecution, OrderSide, OrderType},
+    price::{Price, PriceRange},
+    time::Timestamp,
+    topic::{PricePercentChange, PriceThreshold, SubscriptionMode, ThresholdDirection, Topic},
+    waves::Address,
+};
+
+use crate::{asset, config::ProcessingConfig, localization, muted_pairs::MutedPairs, MessagePump};
+
+pub(crate) const ADDRESS: &str = "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2";
+pub(crate) const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+
+/// Config with the defaults, as if no variables were set
+pub(crate) fn config() -> ProcessingConfig {
+    envy::from_iter(Vec::<(String, String)>::new()).expect("default config")
+}
+
+pub(crate) fn pump(config: ProcessingConfig) -> MessagePump {
+    let tickers = [(Asset::Waves, "WAVES"), (usdn(), "USDN")];
+    let translations = [
+        ("orderFilledTitle", "en", "Order filled"),
+        ("orderFilledMessage", "en", "[%s:side] [%s:pair] filled"),
+        (
+            "orderPartFilledMessage",
+            "en",
+            "[%s:side] [%s:pair] [%s:ratio]% filled",
+        ),
+        ("buy", "en", "Buy"),
+        ("sell", "en", "Sell"),
+        ("priceAlertTitle", "en", "Price alert"),
+        ("priceAlertMessage", "en", "[%s:pair] reached [%s:value]"),
+        ("alertsDigestTitle", "en", "Price alerts"),
+        (
+            "alertsDigestMessage",
+            "en",
+            "[%s:count] of your alerts triggered",
+        ),
+    ];
+    MessagePump::new(
+        subscription::Repo::default(),
+        asset::RemoteGateway::with_tickers(&tickers),
+        device::Repo::default(),
+        localization::Repo::with_translations(&translations),
+        message::Queue {},
+        Arc::new(MutedPairs::default()),
+        config,
+    )
+}
+
+pub(crate) fn usdn() -> Asset {
+    Asset::from_id(USDN).expect("asset id")
+}
+
+pub(crate) fn waves_usdn() -> AssetPair {
+    AssetPair {
+        amount_asset: Asset::Waves,
+        price_asset: usdn(),
+    }
+}
+
+pub(crate) fn address() -> Address {
+    Address::from_string(ADDRESS).expect("address")
+}
+
+/// Registers a device of `ADDRESS`, returns its uid
+pub(crate) async fn register_device(fcm_uid: &str, conn: &mut AsyncPgConnection) -> i32 {
+    let devices = device::Repo::default();
+    let address = address();
+    devices
+        .register(&address, &fcm_uid.to_string(), "en", 0, Platform::Web, conn)
+        .await
+        .expect("register device");
+    let registered = devices.subscribers(&address, conn).await.expect("devices");
+    registered
+        .into_iter()
+        .find(|device| device.fcm_uid == fcm_uid)
+        .expect("registered device")
+        .device_uid
+}
+
+/// Subscribes `ADDRESS` to the price of WAVES/USDN reaching the threshold
+pub(crate) async fn subscribe_price(threshold: Price, conn: &mut AsyncPgConnection) {
+    let topic = Topic::PriceThreshold(PriceThreshold {
+        amount_asset: Asset::Waves,
+        price_asset: usdn(),
+        price_threshold: threshold,
+        direction: ThresholdDirection::Any,
+    });
+    subscribe(topic, conn).await;
+}
+
+/// Subscribes `ADDRESS` to the price of WAVES/USDN changing by the percent from the base price
+pub(crate) async fn subscribe_percent(
+    base_price: Price,
+    percent: f64,
+    conn: &mut AsyncPgConnection,
+) {
+    let topic = Topic::PricePercentChange(PricePercentChange {
+        amount_asset: Asset::Waves,
+        price_asset: usdn(),
+        base_price: Some(base_price),
+        percent,
+    });
+    subscribe(topic, conn).await;
+}
+
+/// Subscribes `ADDRESS` to its orders on the pair, or on all pairs if `None`
+pub(crate) async fn subscribe_orders(pair: Option<AssetPair>, conn: &mut AsyncPgConnection) {
+    subscribe(Topic::OrderFulfilled(pair), conn).await;
+}
+
+async fn subscribe(topic: Topic, conn: &mut AsyncPgConnection) {
+    let request = SubscriptionRequest {
+        topic_url: topic.key(),
+        topic,
+        mode: SubscriptionMode::Repeat,
+        label: None,
+    };
+    let config = SubscribeConfig {
+        max_subscriptions_per_address_per_pair: 100,
+        max_subscriptions_per_address_total: 100,
+    };
+    subscription::Repo::default()
+        .subscribe(&address(), vec![request], &config, conn)
+        .await
+        .expect("subscribe");
+}
+
+/// Order of `ADDRESS` on WAVES/USDN filled right now
+pub(crate) fn order_event(order_id: &str) -> Event {
+    Event::OrderExecuted {
+        order_id: order_id.to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair: waves_usdn(),
+        execution: OrderExecution::Full,
+        address: address(),
+        timestamp: Timestamp::now(),
+    }
+}
+
+/// WAVES/USDN price moving from `from` to `to` right now
+pub(crate) fn price_event(from: Price, to: Price) -> Event {
+    Event::PriceChanged {
+        asset_pair: waves_usdn(),
+        price_range: PriceRange::empty()
+            .extend(from)
+            .extend(to)
+            .exclude_bound(from)
+            .moved_from(from)
+            .moved_to(to),
+        timestamp: Timestamp::now(),
+    }
+}
+
+/// Title and digest count of each queued message, in the order of insertion
+pub(crate) async fn queued(conn: &mut AsyncPgConnection) -> Vec<(String, Option<i32>)> {
+    messages::table
+        .select((messages::notification_title, messages::digest_count))
+        .order(messages::uid)
+        .load(conn)
+        .await
+        .expect("queued messages")
+}
+
+fn ago(secs: i64) -> Timestamp {
+    Timestamp::from_unix_timestamp_millis(Timestamp::now().unix_timestamp_millis() - secs * 1000)
+}
+
+/// Makes the buffered messages due, as if their digest window has passed
+pub(crate) async fn end_digest_window(conn: &mut AsyncPgConnection) {
+    diesel::update(messages::table.filter(messages::digest_count.is_not_null()))
+        .set(messages::scheduled_for.eq(ago(1).date_time_utc().expect("in range")))
+        .execute(conn)
+        .await
+        .expect("end digest window");
+}
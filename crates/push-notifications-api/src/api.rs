@@ -1,7 +1,14 @@
-use crate::{db::PgAsyncPool, error::Error};
-use database::{device, subscription};
-use model::waves::Address;
-use std::sync::Arc;
+use crate::{config::Config, db::PgAsyncPool, error::Error, price::PriceSource, topic::TopicError};
+use database::{
+    device, state,
+    subscription::{self, SubscriptionStamp},
+};
+use model::{secret::Secret, waves::Address};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
 use warp::{http, Filter, Rejection};
 use wavesexchange_warp::{
     error::{error_handler_with_serde_qs, handler, internal, validation, Response},
@@ -14,32 +21,30 @@ const ERROR_CODES_PREFIX: u16 = 95;
 type Pool = Arc<PgAsyncPool>;
 
 pub async fn start(
-    port: u16,
+    config: Config,
     devices: device::Repo,
     subscriptions: subscription::Repo,
+    state: state::Repo,
     subscribe_config: subscription::SubscribeConfig,
+    price_source: Option<PriceSource>,
     pool: PgAsyncPool,
 ) {
-    let error_handler = handler(ERROR_CODES_PREFIX, |err| match err {
-        Error::DbQueryError(e) => {
-            log::error!(e);
-            validation::invalid_parameter(ERROR_CODES_PREFIX, None)
-        }
-        Error::DatabaseError(e @ database::error::Error::LimitExceeded(_, _)) => {
-            log::debug!("{}", e);
-            Response::singleton(
-                http::StatusCode::BAD_REQUEST,
-                "Too many subscriptions",
-                ERROR_CODES_PREFIX as u32 * 10000 + 901,
-                None,
-            )
-        }
-        _ => internal(ERROR_CODES_PREFIX),
-    });
+    let Config {
+        port,
+        max_devices_per_address,
+        max_topics_per_request,
+        admin_token,
+        ..
+    } = config;
+    let error_handler = handler(ERROR_CODES_PREFIX, error_response);
 
     let with_devices = warp::any().map(move || devices.clone());
     let with_subscriptions = warp::any().map(move || subscriptions.clone());
     let with_subscribe_config = warp::any().map(move || subscribe_config.clone());
+    let with_price_source = warp::any().map(move || price_source.clone());
+    let with_state = warp::any().map(move || state.clone());
+    let with_max_devices = warp::any().map(move || max_devices_per_address);
+    let with_max_topics = warp::any().map(move || max_topics_per_request);
 
     let with_pool = {
         let pool = Arc::new(pool);
@@ -53,6 +58,22 @@ pub async fn start(
             .map_err(Rejection::from)
     });
 
+    let admin = {
+        let admin_token = Arc::new(admin_token);
+        warp::header::optional::<String>("X-Admin-Token")
+            .and_then(move |token: Option<String>| {
+                let admin_token = admin_token.clone();
+                async move {
+                    if is_admin(admin_token.as_ref().as_ref(), token.as_deref()) {
+                        Ok(())
+                    } else {
+                        Err(Rejection::from(Error::Forbidden))
+                    }
+                }
+            })
+            .untuple_one()
+    };
+
     let device_unregister = warp::delete()
         .and(warp::path!("device"))
         .and(fcm_uid)
@@ -79,6 +100,22 @@ pub async fn start(
         .and(warp::body::json::<dto::NewDevice>())
         .and_then(controllers::register_device);
 
+    let devices_get = warp::get()
+        .and(warp::path!("device"))
+        .and(user_addr)
+        .and(with_devices.clone())
+        .and(with_pool.clone())
+        .and_then(controllers::get_devices);
+
+    let devices_import = warp::put()
+        .and(warp::path!("devices"))
+        .and(user_addr)
+        .and(with_devices.clone())
+        .and(with_max_devices)
+        .and(with_pool.clone())
+        .and(warp::body::json::<dto::ImportDevices>())
+        .and_then(controllers::import_devices);
+
     let topic_unsubscribe = warp::delete()
         .and(warp::path!("topics"))
         .and(user_addr)
@@ -87,11 +124,21 @@ pub async fn start(
         .and(warp::body::json::<Option<dto::Topics>>())
         .and_then(controllers::unsubscribe_from_topics);
 
+    let topic_unsubscribe_by_pair = warp::delete()
+        .and(warp::path!("topics" / "by-pair"))
+        .and(user_addr)
+        .and(with_subscriptions.clone())
+        .and(with_pool.clone())
+        .and(warp::body::json::<dto::AssetPair>())
+        .and_then(controllers::unsubscribe_by_pair);
+
     let topic_subscribe = warp::post()
         .and(warp::path!("topics"))
         .and(user_addr)
         .and(with_subscriptions.clone())
         .and(with_subscribe_config.clone())
+        .and(with_price_source.clone())
+        .and(with_max_topics)
         .and(with_pool.clone())
         .and(warp::body::json::<dto::Topics>())
         .and_then(controllers::subscribe_to_topics);
@@ -101,8 +148,18 @@ pub async fn start(
         .and(user_addr)
         .and(with_subscriptions.clone())
         .and(with_pool.clone())
+        .and(warp::query::<dto::TopicsQuery>())
+        .and(warp::header::optional::<String>("If-None-Match"))
         .and_then(controllers::get_topics);
 
+    let maintenance_set = warp::put()
+        .and(warp::path!("maintenance"))
+        .and(admin)
+        .and(with_state.clone())
+        .and(with_pool.clone())
+        .and(warp::body::json::<dto::Maintenance>())
+        .and_then(controllers::set_maintenance);
+
     let log = warp::log::custom(access);
 
     log::info!("Starting push-notifications API server at 0.0.0.0:{}", port);
@@ -110,9 +167,13 @@ pub async fn start(
     let routes = device_unregister
         .or(device_update)
         .or(device_register)
+        .or(devices_get)
+        .or(devices_import)
         .or(topic_subscribe)
         .or(topic_unsubscribe)
+        .or(topic_unsubscribe_by_pair)
         .or(topics_get)
+        .or(maintenance_set)
         .recover(move |rej| {
             log::error!("{:?}", rej);
             error_handler_with_serde_qs(ERROR_CODES_PREFIX, error_handler.clone())(rej)
@@ -127,19 +188,207 @@ pub async fn start(
         .await;
 }
 
+fn error_response(err: &Error) -> Response {
+    match err {
+        Error::DbQueryError(e) => {
+            log::error!(e);
+            validation::invalid_parameter(ERROR_CODES_PREFIX, None)
+        }
+        Error::DatabaseError(e @ database::error::Error::LimitExceeded(_, _)) => {
+            log::debug!("{}", e);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Too many subscriptions",
+                ERROR_CODES_PREFIX as u32 * 10000 + 901,
+                None,
+            )
+        }
+        Error::DatabaseError(e @ database::error::Error::DeviceLimitExceeded(_, _)) => {
+            log::debug!("{}", e);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Too many devices",
+                ERROR_CODES_PREFIX as u32 * 10000 + 906,
+                None,
+            )
+        }
+        Error::DatabaseError(e @ database::error::Error::SubscriberDeleted(_)) => {
+            log::warn!("{}", e);
+            Response::singleton(
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                "Concurrent update, please retry",
+                ERROR_CODES_PREFIX as u32 * 10000 + 907,
+                None,
+            )
+        }
+        Error::BatchTooLarge(_, _) => {
+            log::debug!("{}", err);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Too many devices in a batch",
+                ERROR_CODES_PREFIX as u32 * 10000 + 902,
+                None,
+            )
+        }
+        Error::PriceUnavailable(_) => {
+            log::debug!("{}", err);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Price is unavailable for the pair",
+                ERROR_CODES_PREFIX as u32 * 10000 + 903,
+                None,
+            )
+        }
+        Error::TooManyTopics(_, _) => {
+            log::debug!("{}", err);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Too many topics in a request",
+                ERROR_CODES_PREFIX as u32 * 10000 + 905,
+                None,
+            )
+        }
+        Error::InvalidAsset(_) => {
+            log::debug!("{}", err);
+            Response::singleton(
+                http::StatusCode::BAD_REQUEST,
+                "Invalid asset id",
+                ERROR_CODES_PREFIX as u32 * 10000 + 904,
+                None,
+            )
+        }
+        Error::Forbidden => Response::singleton(
+            http::StatusCode::FORBIDDEN,
+            "Forbidden",
+            ERROR_CODES_PREFIX as u32 * 10000 + 403,
+            None,
+        ),
+        Error::BadTopic { url, error } => {
+            log::debug!("{}", err);
+            validation::invalid_parameter(ERROR_CODES_PREFIX, Some(bad_topic_details(url, error)))
+        }
+        _ => internal(ERROR_CODES_PREFIX),
+    }
+}
+
+/// Admin endpoints are disabled if there is no admin token configured
+fn is_admin(admin_token: Option<&Secret<String>>, token: Option<&str>) -> bool {
+    match (admin_token, token) {
+        (Some(admin_token), Some(token)) => admin_token.expose() == token,
+        _ => false,
+    }
+}
+
+/// Tells the client which of the topics is bad, and what's wrong with it
+fn bad_topic_details(url: &str, error: &TopicError) -> HashMap<String, String> {
+    HashMap::from([
+        ("topic".to_string(), url.to_string()),
+        ("part".to_string(), error.part().to_string()),
+        ("reason".to_string(), error.to_string()),
+    ])
+}
+
+/// Outcome of every device of a bulk import, in order: devices registered already are updated,
+/// new ones are registered while the address is under the limit, repeated ones are skipped
+fn plan_device_import(
+    fcm_uids: &[&str],
+    registered: &[String],
+    max_devices: u32,
+) -> Vec<dto::ImportStatus> {
+    use dto::ImportStatus;
+
+    let mut seen = HashSet::new();
+    let mut count = registered.len() as u32;
+    fcm_uids
+        .iter()
+        .map(|&fcm_uid| {
+            if !seen.insert(fcm_uid) {
+                ImportStatus::Duplicate
+            } else if registered.iter().any(|registered| registered == fcm_uid) {
+                ImportStatus::Updated
+            } else if count < max_devices {
+                count += 1;
+                ImportStatus::Created
+            } else {
+                ImportStatus::LimitExceeded
+            }
+        })
+        .collect()
+}
+
+/// Number of topics in a request, checked before they are parsed, let alone subscribed to
+fn check_topics_count(count: usize, max_topics_per_request: u32) -> Result<(), Error> {
+    let max = max_topics_per_request as usize;
+    if count > max {
+        return Err(Error::TooManyTopics(count, max));
+    }
+    Ok(())
+}
+
+/// Only the tail of the token is shown, enough to tell the devices apart
+fn mask_fcm_uid(fcm_uid: &str) -> String {
+    const VISIBLE_CHARS: usize = 4;
+    let len = fcm_uid.chars().count();
+    if len <= VISIBLE_CHARS {
+        return "***".to_string();
+    }
+    let tail = fcm_uid
+        .chars()
+        .skip(len - VISIBLE_CHARS)
+        .collect::<String>();
+    format!("***{}", tail)
+}
+
+/// Strong ETag of a page of topics, which changes whenever the subscriptions do
+fn topics_etag(stamps: &[SubscriptionStamp], cursor: Option<i32>, limit: Option<u32>) -> String {
+    let mut hasher = DefaultHasher::new();
+    (stamps, cursor, limit).hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Whether the `If-None-Match` header matches the ETag (weak comparison, as per RFC 7232)
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let if_none_match = match if_none_match {
+        Some(value) => value,
+        None => return false,
+    };
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+fn with_etag(mut response: warp::reply::Response, etag: &str) -> warp::reply::Response {
+    if let Ok(value) = http::HeaderValue::from_str(etag) {
+        response.headers_mut().insert(http::header::ETAG, value);
+    }
+    response
+}
+
 mod controllers {
-    use super::{dto, Pool};
+    use super::{
+        check_topics_count, dto, etag_matches, plan_device_import, topics_etag, with_etag, Pool,
+    };
     use crate::{
         error::Error,
+        price::PriceSource,
         topic::{build_subscription_url, parse_subscription_url},
     };
     use database::{
-        device,
+        device, state,
         subscription::{self, SubscriptionRequest},
     };
     use diesel_async::AsyncConnection;
-    use model::{device::FcmUid, waves::Address};
-    use warp::{http::StatusCode, reply::Json, Rejection};
+    use model::{
+        device::FcmUid,
+        topic::{SubscriptionMode, Topic},
+        waves::Address,
+    };
+    use warp::{
+        http::StatusCode,
+        reply::{Reply, Response},
+        Rejection,
+    };
 
     use diesel_async::scoped_futures::ScopedFutureExt as _;
 
@@ -178,26 +427,116 @@ mod controllers {
             .transaction(|conn| {
                 async move {
                     // All work only within db transaction
-                    if devices.exists(&address, &fcm_uid, conn).await? {
-                        return Ok::<StatusCode, Error>(StatusCode::NO_CONTENT);
-                    }
-
                     devices
                         .register(
                             &address,
                             &fcm_uid,
                             &device_info.lang.language,
                             device_info.tz.utc_offset_seconds,
+                            device_info
+                                .platform
+                                .as_deref()
+                                .map(dto::platform)
+                                .unwrap_or_default(),
                             conn,
                         )
-                        .await?;
+                        .await
+                }
+                .scope_boxed()
+            })
+            .await
+            .map(register_status)
+            .map_err(|e| Error::from(e).into())
+    }
+
+    /// A repeated (or concurrent) registration of the same device is not an error
+    pub(super) fn register_status(created: bool) -> StatusCode {
+        if created {
+            StatusCode::CREATED
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    /// Devices registered for the address, for support to look into undelivered notifications
+    pub async fn get_devices(
+        address: Address,
+        devices: device::Repo,
+        pool: Pool,
+    ) -> Result<Response, Rejection> {
+        let mut conn = pool.get().await.map_err(Error::from)?;
+        let devices = devices
+            .subscribers(&address, &mut conn)
+            .await
+            .map_err(Error::from)?;
+
+        let devices = devices.iter().map(dto::DeviceInfo::from).collect();
+        Ok(warp::reply::json(&dto::Devices { devices }).into_response())
+    }
 
-                    Ok(StatusCode::CREATED)
+    /// Devices are imported in a single transaction, the outcome is reported per device
+    pub async fn import_devices(
+        address: Address,
+        devices: device::Repo,
+        max_devices_per_address: u32,
+        pool: Pool,
+        import: dto::ImportDevices,
+    ) -> Result<Response, Rejection> {
+        let batch_size = import.devices.len();
+        if batch_size > dto::MAX_IMPORT_DEVICES {
+            return Err(Error::BatchTooLarge(batch_size, dto::MAX_IMPORT_DEVICES).into());
+        }
+
+        let results = pool
+            .get()
+            .await
+            .map_err(Error::from)?
+            .transaction::<_, database::error::Error, _>(|conn| {
+                async move {
+                    // All work only within db transaction
+                    let registered = devices.fcm_uids(&address, conn).await?;
+                    let fcm_uids = import
+                        .devices
+                        .iter()
+                        .map(|device| device.fcm.fcm_uid.as_str())
+                        .collect::<Vec<_>>();
+                    let statuses =
+                        plan_device_import(&fcm_uids, &registered, max_devices_per_address);
+
+                    let mut results = Vec::with_capacity(statuses.len());
+                    for (device, status) in import.devices.into_iter().zip(statuses) {
+                        let fcm_uid = device.fcm.fcm_uid;
+                        let language = device.lang.language;
+                        let tz = device.tz.utc_offset_seconds;
+                        let platform = device.platform.as_deref().map(dto::platform);
+                        let platform = platform.unwrap_or_default();
+                        match status {
+                            dto::ImportStatus::Created => {
+                                devices
+                                    .register(&address, &fcm_uid, &language, tz, platform, conn)
+                                    .await?;
+                            }
+                            dto::ImportStatus::Updated => {
+                                let update = device::DeviceUpdate {
+                                    language: Some(language),
+                                    utc_offset_seconds: Some(tz),
+                                    platform: Some(platform),
+                                    new_fcm_uid: None,
+                                };
+                                devices.update(&address, &fcm_uid, update, conn).await?;
+                            }
+                            dto::ImportStatus::Duplicate | dto::ImportStatus::LimitExceeded => {}
+                        }
+                        results.push(dto::ImportResult { fcm_uid, status });
+                    }
+                    Ok(results)
                 }
                 .scope_boxed()
             })
             .await
-            .map_err(Into::into)
+            .map_err(Error::from)?;
+
+        Ok(warp::reply::json(&dto::ImportResults { results }).into_response())
     }
 
     pub async fn update_device(
@@ -219,9 +558,12 @@ mod controllers {
                         .update(
                             &address,
                             &fcm_uid,
-                            device_info.lang.map(|l| l.language),
-                            device_info.tz.map(|tz| tz.utc_offset_seconds),
-                            device_info.fcm.map(|fcm| fcm.fcm_uid),
+                            device::DeviceUpdate {
+                                language: device_info.lang.map(|l| l.language),
+                                utc_offset_seconds: device_info.tz.map(|tz| tz.utc_offset_seconds),
+                                platform: device_info.platform.as_deref().map(dto::platform),
+                                new_fcm_uid: device_info.fcm.map(|fcm| fcm.fcm_uid),
+                            },
                             conn,
                         )
                         .await
@@ -250,10 +592,10 @@ mod controllers {
                 t.topics
                     .into_iter()
                     .map(|topic_url| {
-                        // Subscription mode (`?oneshot`) is allowed but ignored here,
-                        // so that the subscriber doesn't necessarily need to know it
+                        // Subscription mode (`?oneshot`) and label are allowed but ignored here,
+                        // so that the subscriber doesn't necessarily need to know them
                         // to be able to unsubscribe.
-                        let (topic, _) = parse_subscription_url(&topic_url)?;
+                        let (topic, _, _) = parse_topic_url(&topic_url)?;
                         Ok(topic)
                     })
                     .collect::<Result<Vec<_>, Error>>()
@@ -280,26 +622,71 @@ mod controllers {
         Ok(StatusCode::NO_CONTENT)
     }
 
+    pub async fn unsubscribe_by_pair(
+        address: Address,
+        subscriptions: subscription::Repo,
+        pool: Pool,
+        pair: dto::AssetPair,
+    ) -> Result<StatusCode, Rejection> {
+        let pair = pair.parse()?;
+
+        pool.get()
+            .await
+            .map_err(Error::from)?
+            .transaction(|conn| {
+                async move {
+                    // All work only within db transaction
+                    subscriptions
+                        .unsubscribe_by_pair(&address, &pair, conn)
+                        .await
+                }
+                .scope_boxed()
+            })
+            .await
+            .map_err(Error::from)?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
     pub async fn subscribe_to_topics(
         address: Address,
         subscriptions: subscription::Repo,
         subscribe_config: subscription::SubscribeConfig,
+        price_source: Option<PriceSource>,
+        max_topics_per_request: u32,
         pool: Pool,
         topics: dto::Topics,
     ) -> Result<StatusCode, Rejection> {
-        let subs = topics
+        check_topics_count(topics.topics.len(), max_topics_per_request)?;
+
+        let mut subs = topics
             .topics
             .into_iter()
             .map(|topic_url| {
-                let (topic, mode) = parse_subscription_url(&topic_url)?;
+                let (topic, mode, label) = parse_topic_url(&topic_url)?;
                 Ok(SubscriptionRequest {
                     topic_url, // Can be safely removed
                     topic,
                     mode,
+                    label,
                 })
             })
             .collect::<Result<Vec<SubscriptionRequest>, Error>>()?;
 
+        // Percent changes are counted from the latest price at subscription time
+        for sub in &mut subs {
+            if let Topic::PricePercentChange(topic) = &mut sub.topic {
+                let price_source = price_source.as_ref().ok_or_else(|| {
+                    let pair = format!("{}/{}", topic.amount_asset, topic.price_asset);
+                    Error::PriceUnavailable(pair)
+                })?;
+                let base_price = price_source
+                    .last_price(&topic.amount_asset, &topic.price_asset)
+                    .await?;
+                topic.base_price = Some(base_price);
+            }
+        }
+
         pool.get()
             .await
             .map_err(Error::from)?
@@ -318,35 +705,101 @@ mod controllers {
         Ok(StatusCode::NO_CONTENT)
     }
 
+    /// Responds with `304 Not Modified` if the client already has the current page,
+    /// which is checked before loading the subscriptions
     pub async fn get_topics(
         address: Address,
         subscriptions: subscription::Repo,
         pool: Pool,
-    ) -> Result<Json, Rejection> {
-        let subscriptions = pool
+        query: dto::TopicsQuery,
+        if_none_match: Option<String>,
+    ) -> Result<Response, Rejection> {
+        // Not paginated unless requested, for compatibility
+        let limit = match (query.cursor, query.limit) {
+            (None, None) => None,
+            (_, limit) => Some(
+                limit
+                    .unwrap_or(dto::DEFAULT_TOPICS_PAGE_SIZE)
+                    .clamp(1, dto::MAX_TOPICS_PAGE_SIZE),
+            ),
+        };
+        let (etag, page) = pool
             .get()
             .await
             .map_err(Error::from)?
-            .transaction(|conn| {
+            .transaction::<_, database::error::Error, _>(|conn| {
                 async move {
                     // All work only within db transaction
-                    subscriptions.subscriptions_by_address(&address, conn).await
+                    let stamps = subscriptions.subscription_stamps(&address, conn).await?;
+                    let etag = topics_etag(&stamps, query.cursor, limit);
+                    if etag_matches(if_none_match.as_deref(), &etag) {
+                        return Ok((etag, None));
+                    }
+                    let page = subscriptions
+                        .subscriptions_page(&address, query.cursor, limit, conn)
+                        .await?;
+                    // Counted the same way as listed, which the stamps are not
+                    let total = match limit {
+                        None => page.subscriptions.len(),
+                        Some(_) => subscriptions.subscriptions_count(&address, conn).await?,
+                    };
+                    Ok((etag, Some((page, total))))
                 }
                 .scope_boxed()
             })
             .await
-            .map_err(|e| Error::from(e))?;
+            .map_err(Error::from)?;
+
+        let (page, total) = match page {
+            Some(page) => page,
+            None => return Ok(with_etag(StatusCode::NOT_MODIFIED.into_response(), &etag)),
+        };
 
-        let topics = subscriptions
+        let topics = page
+            .subscriptions
             .into_iter()
-            .map(|(topic, mode)| build_subscription_url(topic, mode))
+            .map(|(topic, mode, label)| build_subscription_url(topic, mode, label.as_deref()))
             .collect();
 
-        Ok(warp::reply::json(&dto::Topics { topics }))
+        let response = warp::reply::json(&dto::TopicsPage {
+            topics,
+            next_cursor: page.next_cursor,
+            total: total as u32,
+        });
+        Ok(with_etag(response.into_response(), &etag))
+    }
+
+    pub async fn set_maintenance(
+        state: state::Repo,
+        pool: Pool,
+        maintenance: dto::Maintenance,
+    ) -> Result<StatusCode, Rejection> {
+        let value = maintenance.enabled.to_string();
+        let mut conn = pool.get().await.map_err(Error::from)?;
+        state
+            .set(state::SENDER_MAINTENANCE_KEY, &value, &mut conn)
+            .await
+            .map_err(Error::from)?;
+        log::info!("Sender maintenance mode set to {}", value);
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    pub(super) fn parse_topic_url(
+        topic_url: &str,
+    ) -> Result<(Topic, SubscriptionMode, Option<String>), Error> {
+        parse_subscription_url(topic_url).map_err(|error| Error::BadTopic {
+            url: topic_url.to_string(),
+            error,
+        })
     }
 }
 
 mod dto {
+    use crate::error::Error;
+    use model::{
+        asset::Asset,
+        device::{Device, Platform},
+    };
     use serde::{Deserialize, Serialize};
 
     #[derive(Deserialize)]
@@ -357,6 +810,7 @@ mod dto {
         pub tz: Option<Timezone>,
         #[serde(flatten)]
         pub fcm: Option<FcmUid>,
+        pub platform: Option<String>,
     }
 
     #[derive(Deserialize)]
@@ -365,6 +819,79 @@ mod dto {
         pub lang: Lang,
         #[serde(flatten)]
         pub tz: Timezone,
+        pub platform: Option<String>,
+    }
+
+    /// Devices registered without a platform (or with an unknown one) are web,
+    /// just like the ones registered before the platform was stored
+    pub fn platform(platform: &str) -> Platform {
+        Platform::parse(platform).unwrap_or_default()
+    }
+
+    /// Larger imports are to be split into several requests
+    pub const MAX_IMPORT_DEVICES: usize = 100;
+
+    #[derive(Deserialize)]
+    pub struct ImportDevices {
+        pub devices: Vec<ImportDevice>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ImportDevice {
+        #[serde(flatten)]
+        pub fcm: FcmUid,
+        #[serde(flatten)]
+        pub lang: Lang,
+        #[serde(flatten)]
+        pub tz: Timezone,
+        pub platform: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    pub struct ImportResults {
+        pub results: Vec<ImportResult>,
+    }
+
+    #[derive(Serialize)]
+    pub struct ImportResult {
+        pub fcm_uid: String,
+        pub status: ImportStatus,
+    }
+
+    #[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ImportStatus {
+        Created,
+        Updated,
+        /// Repeated within the batch, only the first one is imported
+        Duplicate,
+        /// The address has reached the devices limit
+        LimitExceeded,
+    }
+
+    #[derive(Serialize)]
+    pub struct Devices {
+        pub devices: Vec<DeviceInfo>,
+    }
+
+    #[derive(Serialize)]
+    pub struct DeviceInfo {
+        /// Masked, see `mask_fcm_uid`
+        pub fcm_uid: String,
+        pub language: String,
+        pub utc_offset_seconds: i32,
+        pub platform: &'static str,
+    }
+
+    impl From<&Device> for DeviceInfo {
+        fn from(device: &Device) -> Self {
+            DeviceInfo {
+                fcm_uid: super::mask_fcm_uid(&device.fcm_uid),
+                language: device.locale.lang.clone(),
+                utc_offset_seconds: device.locale.utc_offset_seconds,
+                platform: device.platform.as_str(),
+            }
+        }
     }
 
     #[derive(Deserialize)]
@@ -386,4 +913,454 @@ mod dto {
     pub struct Topics {
         pub topics: Vec<String>,
     }
+
+    #[derive(Deserialize)]
+    pub struct AssetPair {
+        pub amount_asset: String,
+        pub price_asset: String,
+    }
+
+    impl AssetPair {
+        pub fn parse(&self) -> Result<model::asset::AssetPair, Error> {
+            let asset = |id: &str| Asset::from_id(id).map_err(|_| Error::InvalidAsset(id.into()));
+            Ok(model::asset::AssetPair {
+                amount_asset: asset(&self.amount_asset)?,
+                price_asset: asset(&self.price_asset)?,
+            })
+        }
+    }
+
+    pub const DEFAULT_TOPICS_PAGE_SIZE: u32 = 100;
+    pub const MAX_TOPICS_PAGE_SIZE: u32 = 1000;
+
+    #[derive(Deserialize)]
+    pub struct TopicsQuery {
+        pub cursor: Option<i32>,
+        pub limit: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Maintenance {
+        pub enabled: bool,
+    }
+
+    #[derive(Serialize)]
+    pub struct TopicsPage {
+        pub topics: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_cursor: Option<i32>,
+        /// Subscriptions of the address, on all pages
+        pub total: u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bad_topic_details, check_topics_count,
+        controllers::{self, parse_topic_url, register_status},
+        dto::{DeviceInfo, ImportStatus, Topics, TopicsPage, TopicsQuery},
+        error_response, is_admin, mask_fcm_uid, plan_device_import, Pool,
+    };
+    use crate::{error::Error, topic::TopicError};
+    use database::{
+        device,
+        subscription::{self, SubscribeConfig},
+        testing::TestDb,
+    };
+    use model::{device::Platform, secret::Secret, waves::Address};
+    use std::{sync::Arc, time::Duration};
+    use warp::{http::StatusCode, Reply};
+
+    const ADDRESS: &str = "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2";
+    const ORDERS_URL: &str = "push://orders/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+    const THRESHOLD_URL: &str =
+        "push://price_threshold/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p/10";
+
+    fn address() -> Address {
+        Address::from_string(ADDRESS).unwrap()
+    }
+
+    async fn pool(db: &TestDb) -> Pool {
+        let pool = crate::db::async_pool(&db.config, Duration::from_secs(5)).await;
+        Arc::new(pool.unwrap())
+    }
+
+    async fn subscribe(pool: &Pool, topics: &[&str]) {
+        let config = SubscribeConfig {
+            max_subscriptions_per_address_per_pair: 100,
+            max_subscriptions_per_address_total: 100,
+        };
+        let topics = Topics {
+            topics: topics.iter().map(ToString::to_string).collect(),
+        };
+        controllers::subscribe_to_topics(
+            address(),
+            subscription::Repo::default(),
+            config,
+            None,
+            100,
+            pool.clone(),
+            topics,
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn get_topics(
+        pool: &Pool,
+        cursor: Option<i32>,
+        limit: Option<u32>,
+        if_none_match: Option<&str>,
+    ) -> warp::reply::Response {
+        controllers::get_topics(
+            address(),
+            subscription::Repo::default(),
+            pool.clone(),
+            TopicsQuery { cursor, limit },
+            if_none_match.map(ToString::to_string),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bad_topic_error() {
+        let url = "push://price_threshold/WAVES/!!!/-10.5";
+        let err = parse_topic_url(url).unwrap_err();
+        let (bad_url, error) = match &err {
+            Error::BadTopic { url, error } => (url, error),
+            _ => panic!("unexpected error: {:?}", err),
+        };
+        assert_eq!(bad_url, url);
+        assert_eq!(error, &TopicError::InvalidPriceAsset);
+
+        let details = bad_topic_details(bad_url, error);
+        assert_eq!(details["topic"], url);
+        assert_eq!(details["part"], "price_asset");
+        assert_eq!(details["reason"], "Invalid/missing price asset");
+
+        let response = error_response(&err).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_subscriber_deleted_error() {
+        let err = Error::DatabaseError(database::error::Error::SubscriberDeleted(address()));
+        let response = error_response(&err).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_register_status() {
+        // First registration creates the device, duplicate ones (including the losers
+        // of a concurrent registration race) find it in place
+        assert_eq!(register_status(true), StatusCode::CREATED);
+        assert_eq!(register_status(false), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_plan_device_import() {
+        use ImportStatus::*;
+
+        let registered = vec!["phone".to_string(), "tablet".to_string()];
+
+        // Mixed batch: a registered device, new ones and a duplicate
+        let batch = ["phone", "laptop", "phone", "laptop", "watch"];
+        assert_eq!(
+            plan_device_import(&batch, &registered, 10),
+            vec![Updated, Created, Duplicate, Duplicate, Created]
+        );
+
+        // Over the cap: new devices are rejected, registered ones are still updated
+        let batch = ["laptop", "watch", "tablet", "tv"];
+        assert_eq!(
+            plan_device_import(&batch, &registered, 3),
+            vec![Created, LimitExceeded, Updated, LimitExceeded]
+        );
+        assert_eq!(
+            plan_device_import(&["laptop", "phone"], &registered, 1),
+            vec![LimitExceeded, Updated]
+        );
+        assert!(plan_device_import(&[], &registered, 10).is_empty());
+    }
+
+    #[test]
+    fn test_device_info() {
+        use model::{
+            device::{Device, LocaleInfo, Platform},
+            waves::Address,
+        };
+
+        let device = Device {
+            device_uid: 1,
+            address: Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap(),
+            fcm_uid: "dQw4w9WgXcQ:APA91bHun4MxP5egoKMwt2KZFBaFUH-1RYqx".to_string(),
+            locale: LocaleInfo {
+                lang: "de".to_string(),
+                utc_offset_seconds: 7200,
+            },
+            platform: Platform::Android,
+        };
+        let info = serde_json::to_value(DeviceInfo::from(&device)).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "fcm_uid": "***RYqx",
+                "language": "de",
+                "utc_offset_seconds": 7200,
+                "platform": "android",
+            })
+        );
+
+        // Short tokens are masked entirely
+        assert_eq!(mask_fcm_uid("RYqx"), "***");
+        assert_eq!(mask_fcm_uid(""), "***");
+        assert_eq!(mask_fcm_uid("токен-abcd"), "***abcd");
+    }
+
+    #[test]
+    fn test_topics_page() {
+        let page = |topics: &[&str], next_cursor| TopicsPage {
+            topics: topics.iter().map(ToString::to_string).collect(),
+            next_cursor,
+            total: 3,
+        };
+        let first_page = page(&["push://orders", "push://orders/WAVES/BTC"], Some(7));
+        assert_eq!(
+            serde_json::to_value(first_page).unwrap(),
+            serde_json::json!({
+                "topics": ["push://orders", "push://orders/WAVES/BTC"],
+                "next_cursor": 7,
+                "total": 3,
+            })
+        );
+
+        // The last page has no cursor to go on with
+        let second_page = page(&["push://orders/WAVES/ETH"], None);
+        assert_eq!(
+            serde_json::to_value(second_page).unwrap(),
+            serde_json::json!({"topics": ["push://orders/WAVES/ETH"], "total": 3})
+        );
+    }
+
+    #[test]
+    fn test_parse_asset_pair() {
+        use super::dto::AssetPair;
+
+        let pair = |amount_asset: &str, price_asset: &str| AssetPair {
+            amount_asset: amount_asset.to_string(),
+            price_asset: price_asset.to_string(),
+        };
+        let usdn = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+        let parsed = pair("WAVES", usdn).parse().unwrap();
+        assert_eq!(parsed.amount_asset.id(), "WAVES");
+        assert_eq!(parsed.price_asset.id(), usdn);
+
+        let err = pair("WAVES", "!!!").parse().unwrap_err();
+        assert!(matches!(&err, Error::InvalidAsset(id) if id == "!!!"));
+        assert_eq!(
+            error_response(&err).into_response().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_device_platform() {
+        use super::dto::{self, NewDevice, UpdateDevice};
+        use model::device::Platform;
+
+        let new_device = |json| serde_json::from_str::<NewDevice>(json).unwrap();
+        let device = new_device(r#"{"language": "en", "utc_offset_seconds": 0}"#);
+        assert_eq!(device.platform, None);
+        let device =
+            new_device(r#"{"language": "en", "utc_offset_seconds": 0, "platform": "iOS"}"#);
+        assert_eq!(
+            device.platform.as_deref().map(dto::platform),
+            Some(Platform::Ios)
+        );
+
+        let update = |json| serde_json::from_str::<UpdateDevice>(json).unwrap();
+        assert!(update(r#"{"language": "ru"}"#).platform.is_none());
+        let device = update(r#"{"platform": "android"}"#);
+        assert!(device.lang.is_none());
+        assert_eq!(
+            device.platform.as_deref().map(dto::platform),
+            Some(Platform::Android)
+        );
+
+        // Unknown platforms are stored as web
+        assert_eq!(dto::platform("tizen"), Platform::Web);
+    }
+
+    #[test]
+    fn test_check_topics_count() {
+        assert!(check_topics_count(0, 50).is_ok());
+        assert!(check_topics_count(50, 50).is_ok());
+
+        let err = check_topics_count(51, 50).unwrap_err();
+        assert!(matches!(err, Error::TooManyTopics(51, 50)));
+        let response = error_response(&err).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let token = Secret::new("s3cr3t".to_string());
+        assert!(is_admin(Some(&token), Some("s3cr3t")));
+        assert!(!is_admin(Some(&token), Some("guess")));
+        assert!(!is_admin(Some(&token), None));
+
+        // Disabled without a configured token
+        assert!(!is_admin(None, Some("s3cr3t")));
+        assert!(!is_admin(None, None));
+    }
+    #[test]
+    #[ignore = "needs Postgres"]
+    fn test_topics_etag() {
+        let db = TestDb::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool(&db).await;
+            let etag = |response: &warp::reply::Response| {
+                response.headers()["ETag"].to_str().unwrap().to_string()
+            };
+
+            subscribe(&pool, &[ORDERS_URL, THRESHOLD_URL]).await;
+            let response = get_topics(&pool, None, None, None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let first_etag = etag(&response);
+
+            // Unchanged set
+            let response = get_topics(&pool, None, None, Some(&first_etag)).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(etag(&response), first_etag);
+            let weak = format!("\"stale\", W/{}", first_etag);
+            let response = get_topics(&pool, None, None, Some(&weak)).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+            // Changed set: subscription added, then removed
+            subscribe(&pool, &["push://orders"]).await;
+            let response = get_topics(&pool, None, None, Some(&first_etag)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let second_etag = etag(&response);
+            assert_ne!(second_etag, first_etag);
+
+            let topics = Topics {
+                topics: vec![THRESHOLD_URL.to_string()],
+            };
+            controllers::unsubscribe_from_topics(
+                address(),
+                subscription::Repo::default(),
+                pool.clone(),
+                Some(topics),
+            )
+            .await
+            .unwrap();
+            let response = get_topics(&pool, None, None, Some(&second_etag)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_ne!(etag(&response), first_etag);
+            assert_ne!(etag(&response), second_etag);
+
+            // Different pages of the same set
+            let first_page = get_topics(&pool, None, Some(1), None).await;
+            let second_page = get_topics(&pool, Some(0), Some(1), None).await;
+            assert_ne!(etag(&first_page), etag(&second_page));
+        });
+    }
+
+    #[test]
+    #[ignore = "needs Postgres"]
+    fn test_topics_total() {
+        use diesel_async::RunQueryDsl;
+
+        let db = TestDb::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool(&db).await;
+            let page = |response: warp::reply::Response| async {
+                let body = warp::hyper::body::to_bytes(response.into_body());
+                serde_json::from_slice::<serde_json::Value>(&body.await.unwrap()).unwrap()
+            };
+
+            subscribe(&pool, &[ORDERS_URL, THRESHOLD_URL, "push://orders"]).await;
+            // Without a topic of any type, so skipped when listed
+            let broken = format!(
+                "insert into subscriptions (subscriber_address, topic, topic_type) values ('{}', 'push://broken', 0)",
+                ADDRESS
+            );
+            let mut conn = pool.get().await.unwrap();
+            diesel::sql_query(broken).execute(&mut conn).await.unwrap();
+
+            let first_page = page(get_topics(&pool, None, Some(2), None).await).await;
+            assert_eq!(first_page["topics"].as_array().unwrap().len(), 2);
+            assert_eq!(first_page["total"], 3);
+
+            let cursor = first_page["next_cursor"].as_i64().unwrap() as i32;
+            let second_page = page(get_topics(&pool, Some(cursor), Some(2), None).await).await;
+            assert_eq!(second_page["topics"].as_array().unwrap().len(), 1);
+            assert_eq!(second_page["total"], 3);
+            assert!(second_page.get("next_cursor").is_none());
+
+            let all = page(get_topics(&pool, None, None, None).await).await;
+            assert_eq!(all["topics"].as_array().unwrap().len(), 3);
+            assert_eq!(all["total"], 3);
+        });
+    }
+
+    #[test]
+    #[ignore = "needs Postgres"]
+    fn test_get_devices() {
+        let db = TestDb::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool(&db).await;
+            let mut conn = pool.get().await.unwrap();
+            let devices = device::Repo::default();
+            for (fcm_uid, lang, platform) in [
+                ("fcm_uid_1234", "en", Platform::Web),
+                ("5678", "ru", Platform::Ios),
+            ] {
+                devices
+                    .register(
+                        &address(),
+                        &fcm_uid.to_string(),
+                        lang,
+                        3600,
+                        platform,
+                        &mut conn,
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let response = controllers::get_devices(address(), devices, pool.clone())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = warp::hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "devices": [
+                        {
+                            "fcm_uid": "***1234",
+                            "language": "en",
+                            "utc_offset_seconds": 3600,
+                            "platform": "web",
+                        },
+                        {
+                            "fcm_uid": "***",
+                            "language": "ru",
+                            "utc_offset_seconds": 3600,
+                            "platform": "ios",
+                        },
+                    ]
+                })
+            );
+        });
+    }
 }
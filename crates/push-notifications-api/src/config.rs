@@ -1,5 +1,6 @@
 //! Push notifications API config
 
+use model::secret::Secret;
 use serde::Deserialize;
 use std::time::Duration;
 
@@ -19,6 +20,14 @@ fn default_max_subscriptions_per_address_total() -> u32 {
     50
 }
 
+fn default_max_devices_per_address() -> u32 {
+    10
+}
+
+fn default_max_topics_per_request() -> u32 {
+    50
+}
+
 #[derive(Deserialize)]
 struct ConfigFlat {
     #[serde(default = "default_port")]
@@ -32,6 +41,16 @@ struct ConfigFlat {
 
     #[serde(default = "default_max_subscriptions_per_address_total")]
     max_subscriptions_per_address_total: u32,
+
+    #[serde(default = "default_max_devices_per_address")]
+    max_devices_per_address: u32,
+
+    #[serde(default = "default_max_topics_per_request")]
+    max_topics_per_request: u32,
+
+    admin_token: Option<Secret<String>>,
+
+    data_service_url: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +59,14 @@ pub struct Config {
     pub pool_connection_timeout: Duration,
     pub max_subscriptions_per_address_per_pair: u32,
     pub max_subscriptions_per_address_total: u32,
+    /// Devices an address can have registered, by a bulk import or one by one
+    pub max_devices_per_address: u32,
+    /// Topics a single subscribe request can have, larger requests are rejected upfront
+    pub max_topics_per_request: u32,
+    /// Token for the admin endpoints (`X-Admin-Token` header), which are disabled if not set
+    pub admin_token: Option<Secret<String>>,
+    /// Source of the base prices of percent change subscriptions, which are rejected if not set
+    pub data_service_url: Option<String>,
 }
 
 impl Config {
@@ -51,6 +78,10 @@ impl Config {
             pool_connection_timeout: Duration::from_secs(conf.pool_connection_timeout_sec as u64),
             max_subscriptions_per_address_per_pair: conf.max_subscriptions_per_address_per_pair,
             max_subscriptions_per_address_total: conf.max_subscriptions_per_address_total,
+            max_devices_per_address: conf.max_devices_per_address,
+            max_topics_per_request: conf.max_topics_per_request,
+            admin_token: conf.admin_token,
+            data_service_url: conf.data_service_url,
         })
     }
 }
@@ -12,14 +12,32 @@ pub enum Error {
     #[error("Base Waves address: {0}")]
     AddressParseError(String),
 
-    #[error("Bad topic: {0}")]
-    BadTopic(#[from] crate::topic::TopicError),
+    #[error("Bad topic '{url}': {error}")]
+    BadTopic {
+        url: String,
+        error: crate::topic::TopicError,
+    },
 
     #[error("Database pool error: {0}")]
     PoolError(#[from] bb8::RunError<PoolError>),
 
     #[error("Database error: {0}")]
     DatabaseError(#[from] database::error::Error),
+
+    #[error("Too many devices in a batch: {0}, at most {1} allowed")]
+    BatchTooLarge(usize, usize),
+
+    #[error("Admin token is missing or invalid")]
+    Forbidden,
+
+    #[error("Price is unavailable for the pair {0}")]
+    PriceUnavailable(String),
+
+    #[error("Too many topics in a request: {0}, at most {1} allowed")]
+    TooManyTopics(usize, usize),
+
+    #[error("Invalid asset id '{0}'")]
+    InvalidAsset(String),
 }
 
 impl Reject for Error {}
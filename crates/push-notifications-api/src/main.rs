@@ -6,9 +6,10 @@ mod api;
 mod config;
 mod db;
 mod error;
+mod price;
 mod topic;
 
-use database::{device, subscription};
+use database::{device, state, subscription};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -19,8 +20,13 @@ async fn main() -> Result<(), anyhow::Error> {
     log::info!("Connecting to postgres database: {:?}", pg_config);
     let pool = db::async_pool(&pg_config, config.pool_connection_timeout).await?;
 
-    let devices = device::Repo {};
-    let subscriptions = subscription::Repo {};
+    let devices = device::Repo::with_max_devices_per_address(config.max_devices_per_address);
+    let subscriptions = subscription::Repo::default();
+    let state = state::Repo {};
+    let price_source = config
+        .data_service_url
+        .as_deref()
+        .map(price::PriceSource::new);
 
     let subscribe_config = subscription::SubscribeConfig {
         max_subscriptions_per_address_per_pair: config.max_subscriptions_per_address_per_pair,
@@ -28,10 +34,12 @@ async fn main() -> Result<(), anyhow::Error> {
     };
 
     api::start(
-        config.port,
+        config,
         devices,
         subscriptions,
+        state,
         subscribe_config,
+        price_source,
         pool,
     )
     .await;
@@ -0,0 +1,87 @@
+//! Latest prices from the Data Service, which percent change subscriptions are based on
+
+use model::{asset::Asset, price::Price};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+#[derive(Clone)]
+pub struct PriceSource {
+    client: reqwest::Client,
+    data_service_url: String,
+}
+
+#[derive(Deserialize)]
+struct PairDto {
+    /// Not set if the pair is unknown to the Data Service
+    data: Option<PairDataDto>,
+}
+
+#[derive(Deserialize)]
+struct PairDataDto {
+    #[serde(rename = "lastPrice")]
+    last_price: Price,
+}
+
+impl PriceSource {
+    pub fn new(data_service_url: &str) -> Self {
+        PriceSource {
+            client: reqwest::Client::new(),
+            data_service_url: data_service_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The latest price of the pair, as the Data Service knows it
+    pub async fn last_price(
+        &self,
+        amount_asset: &Asset,
+        price_asset: &Asset,
+    ) -> Result<Price, Error> {
+        let url = pair_url(&self.data_service_url, amount_asset, price_asset);
+        let pair = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::Generic(format!("Failed to load the price from {}: {}", url, e)))?
+            .json::<PairDto>()
+            .await
+            .map_err(|e| Error::Generic(format!("Bad price response from {}: {}", url, e)))?;
+        match pair.data {
+            Some(data) => Ok(data.last_price),
+            None => Err(Error::PriceUnavailable(format!(
+                "{}/{}",
+                amount_asset, price_asset
+            ))),
+        }
+    }
+}
+
+fn pair_url(data_service_url: &str, amount_asset: &Asset, price_asset: &Asset) -> String {
+    format!(
+        "{}/v0/pairs/{}/{}",
+        data_service_url,
+        amount_asset.id(),
+        price_asset.id()
+    )
+}
+
+#[test]
+fn test_pair_url() {
+    let usdn = Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap();
+    let source = PriceSource::new("https://api.wavesplatform.com/");
+    assert_eq!(
+        pair_url(&source.data_service_url, &Asset::Waves, &usdn),
+        "https://api.wavesplatform.com/v0/pairs/WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p"
+    );
+
+    let pair = r#"{"__type": "pair", "data": {"firstPrice": 1.9, "lastPrice": 2.05}}"#;
+    let pair = serde_json::from_str::<PairDto>(pair).unwrap();
+    assert_eq!(pair.data.map(|data| data.last_price), Some(2.05));
+    let unknown_pair = r#"{"__type": "pair", "data": null}"#;
+    assert!(serde_json::from_str::<PairDto>(unknown_pair)
+        .unwrap()
+        .data
+        .is_none());
+}
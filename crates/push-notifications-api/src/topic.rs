@@ -1,8 +1,8 @@
 //! Topic URLs parsing & formatting
 
 use model::{
-    asset::Asset,
-    topic::{PriceThreshold, SubscriptionMode, Topic},
+    asset::{Asset, AssetPair},
+    topic::{PricePercentChange, PriceThreshold, SubscriptionMode, ThresholdDirection, Topic},
 };
 use reqwest::Url;
 
@@ -14,7 +14,9 @@ pub enum TopicError {
     #[error("Topic parse error: {0}")]
     ParseError(String),
 
-    #[error("Unknown topic kind, only 'orders' and 'price_threshold' are allowed")]
+    #[error(
+        "Unknown topic kind, only 'orders', 'price_threshold' and 'price_percent' are allowed"
+    )]
     UnknownTopicKind(String),
 
     #[error("Invalid/missing amount asset")]
@@ -25,12 +27,40 @@ pub enum TopicError {
 
     #[error("Invalid/missing threshold value")]
     InvalidThreshold,
+
+    #[error("Invalid threshold direction, only 'above', 'below' and 'any' are allowed")]
+    InvalidDirection,
+
+    #[error("Invalid/missing percent value, must be positive")]
+    InvalidPercent,
+}
+
+impl TopicError {
+    /// Part of the topic URL which is invalid
+    pub fn part(&self) -> &'static str {
+        match self {
+            TopicError::UnknownScheme => "scheme",
+            TopicError::ParseError(_) => "url",
+            TopicError::UnknownTopicKind(_) => "kind",
+            TopicError::InvalidAmountAsset => "amount_asset",
+            TopicError::InvalidPriceAsset => "price_asset",
+            TopicError::InvalidThreshold => "threshold",
+            TopicError::InvalidDirection => "direction",
+            TopicError::InvalidPercent => "percent",
+        }
+    }
 }
 
-pub fn parse_subscription_url(topic_url: &str) -> Result<(Topic, SubscriptionMode), TopicError> {
+/// Maximum length of a subscription label, in characters
+const MAX_LABEL_LENGTH: usize = 64;
+
+pub fn parse_subscription_url(
+    topic_url: &str,
+) -> Result<(Topic, SubscriptionMode, Option<String>), TopicError> {
     enum TopicKind {
         Orders,
         PriceThreshold,
+        PricePercent,
     }
 
     impl TopicKind {
@@ -38,6 +68,7 @@ pub fn parse_subscription_url(topic_url: &str) -> Result<(Topic, SubscriptionMod
             match s {
                 "orders" => Ok(TopicKind::Orders),
                 "price_threshold" => Ok(TopicKind::PriceThreshold),
+                "price_percent" => Ok(TopicKind::PricePercent),
                 _ => Err(s),
             }
         }
@@ -65,8 +96,39 @@ pub fn parse_subscription_url(topic_url: &str) -> Result<(Topic, SubscriptionMod
         None => SubscriptionMode::Repeat,
     };
 
+    let label = topic_url
+        .query_pairs()
+        .find(|(k, _)| k == "label")
+        .and_then(|(_, label)| sanitize_label(&label));
+
     let topic = match topic_kind {
-        TopicKind::Orders => Topic::OrderFulfilled,
+        TopicKind::Orders => {
+            // Bare `push://orders` is for orders on any pair, it has no path segments at all
+            let pair_info = topic_url
+                .path_segments()
+                .into_iter()
+                .flatten()
+                .filter(|segment| !segment.is_empty())
+                .take(2)
+                .collect::<Vec<&str>>();
+
+            if pair_info.is_empty() {
+                Topic::OrderFulfilled(None)
+            } else {
+                let amount_asset =
+                    Asset::from_id(pair_info[0]).map_err(|_| TopicError::InvalidAmountAsset)?;
+
+                let price_asset = pair_info
+                    .get(1)
+                    .ok_or(TopicError::InvalidPriceAsset)
+                    .and_then(|a| Asset::from_id(a).map_err(|_| TopicError::InvalidPriceAsset))?;
+
+                Topic::OrderFulfilled(Some(AssetPair {
+                    amount_asset,
+                    price_asset,
+                }))
+            }
+        }
         TopicKind::PriceThreshold => {
             let threshold_info = topic_url
                 .path_segments()
@@ -89,43 +151,128 @@ pub fn parse_subscription_url(topic_url: &str) -> Result<(Topic, SubscriptionMod
                 .ok_or_else(|| TopicError::InvalidThreshold)
                 .and_then(|v| v.parse().map_err(|_| TopicError::InvalidThreshold))?;
 
+            let direction = match topic_url.query_pairs().find(|(k, _)| k == "direction") {
+                Some((_, direction)) => {
+                    ThresholdDirection::parse(&direction).ok_or(TopicError::InvalidDirection)?
+                }
+                None => ThresholdDirection::Any,
+            };
+
             Topic::PriceThreshold(PriceThreshold {
                 amount_asset,
                 price_asset,
                 price_threshold,
+                direction,
+            })
+        }
+        TopicKind::PricePercent => {
+            let percent_info = topic_url
+                .path_segments()
+                .expect("relative url")
+                .take(3)
+                .collect::<Vec<&str>>();
+
+            let amount_asset = percent_info
+                .first()
+                .ok_or(TopicError::InvalidAmountAsset)
+                .and_then(|a| Asset::from_id(a).map_err(|_| TopicError::InvalidAmountAsset))?;
+
+            let price_asset = percent_info
+                .get(1)
+                .ok_or(TopicError::InvalidPriceAsset)
+                .and_then(|a| Asset::from_id(a).map_err(|_| TopicError::InvalidPriceAsset))?;
+
+            let percent = percent_info
+                .get(2)
+                .ok_or(TopicError::InvalidPercent)
+                .and_then(|v| v.parse::<f64>().map_err(|_| TopicError::InvalidPercent))
+                .and_then(|v| {
+                    if v > 0.0 && v.is_finite() {
+                        Ok(v)
+                    } else {
+                        Err(TopicError::InvalidPercent)
+                    }
+                })?;
+
+            // Filled in with the latest price when subscribing
+            Topic::PricePercentChange(PricePercentChange {
+                amount_asset,
+                price_asset,
+                base_price: None,
+                percent,
             })
         }
     };
 
-    Ok((topic, subscription_mode))
+    Ok((topic, subscription_mode, label))
 }
 
-pub fn build_subscription_url(topic: Topic, mode: SubscriptionMode) -> String {
+/// Strips control characters and surrounding whitespace, limits the length.
+/// Empty labels are treated as absent.
+fn sanitize_label(label: &str) -> Option<String> {
+    let label = label
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+    let label = label
+        .trim()
+        .chars()
+        .take(MAX_LABEL_LENGTH)
+        .collect::<String>();
+    let label = label.trim_end();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+pub fn build_subscription_url(topic: Topic, mode: SubscriptionMode, label: Option<&str>) -> String {
+    let mut query = Vec::new();
     let topic = match topic {
-        Topic::OrderFulfilled => format!("push://orders"),
+        Topic::OrderFulfilled(None) => format!("push://orders"),
+        Topic::OrderFulfilled(Some(pair)) => {
+            format!("push://orders/{}/{}", pair.amount_asset, pair.price_asset)
+        }
         Topic::PriceThreshold(t) => {
+            if t.direction != ThresholdDirection::Any {
+                query.push(format!("direction={}", t.direction.as_str()));
+            }
             format!(
                 "push://price_threshold/{}/{}/{}",
                 t.amount_asset, t.price_asset, t.price_threshold
             )
         }
+        Topic::PricePercentChange(t) => {
+            format!(
+                "push://price_percent/{}/{}/{}",
+                t.amount_asset, t.price_asset, t.percent
+            )
+        }
     };
 
-    let subscription_mode = if let SubscriptionMode::Once = mode {
-        "?oneshot"
+    if let SubscriptionMode::Once = mode {
+        query.push("oneshot".to_string());
+    }
+    if let Some(label) = label {
+        query.push(encode_query_pair("label", label));
+    }
+
+    if query.is_empty() {
+        topic
     } else {
-        ""
-    };
+        format!("{}?{}", topic, query.join("&"))
+    }
+}
 
-    format!("{topic}{subscription_mode}")
+fn encode_query_pair(key: &str, value: &str) -> String {
+    let mut url = Url::parse("push://encode").expect("valid url");
+    url.query_pairs_mut().append_pair(key, value);
+    url.query().unwrap_or_default().to_string()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_subscription_url, parse_subscription_url, TopicError};
+    use super::{build_subscription_url, parse_subscription_url, TopicError, MAX_LABEL_LENGTH};
     use model::{
-        asset::Asset,
-        topic::{PriceThreshold, SubscriptionMode, Topic},
+        asset::{Asset, AssetPair},
+        topic::{PricePercentChange, PriceThreshold, SubscriptionMode, ThresholdDirection, Topic},
     };
 
     #[test]
@@ -134,15 +281,31 @@ mod tests {
             (
                 "push://orders",
                 (
-                    Topic::OrderFulfilled,
+                    Topic::OrderFulfilled(None),
                     SubscriptionMode::Repeat,
+                    None,
                 ),
             ),
             (
                 "push://orders?oneshot",
                 (
-                    Topic::OrderFulfilled,
+                    Topic::OrderFulfilled(None),
+                    SubscriptionMode::Once,
+                    None,
+                ),
+            ),
+            (
+                "push://orders/WAVES/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc?oneshot",
+                (
+                    Topic::OrderFulfilled(Some(AssetPair {
+                        amount_asset: Asset::Waves,
+                        price_asset: Asset::from_id(
+                            "8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc",
+                        )
+                            .unwrap(),
+                    })),
                     SubscriptionMode::Once,
+                    None,
                 ),
             ),
             (
@@ -155,8 +318,10 @@ mod tests {
                             .unwrap(),
                         price_asset: Asset::Waves,
                         price_threshold: 500.0,
+                        direction: ThresholdDirection::Any,
                     }),
                     SubscriptionMode::Repeat,
+                    None,
                 ),
             ),
             (
@@ -169,8 +334,23 @@ mod tests {
                         )
                             .unwrap(),
                         price_threshold: 500.0,
+                        direction: ThresholdDirection::Any,
                     }),
                     SubscriptionMode::Once,
+                    None,
+                ),
+            ),
+            (
+                "push://price_threshold/WAVES/WAVES/1.5?direction=below&oneshot",
+                (
+                    Topic::PriceThreshold(PriceThreshold {
+                        amount_asset: Asset::Waves,
+                        price_asset: Asset::Waves,
+                        price_threshold: 1.5,
+                        direction: ThresholdDirection::Below,
+                    }),
+                    SubscriptionMode::Once,
+                    None,
                 ),
             ),
             (
@@ -180,8 +360,26 @@ mod tests {
                         amount_asset: Asset::Waves,
                         price_asset: Asset::Waves,
                         price_threshold: -10.5,
+                        direction: ThresholdDirection::Any,
                     }),
                     SubscriptionMode::Repeat,
+                    None,
+                ),
+            ),
+            (
+                "push://price_percent/WAVES/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/5?oneshot",
+                (
+                    Topic::PricePercentChange(PricePercentChange {
+                        amount_asset: Asset::Waves,
+                        price_asset: Asset::from_id(
+                            "8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc",
+                        )
+                            .unwrap(),
+                        base_price: None,
+                        percent: 5.0,
+                    }),
+                    SubscriptionMode::Once,
+                    None,
                 ),
             ),
         ];
@@ -197,6 +395,8 @@ mod tests {
                 TopicError::UnknownTopicKind("pop".to_string()),
             ),
             ("shush://orders", TopicError::UnknownScheme),
+            ("push://orders/WAVES", TopicError::InvalidPriceAsset),
+            ("push://orders/!!!/WAVES", TopicError::InvalidAmountAsset),
             (
                 "push://price_threshold/WAVES/WAVES",
                 TopicError::InvalidThreshold,
@@ -209,6 +409,35 @@ mod tests {
                 "push://price_threshold/WAVES/!!!/-10.5",
                 TopicError::InvalidPriceAsset,
             ),
+            (
+                "push://price_threshold/WAVES/WAVES/1.5?direction=up",
+                TopicError::InvalidDirection,
+            ),
+            (
+                "push://price_percent/WAVES/WAVES",
+                TopicError::InvalidPercent,
+            ),
+            (
+                "push://price_percent/WAVES/WAVES/-5",
+                TopicError::InvalidPercent,
+            ),
+            (
+                "push://price_percent/WAVES/WAVES/0",
+                TopicError::InvalidPercent,
+            ),
+            (
+                "push://price_percent/WAVES/!!!/5",
+                TopicError::InvalidPriceAsset,
+            ),
+            // Valid base58, but not an asset id
+            (
+                "push://orders/1234567qwe/WAVES",
+                TopicError::InvalidAmountAsset,
+            ),
+            (
+                "push://price_threshold/WAVES/1234567qwe/1.5",
+                TopicError::InvalidPriceAsset,
+            ),
         ];
 
         for (url, expected_error) in topic_urls_and_parsed_err {
@@ -226,6 +455,7 @@ mod tests {
                     price_asset: Asset::from_id("8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc")
                         .unwrap(),
                     price_threshold: 1.7,
+                    direction: ThresholdDirection::Any,
                 }),
                 SubscriptionMode::Repeat,
                 "push://price_threshold/WAVES/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/1.7",
@@ -236,24 +466,119 @@ mod tests {
                         .unwrap(),
                     price_asset: Asset::Waves,
                     price_threshold: 2.,
+                    direction: ThresholdDirection::Any,
                 }),
                 SubscriptionMode::Once,
                 "push://price_threshold/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/WAVES/2?oneshot",
             ),
             (
-                Topic::OrderFulfilled,
+                Topic::PriceThreshold(PriceThreshold {
+                    amount_asset: Asset::Waves,
+                    price_asset: Asset::Waves,
+                    price_threshold: 2.,
+                    direction: ThresholdDirection::Above,
+                }),
+                SubscriptionMode::Once,
+                "push://price_threshold/WAVES/WAVES/2?direction=above&oneshot",
+            ),
+            (
+                Topic::OrderFulfilled(None),
                 SubscriptionMode::Once,
                 "push://orders?oneshot"
             ),
             (
-                Topic::OrderFulfilled,
+                Topic::OrderFulfilled(None),
                 SubscriptionMode::Repeat,
                 "push://orders"
-            )
+            ),
+            (
+                Topic::OrderFulfilled(Some(AssetPair {
+                    amount_asset: Asset::from_id("8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc")
+                        .unwrap(),
+                    price_asset: Asset::Waves,
+                })),
+                SubscriptionMode::Repeat,
+                "push://orders/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/WAVES"
+            ),
+            (
+                Topic::PricePercentChange(PricePercentChange {
+                    amount_asset: Asset::Waves,
+                    price_asset: Asset::from_id("8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc")
+                        .unwrap(),
+                    base_price: Some(2.5),
+                    percent: 7.5,
+                }),
+                SubscriptionMode::Repeat,
+                "push://price_percent/WAVES/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/7.5"
+            ),
         ];
 
         for (topic, sub_mode, expected_url) in topics_sub_modes_urls {
-            assert_eq!(build_subscription_url(topic, sub_mode), expected_url);
+            assert_eq!(build_subscription_url(topic, sub_mode, None), expected_url);
         }
     }
+
+    #[test]
+    fn test_subscription_url_label() {
+        let url = "push://orders?oneshot&label=Sell%20my%20WAVES+bag";
+        let (topic, mode, label) = parse_subscription_url(url).unwrap();
+        assert_eq!(topic, Topic::OrderFulfilled(None));
+        assert_eq!(mode, SubscriptionMode::Once);
+        assert_eq!(label.as_deref(), Some("Sell my WAVES bag"));
+
+        // Round trip
+        let url = build_subscription_url(topic, mode, label.as_deref());
+        assert_eq!(url, "push://orders?oneshot&label=Sell+my+WAVES+bag");
+        let (_, _, parsed_label) = parse_subscription_url(&url).unwrap();
+        assert_eq!(parsed_label, label);
+
+        let url = build_subscription_url(
+            Topic::OrderFulfilled(None),
+            SubscriptionMode::Repeat,
+            Some("a&b=c/d?"),
+        );
+        let (_, mode, label) = parse_subscription_url(&url).unwrap();
+        assert_eq!(mode, SubscriptionMode::Repeat);
+        assert_eq!(label.as_deref(), Some("a&b=c/d?"));
+
+        // Sanitization
+        let label_of = |url: &str| parse_subscription_url(url).unwrap().2;
+        assert_eq!(label_of("push://orders?label="), None);
+        assert_eq!(label_of("push://orders?label=%20%0A%20"), None);
+        assert_eq!(
+            label_of("push://orders?label=%20x%0Ay%09"),
+            Some("xy".to_string())
+        );
+        let long_label = "ж".repeat(MAX_LABEL_LENGTH + 10);
+        let url = build_subscription_url(
+            Topic::OrderFulfilled(None),
+            SubscriptionMode::Repeat,
+            Some(&long_label),
+        );
+        assert_eq!(label_of(&url), Some("ж".repeat(MAX_LABEL_LENGTH)));
+    }
+
+    #[test]
+    fn test_price_percent_url_round_trip() {
+        for url in [
+            "push://price_percent/WAVES/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/5",
+            "push://price_percent/8cwrggsqQREpCLkPwZcD2xMwChi1MLaP7rofenGZ5Xuc/WAVES/0.25?oneshot",
+        ] {
+            let (topic, mode, label) = parse_subscription_url(url).unwrap();
+            assert_eq!(build_subscription_url(topic, mode, label.as_deref()), url);
+        }
+
+        // The base price is not a part of the url
+        let (topic, mode, _) =
+            parse_subscription_url("push://price_percent/WAVES/WAVES/10").unwrap();
+        let topic = match topic {
+            Topic::PricePercentChange(t) => Topic::PricePercentChange(PricePercentChange {
+                base_price: Some(3.0),
+                ..t
+            }),
+            _ => panic!("unexpected topic: {:?}", topic),
+        };
+        let url = build_subscription_url(topic, mode, None);
+        assert_eq!(url, "push://price_percent/WAVES/WAVES/10");
+    }
 }
@@ -4,7 +4,10 @@ use std::fmt;
 
 use serde::Deserialize;
 
-use processing::localization::LokaliseConfig;
+use model::secret::Secret;
+use processing::{localization::LokaliseConfig, ProcessingConfig};
+
+use crate::source::orders::MessageType;
 
 #[derive(Clone)]
 pub struct Config {
@@ -12,28 +15,45 @@ pub struct Config {
     pub redis_hostname: String,
     pub redis_port: u16,
     pub redis_user: String,
-    pub redis_password: String,
+    pub redis_password: Secret<String>,
     pub redis_stream_name: String,
     pub redis_group_name: String,
     pub redis_consumer_name: String,
     pub redis_batch_size: u32,
+    pub redis_message_types: Vec<MessageType>,
+    pub redis_reconnect_backoff_sec: u32,
+    pub redis_reclaim_idle_sec: Option<u32>,
+    pub partial_fill_cooldown_sec: Option<u32>,
+    pub min_partial_fill_percentage: Option<f64>,
     pub lokalise: LokaliseConfig,
+    pub processing: ProcessingConfig,
 }
 
 impl fmt::Debug for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Intentionally avoid printing passwords for security reasons
         f.debug_struct("Config")
             .field("assets_service_url", &self.assets_service_url)
             .field("redis_hostname", &self.redis_hostname)
             .field("redis_port", &self.redis_port)
             .field("redis_user", &self.redis_user)
-            .field("redis_password", &"****")
+            .field("redis_password", &self.redis_password)
             .field("redis_stream_name", &self.redis_stream_name)
             .field("redis_group_name", &self.redis_group_name)
             .field("redis_consumer_name", &self.redis_consumer_name)
             .field("redis_batch_size", &self.redis_batch_size)
+            .field("redis_message_types", &self.redis_message_types)
+            .field(
+                "redis_reconnect_backoff_sec",
+                &self.redis_reconnect_backoff_sec,
+            )
+            .field("redis_reclaim_idle_sec", &self.redis_reclaim_idle_sec)
+            .field("partial_fill_cooldown_sec", &self.partial_fill_cooldown_sec)
+            .field(
+                "min_partial_fill_percentage",
+                &self.min_partial_fill_percentage,
+            )
             .field("lokalise", &self.lokalise)
+            .field("processing", &self.processing)
             .finish()
     }
 }
@@ -41,6 +61,14 @@ impl fmt::Debug for Config {
 impl Config {
     pub fn load() -> Result<Self, envy::Error> {
         let config = envy::from_env::<RawConfig>()?;
+        if config.redis_message_types.contains(&MessageType::Unknown) {
+            return Err(envy::Error::Custom(
+                "unsupported message type in redis_message_types".to_string(),
+            ));
+        }
+        let consumer_name = config.redis_consumer_name.filter(|name| !name.is_empty());
+        let redis_reclaim_idle_sec =
+            reclaim_idle_sec(consumer_name.is_some(), config.redis_reclaim_idle_sec);
         let config = Config {
             assets_service_url: config.assets_service_url,
             redis_hostname: config.redis_hostname,
@@ -49,9 +77,15 @@ impl Config {
             redis_password: config.redis_password,
             redis_stream_name: config.redis_stream_name,
             redis_group_name: config.redis_group_name,
-            redis_consumer_name: config.redis_consumer_name,
+            redis_consumer_name: consumer_name.unwrap_or_else(default_redis_consumer_name),
             redis_batch_size: config.redis_batch_size,
+            redis_message_types: config.redis_message_types,
+            redis_reconnect_backoff_sec: config.redis_reconnect_backoff_sec,
+            redis_reclaim_idle_sec,
+            partial_fill_cooldown_sec: config.partial_fill_cooldown_sec,
+            min_partial_fill_percentage: config.min_partial_fill_percentage,
             lokalise: LokaliseConfig::load()?,
+            processing: ProcessingConfig::load()?,
         };
         Ok(config)
     }
@@ -65,12 +99,20 @@ struct RawConfig {
     redis_port: u16,
     #[serde(default = "default_redis_user")]
     redis_user: String,
-    redis_password: String,
+    redis_password: Secret<String>,
     redis_stream_name: String,
     redis_group_name: String,
-    redis_consumer_name: String,
+    /// Unique per instance if not set, see `default_redis_consumer_name`
+    redis_consumer_name: Option<String>,
     #[serde(default = "default_redis_batch_size")]
     redis_batch_size: u32,
+    #[serde(default = "default_redis_message_types")]
+    redis_message_types: Vec<MessageType>,
+    #[serde(default = "default_redis_reconnect_backoff_sec")]
+    redis_reconnect_backoff_sec: u32,
+    redis_reclaim_idle_sec: Option<u32>,
+    partial_fill_cooldown_sec: Option<u32>,
+    min_partial_fill_percentage: Option<f64>,
 }
 
 fn default_redis_port() -> u16 {
@@ -81,6 +123,69 @@ fn default_redis_user() -> String {
     "default".to_string()
 }
 
+/// Name of this instance as a consumer of the group, so that several instances share the stream
+/// messages. The pod name (the hostname in Kubernetes) is kept across container restarts,
+/// but not when a pod is replaced, so the pending messages are reclaimed, see `reclaim_idle_sec`.
+fn default_redis_consumer_name() -> String {
+    let hostname = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok());
+    consumer_name(hostname, std::process::id())
+}
+
+fn consumer_name(hostname: Option<String>, pid: u32) -> String {
+    match hostname.as_deref().map(str::trim) {
+        Some(hostname) if !hostname.is_empty() => format!("{}-{}", hostname, pid),
+        _ => format!("push-notifications-{}", pid),
+    }
+}
+
+/// Pending messages of a consumer named by default are read by no one once its pod is replaced,
+/// so they are reclaimed by the other consumers unless configured otherwise
+fn reclaim_idle_sec(consumer_named: bool, configured: Option<u32>) -> Option<u32> {
+    match configured {
+        Some(secs) => Some(secs),
+        None if !consumer_named => Some(default_redis_reclaim_idle_sec()),
+        None => None,
+    }
+}
+
+fn default_redis_reclaim_idle_sec() -> u32 {
+    300
+}
+
 fn default_redis_batch_size() -> u32 {
     100
 }
+
+fn default_redis_reconnect_backoff_sec() -> u32 {
+    5
+}
+
+fn default_redis_message_types() -> Vec<MessageType> {
+    vec![MessageType::OrdersUpdated]
+}
+
+#[test]
+fn test_consumer_name() {
+    let pod = |name: &str| Some(name.to_string());
+    assert_eq!(consumer_name(pod("orders-0\n"), 1), "orders-0-1");
+    assert_eq!(consumer_name(None, 42), "push-notifications-42");
+    assert_eq!(consumer_name(pod(""), 42), "push-notifications-42");
+
+    // Distinct consumers of the group for the pods of a deployment
+    assert_ne!(
+        consumer_name(pod("orders-0"), 1),
+        consumer_name(pod("orders-1"), 1)
+    );
+}
+
+#[test]
+fn test_reclaim_idle_sec() {
+    // The default name is not reused by a replaced pod
+    assert_eq!(reclaim_idle_sec(false, None), Some(300));
+    assert_eq!(reclaim_idle_sec(false, Some(60)), Some(60));
+    // A configured name is, so there is no one to reclaim from unless configured
+    assert_eq!(reclaim_idle_sec(true, None), None);
+    assert_eq!(reclaim_idle_sec(true, Some(60)), Some(60));
+}
@@ -3,20 +3,24 @@
 extern crate wavesexchange_log as log;
 
 mod config;
+mod metrics;
 mod source;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use diesel_async::{AsyncConnection, AsyncPgConnection};
 use tokio::{sync::mpsc, task, try_join};
 
 use wavesexchange_warp::MetricsWarpBuilder;
 
-use database::{device, message, subscription};
-use processing::{asset, localization, MessagePump};
+use database::{device, message, state, subscription};
+use processing::{asset, localization, muted_pairs::MutedPairs, readiness::Readiness, MessagePump};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    // Must be done before anything is logged
+    processing::log_levels::apply_overrides(env!("CARGO_CRATE_NAME"))?;
+
     // Configs
     let pg_config = database::config::Config::load()?;
     let config = config::Config::load()?;
@@ -27,27 +31,44 @@ async fn main() -> Result<(), anyhow::Error> {
     );
 
     // Initialization
-    //let (init_finished_tx, init_finished_rx) = oneshot::channel(); //TODO readyz
+    let readiness = Readiness::default();
 
     // Stats & liveness endpoints
+    let readyz_checker = readiness.checker();
     task::spawn(async move {
         MetricsWarpBuilder::new()
             .with_metrics_port_from_env()
-            //.with_readyz_checker(|| async move { init_finished_rx.await }) //TODO readyz
+            .with_metric(&*processing::metrics::EVENTS_RECEIVED)
+            .with_metric(&*processing::metrics::SUBSCRIPTIONS_MATCHED)
+            .with_metric(&*processing::metrics::MESSAGES_ENQUEUED)
+            .with_metric(&*processing::metrics::EVENT_PROCESSING_DURATION)
+            .with_metric(&*processing::metrics::UNLISTED_ASSET_NOTIFICATIONS_SKIPPED)
+            .with_metric(&*processing::metrics::MUTED_PAIR_EVENTS_SKIPPED)
+            .with_metric(&*processing::metrics::UNTRANSLATED_NOTIFICATIONS_SKIPPED)
+            .with_metric(&*processing::metrics::DEVICE_CAP_NOTIFICATIONS_DROPPED)
+            .with_metric(&*processing::metrics::DUPLICATE_MESSAGES_SKIPPED)
+            .with_metric(&*metrics::UNKNOWN_ENVELOPES_SKIPPED)
+            .with_metric(&*metrics::MALFORMED_ORDERS_SKIPPED)
+            .with_readyz_checker(readyz_checker)
             .run_async()
     });
 
     // Database
     log::info!("Connecting to postgres database: {:?}", pg_config);
-    let conn = AsyncPgConnection::establish(&pg_config.database_url()).await?;
+    let mut conn = AsyncPgConnection::establish(&pg_config.database_url()).await?;
 
     // Repo
     log::info!("Initializing repositories");
-    let subscriptions = subscription::Repo {};
+    let mut subscriptions = subscription::Repo::default();
+    if config.processing.skip_subscribers_without_devices {
+        subscriptions = subscriptions.with_devices_only();
+    }
     let assets = asset::RemoteGateway::new(config.assets_service_url);
-    let devices = device::Repo {};
+    let devices = device::Repo::default();
     let localizer = task::spawn(localization::Repo::new(config.lokalise));
     let messages = message::Queue {};
+    let state = state::Repo {};
+    let muted_pairs = MutedPairs::start(&config.processing)?;
 
     // Create event sources
     log::info!("Initializing orders event source");
@@ -58,13 +79,23 @@ async fn main() -> Result<(), anyhow::Error> {
                 port: config.redis_port,
                 user: config.redis_user,
                 password: config.redis_password,
+                reconnect_backoff: Duration::from_secs(config.redis_reconnect_backoff_sec as u64),
             },
             stream: source::orders::RedisStreamConfig {
                 stream_name: config.redis_stream_name,
                 group_name: config.redis_group_name,
                 consumer_name: config.redis_consumer_name,
+                reclaim_idle: config
+                    .redis_reclaim_idle_sec
+                    .map(|secs| Duration::from_secs(secs as u64)),
             },
             batch_max_size: config.redis_batch_size,
+            partial_fill_cooldown: config
+                .partial_fill_cooldown_sec
+                .map(|secs| Duration::from_secs(secs as u64)),
+            min_partial_fill_percentage: config.min_partial_fill_percentage,
+            message_types: config.redis_message_types,
+            last_processed: state.get(source::orders::CHECKPOINT_KEY, &mut conn).await?,
         };
         source::orders::Source::new(config).await?
     };
@@ -81,15 +112,23 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Event processor
     log::info!("Initialization finished, starting service");
-    let processor = MessagePump::new(subscriptions, assets, devices, localizer, messages);
+    let processor = MessagePump::new(
+        subscriptions,
+        assets,
+        devices,
+        localizer,
+        messages,
+        muted_pairs,
+        config.processing,
+    );
     let processor = Arc::new(processor);
     let h_processor = task::spawn(async { processor.run_event_loop(events_rx, conn).await });
 
     // Initialization phase finished
-    //let () = init_finished_tx.send(()).expect("init"); //TODO readyz
+    readiness.set_ready();
 
     // Join all the background tasks
-    let ((), r_orders_source) = try_join!(h_processor, h_orders_source)?;
+    let (_summary, r_orders_source) = try_join!(h_processor, h_orders_source)?;
     let () = r_orders_source?;
 
     log::info!("Service finished.");
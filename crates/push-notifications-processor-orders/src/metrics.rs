@@ -0,0 +1,17 @@
+//! Orders processor metrics
+
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+
+lazy_static! {
+    pub static ref UNKNOWN_ENVELOPES_SKIPPED: IntCounter = IntCounter::new(
+        "unknown_envelopes_skipped",
+        "Matcher feed messages skipped because of an unknown message type"
+    )
+    .unwrap();
+    pub static ref MALFORMED_ORDERS_SKIPPED: IntCounter = IntCounter::new(
+        "malformed_orders_skipped",
+        "Order updates skipped because of a bad asset id or owner address"
+    )
+    .unwrap();
+}
@@ -1,5 +1,7 @@
 //! Source of Order events
 
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+
 use bigdecimal::BigDecimal;
 use tokio::sync::{mpsc, oneshot};
 
@@ -11,10 +13,13 @@ use model::{
     waves::Address,
 };
 
-use processing::EventWithFeedback;
+use processing::{Checkpoint, EventWithFeedback};
+
+use crate::metrics;
 
 use self::redis_stream::{HandleError, RedisStreamReader};
 
+pub use self::json::MessageType;
 pub use self::redis_stream::{RedisConnectionConfig, RedisStreamConfig};
 
 /// Config for the Order Execution events stream
@@ -22,44 +27,126 @@ pub struct SourceConfig {
     pub connection: RedisConnectionConfig,
     pub stream: RedisStreamConfig,
     pub batch_max_size: u32,
+    /// Minimum interval between partial fill notifications of the same order
+    pub partial_fill_cooldown: Option<Duration>,
+    /// Last processed stream position, as saved with `CHECKPOINT_KEY`
+    pub last_processed: Option<String>,
+    /// Partial fills of less than this percentage of the order (in a single match) are ignored
+    pub min_partial_fill_percentage: Option<f64>,
+    /// Types of the feed messages to take order updates from
+    pub message_types: Vec<MessageType>,
 }
 
+/// Key of the last processed stream position in the service state
+pub const CHECKPOINT_KEY: &str = "orders_stream_position";
+
 /// Source of Order Execution events (based on the Redis feed)
 pub struct Source {
     reader: RedisStreamReader,
+    cooldown: Option<Mutex<PartialFillCooldown>>,
+    last_processed: Option<StreamPosition>,
+    min_partial_fill_percentage: Option<f64>,
+    message_types: Vec<MessageType>,
 }
 
 impl Source {
     pub async fn new(config: SourceConfig) -> anyhow::Result<Self> {
+        let last_processed = match config.last_processed {
+            Some(position) => Some(StreamPosition::parse(&position).ok_or_else(|| {
+                anyhow::anyhow!("Bad saved orders stream position: {}", position)
+            })?),
+            None => None,
+        };
+        log::info!(
+            "Last processed orders stream position: {:?}",
+            last_processed
+        );
         let reader =
             RedisStreamReader::new(config.connection, config.stream, config.batch_max_size).await?;
-        let source = Source { reader };
+        let cooldown = config
+            .partial_fill_cooldown
+            .map(|window| Mutex::new(PartialFillCooldown::new(window)));
+        let source = Source {
+            reader,
+            cooldown,
+            last_processed,
+            min_partial_fill_percentage: config.min_partial_fill_percentage,
+            message_types: config.message_types,
+        };
         Ok(source)
     }
 
     pub async fn run(self, sink: mpsc::Sender<EventWithFeedback>) -> anyhow::Result<()> {
-        let process_fn = |message: Vec<u8>| {
+        let Source {
+            reader,
+            cooldown,
+            last_processed,
+            min_partial_fill_percentage,
+            message_types,
+        } = self;
+        let message_types = &message_types;
+        let process_fn = |id: String, message: Vec<u8>| {
             let sink = sink.clone();
+            let cooldown = cooldown.as_ref();
             async move {
-                let (orders, timestamp) =
-                    json::parse_orders(&message).map_err(|e| HandleError::Error(e.into()))?;
+                let received_at = Timestamp::now();
+                let (orders, timestamp) = json::parse_orders(&message, message_types)
+                    .map_err(|e| HandleError::Error(e.into()))?;
                 log::debug!("Got {} order updates @ {:?}", orders.len(), timestamp);
-                Self::send_order_events(orders, &sink).await
+                Self::send_order_events(
+                    &id,
+                    orders,
+                    received_at,
+                    last_processed,
+                    min_partial_fill_percentage,
+                    cooldown,
+                    &sink,
+                )
+                .await
             }
         };
-        self.reader.run(process_fn).await
+        reader.run(process_fn).await
     }
 
     async fn send_order_events(
+        entry_id: &str,
         orders: Vec<json::OrderUpdate>,
+        received_at: Timestamp,
+        last_processed: Option<StreamPosition>,
+        min_partial_fill_percentage: Option<f64>,
+        cooldown: Option<&Mutex<PartialFillCooldown>>,
         sink: &mpsc::Sender<EventWithFeedback>,
     ) -> Result<(), HandleError> {
-        for order in orders {
-            if let Some(event) = Self::event_from_order_update(order) {
+        for (index, order) in orders.into_iter().enumerate() {
+            let position = StreamPosition::new(entry_id, index);
+            if let Some(position) = position {
+                if position.is_processed(last_processed) {
+                    log::debug!(
+                        "Order update at {} is already processed - skipped",
+                        position
+                    );
+                    continue;
+                }
+            }
+            let order_id = order.order_id.clone();
+            if let Some(event) = Self::event_from_order_update(order, min_partial_fill_percentage) {
+                if let Some(cooldown) = cooldown {
+                    if !cooldown.lock().expect("lock").admit(&order_id, &event) {
+                        log::debug!("Repeated partial fill of order {} - skipped", order_id);
+                        continue;
+                    }
+                }
                 log::trace!("Sending order event: {:?}", event);
+                let event_id = order_event_id(&order_id, event.timestamp());
                 let (tx, rx) = oneshot::channel();
                 let evf = EventWithFeedback {
                     event,
+                    received_at,
+                    checkpoint: position.map(|position| Checkpoint {
+                        key: CHECKPOINT_KEY,
+                        value: position.to_string(),
+                    }),
+                    event_id: Some(event_id),
                     result_tx: tx,
                 };
                 sink.send(evf).await.map_err(|_| HandleError::Terminate)?;
@@ -70,9 +157,59 @@ impl Source {
         Ok(())
     }
 
-    fn event_from_order_update(order: json::OrderUpdate) -> Option<Event> {
-        use bigdecimal::ToPrimitive;
+    /// Cancellations and negligible partial fills (see `min_partial_fill_percentage`) are not events.
+    /// Malformed updates (bad asset ids or owner address) are skipped with a warning.
+    fn event_from_order_update(
+        order: json::OrderUpdate,
+        min_partial_fill_percentage: Option<f64>,
+    ) -> Option<Event> {
+        if let (json::OrderStatus::PartiallyFilled, Some(min_percentage)) =
+            (order.status, min_partial_fill_percentage)
+        {
+            // Amount of this match, if known, otherwise the order is judged by its total fill
+            let filled = order
+                .executed_amount
+                .as_ref()
+                .unwrap_or(&order.filled_amount_accumulated);
+            if percentage(filled, &order.amount) < min_percentage {
+                log::debug!(
+                    "Negligible partial fill of order {} - skipped",
+                    order.order_id
+                );
+                return None;
+            }
+        }
+        let execution = match order.status {
+            json::OrderStatus::Filled => OrderExecution::Full,
+            json::OrderStatus::PartiallyFilled => OrderExecution::Partial {
+                percentage: percentage(&order.filled_amount_accumulated, &order.amount),
+            },
+            json::OrderStatus::Cancelled => return None,
+        };
+        let malformed = |field: &str, value: &str| {
+            log::warn!(
+                "Order {} has bad {} '{}' - skipped",
+                order.order_id,
+                field,
+                value
+            );
+            metrics::MALFORMED_ORDERS_SKIPPED.inc();
+            None
+        };
+        let amount_asset = match Asset::from_id(&order.amount_asset) {
+            Ok(asset) => asset,
+            Err(_) => return malformed("amount asset", &order.amount_asset),
+        };
+        let price_asset = match Asset::from_id(&order.price_asset) {
+            Ok(asset) => asset,
+            Err(_) => return malformed("price asset", &order.price_asset),
+        };
+        let address = match Address::from_string(&order.owner_address) {
+            Ok(address) => address,
+            Err(_) => return malformed("owner address", &order.owner_address),
+        };
         let event = Event::OrderExecuted {
+            order_id: order.order_id.clone(),
             order_type: match order.order_type {
                 json::OrderType::Limit => OrderType::Limit,
                 json::OrderType::Market => OrderType::Market,
@@ -82,37 +219,340 @@ impl Source {
                 json::OrderSide::Sell => OrderSide::Sell,
             },
             asset_pair: AssetPair {
-                amount_asset: Asset::from_id(&order.amount_asset).expect("amount asset"),
-                price_asset: Asset::from_id(&order.price_asset).expect("price asset"),
+                amount_asset,
+                price_asset,
             },
-            execution: match order.status {
-                json::OrderStatus::Filled => OrderExecution::Full,
-                json::OrderStatus::PartiallyFilled => OrderExecution::Partial {
-                    percentage: {
-                        let filled = order.filled_amount_accumulated;
-                        let total = order.amount;
-                        let ratio = BigDecimal::from(100) * filled / total;
-                        ratio.to_f64().expect("percentage")
-                    },
-                },
-                json::OrderStatus::Cancelled => return None,
-            },
-            address: Address::from_string(&order.owner_address).expect("order owner address"),
+            execution,
+            address,
             timestamp: Timestamp::from_unix_timestamp_millis(order.event_timestamp),
         };
         Some(event)
     }
 }
 
+/// An order is executed at most once at a time, whether it is a partial or a full fill
+fn order_event_id(order_id: &str, timestamp: Timestamp) -> String {
+    format!("order:{}:{}", order_id, timestamp.unix_timestamp_millis())
+}
+
+#[test]
+fn test_order_event_id() {
+    let order_id = "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq";
+    let timestamp = Timestamp::from_unix_timestamp_millis(1673428865872);
+    let id = order_event_id(order_id, timestamp);
+    assert_eq!(
+        id,
+        "order:DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq:1673428865872"
+    );
+    // The same update read again
+    assert_eq!(order_event_id(order_id, timestamp), id);
+    // Another fill of the same order
+    let later = Timestamp::from_unix_timestamp_millis(1673428870000);
+    assert_ne!(order_event_id(order_id, later), id);
+}
+
+fn percentage(filled: &BigDecimal, total: &BigDecimal) -> f64 {
+    use bigdecimal::ToPrimitive;
+    let ratio = BigDecimal::from(100) * filled / total;
+    ratio.to_f64().expect("percentage")
+}
+
+#[test]
+fn test_min_partial_fill() {
+    use serde_json::json;
+
+    let order = |status: &str, filled: &str, executed: &str| -> json::OrderUpdate {
+        serde_json::from_value(json!({
+            "i": "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq",
+            "o": "3Q6pToUA28zJbMJUfB5xoGgfqqni11H7NPq",
+            "t": 1673428865872_i64,
+            "A": "WAVES",
+            "P": "GwT5y18jcrrppAuj5VkfnHLG8WRf3TNzmhREQkY4pzd8",
+            "S": "buy",
+            "T": "limit",
+            "p": "5.0",
+            "a": "1000.0",
+            "f": "0.003",
+            "F": "WAVES",
+            "s": status,
+            "q": filled,
+            "Q": "0.003",
+            "r": "5.0",
+            "Z": 1673428865504_i64,
+            "c": executed,
+            "h": "0.003",
+            "e": "5.0",
+            "E": "5.0"
+        }))
+        .unwrap()
+    };
+    let event = |order| Source::event_from_order_update(order, Some(1.0));
+
+    // 0.1% of the order is dropped, 5% is kept
+    assert!(event(order("PartiallyFilled", "1.0", "1.0")).is_none());
+    assert!(event(order("PartiallyFilled", "50.0", "50.0")).is_some());
+
+    // Judged by the amount of the match, not the accumulated fill
+    assert!(event(order("PartiallyFilled", "500.0", "1.0")).is_none());
+
+    // Full fill is notified whatever the last match is
+    assert!(event(order("Filled", "1000.0", "1.0")).is_some());
+
+    // No threshold - every partial fill is an event
+    let event = Source::event_from_order_update(order("PartiallyFilled", "1.0", "1.0"), None);
+    assert!(matches!(
+        event,
+        Some(Event::OrderExecuted {
+            execution: OrderExecution::Partial { percentage },
+            ..
+        }) if (percentage - 0.1).abs() < 1e-9
+    ));
+}
+
+#[test]
+fn test_malformed_order_skipped() {
+    use serde_json::json;
+
+    let order = |order_id: &str, amount_asset: &str| -> json::OrderUpdate {
+        serde_json::from_value(json!({
+            "i": order_id,
+            "o": "3Q6pToUA28zJbMJUfB5xoGgfqqni11H7NPq",
+            "t": 1673428865872_i64,
+            "A": amount_asset,
+            "P": "GwT5y18jcrrppAuj5VkfnHLG8WRf3TNzmhREQkY4pzd8",
+            "S": "buy",
+            "T": "limit",
+            "p": "5.0",
+            "a": "1.0",
+            "f": "0.003",
+            "F": "WAVES",
+            "s": "Filled",
+            "q": "1.0",
+            "Q": "0.003",
+            "Z": 1673428865504_i64
+        }))
+        .unwrap()
+    };
+    let orders = vec![
+        order("DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq", "WAVES"),
+        order(
+            "GR6WbwBxs6q8MXqLaz8a53epGKuqxBaM8fF9RDD5NiLW",
+            "not-an-asset",
+        ),
+        order("JX4G8f5ehPyUPfH12DRevvjCGSP7LaRcy9ToddLdqKL", "WAVES"),
+    ];
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let skipped_before = metrics::MALFORMED_ORDERS_SKIPPED.get();
+    let (sink, mut events) = mpsc::channel::<EventWithFeedback>(1);
+    // Acknowledges the events, as the event loop does
+    let event_ids = rt.spawn(async move {
+        let mut event_ids = Vec::new();
+        while let Some(evf) = events.recv().await {
+            event_ids.push(evf.event_id);
+            evf.result_tx.send(Ok(())).unwrap();
+        }
+        event_ids
+    });
+    let now = Timestamp::now();
+    let res = rt.block_on(Source::send_order_events(
+        "1673428865504-0",
+        orders,
+        now,
+        None,
+        None,
+        None,
+        &sink,
+    ));
+    drop(sink);
+
+    // The batch is not failed, the good orders around the bad one still produce events
+    assert!(res.is_ok());
+    let event_ids = rt.block_on(event_ids).unwrap();
+    assert_eq!(
+        event_ids,
+        vec![
+            Some("order:DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq:1673428865504".to_string()),
+            Some("order:JX4G8f5ehPyUPfH12DRevvjCGSP7LaRcy9ToddLdqKL:1673428865504".to_string()),
+        ]
+    );
+    assert!(metrics::MALFORMED_ORDERS_SKIPPED.get() > skipped_before);
+}
+
+/// Position of an order update in the Redis stream:
+/// id of the stream entry (`<millis>-<seq>`) and index of the update within the entry
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct StreamPosition {
+    entry_id: (u64, u64),
+    index: usize,
+}
+
+impl StreamPosition {
+    fn new(entry_id: &str, index: usize) -> Option<Self> {
+        let (millis, seq) = entry_id.split_once('-')?;
+        let entry_id = (millis.parse().ok()?, seq.parse().ok()?);
+        Some(StreamPosition { entry_id, index })
+    }
+
+    /// Parses the `Display` format, `<millis>-<seq>/<index>`
+    fn parse(s: &str) -> Option<Self> {
+        let (entry_id, index) = s.split_once('/')?;
+        StreamPosition::new(entry_id, index.parse().ok()?)
+    }
+
+    /// Stream ids only grow, so everything up to the last processed position is processed
+    fn is_processed(&self, last_processed: Option<StreamPosition>) -> bool {
+        last_processed.map_or(false, |last| *self <= last)
+    }
+}
+
+impl fmt::Display for StreamPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (millis, seq) = self.entry_id;
+        write!(f, "{}-{}/{}", millis, seq, self.index)
+    }
+}
+
+#[test]
+fn test_stream_position() {
+    let position = StreamPosition::new("1673428865504-1", 2).unwrap();
+    assert_eq!(position.to_string(), "1673428865504-1/2");
+    assert_eq!(StreamPosition::parse("1673428865504-1/2"), Some(position));
+    assert_eq!(StreamPosition::new("1673428865504", 0), None);
+    assert_eq!(StreamPosition::parse("1673428865504-1"), None);
+
+    // Ids are compared numerically, not as strings
+    let pos = |id: &str, index: usize| StreamPosition::new(id, index).unwrap();
+    assert!(pos("999-0", 0) < pos("1000-0", 0));
+    assert!(pos("1000-9", 0) < pos("1000-10", 0));
+    assert!(pos("1000-0", 1) < pos("1000-0", 2));
+}
+
+#[test]
+fn test_reprocessing_skipped() {
+    // After a crash between the database commit and the stream ack,
+    // the entry is read again, and its updates up to the saved position are skipped
+    let last_processed = StreamPosition::parse("1000-0/1");
+    let is_processed = |id: &str, index: usize| {
+        StreamPosition::new(id, index)
+            .unwrap()
+            .is_processed(last_processed)
+    };
+    assert!(is_processed("999-5", 3));
+    assert!(is_processed("1000-0", 0));
+    assert!(is_processed("1000-0", 1));
+    assert!(!is_processed("1000-0", 2));
+    assert!(!is_processed("1000-1", 0));
+
+    // Nothing is skipped on the very first run
+    let position = StreamPosition::new("1000-0", 0).unwrap();
+    assert!(!position.is_processed(None));
+}
+
+/// Suppresses repeated partial fill notifications of the same order within a time window,
+/// which is what algorithmic orders filled in many small chunks would produce otherwise.
+/// The final full fill is always let through.
+///
+/// Event timestamps are used rather than the wall clock, so that a backlog of events
+/// read after a restart is handled the same way.
+struct PartialFillCooldown {
+    window_millis: i64,
+    /// Time of the last notified partial fill, by order id
+    last_notified: HashMap<String, Timestamp>,
+}
+
+impl PartialFillCooldown {
+    fn new(window: Duration) -> Self {
+        PartialFillCooldown {
+            window_millis: window.as_millis() as i64,
+            last_notified: HashMap::new(),
+        }
+    }
+
+    /// Whether a notification should be sent about the event
+    fn admit(&mut self, order_id: &str, event: &Event) -> bool {
+        let (execution, now) = match event {
+            Event::OrderExecuted {
+                execution,
+                timestamp,
+                ..
+            } => (execution, timestamp.unix_timestamp_millis()),
+            Event::PriceChanged { .. } => return true,
+        };
+
+        // Forget orders which are out of the window anyway, so that the map doesn't grow
+        let window_millis = self.window_millis;
+        self.last_notified
+            .retain(|_, last| now - last.unix_timestamp_millis() < window_millis);
+
+        match execution {
+            OrderExecution::Full => {
+                self.last_notified.remove(order_id);
+                true
+            }
+            OrderExecution::Partial { .. } => {
+                if self.last_notified.contains_key(order_id) {
+                    false
+                } else {
+                    let timestamp = Timestamp::from_unix_timestamp_millis(now);
+                    self.last_notified.insert(order_id.to_string(), timestamp);
+                    true
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_partial_fill_cooldown() {
+    let mut cooldown = PartialFillCooldown::new(Duration::from_secs(60));
+    let event = |execution: OrderExecution, time_sec: i64| Event::OrderExecuted {
+        order_id: "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq".to_string(),
+        order_type: OrderType::Limit,
+        side: OrderSide::Buy,
+        asset_pair: AssetPair {
+            amount_asset: Asset::Waves,
+            price_asset: Asset::Waves,
+        },
+        execution,
+        address: Address::from_string("3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2").unwrap(),
+        timestamp: Timestamp::from_unix_timestamp_millis(1_700_000_000_000 + time_sec * 1000),
+    };
+    let partial =
+        |percentage: f64, time_sec: i64| event(OrderExecution::Partial { percentage }, time_sec);
+
+    // Rapid partial fills are notified about once
+    assert!(cooldown.admit("order1", &partial(10.0, 0)));
+    assert!(!cooldown.admit("order1", &partial(20.0, 1)));
+    assert!(!cooldown.admit("order1", &partial(30.0, 30)));
+
+    // Other orders are not affected
+    assert!(cooldown.admit("order2", &partial(10.0, 30)));
+
+    // The window is over
+    assert!(cooldown.admit("order1", &partial(40.0, 61)));
+    assert!(!cooldown.admit("order1", &partial(50.0, 62)));
+
+    // Full fill is never suppressed
+    assert!(cooldown.admit("order1", &event(OrderExecution::Full, 63)));
+    assert!(cooldown.admit("order2", &event(OrderExecution::Full, 63)));
+}
+
 mod redis_stream {
-    use std::{fmt, future::Future, time::Duration};
+    use std::{
+        fmt,
+        future::Future,
+        time::{Duration, Instant},
+    };
 
+    use model::secret::Secret;
     use redis::{
+        aio::ConnectionLike,
         streams::{
             StreamInfoConsumersReply, StreamInfoGroupsReply, StreamInfoStreamReply,
             StreamReadOptions, StreamReadReply,
         },
-        AsyncCommands, Value,
+        AsyncCommands, ErrorKind, RedisError, Value,
     };
 
     #[derive(Clone)]
@@ -120,7 +560,9 @@ mod redis_stream {
         pub hostname: String,
         pub port: u16,
         pub user: String,
-        pub password: String,
+        pub password: Secret<String>,
+        /// Delay before reconnecting once the connection is lost, and between attempts
+        pub reconnect_backoff: Duration,
     }
 
     #[derive(Clone)]
@@ -128,6 +570,9 @@ mod redis_stream {
         pub stream_name: String,
         pub group_name: String,
         pub consumer_name: String,
+        /// Pending messages of other consumers (crashed ones) idle for this long are claimed
+        /// by this consumer, not claimed if not set. Claiming needs `XAUTOCLAIM`, Redis 6.2+
+        pub reclaim_idle: Option<Duration>,
     }
 
     impl RedisConnectionConfig {
@@ -137,7 +582,7 @@ mod redis_stream {
             format!(
                 "redis://{}:{}@{}:{}/{}",
                 self.user,
-                self.password,
+                self.password.expose(),
                 self.hostname,
                 self.port,
                 Self::DATABASE_ID
@@ -147,20 +592,23 @@ mod redis_stream {
 
     impl fmt::Debug for RedisConnectionConfig {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            // Intentionally avoid printing password for security reasons
             write!(
                 f,
-                "Redis(server={}:{}; database={}; user={}; password=***)",
+                "Redis(server={}:{}; database={}; user={}; password={:?}; reconnect_backoff={:?})",
                 self.hostname,
                 self.port,
                 Self::DATABASE_ID,
-                self.user
+                self.user,
+                self.password,
+                self.reconnect_backoff
             )
         }
     }
 
     pub(super) struct RedisStreamReader {
+        client: redis::Client,
         conn: redis::aio::Connection,
+        reconnect_backoff: Duration,
         stream: RedisStreamConfig,
         batch_max_size: u32,
     }
@@ -170,6 +618,36 @@ mod redis_stream {
         Error(anyhow::Error),
     }
 
+    /// Error of the stream reading loop: either the connection is lost and can be re-established,
+    /// or it is fatal to the service
+    enum RunError {
+        Connection(RedisError),
+        Fatal(anyhow::Error),
+    }
+
+    impl From<RedisError> for RunError {
+        fn from(err: RedisError) -> Self {
+            if is_connection_error(&err) {
+                RunError::Connection(err)
+            } else {
+                RunError::Fatal(err.into())
+            }
+        }
+    }
+
+    impl From<anyhow::Error> for RunError {
+        fn from(err: anyhow::Error) -> Self {
+            RunError::Fatal(err)
+        }
+    }
+
+    fn is_connection_error(err: &RedisError) -> bool {
+        err.is_connection_dropped()
+            || err.is_connection_refusal()
+            || err.is_io_error()
+            || err.is_timeout()
+    }
+
     impl RedisStreamReader {
         pub(super) async fn new(
             conn: RedisConnectionConfig,
@@ -177,24 +655,41 @@ mod redis_stream {
             batch_max_size: u32,
         ) -> anyhow::Result<Self> {
             log::info!("Connecting to {:?}", conn);
-            let redis_client = redis::Client::open(conn.connection_url())?;
-            let mut redis_conn = redis_client.get_async_connection().await?;
-            log::info!("Redis connected.");
-            prepare(&mut redis_conn, stream.clone()).await?;
+            let client = redis::Client::open(conn.connection_url())?;
+            let redis_conn = connect(&client, &stream).await?;
             let reader = RedisStreamReader {
+                client,
                 conn: redis_conn,
+                reconnect_backoff: conn.reconnect_backoff,
                 stream,
                 batch_max_size,
             };
             Ok(reader)
         }
 
-        pub(super) async fn run<F, R>(self, process_fn: F) -> anyhow::Result<()>
+        /// Reads the stream until a fatal error, reconnecting whenever the connection is lost.
+        /// Messages not acknowledged before that are pending, and are read again after it.
+        pub(super) async fn run<F, R>(self, mut process_fn: F) -> anyhow::Result<()>
         where
-            F: FnMut(Vec<u8>) -> R,
+            F: FnMut(String, Vec<u8>) -> R,
             R: Future<Output = Result<(), HandleError>>,
         {
-            let res = run(self.conn, self.stream, self.batch_max_size, process_fn).await;
+            let RedisStreamReader {
+                client,
+                conn,
+                reconnect_backoff,
+                stream,
+                batch_max_size,
+            } = self;
+            let res = run_reconnecting(
+                conn,
+                || connect(&client, &stream),
+                reconnect_backoff,
+                &stream,
+                batch_max_size,
+                &mut process_fn,
+            )
+            .await;
             match res {
                 Ok(()) => log::debug!("Redis stream reading loop exited normally"),
                 Err(ref err) => {
@@ -205,9 +700,124 @@ mod redis_stream {
         }
     }
 
+    /// Reads the stream from the connection, replacing it with a new one whenever it is lost
+    async fn run_reconnecting<C, Fc, Rc, F, R>(
+        mut conn: C,
+        mut connect: Fc,
+        reconnect_backoff: Duration,
+        stream: &RedisStreamConfig,
+        batch_max_size: u32,
+        process_fn: &mut F,
+    ) -> anyhow::Result<()>
+    where
+        C: ConnectionLike + Send,
+        Fc: FnMut() -> Rc,
+        Rc: Future<Output = anyhow::Result<C>>,
+        F: FnMut(String, Vec<u8>) -> R,
+        R: Future<Output = Result<(), HandleError>>,
+    {
+        loop {
+            match run(&mut conn, stream, batch_max_size, process_fn).await {
+                Ok(()) => return Ok(()),
+                Err(RunError::Fatal(err)) => return Err(err),
+                Err(RunError::Connection(err)) => {
+                    log::warn!(
+                        "Redis connection lost: {}, reconnecting in {:?}",
+                        err,
+                        reconnect_backoff
+                    );
+                    conn = reconnect(&mut connect, reconnect_backoff).await;
+                }
+            }
+        }
+    }
+
     const BEGIN_OF_STREAM: &str = "0-0";
     const NEW_MESSAGES: &str = ">";
 
+    async fn connect(
+        client: &redis::Client,
+        stream: &RedisStreamConfig,
+    ) -> anyhow::Result<redis::aio::Connection> {
+        let mut conn = client.get_async_connection().await?;
+        log::info!("Redis connected.");
+        prepare(&mut conn, stream.clone()).await?;
+        Ok(conn)
+    }
+
+    /// Retries to connect after the backoff until connected
+    async fn reconnect<C, F, R>(mut connect: F, backoff: Duration) -> C
+    where
+        F: FnMut() -> R,
+        R: Future<Output = anyhow::Result<C>>,
+    {
+        loop {
+            tokio::time::sleep(backoff).await;
+            match connect().await {
+                Ok(conn) => return conn,
+                Err(err) => {
+                    log::warn!(
+                        "Failed to reconnect to Redis: {}, retrying in {:?}",
+                        err,
+                        backoff
+                    )
+                }
+            }
+        }
+    }
+
+    /// Claims the pending messages of other consumers of the group idle for at least `min_idle`,
+    /// so that they are read along with the pending messages of this consumer.
+    /// Returns the number of messages claimed. `XAUTOCLAIM` is only there since Redis 6.2,
+    /// older servers fail it with an unknown command error, which is fatal.
+    async fn reclaim(
+        con: &mut (impl ConnectionLike + Send),
+        stream: &RedisStreamConfig,
+        min_idle: Duration,
+        batch_max_size: u32,
+    ) -> Result<usize, RedisError> {
+        let mut start_id = BEGIN_OF_STREAM.to_string();
+        let mut claimed = 0;
+        loop {
+            let reply = redis::cmd("XAUTOCLAIM")
+                .arg(&stream.stream_name)
+                .arg(&stream.group_name)
+                .arg(&stream.consumer_name)
+                .arg(min_idle.as_millis() as u64)
+                .arg(&start_id)
+                .arg("COUNT")
+                .arg(batch_max_size)
+                .arg("JUSTID")
+                .query_async::<_, Value>(con)
+                .await?;
+            let (next_id, ids) = parse_autoclaim_reply(reply).ok_or_else(|| {
+                RedisError::from((ErrorKind::TypeError, "Unexpected XAUTOCLAIM reply"))
+            })?;
+            claimed += ids.len();
+            // The whole pending list is scanned once the next id is back to the beginning
+            if next_id == BEGIN_OF_STREAM {
+                break;
+            }
+            start_id = next_id;
+        }
+        if claimed > 0 {
+            log::info!("Claimed {} pending messages of other consumers", claimed);
+        }
+        Ok(claimed)
+    }
+
+    /// Reply is `[next start id, [claimed ids], [deleted ids]]`, the latter since Redis 7
+    fn parse_autoclaim_reply(reply: Value) -> Option<(String, Vec<String>)> {
+        let items = match reply {
+            Value::Bulk(items) => items,
+            _ => return None,
+        };
+        let mut items = items.into_iter();
+        let next_id = redis::from_redis_value::<String>(&items.next()?).ok()?;
+        let ids = redis::from_redis_value::<Vec<String>>(&items.next()?).ok()?;
+        Some((next_id, ids))
+    }
+
     async fn prepare(
         con: &mut redis::aio::Connection,
         stream: RedisStreamConfig,
@@ -216,6 +826,7 @@ mod redis_stream {
             stream_name,
             group_name,
             consumer_name,
+            reclaim_idle: _,
         } = stream;
 
         // Probe whether the configured Redis stream exists
@@ -253,21 +864,20 @@ mod redis_stream {
     }
 
     async fn run<F, R>(
-        mut con: redis::aio::Connection,
-        stream: RedisStreamConfig,
+        con: &mut (impl ConnectionLike + Send),
+        stream: &RedisStreamConfig,
         batch_max_size: u32,
-        mut process_fn: F,
-    ) -> anyhow::Result<()>
+        process_fn: &mut F,
+    ) -> Result<(), RunError>
     where
-        F: FnMut(Vec<u8>) -> R,
+        F: FnMut(String, Vec<u8>) -> R,
         R: Future<Output = Result<(), HandleError>>,
     {
-        let con = &mut con;
-
         let RedisStreamConfig {
             stream_name,
             group_name,
             consumer_name,
+            reclaim_idle,
         } = stream;
 
         log::info!(
@@ -277,6 +887,11 @@ mod redis_stream {
             consumer_name,
         );
 
+        if let Some(min_idle) = *reclaim_idle {
+            reclaim(con, stream, min_idle, batch_max_size).await?;
+        }
+        let mut last_reclaimed = Instant::now();
+
         log::debug!("Re-fetching pending messages (not acknowledged since last run)");
         let mut fetching_backlog = true;
         let mut from_id = BEGIN_OF_STREAM.to_string();
@@ -287,11 +902,23 @@ mod redis_stream {
         const MAX_BLOCK_TIME: Duration = Duration::from_secs(6);
 
         let read_options = StreamReadOptions::default()
-            .group(&group_name, &consumer_name)
+            .group(group_name, consumer_name)
             .count(batch_max_size as usize)
             .block(MAX_BLOCK_TIME.as_millis() as usize);
 
         loop {
+            // Consumers may crash while this one is running, so their messages are reclaimed
+            // periodically, and read as pending ones
+            if let Some(min_idle) = *reclaim_idle {
+                if !fetching_backlog && last_reclaimed.elapsed() >= min_idle {
+                    last_reclaimed = Instant::now();
+                    if reclaim(con, stream, min_idle, batch_max_size).await? > 0 {
+                        fetching_backlog = true;
+                        from_id = BEGIN_OF_STREAM.to_string();
+                    }
+                }
+            }
+
             log::trace!(
                 "Reading up to {} messages starting from '{}'",
                 batch_max_size,
@@ -300,7 +927,7 @@ mod redis_stream {
 
             let reply = loop {
                 let reply: StreamReadReply = con
-                    .xread_options(&[&stream_name], &[&from_id], &read_options)
+                    .xread_options(&[stream_name], &[&from_id], &read_options)
                     .await?;
 
                 if !reply.keys.is_empty() {
@@ -312,7 +939,7 @@ mod redis_stream {
             let ids = {
                 assert_eq!(reply.keys.len(), 1, "Redis misbehaves: {reply:?}");
                 let key = reply.keys.into_iter().next().unwrap(); // Unwrap is safe due to assert above
-                assert_eq!(key.key, stream_name, "Redis misbehaves: {key:?}");
+                assert_eq!(&key.key, stream_name, "Redis misbehaves: {key:?}");
                 key.ids
             };
 
@@ -358,7 +985,7 @@ mod redis_stream {
             for (id, message) in messages {
                 log::trace!("Got message '{}' of {} bytes", id, message.len());
 
-                let result = process_fn(message).await;
+                let result = process_fn(id.clone(), message).await;
                 match result {
                     Ok(()) => {}
                     Err(HandleError::Terminate) => break,
@@ -368,9 +995,9 @@ mod redis_stream {
                     }
                 }
 
-                con.xack(&stream_name, &group_name, &[&id]).await?;
+                con.xack(stream_name, group_name, &[&id]).await?;
 
-                con.xdel(&stream_name, &[&id]).await?;
+                con.xdel(stream_name, &[&id]).await?;
 
                 if fetching_backlog {
                     from_id = id;
@@ -412,6 +1039,306 @@ mod redis_stream {
             })
             .unwrap_or_else(|| format!("consumer '{consumer_name}' is not known yet"))
     }
+
+    #[test]
+    fn test_connection_errors() {
+        use std::io;
+
+        let dropped = RedisError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_connection_error(&dropped));
+        assert!(matches!(RunError::from(dropped), RunError::Connection(_)));
+
+        let refused = RedisError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(is_connection_error(&refused));
+
+        let bad_reply = RedisError::from((ErrorKind::TypeError, "bad reply"));
+        assert!(!is_connection_error(&bad_reply));
+        assert!(matches!(RunError::from(bad_reply), RunError::Fatal(_)));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut attempts = 0;
+        // Redis is back on the third attempt
+        let conn = rt.block_on(reconnect(
+            || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        Err(anyhow::anyhow!("connection refused"))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            },
+            Duration::from_millis(1),
+        ));
+        assert_eq!(conn, 3);
+        assert_eq!(attempts, 3);
+    }
+
+    /// Redis stream of a consumer group, as seen through `ConnectionLike`
+    #[cfg(test)]
+    mod mock {
+        use redis::{aio::ConnectionLike, Cmd, ErrorKind, Pipeline, RedisFuture, Value};
+        use std::{
+            collections::BTreeMap,
+            io,
+            sync::{Arc, Mutex},
+        };
+
+        use super::NEW_MESSAGES;
+
+        /// Messages of the stream as Redis keeps them for the group
+        #[derive(Default)]
+        pub(super) struct MockStream {
+            /// Not delivered to any consumer yet
+            pub new: BTreeMap<String, Vec<u8>>,
+            /// Delivered but not acknowledged, with the consumer they were delivered to
+            pub pending: BTreeMap<String, (String, Vec<u8>)>,
+        }
+
+        /// Connection to the mock stream, lost once it has served `commands_left` commands
+        pub(super) struct MockConnection {
+            pub stream: Arc<Mutex<MockStream>>,
+            pub commands_left: usize,
+        }
+
+        fn entries(name: &str, entries: Vec<(String, Vec<u8>)>) -> Value {
+            let data = |bytes: &[u8]| Value::Data(bytes.to_vec());
+            let entries = entries
+                .into_iter()
+                .map(|(id, event)| {
+                    let fields = Value::Bulk(vec![data(b"event"), Value::Data(event)]);
+                    Value::Bulk(vec![data(id.as_bytes()), fields])
+                })
+                .collect();
+            Value::Bulk(vec![Value::Bulk(vec![
+                data(name.as_bytes()),
+                Value::Bulk(entries),
+            ])])
+        }
+
+        impl ConnectionLike for MockConnection {
+            fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+                let args = cmd
+                    .args_iter()
+                    .map(|arg| match arg {
+                        redis::Arg::Simple(arg) => String::from_utf8_lossy(arg).into_owned(),
+                        redis::Arg::Cursor => String::new(),
+                    })
+                    .collect::<Vec<_>>();
+                let reply = if self.commands_left == 0 {
+                    Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+                } else {
+                    self.commands_left -= 1;
+                    let mut stream = self.stream.lock().unwrap();
+                    let last = |n: usize| args[args.len() - n].clone();
+                    // Argument `n` positions after the keyword
+                    let after = |keyword: &str, n: usize| {
+                        let pos = args.iter().position(|arg| arg == keyword);
+                        pos.map(|pos| args[pos + n].clone())
+                    };
+                    let consumer = after("GROUP", 2).unwrap_or_default();
+                    match args[0].as_str() {
+                        // New messages become pending for the consumer once read,
+                        // until acknowledged
+                        "XREADGROUP" if last(1) == NEW_MESSAGES => {
+                            if stream.new.is_empty() {
+                                Err((ErrorKind::TypeError, "No more messages").into())
+                            } else {
+                                let count = after("COUNT", 1)
+                                    .map_or(usize::MAX, |count| count.parse().unwrap());
+                                let ids =
+                                    stream.new.keys().take(count).cloned().collect::<Vec<_>>();
+                                let mut new = Vec::with_capacity(ids.len());
+                                for id in ids {
+                                    let event = stream.new.remove(&id).unwrap();
+                                    let delivered = (consumer.clone(), event.clone());
+                                    stream.pending.insert(id.clone(), delivered);
+                                    new.push((id, event));
+                                }
+                                Ok(entries(&last(2), new))
+                            }
+                        }
+                        "XREADGROUP" => {
+                            let from_id = last(1);
+                            let pending = stream
+                                .pending
+                                .iter()
+                                .filter(|(id, (owner, _))| **id > from_id && *owner == consumer)
+                                .map(|(id, (_, event))| (id.clone(), event.clone()))
+                                .collect();
+                            Ok(entries(&last(2), pending))
+                        }
+                        "XACK" => Ok(Value::Int(stream.pending.remove(&last(1)).is_some() as i64)),
+                        "XDEL" => Ok(Value::Int(1)),
+                        other => panic!("unexpected command {}", other),
+                    }
+                };
+                Box::pin(async move { reply })
+            }
+
+            fn req_packed_commands<'a>(
+                &'a mut self,
+                _cmd: &'a Pipeline,
+                _offset: usize,
+                _count: usize,
+            ) -> RedisFuture<'a, Vec<Value>> {
+                unimplemented!("pipelines are not used")
+            }
+
+            fn get_db(&self) -> i64 {
+                0
+            }
+        }
+    }
+
+    #[test]
+    fn test_pending_reread_after_reconnect() {
+        use mock::{MockConnection, MockStream};
+        use std::sync::{Arc, Mutex};
+
+        let stream = Arc::new(Mutex::new(MockStream::default()));
+        for id in ["1-0", "2-0"] {
+            let event = format!("event {}", id).into_bytes();
+            stream.lock().unwrap().new.insert(id.to_string(), event);
+        }
+        let config = RedisStreamConfig {
+            stream_name: "orders".to_string(),
+            group_name: "group".to_string(),
+            consumer_name: "consumer".to_string(),
+            reclaim_idle: None,
+        };
+        // Lost after reading the (empty) pending list and both new messages,
+        // when acknowledging the first one
+        let conn = MockConnection {
+            stream: stream.clone(),
+            commands_left: 2,
+        };
+        let connect = || {
+            let conn = MockConnection {
+                stream: stream.clone(),
+                commands_left: usize::MAX,
+            };
+            async move { Ok(conn) }
+        };
+        let mut processed = Vec::new();
+        let mut process_fn = |id: String, event: Vec<u8>| {
+            processed.push((id, String::from_utf8(event).unwrap()));
+            async { Ok(()) }
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let res = rt.block_on(run_reconnecting(
+            conn,
+            connect,
+            Duration::from_millis(1),
+            &config,
+            10,
+            &mut process_fn,
+        ));
+
+        // Stopped by the mock once the stream is drained
+        assert!(res.unwrap_err().to_string().contains("No more messages"));
+        // Both messages are pending after the reconnect, so the first one is read again
+        let expected = ["1-0", "1-0", "2-0"]
+            .map(|id| (id.to_string(), format!("event {}", id)))
+            .to_vec();
+        assert_eq!(processed, expected);
+        assert!(stream.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_consumers_disjoint() {
+        use mock::{MockConnection, MockStream};
+        use std::sync::{Arc, Mutex};
+
+        let ids = ["1-0", "2-0", "3-0", "4-0", "5-0", "6-0"];
+        let stream = Arc::new(Mutex::new(MockStream::default()));
+        for id in ids {
+            let event = format!("event {}", id).into_bytes();
+            stream.lock().unwrap().new.insert(id.to_string(), event);
+        }
+        let config = |consumer: &str| RedisStreamConfig {
+            stream_name: "orders".to_string(),
+            group_name: "group".to_string(),
+            consumer_name: consumer.to_string(),
+            reclaim_idle: None,
+        };
+        let connect = || {
+            let conn = MockConnection {
+                stream: stream.clone(),
+                commands_left: usize::MAX,
+            };
+            async move { Ok(conn) }
+        };
+        // Yielding after every message, so that the consumers take turns reading the stream
+        let consume = |config: RedisStreamConfig| async move {
+            let mut processed = Vec::new();
+            let mut process_fn = |id: String, _event: Vec<u8>| {
+                processed.push(id);
+                async {
+                    tokio::task::yield_now().await;
+                    Ok(())
+                }
+            };
+            let res = run_reconnecting(
+                connect().await.unwrap(),
+                connect,
+                Duration::from_millis(1),
+                &config,
+                1,
+                &mut process_fn,
+            )
+            .await;
+            (res, processed)
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let ((res_a, processed_a), (res_b, processed_b)) = rt.block_on(async {
+            tokio::join!(consume(config("consumer-a")), consume(config("consumer-b")))
+        });
+
+        // Stopped by the mock once the stream is drained
+        assert!(res_a.unwrap_err().to_string().contains("No more messages"));
+        assert!(res_b.unwrap_err().to_string().contains("No more messages"));
+        // Every message is delivered to exactly one of the consumers
+        assert!(!processed_a.is_empty());
+        assert!(!processed_b.is_empty());
+        assert!(processed_a.iter().all(|id| !processed_b.contains(id)));
+        let mut processed = [processed_a, processed_b].concat();
+        processed.sort();
+        assert_eq!(processed, ids.map(String::from).to_vec());
+        assert!(stream.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_parse_autoclaim_reply() {
+        let id = |s: &str| Value::Data(s.as_bytes().to_vec());
+
+        // Redis 7 reply with deleted ids, more to claim from `1-5`
+        let reply = Value::Bulk(vec![
+            id("1-5"),
+            Value::Bulk(vec![id("1-1"), id("1-3")]),
+            Value::Bulk(vec![id("1-2")]),
+        ]);
+        let expected = (
+            "1-5".to_string(),
+            vec!["1-1".to_string(), "1-3".to_string()],
+        );
+        assert_eq!(parse_autoclaim_reply(reply), Some(expected));
+
+        // Redis 6.2 reply, the whole pending list is scanned
+        let reply = Value::Bulk(vec![id(BEGIN_OF_STREAM), Value::Bulk(vec![])]);
+        let expected = (BEGIN_OF_STREAM.to_string(), vec![]);
+        assert_eq!(parse_autoclaim_reply(reply), Some(expected));
+
+        assert_eq!(parse_autoclaim_reply(Value::Nil), None);
+        assert_eq!(parse_autoclaim_reply(Value::Bulk(vec![id("0-0")])), None);
+    }
 }
 
 mod json {
@@ -420,20 +1347,51 @@ mod json {
 
     use model::time::Timestamp;
 
-    pub(super) fn parse_orders(json: &[u8]) -> serde_json::Result<(Vec<OrderUpdate>, Timestamp)> {
-        let envelope = serde_json::from_slice::<Envelope>(json)?;
-        let timestamp = Timestamp::from_unix_timestamp_millis(envelope.timestamp);
-        if envelope.msg_type == MessageType::OrdersUpdated {
-            Ok((envelope.data, timestamp))
-        } else {
-            log::warn!("Unsupported orders envelope: {:?}", envelope);
-            Ok((Vec::new(), timestamp))
+    use crate::metrics;
+
+    /// Order updates from the message, if its type is one of `message_types`
+    pub(super) fn parse_orders(
+        json: &[u8],
+        message_types: &[MessageType],
+    ) -> serde_json::Result<(Vec<OrderUpdate>, Timestamp)> {
+        // The type is checked first, because messages of other types can have other contents
+        let header = serde_json::from_slice::<Header>(json)?;
+        let timestamp = Timestamp::from_unix_timestamp_millis(header.timestamp);
+        if header.msg_type == MessageType::Unknown {
+            log::debug!(
+                "Skipped message of unknown type: {}",
+                String::from_utf8_lossy(json)
+            );
+            metrics::UNKNOWN_ENVELOPES_SKIPPED.inc();
+            return Ok((Vec::new(), timestamp));
+        }
+        if !message_types.contains(&header.msg_type) {
+            log::debug!(
+                "Skipped message of type {:?} (not enabled)",
+                header.msg_type
+            );
+            return Ok((Vec::new(), timestamp));
         }
+        let envelope = serde_json::from_slice::<Envelope>(json)?;
+        Ok((envelope.data, timestamp))
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
+    struct Header {
+        /// The type of the message, see `MessageType`
+        #[serde(rename = "T")]
+        msg_type: MessageType,
+
+        /// Unix timestamp of this message in milliseconds. It is a Matcher's timestamp.
+        /// This parameter is internal to the Matcher and is not recommended to use.
+        #[serde(rename = "_")]
+        timestamp: i64,
     }
 
+    #[allow(dead_code)] // The header fields are read via `Header`, but keep them for completeness
     #[derive(Deserialize, Debug, Clone)]
     struct Envelope {
-        /// The type of the message: 'osu'.
+        /// The type of the message: 'osu' or 'au'.
         #[serde(rename = "T")]
         msg_type: MessageType,
 
@@ -442,17 +1400,25 @@ mod json {
         #[serde(rename = "_")]
         timestamp: i64,
 
-        /// Updated orders
-        #[serde(rename = "o")] // o = [o]rders
+        /// Updated orders (an address update may have none, if only balances changed)
+        #[serde(rename = "o", default)] // o = [o]rders
         data: Vec<OrderUpdate>,
     }
 
-    #[non_exhaustive]
-    // The Redis feed only supports "osu" variant as ow now, but other feeds (websockets) supports more.
+    /// Types of the matcher feed messages.
+    ///
+    /// The Redis feed only publishes "osu" as of now, but other feeds (websockets) have more types,
+    /// of which "au" also carries order updates. Messages of other types are skipped.
     #[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
-    enum MessageType {
+    pub enum MessageType {
         #[serde(rename = "osu")] // osu = [o]rder[s] [u]pdated
         OrdersUpdated,
+
+        #[serde(rename = "au")] // au = [a]ddress [u]pdated, with balances and orders
+        AddressUpdated,
+
+        #[serde(other)]
+        Unknown,
     }
 
     #[allow(dead_code)] // Some fields are never read, but keep them for completeness
@@ -481,12 +1447,12 @@ mod json {
         /// The order side: BUY | SELL | buy | sell.
         /// Lowercase variants will be in the future versions (2.2.0+). Please support all variants!
         #[serde(rename = "S")]
-        pub(super) side: OrderSide, // buy | sell
+        pub(super) side: OrderSide, // buy | sell, either case
 
         /// The order type: LIMIT | MARKET | limit | market.
         /// Lowercase variants will be in the future versions (2.2.0+). Please support all variants!
         #[serde(rename = "T")]
-        pub(super) order_type: OrderType, // limit | market
+        pub(super) order_type: OrderType, // limit | market, either case
 
         /// The specified order's price
         #[serde(rename = "p")]
@@ -507,7 +1473,7 @@ mod json {
         /// The order status: FILLED | Filled | PARTIALLY_FILLED | PartiallyFilled | CANCELLED | Cancelled.
         /// Uppercase variants will be removed in the future versions (2.2.0+). Please support all variants!
         #[serde(rename = "s")]
-        pub(super) status: OrderStatus, // Filled | PartiallyFilled | Cancelled
+        pub(super) status: OrderStatus, // Filled | PartiallyFilled | Cancelled, either case
 
         /// The current filled amount, including this and all previous matches
         #[serde(rename = "q")]
@@ -546,31 +1512,34 @@ mod json {
 
     #[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     pub(super) enum OrderSide {
-        #[serde(rename = "buy")]
+        #[serde(rename = "buy", alias = "BUY")]
         Buy,
 
-        #[serde(rename = "sell")]
+        #[serde(rename = "sell", alias = "SELL")]
         Sell,
     }
 
     #[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     pub(super) enum OrderType {
-        #[serde(rename = "limit")]
+        #[serde(rename = "limit", alias = "LIMIT")]
         Limit,
 
-        #[serde(rename = "market")]
+        #[serde(rename = "market", alias = "MARKET")]
         Market,
     }
 
+    /// The Redis feed only reports order executions and cancellations.
+    /// Accepted (placed) orders are never published there, so there are no
+    /// order placement notifications - that would need a different feed.
     #[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     pub(super) enum OrderStatus {
-        #[serde(rename = "Filled")]
+        #[serde(rename = "Filled", alias = "FILLED")]
         Filled,
 
-        #[serde(rename = "PartiallyFilled")]
+        #[serde(rename = "PartiallyFilled", alias = "PARTIALLY_FILLED")]
         PartiallyFilled,
 
-        #[serde(rename = "Cancelled")]
+        #[serde(rename = "Cancelled", alias = "CANCELLED")]
         Cancelled,
     }
 
@@ -752,6 +1721,106 @@ mod json {
             check_avg_price(order);
         }
 
+        // Both the current and the future (2.2.0+) cases of the enum fields
+        let field = |side: &str, order_type: &str, status: &str| {
+            let order = json!({
+                "i" : "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq",
+                "o" : "3Q6pToUA28zJbMJUfB5xoGgfqqni11H7NPq",
+                "t" : 1673428865872_i64,
+                "A" : "WAVES",
+                "P" : "GwT5y18jcrrppAuj5VkfnHLG8WRf3TNzmhREQkY4pzd8",
+                "S" : side,
+                "T" : order_type,
+                "p" : "5.0",
+                "a" : "1.0",
+                "f" : "0.003",
+                "F" : "WAVES",
+                "s" : status,
+                "q" : "0.0",
+                "Q" : "0.0",
+                "Z" : 1673428865504_i64
+            });
+            serde_json::from_value::<OrderUpdate>(order).map(|o| (o.side, o.order_type, o.status))
+        };
+        assert_eq!(
+            field("BUY", "LIMIT", "FILLED")?,
+            (OrderSide::Buy, OrderType::Limit, OrderStatus::Filled)
+        );
+        assert_eq!(
+            field("buy", "limit", "Filled")?,
+            (OrderSide::Buy, OrderType::Limit, OrderStatus::Filled)
+        );
+        assert_eq!(
+            field("SELL", "MARKET", "PARTIALLY_FILLED")?,
+            (OrderSide::Sell, OrderType::Market, OrderStatus::PartiallyFilled)
+        );
+        assert_eq!(
+            field("sell", "market", "PartiallyFilled")?,
+            (OrderSide::Sell, OrderType::Market, OrderStatus::PartiallyFilled)
+        );
+        assert_eq!(
+            field("sell", "market", "CANCELLED")?,
+            (OrderSide::Sell, OrderType::Market, OrderStatus::Cancelled)
+        );
+        assert_eq!(
+            field("SELL", "LIMIT", "Cancelled")?,
+            (OrderSide::Sell, OrderType::Limit, OrderStatus::Cancelled)
+        );
+        assert!(field("Buy", "limit", "Filled").is_err());
+        assert!(field("buy", "limit", "Accepted").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_message_types() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        let message = |msg_type: &str, orders: serde_json::Value| {
+            serde_json::to_vec(&json!({"T": msg_type, "_": 1673428865504_i64, "o": orders}))
+        };
+        let orders = json!([{
+            "i": "DbGrYjRnRazkajgYHpekfB72EHBmmQjVPrgpLSJb3MTq",
+            "o": "3Q6pToUA28zJbMJUfB5xoGgfqqni11H7NPq",
+            "t": 1673428865872_i64,
+            "A": "WAVES",
+            "P": "GwT5y18jcrrppAuj5VkfnHLG8WRf3TNzmhREQkY4pzd8",
+            "S": "buy",
+            "T": "limit",
+            "p": "5.0",
+            "a": "1.0",
+            "f": "0.003",
+            "F": "WAVES",
+            "s": "Filled",
+            "q": "1.0",
+            "Q": "0.003",
+            "Z": 1673428865504_i64
+        }]);
+        let all_types = [MessageType::OrdersUpdated, MessageType::AddressUpdated];
+
+        // Address update, when enabled
+        let (parsed, timestamp) = parse_orders(&message("au", orders.clone())?, &all_types)?;
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].status, OrderStatus::Filled);
+        assert_eq!(
+            timestamp,
+            Timestamp::from_unix_timestamp_millis(1673428865504)
+        );
+        let (parsed, _) = parse_orders(&message("au", orders)?, &all_types[..1])?;
+        assert!(parsed.is_empty());
+        let balances_only = br#"{"T":"au","_":1673428865504,"b":{"WAVES":["1.0","0.0"]}}"#;
+        let (parsed, _) = parse_orders(balances_only, &all_types)?;
+        assert!(parsed.is_empty());
+
+        // Unknown type with contents of some other shape is skipped, not an error
+        let skipped_before = metrics::UNKNOWN_ENVELOPES_SKIPPED.get();
+        let (parsed, _) = parse_orders(&message("ob", json!({"asks": []}))?, &all_types)?;
+        assert!(parsed.is_empty());
+        assert!(metrics::UNKNOWN_ENVELOPES_SKIPPED.get() > skipped_before);
+
+        // Broken message is still an error
+        assert!(parse_orders(br#"{"T":"osu","o":[]}"#, &all_types).is_err());
+
         Ok(())
     }
 }
@@ -1,11 +1,13 @@
 //! Push notifications Processor config
 
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use serde::Deserialize;
 
 use model::waves::{Address, AsBase58String};
-use processing::localization::LokaliseConfig;
+use processing::{localization::LokaliseConfig, ProcessingConfig};
+
+use crate::source::{MicroblockTimestamp, OnResultTimeout, ResultTimeout, UnchangedPrice};
 
 use self::error::Error;
 
@@ -14,9 +16,17 @@ pub struct Config {
     pub assets_service_url: String,
     pub blockchain_updates_url: String,
     pub starting_height: Option<u32>,
+    pub seed_max_age: Option<Duration>,
+    pub microblock_timestamp: MicroblockTimestamp,
+    pub event_result_timeout: Option<ResultTimeout>,
+    pub min_confirmations: u32,
+    pub unchanged_price: UnchangedPrice,
+    pub subscribed_pairs_refresh_interval: Option<Duration>,
+    pub match_by_asset_uids: bool,
     pub matcher_address: Address,
     pub data_service_url: String,
     pub lokalise: LokaliseConfig,
+    pub processing: ProcessingConfig,
 }
 
 impl fmt::Debug for Config {
@@ -26,12 +36,23 @@ impl fmt::Debug for Config {
             .field("assets_service_url", &self.assets_service_url)
             .field("blockchain_updates_url", &self.blockchain_updates_url)
             .field("starting_height", &self.starting_height)
+            .field("seed_max_age", &self.seed_max_age)
+            .field("microblock_timestamp", &self.microblock_timestamp)
+            .field("event_result_timeout", &self.event_result_timeout)
+            .field("min_confirmations", &self.min_confirmations)
+            .field("unchanged_price", &self.unchanged_price)
+            .field(
+                "subscribed_pairs_refresh_interval",
+                &self.subscribed_pairs_refresh_interval,
+            )
+            .field("match_by_asset_uids", &self.match_by_asset_uids)
             .field(
                 "matcher_address",
                 &format_args!("{}", self.matcher_address.as_base58_string()),
             )
             .field("data_service_url", &self.data_service_url)
             .field("lokalise", &self.lokalise)
+            .field("processing", &self.processing)
             .finish()
     }
 }
@@ -47,10 +68,25 @@ impl Config {
             } else {
                 None
             },
+            seed_max_age: config
+                .seed_max_age_sec
+                .map(|sec| Duration::from_secs(sec as u64)),
+            microblock_timestamp: config.microblock_timestamp,
+            event_result_timeout: config.event_result_timeout_sec.map(|sec| ResultTimeout {
+                timeout: Duration::from_secs(sec as u64),
+                action: config.event_result_timeout_action,
+            }),
+            min_confirmations: config.min_confirmations,
+            unchanged_price: config.unchanged_price,
+            subscribed_pairs_refresh_interval: config
+                .subscribed_pairs_refresh_interval_sec
+                .map(|sec| Duration::from_secs(sec as u64)),
+            match_by_asset_uids: config.match_by_asset_uids,
             matcher_address: Address::from_string(&config.matcher_address)
                 .map_err(|_| Error::BadConfigValue("matcher_address"))?,
             data_service_url: config.data_service_url,
             lokalise: LokaliseConfig::load()?,
+            processing: ProcessingConfig::load()?,
         };
         Ok(config)
     }
@@ -62,6 +98,19 @@ struct RawConfig {
     data_service_url: String,
     blockchain_updates_url: String,
     starting_height: Option<u32>,
+    seed_max_age_sec: Option<u32>,
+    #[serde(default)]
+    microblock_timestamp: MicroblockTimestamp,
+    event_result_timeout_sec: Option<u32>,
+    #[serde(default)]
+    event_result_timeout_action: OnResultTimeout,
+    #[serde(default)]
+    min_confirmations: u32,
+    #[serde(default)]
+    unchanged_price: UnchangedPrice,
+    subscribed_pairs_refresh_interval_sec: Option<u32>,
+    #[serde(default)]
+    match_by_asset_uids: bool,
     matcher_address: String,
 }
 
@@ -3,6 +3,7 @@
 extern crate wavesexchange_log as log;
 
 mod config;
+mod metrics;
 mod source;
 
 use std::sync::Arc;
@@ -12,11 +13,14 @@ use tokio::{sync::mpsc, task, try_join};
 
 use wavesexchange_warp::MetricsWarpBuilder;
 
-use database::{device, message, subscription};
-use processing::{asset, localization, MessagePump};
+use database::{device, message, state, subscription};
+use processing::{asset, localization, muted_pairs::MutedPairs, readiness::Readiness, MessagePump};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    // Must be done before anything is logged
+    processing::log_levels::apply_overrides(env!("CARGO_CRATE_NAME"))?;
+
     // Configs
     let pg_config = database::config::Config::load()?;
     let config = config::Config::load()?;
@@ -27,27 +31,50 @@ async fn main() -> Result<(), anyhow::Error> {
     );
 
     // Initialization
-    //let (init_finished_tx, init_finished_rx) = oneshot::channel(); //TODO readyz
+    let readiness = Readiness::default();
 
     // Stats & liveness endpoints
+    let readyz_checker = readiness.checker();
     task::spawn(async move {
         MetricsWarpBuilder::new()
             .with_metrics_port_from_env()
-            //.with_readyz_checker(|| async move { init_finished_rx.await }) //TODO readyz
+            .with_metric(&*processing::metrics::EVENTS_RECEIVED)
+            .with_metric(&*processing::metrics::SUBSCRIPTIONS_MATCHED)
+            .with_metric(&*processing::metrics::MESSAGES_ENQUEUED)
+            .with_metric(&*processing::metrics::EVENT_PROCESSING_DURATION)
+            .with_metric(&*processing::metrics::UNLISTED_ASSET_NOTIFICATIONS_SKIPPED)
+            .with_metric(&*processing::metrics::MUTED_PAIR_EVENTS_SKIPPED)
+            .with_metric(&*processing::metrics::UNTRANSLATED_NOTIFICATIONS_SKIPPED)
+            .with_metric(&*processing::metrics::DEVICE_CAP_NOTIFICATIONS_DROPPED)
+            .with_metric(&*processing::metrics::DUPLICATE_MESSAGES_SKIPPED)
+            .with_metric(&*metrics::MALFORMED_BLOCKS_SKIPPED)
+            .with_metric(&*metrics::EVENT_RESULT_TIMEOUTS)
+            .with_readyz_checker(readyz_checker)
             .run_async()
     });
 
     // Database
     log::info!("Connecting to postgres database: {:?}", pg_config);
-    let conn = AsyncPgConnection::establish(&pg_config.database_url()).await?;
+    let mut conn = AsyncPgConnection::establish(&pg_config.database_url()).await?;
 
     // Repo
     log::info!("Initializing repositories");
-    let subscriptions = subscription::Repo {};
+    let mut subscriptions = match config.subscribed_pairs_refresh_interval {
+        Some(interval) => subscription::Repo::with_subscribed_pairs(interval),
+        None => subscription::Repo::default(),
+    };
+    if config.match_by_asset_uids {
+        subscriptions = subscriptions.with_asset_uids();
+    }
+    if config.processing.skip_subscribers_without_devices {
+        subscriptions = subscriptions.with_devices_only();
+    }
     let assets = asset::RemoteGateway::new(config.assets_service_url);
-    let devices = device::Repo {};
+    let devices = device::Repo::default();
     let localizer = task::spawn(localization::Repo::new(config.lokalise));
     let messages = message::Queue {};
+    let state = state::Repo {};
+    let muted_pairs = MutedPairs::start(&config.processing)?;
 
     // Create event sources
     log::info!("Initializing price event source");
@@ -60,6 +87,12 @@ async fn main() -> Result<(), anyhow::Error> {
             // Starting height in config is mostly for debugging purposes.
             // For production is should not be set so that we can use current blockchain height.
             starting_height: config.starting_height,
+            last_processed: state.get(source::prices::CHECKPOINT_KEY, &mut conn).await?,
+            seed_max_age: config.seed_max_age,
+            microblock_timestamp: config.microblock_timestamp,
+            result_timeout: config.event_result_timeout,
+            min_confirmations: config.min_confirmations,
+            unchanged_price: config.unchanged_price,
         };
 
         factory.new_source().await?
@@ -70,22 +103,31 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Start event sources
     log::info!("Starting price event source");
-    let h_prices_source = task::spawn(prices_source.run(events_tx));
+    let source_conn = AsyncPgConnection::establish(&pg_config.database_url()).await?;
+    let h_prices_source = task::spawn(prices_source.run(events_tx, source_conn));
 
     // Await on all remaining initialization tasks running in background
     let localizer = localizer.await??;
 
     // Event processor
     log::info!("Initialization finished, starting service");
-    let processor = MessagePump::new(subscriptions, assets, devices, localizer, messages);
+    let processor = MessagePump::new(
+        subscriptions,
+        assets,
+        devices,
+        localizer,
+        messages,
+        muted_pairs,
+        config.processing,
+    );
     let processor = Arc::new(processor);
     let h_processor = task::spawn(async { processor.run_event_loop(events_rx, conn).await });
 
     // Initialization phase finished
-    //let () = init_finished_tx.send(()).expect("init"); //TODO readyz
+    readiness.set_ready();
 
     // Join all the background tasks
-    let ((), r_prices_source) = try_join!(h_processor, h_prices_source)?;
+    let (_summary, r_prices_source) = try_join!(h_processor, h_prices_source)?;
     let () = r_prices_source?;
 
     log::info!("Service finished.");
@@ -0,0 +1,17 @@
+//! Prices processor metrics
+
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+
+lazy_static! {
+    pub static ref MALFORMED_BLOCKS_SKIPPED: IntCounter = IntCounter::new(
+        "malformed_blocks_skipped",
+        "Blockchain updates skipped because of inconsistent contents"
+    )
+    .unwrap();
+    pub static ref EVENT_RESULT_TIMEOUTS: IntCounter = IntCounter::new(
+        "event_result_timeouts",
+        "Price events not processed within the configured timeout"
+    )
+    .unwrap();
+}
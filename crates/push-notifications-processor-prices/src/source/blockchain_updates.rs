@@ -1,5 +1,6 @@
 //! Interaction with the Blockchain-updates
 
+use serde::Deserialize;
 use tokio::{sync::mpsc, task};
 
 use waves_protobuf_schemas::{
@@ -12,29 +13,42 @@ use waves_protobuf_schemas::{
 
 use model::{asset::Asset, price::RawPrice, time::Timestamp, waves::Address};
 
+use crate::metrics;
+
+/// Timestamp to use for microblocks, which (unlike full blocks) have none
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MicroblockTimestamp {
+    /// Current system time, which differs from the actual one by a negligible margin,
+    /// unless the updates are lagging behind (say, while catching up after a restart)
+    #[default]
+    SystemTime,
+    /// Timestamp of the last full block, which is never later than the actual one
+    LastBlock,
+}
+
 #[derive(Debug)]
 pub(super) enum BlockchainUpdate {
     Append(AppendBlock),
     Rollback(Rollback),
 }
 
-#[allow(dead_code)] // fields `block_id`, `height` and `is_microblock` are never read
+#[allow(dead_code)] // field `is_microblock` is never read
 #[derive(Debug)]
 pub(super) struct AppendBlock {
     pub block_id: String,     // Do we needed it?
     pub height: u32,          // Do we need it?
-    pub timestamp: Timestamp, // Either block timestamp or current system time for microblock
+    pub timestamp: Timestamp, // Block timestamp, or as per `MicroblockTimestamp` for microblock
     pub is_microblock: bool,  // Do we need it?
     pub transactions: Vec<Transaction>,
 }
 
-#[allow(dead_code)] // field `block_id` is never read
 #[derive(Debug)]
 pub(super) struct Rollback {
     pub block_id: String,
 }
 
-#[allow(dead_code)] // fields `id`, `height` and `timestamp` are never read
+#[allow(dead_code)] // fields `height` and `timestamp` are never read
 #[derive(Debug)]
 pub(super) struct Transaction {
     pub id: String,
@@ -67,6 +81,7 @@ impl BlockchainUpdatesClient {
     pub(super) async fn stream(
         self,
         from_height: u32,
+        microblock_timestamp: MicroblockTimestamp,
     ) -> Result<mpsc::Receiver<BlockchainUpdate>, anyhow::Error> {
         let BlockchainUpdatesClient(mut grpc_client) = self;
 
@@ -80,7 +95,8 @@ impl BlockchainUpdatesClient {
         let (tx, rx) = mpsc::channel::<BlockchainUpdate>(1);
 
         task::spawn(async move {
-            let res = pump_messages(stream, tx).await;
+            let clock = convert::BlockClock::new(microblock_timestamp);
+            let res = pump_messages(stream, tx, clock).await;
             if let Err(err) = res {
                 log::error!("Error receiving blockchain updates: {}", err);
             } else {
@@ -91,10 +107,20 @@ impl BlockchainUpdatesClient {
         async fn pump_messages(
             mut stream: tonic::Streaming<SubscribeEvent>,
             tx: mpsc::Sender<BlockchainUpdate>,
+            mut clock: convert::BlockClock,
         ) -> anyhow::Result<()> {
             while let Some(event) = stream.message().await? {
                 if let Some(update) = event.update {
-                    let update = convert::convert_update(update)?;
+                    let height = update.height;
+                    let update = match convert::convert_update(update, &mut clock) {
+                        Ok(update) => update,
+                        Err(err) if err.is_skippable() => {
+                            log::warn!("Blockchain update at height {} skipped: {}", height, err);
+                            metrics::MALFORMED_BLOCKS_SKIPPED.inc();
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
                     tx.send(update).await?;
                 }
             }
@@ -113,6 +139,8 @@ mod convert {
 
     /// This module reexports all necessary structs from the protobuf crate, for convenience.
     mod proto {
+        #[cfg(test)]
+        pub(super) use waves_protobuf_schemas::waves::block::Header;
         pub(super) use waves_protobuf_schemas::waves::{
             events::{
                 blockchain_updated::{
@@ -132,7 +160,7 @@ mod convert {
     /// This module reexports all necessary structs from the application model, for convenience.
     mod model {
         pub(super) use super::super::{
-            AppendBlock, BlockchainUpdate, Rollback, Transaction, TxExchange,
+            AppendBlock, BlockchainUpdate, MicroblockTimestamp, Rollback, Transaction, TxExchange,
         };
         pub(super) use model::{
             asset::Asset,
@@ -142,17 +170,64 @@ mod convert {
     }
 
     #[derive(Error, Debug)]
-    #[error("failed to convert blockchain update: {0}")]
-    pub(super) struct ConvertError(&'static str);
+    pub(super) enum ConvertError {
+        #[error("failed to convert blockchain update: {0}")]
+        Malformed(&'static str),
+
+        #[error("transaction counts disagree: {ids} ids, {transactions} transactions, {metadata} metadata")]
+        TransactionCountMismatch {
+            ids: usize,
+            transactions: usize,
+            metadata: usize,
+        },
+    }
+
+    impl ConvertError {
+        /// Whether the update can be skipped, keeping the stream alive
+        pub(super) fn is_skippable(&self) -> bool {
+            matches!(self, ConvertError::TransactionCountMismatch { .. })
+        }
+    }
+
+    /// Timestamps of appended blocks, which are carried forward to microblocks if configured
+    pub(super) struct BlockClock {
+        microblock_timestamp: model::MicroblockTimestamp,
+        last_block: Option<model::Timestamp>,
+    }
+
+    impl BlockClock {
+        pub(super) fn new(microblock_timestamp: model::MicroblockTimestamp) -> Self {
+            BlockClock {
+                microblock_timestamp,
+                last_block: None,
+            }
+        }
+
+        /// `block_timestamp` is `None` for a microblock
+        fn timestamp(&mut self, block_timestamp: Option<model::Timestamp>) -> model::Timestamp {
+            if let Some(timestamp) = block_timestamp {
+                self.last_block = Some(timestamp);
+                return timestamp;
+            }
+            match (self.microblock_timestamp, self.last_block) {
+                (model::MicroblockTimestamp::LastBlock, Some(timestamp)) => timestamp,
+                // System time is used until a full block is seen
+                _ => current_timestamp(),
+            }
+        }
+    }
 
     pub(super) fn convert_update(
         src: proto::BlockchainUpdated,
+        clock: &mut BlockClock,
     ) -> Result<model::BlockchainUpdate, ConvertError> {
         let height = src.height as u32;
         let update = src.update;
         match update {
             Some(proto::Update::Append(append)) => {
-                let body = append.body.ok_or(ConvertError("append body is None"))?;
+                let body = append
+                    .body
+                    .ok_or(ConvertError::Malformed("append body is None"))?;
                 let proto::Append {
                     transaction_ids,
                     transactions_metadata,
@@ -160,24 +235,28 @@ mod convert {
                 } = append;
 
                 let is_microblock = extract_is_microblock(&body)
-                    .ok_or(ConvertError("failed to extract is_microblock"))?;
+                    .ok_or(ConvertError::Malformed("failed to extract is_microblock"))?;
 
                 let id = extract_id(&body, &src.id)
-                    .ok_or(ConvertError("failed to extract block id"))?;
+                    .ok_or(ConvertError::Malformed("failed to extract block id"))?;
                 let id = base58(id);
 
                 // Only full blocks have timestamp, microblocks doesn't.
-                // But it is okay to use current system time in case of a microblock,
-                // because it will differ from a real microblock timestamp by a negligible margin.
-                // Though, it is a hack.
-                let timestamp = extract_timestamp(&body).unwrap_or_else(current_timestamp);
-
-                let transactions =
-                    extract_transactions(body).ok_or(ConvertError("transactions is None"))?;
-                assert!(
-                    transaction_ids.len() == transactions.len()
-                        && transactions.len() == transactions_metadata.len()
-                );
+                // For a microblock either the current system time is used (which is a hack)
+                // or the timestamp of the last full block, see `MicroblockTimestamp`.
+                let timestamp = clock.timestamp(extract_timestamp(&body));
+
+                let transactions = extract_transactions(body)
+                    .ok_or(ConvertError::Malformed("transactions is None"))?;
+                if transaction_ids.len() != transactions.len()
+                    || transactions.len() != transactions_metadata.len()
+                {
+                    return Err(ConvertError::TransactionCountMismatch {
+                        ids: transaction_ids.len(),
+                        transactions: transactions.len(),
+                        metadata: transactions_metadata.len(),
+                    });
+                }
                 let transactions = convert_transactions(
                     transaction_ids,
                     transactions,
@@ -201,7 +280,7 @@ mod convert {
                 };
                 Ok(model::BlockchainUpdate::Rollback(rollback))
             }
-            _ => Err(ConvertError("failed to parse blockchain update")),
+            _ => Err(ConvertError::Malformed("failed to parse blockchain update")),
         }
     }
 
@@ -295,12 +374,12 @@ mod convert {
     ) -> Result<Option<model::Transaction>, ConvertError> {
         let maybe_tx = {
             if is_exchange_transaction(&meta) {
-                let tx = extract_transaction(&tx).ok_or(ConvertError("missing tx"))?;
+                let tx = extract_transaction(&tx).ok_or(ConvertError::Malformed("missing tx"))?;
                 let (data, _meta) = extract_exchange_tx(tx, &meta)?;
                 let asset_pair = data.orders[0]
                     .asset_pair
                     .as_ref()
-                    .ok_or(ConvertError("missing asset_pair"))?;
+                    .ok_or(ConvertError::Malformed("missing asset_pair"))?;
                 let tx = model::Transaction {
                     id: base58(&id),
                     height,
@@ -349,7 +428,7 @@ mod convert {
                 ..
             } => data,
             _ => {
-                return Err(ConvertError(
+                return Err(ConvertError::Malformed(
                     "unexpected transaction contents - want Exchange",
                 ))
             }
@@ -357,7 +436,11 @@ mod convert {
 
         let meta = match &meta.metadata {
             Some(proto::Metadata::Exchange(meta)) => meta,
-            _ => return Err(ConvertError("unexpected metadata contents - want Exchange")),
+            _ => {
+                return Err(ConvertError::Malformed(
+                    "unexpected metadata contents - want Exchange",
+                ))
+            }
         };
 
         Ok((data, meta))
@@ -379,4 +462,105 @@ mod convert {
     fn base58(bytes: &[u8]) -> String {
         bs58::encode(bytes).into_string()
     }
+    #[test]
+    fn test_transaction_count_mismatch() {
+        let block = proto::Block {
+            transactions: vec![proto::SignedTransaction::default(); 2],
+            ..Default::default()
+        };
+        let append = proto::Append {
+            body: Some(proto::Body::Block(proto::BlockAppend {
+                block: Some(block),
+                ..Default::default()
+            })),
+            transaction_ids: vec![vec![1; 32]],
+            transactions_metadata: vec![proto::TransactionMetadata::default(); 2],
+            ..Default::default()
+        };
+        let update = proto::BlockchainUpdated {
+            id: vec![2; 64],
+            height: 100,
+            update: Some(proto::Update::Append(append)),
+            ..Default::default()
+        };
+
+        // An error to skip the block, not a panic
+        let mut clock = BlockClock::new(model::MicroblockTimestamp::SystemTime);
+        let err = convert_update(update, &mut clock).unwrap_err();
+        assert!(err.is_skippable());
+        assert!(matches!(
+            err,
+            ConvertError::TransactionCountMismatch {
+                ids: 1,
+                transactions: 2,
+                metadata: 2,
+            }
+        ));
+        assert!(!ConvertError::Malformed("append body is None").is_skippable());
+    }
+    #[cfg(test)]
+    fn append_update(body: proto::Body) -> proto::BlockchainUpdated {
+        proto::BlockchainUpdated {
+            id: vec![1; 64],
+            height: 100,
+            update: Some(proto::Update::Append(proto::Append {
+                body: Some(body),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    fn block_timestamp(
+        update: proto::BlockchainUpdated,
+        clock: &mut BlockClock,
+    ) -> model::Timestamp {
+        match convert_update(update, clock).expect("converted") {
+            model::BlockchainUpdate::Append(append) => append.timestamp,
+            update => panic!("unexpected update: {:?}", update),
+        }
+    }
+
+    #[test]
+    fn test_microblock_timestamp() {
+        let block = || {
+            append_update(proto::Body::Block(proto::BlockAppend {
+                block: Some(proto::Block {
+                    header: Some(proto::Header {
+                        timestamp: 1_700_000_000_000,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+        };
+        let microblock = || {
+            append_update(proto::Body::MicroBlock(proto::MicroBlockAppend {
+                micro_block: Some(proto::SignedMicroBlock {
+                    micro_block: Some(proto::MicroBlock::default()),
+                    total_block_id: vec![2; 64],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+        };
+        let block_ts = model::Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+
+        // System time
+        let mut clock = BlockClock::new(model::MicroblockTimestamp::SystemTime);
+        let before = current_timestamp();
+        assert!(block_timestamp(microblock(), &mut clock) >= before);
+        assert_eq!(block_timestamp(block(), &mut clock), block_ts);
+        assert!(block_timestamp(microblock(), &mut clock) >= before);
+
+        // Last full block timestamp, carried forward
+        let mut clock = BlockClock::new(model::MicroblockTimestamp::LastBlock);
+        let before = current_timestamp();
+        assert!(block_timestamp(microblock(), &mut clock) >= before);
+        assert_eq!(block_timestamp(block(), &mut clock), block_ts);
+        assert_eq!(block_timestamp(microblock(), &mut clock), block_ts);
+        assert_eq!(block_timestamp(microblock(), &mut clock), block_ts);
+    }
 }
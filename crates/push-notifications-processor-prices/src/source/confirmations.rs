@@ -0,0 +1,122 @@
+//! Blocks are held back until they are deep enough in the blockchain to be unlikely rolled back,
+//! so that no price alerts are sent about trades which end up not happening.
+
+use std::collections::VecDeque;
+
+use super::blockchain_updates::AppendBlock;
+
+pub(super) struct ConfirmationBuffer {
+    /// Number of blocks on top of a block for it to be released, 0 to release right away
+    min_confirmations: u32,
+    pending: VecDeque<AppendBlock>,
+}
+
+impl ConfirmationBuffer {
+    pub(super) fn new(min_confirmations: u32) -> Self {
+        ConfirmationBuffer {
+            min_confirmations,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Buffers the block (or microblock, which is a part of the block at its height),
+    /// returns the blocks confirmed by now, in order
+    pub(super) fn push(&mut self, block: AppendBlock) -> Vec<AppendBlock> {
+        let height = block.height;
+        self.pending.push_back(block);
+        let mut confirmed = Vec::new();
+        while let Some(oldest) = self.pending.front() {
+            if oldest.height + self.min_confirmations > height {
+                break;
+            }
+            confirmed.extend(self.pending.pop_front());
+        }
+        confirmed
+    }
+
+    /// Drops the buffered blocks after the one rolled back to.
+    /// If it is not buffered (already released, or even older), all the buffered ones are dropped.
+    /// Returns whether the block was buffered, so that no released block is rolled back.
+    pub(super) fn rollback(&mut self, block_id: &str) -> bool {
+        match self
+            .pending
+            .iter()
+            .rposition(|block| block.block_id == block_id)
+        {
+            Some(index) => {
+                self.pending.truncate(index + 1);
+                true
+            }
+            None => {
+                if !self.pending.is_empty() {
+                    log::debug!(
+                        "Rollback to {} dropped {} unconfirmed blocks",
+                        block_id,
+                        self.pending.len()
+                    );
+                }
+                self.pending.clear();
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppendBlock, ConfirmationBuffer};
+    use model::time::Timestamp;
+
+    fn block(block_id: &str, height: u32) -> AppendBlock {
+        AppendBlock {
+            block_id: block_id.to_string(),
+            height,
+            timestamp: Timestamp::from_unix_timestamp_millis(0),
+            is_microblock: false,
+            transactions: vec![],
+        }
+    }
+
+    fn ids(blocks: Vec<AppendBlock>) -> Vec<String> {
+        blocks.into_iter().map(|block| block.block_id).collect()
+    }
+
+    #[test]
+    fn test_no_confirmations() {
+        let mut buffer = ConfirmationBuffer::new(0);
+        assert_eq!(ids(buffer.push(block("a", 1))), vec!["a"]);
+        assert_eq!(ids(buffer.push(block("a1", 1))), vec!["a1"]);
+    }
+
+    #[test]
+    fn test_held_until_confirmed() {
+        let mut buffer = ConfirmationBuffer::new(2);
+        assert!(buffer.push(block("a", 10)).is_empty());
+        // Microblock at the same height
+        assert!(buffer.push(block("a1", 10)).is_empty());
+        assert!(buffer.push(block("b", 11)).is_empty());
+        // Block and its microblock are released together, once 2 blocks deep
+        assert_eq!(ids(buffer.push(block("c", 12))), vec!["a", "a1"]);
+        assert_eq!(ids(buffer.push(block("d", 13))), vec!["b"]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut buffer = ConfirmationBuffer::new(2);
+        buffer.push(block("a", 10));
+        buffer.push(block("b", 11));
+        buffer.push(block("b1", 11));
+
+        // Forked block is never released
+        assert!(buffer.rollback("b"));
+        assert!(buffer.push(block("b2", 11)).is_empty());
+        assert_eq!(ids(buffer.push(block("c", 12))), vec!["a"]);
+        assert_eq!(ids(buffer.push(block("d", 13))), vec!["b", "b2"]);
+
+        // Rollback beyond the buffer
+        assert!(!buffer.rollback("a"));
+        assert!(buffer.push(block("c", 12)).is_empty());
+        assert!(buffer.push(block("d", 13)).is_empty());
+        assert_eq!(ids(buffer.push(block("e", 14))), vec!["c"]);
+    }
+}
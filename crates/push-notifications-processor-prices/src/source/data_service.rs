@@ -1,9 +1,12 @@
 //! Interaction with the Data Service
 
+use std::time::Duration;
+
 use anyhow::ensure;
 use model::{
     asset::{Asset, AssetPair},
     price::Price,
+    time::Timestamp,
     waves::{Address, AsBase58String},
 };
 use wavesexchange_apis::{
@@ -15,9 +18,15 @@ use wavesexchange_apis::{
     HttpClient,
 };
 
+/// Period of the pair stats in Data Service
+pub(super) const STATS_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub(super) struct Pair {
     pub pair: AssetPair,
     pub last_price: Price,
+    /// Number of trades within `STATS_WINDOW`.
+    /// If there were none, `last_price` can be arbitrarily old.
+    pub txs_count: i64,
 }
 
 pub(super) async fn load_pairs(data_service_url: &str) -> anyhow::Result<Vec<Pair>> {
@@ -44,10 +53,37 @@ async fn convert_pair(pair: &dto::Pair) -> anyhow::Result<Pair> {
             price_asset,
         },
         last_price,
+        txs_count: pair.data.txs_count,
     };
     Ok(pair)
 }
 
+/// Time of the last trade of the pair, `None` if it was never traded
+pub(super) async fn load_last_trade_timestamp(
+    data_service_url: &str,
+    pair: &AssetPair,
+) -> anyhow::Result<Option<Timestamp>> {
+    let client = HttpClient::<DataService>::from_base_url(data_service_url);
+    let res = client
+        .transactions_exchange(
+            None::<&str>,
+            None::<&str>,
+            Some(pair.amount_asset.id()),
+            Some(pair.price_asset.id()),
+            None,
+            None,
+            Sort::Desc,
+            1,
+            None::<&str>,
+        )
+        .await?;
+    let timestamp = res
+        .items
+        .first()
+        .map(|item| Timestamp::from_unix_timestamp_millis(item.data.timestamp.timestamp_millis()));
+    Ok(timestamp)
+}
+
 pub(super) async fn load_current_blockchain_height(
     data_service_url: &str,
     matcher_address: &Address,
@@ -0,0 +1,70 @@
+//! Undo data of the recently processed blocks, so that the prices state can be restored
+//! to what it was before the blocks which are rolled back.
+
+use std::collections::VecDeque;
+
+pub(super) struct BlockHistory<T> {
+    /// Blocks (and microblocks) to remember, rollbacks deeper than that can't be undone
+    max_blocks: usize,
+    blocks: VecDeque<(String, T)>,
+}
+
+impl<T> BlockHistory<T> {
+    pub(super) fn new(max_blocks: usize) -> Self {
+        BlockHistory {
+            max_blocks,
+            blocks: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the undo data of a processed block, forgetting the oldest one beyond the limit
+    pub(super) fn push(&mut self, block_id: String, undo: T) {
+        if self.blocks.len() == self.max_blocks {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((block_id, undo));
+    }
+
+    /// Takes the undo data of the blocks after the one rolled back to, the latest block first.
+    /// Returns `None` if the block is not remembered (too old or unknown), and forgets them all.
+    pub(super) fn rollback(&mut self, block_id: &str) -> Option<Vec<T>> {
+        match self.blocks.iter().rposition(|(id, _)| id == block_id) {
+            Some(index) => {
+                let undone = self.blocks.drain(index + 1..).rev().map(|(_, undo)| undo);
+                Some(undone.collect())
+            }
+            None => {
+                self.blocks.clear();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockHistory;
+
+    #[test]
+    fn test_rollback() {
+        let mut history = BlockHistory::new(3);
+        history.push("a".to_string(), 1);
+        history.push("b".to_string(), 2);
+        history.push("b1".to_string(), 3);
+
+        // Undone latest first, the block rolled back to is kept
+        assert_eq!(history.rollback("a"), Some(vec![3, 2]));
+        assert_eq!(history.rollback("a"), Some(vec![]));
+
+        // The oldest block is forgotten beyond the limit
+        history.push("b".to_string(), 4);
+        history.push("c".to_string(), 5);
+        history.push("d".to_string(), 6);
+        assert_eq!(history.rollback("a"), None);
+
+        // Nothing is left to undo after an unknown rollback
+        history.push("e".to_string(), 7);
+        assert_eq!(history.rollback("e"), Some(vec![]));
+        assert_eq!(history.rollback("d"), None);
+    }
+}
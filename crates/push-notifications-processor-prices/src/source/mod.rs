@@ -4,4 +4,9 @@
 pub mod prices;
 
 mod blockchain_updates;
+mod confirmations;
 mod data_service;
+mod history;
+
+pub use blockchain_updates::MicroblockTimestamp;
+pub use prices::{OnResultTimeout, ResultTimeout, UnchangedPrice};
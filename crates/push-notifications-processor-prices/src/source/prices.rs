@@ -1,12 +1,22 @@
 //! Source of Price events
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
+use diesel_async::AsyncPgConnection;
+use futures::{
+    future,
+    stream::{self, StreamExt},
+};
+use serde::Deserialize;
 use tokio::{
     sync::{mpsc, oneshot},
     try_join,
 };
 
+use database::state;
 use model::{
     asset::AssetPair,
     event::Event,
@@ -17,10 +27,15 @@ use model::{
 
 use self::aggregator::PriceAggregator;
 use super::{
-    blockchain_updates::{AppendBlock, BlockchainUpdate, BlockchainUpdatesClient},
+    blockchain_updates::{
+        AppendBlock, BlockchainUpdate, BlockchainUpdatesClient, MicroblockTimestamp,
+    },
+    confirmations::ConfirmationBuffer,
     data_service,
+    history::BlockHistory,
 };
-use processing::{asset, EventWithFeedback};
+use crate::metrics;
+use processing::{asset, Checkpoint, EventWithFeedback};
 
 /// A factory that creates and initializes instances of `Source`
 pub struct SourceFactory<'a> {
@@ -29,13 +44,81 @@ pub struct SourceFactory<'a> {
     pub matcher_address: &'a Address,
     pub blockchain_updates_url: &'a str,
     pub starting_height: Option<u32>,
+    /// Height of the last processed block, as saved with `CHECKPOINT_KEY`
+    pub last_processed: Option<String>,
+    /// Pairs last traded earlier than this are seeded cold, see `PriceAggregator::new_cold`
+    pub seed_max_age: Option<Duration>,
+    pub microblock_timestamp: MicroblockTimestamp,
+    pub result_timeout: Option<ResultTimeout>,
+    /// Number of blocks on top of a block for its trades to be reported, see `ConfirmationBuffer`
+    pub min_confirmations: u32,
+    pub unchanged_price: UnchangedPrice,
 }
 
+/// Key of the height of the last processed block in the service state
+pub const CHECKPOINT_KEY: &str = "prices_block_height";
+
+/// Blocks (and microblocks) processed recently enough for a rollback to restore the prices
+const ROLLBACK_HISTORY_BLOCKS: usize = 1000;
+
+/// Concurrent data-service requests for the last trades of the pairs on startup
+const LAST_TRADE_LOOKUPS: usize = 10;
+
+/// Aggregators of the pairs traded in a block as they were before it,
+/// `None` for pairs first traded in it
+type BlockUndo = HashMap<AssetPair, Option<PriceAggregator>>;
+
 /// Source of Price Events (based on blockchain-updates)
 pub struct Source {
     stream: mpsc::Receiver<BlockchainUpdate>,
     matcher_address: Address,
+    assets: asset::RemoteGateway,
     aggregators: HashMap<AssetPair, PriceAggregator>,
+    /// Decimals of the raw prices of each pair, see `price_decimals`
+    price_decimals: HashMap<AssetPair, u8>,
+    result_timeout: Option<ResultTimeout>,
+    confirmations: ConfirmationBuffer,
+    history: BlockHistory<BlockUndo>,
+    unchanged_price: UnchangedPrice,
+}
+
+/// How long to wait for the result of an event processing before acting on it,
+/// so that a stalled processor shows up in logs and metrics instead of blocking silently
+#[derive(Clone, Copy, Debug)]
+pub struct ResultTimeout {
+    pub timeout: Duration,
+    pub action: OnResultTimeout,
+}
+
+/// What to do when the result of an event processing is not received within the timeout
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnResultTimeout {
+    /// Log a warning and keep waiting, for another timeout
+    #[default]
+    Wait,
+    /// Log a warning and go on with the next event, the result of this one is ignored
+    Skip,
+    /// Stop the source with an error, so that the service is restarted
+    Fail,
+}
+
+/// How to report a block which closes at the previous block close price.
+///
+/// The previous close is always excluded from the reported range (see `PriceAggregator`),
+/// so that a threshold the price rested at is not reported over and over again.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnchangedPrice {
+    /// A block trading at the previous close only reports nothing,
+    /// a round trip (moving away and back within the block) reports the prices it touched
+    #[default]
+    Skip,
+    /// A block trading at the previous close only reports that price, so a threshold
+    /// equal to it is reported in every such block
+    Report,
+    /// A block closing at the previous close reports nothing, even if it made a round trip
+    SkipRoundTrips,
 }
 
 impl SourceFactory<'_> {
@@ -44,14 +127,27 @@ impl SourceFactory<'_> {
         let starting_height = self.load_starting_height();
         let client = self.create_grpc_client();
         let (client, starting_height) = try_join!(client, starting_height)?;
-        let updates_stream = client.stream(starting_height);
+        let updates_stream = client.stream(starting_height, self.microblock_timestamp);
         let (initial_prices, updates_stream) = try_join!(initial_prices, updates_stream)?;
         self.preload_assets_from_pairs(initial_prices.keys())
             .await?;
+        let mut decimals = HashMap::with_capacity(initial_prices.len());
+        for pair in initial_prices.keys() {
+            decimals.insert(
+                pair.to_owned(),
+                load_price_decimals(self.assets, pair).await?,
+            );
+        }
         let res = Source {
             stream: updates_stream,
             matcher_address: self.matcher_address.to_owned(),
+            assets: self.assets.to_owned(),
             aggregators: initial_prices,
+            price_decimals: decimals,
+            result_timeout: self.result_timeout,
+            confirmations: ConfirmationBuffer::new(self.min_confirmations),
+            history: BlockHistory::new(ROLLBACK_HISTORY_BLOCKS),
+            unchanged_price: self.unchanged_price,
         };
         Ok(res)
     }
@@ -59,15 +155,61 @@ impl SourceFactory<'_> {
     async fn load_initial_prices(&self) -> anyhow::Result<HashMap<AssetPair, PriceAggregator>> {
         log::info!("Loading pairs from data-service");
         let pairs = data_service::load_pairs(self.data_service_url).await?;
+        let mut last_trades = match self.seed_max_age {
+            Some(max_age) => self.load_last_trades(&pairs, max_age).await,
+            None => HashMap::new(),
+        };
         let mut res = HashMap::with_capacity(pairs.len());
+        let mut cold_count = 0;
+        let now = Timestamp::now();
         for pair in pairs {
-            let aggregator = PriceAggregator::new(pair.last_price);
+            let stale = match (self.seed_max_age, last_trades.remove(&pair.pair)) {
+                (Some(max_age), Some(Ok(last_trade))) => is_stale_seed(last_trade, now, max_age),
+                (_, Some(Err(err))) => {
+                    log::warn!(
+                        "Failed to load the last trade of {}, seeding it cold: {}",
+                        pair.pair,
+                        err
+                    );
+                    true
+                }
+                _ => false,
+            };
+            let aggregator = if stale {
+                cold_count += 1;
+                PriceAggregator::new_cold(pair.last_price)
+            } else {
+                PriceAggregator::new(pair.last_price)
+            };
             res.insert(pair.pair, aggregator);
         }
-        log::info!("Loaded {} pairs", res.len());
+        log::info!(
+            "Loaded {} pairs ({} with a stale last price)",
+            res.len(),
+            cold_count
+        );
         Ok(res)
     }
 
+    /// Last trade times of the pairs which are not known to be traded recently,
+    /// looked up concurrently (at most `LAST_TRADE_LOOKUPS` at a time)
+    async fn load_last_trades(
+        &self,
+        pairs: &[data_service::Pair],
+        max_age: Duration,
+    ) -> HashMap<AssetPair, anyhow::Result<Option<Timestamp>>> {
+        let url = self.data_service_url;
+        stream::iter(pairs)
+            .filter(|pair| future::ready(!recently_traded(pair.txs_count, max_age)))
+            .map(|pair| async move {
+                let last_trade = data_service::load_last_trade_timestamp(url, &pair.pair).await;
+                (pair.pair.clone(), last_trade)
+            })
+            .buffer_unordered(LAST_TRADE_LOOKUPS)
+            .collect()
+            .await
+    }
+
     async fn preload_assets_from_pairs<'a>(
         &self,
         pairs: impl Iterator<Item = &'a AssetPair>,
@@ -89,10 +231,22 @@ impl SourceFactory<'_> {
         Ok(())
     }
 
+    /// The configured starting height (for debugging) comes first. Otherwise, the block last
+    /// processed before a restart is processed again (it may be a block which microblocks were
+    /// being appended to), the events already processed are skipped, see `price_event_id`.
+    /// The height is looked up in the data-service on the first start only.
     async fn load_starting_height(&self) -> anyhow::Result<u32> {
-        let height = match self.starting_height {
+        let last_processed = match &self.last_processed {
+            Some(height) => Some(
+                height
+                    .parse::<u32>()
+                    .map_err(|_| anyhow::anyhow!("Bad saved block height: {}", height))?,
+            ),
+            None => None,
+        };
+        let height = match self.starting_height.or(last_processed) {
             Some(height) => {
-                log::info!("Starting height is configured to be {}", height);
+                log::info!("Starting height is {}", height);
                 height
             }
             None => {
@@ -118,21 +272,48 @@ impl SourceFactory<'_> {
 }
 
 impl Source {
-    pub async fn run(mut self, sink: mpsc::Sender<EventWithFeedback>) -> anyhow::Result<()> {
-        while let Some(upd) = self.stream.recv().await {
-            match upd {
-                BlockchainUpdate::Append(block) => {
-                    let result = self.process_block(block, &sink).await;
-                    match result {
-                        Ok(()) => {}
-                        Err(Error::StopProcessing) => break,
-                        Err(Error::EventProcessingFailed(err)) => {
-                            log::error!("Event processing failed: {}", err);
-                            return Err(err.into());
-                        }
+    /// The connection is for saving the height of the blocks without price events,
+    /// the height of the other blocks is saved along with their events
+    pub async fn run(
+        mut self,
+        sink: mpsc::Sender<EventWithFeedback>,
+        mut conn: AsyncPgConnection,
+    ) -> anyhow::Result<()> {
+        'updates: while let Some(upd) = self.stream.recv().await {
+            let blocks = match upd {
+                BlockchainUpdate::Append(block) => self.confirmations.push(block),
+                BlockchainUpdate::Rollback(rollback) => {
+                    if !self.confirmations.rollback(&rollback.block_id) {
+                        self.rollback(&rollback.block_id);
+                    }
+                    continue;
+                }
+            };
+            for block in blocks {
+                let result = self.process_block(block, &sink, &mut conn).await;
+                match result {
+                    Ok(()) => {}
+                    Err(Error::StopProcessing) => break 'updates,
+                    Err(Error::AssetsUnavailable(err)) => {
+                        log::error!("Failed to load asset decimals: {}", err);
+                        return Err(err.into());
+                    }
+                    Err(Error::EventProcessingFailed(err)) => {
+                        log::error!("Event processing failed: {}", err);
+                        return Err(err.into());
+                    }
+                    Err(Error::CheckpointFailed(err)) => {
+                        log::error!("Failed to save the block height: {}", err);
+                        return Err(err.into());
+                    }
+                    Err(Error::ResultTimeout(timeout)) => {
+                        log::error!("No event processing result within {:?}", timeout);
+                        return Err(anyhow::anyhow!(
+                            "No event processing result within {:?}",
+                            timeout
+                        ));
                     }
                 }
-                BlockchainUpdate::Rollback(_) => {}
             }
         }
         log::debug!("Blockchain updates loop finished");
@@ -143,11 +324,95 @@ impl Source {
         &mut self,
         block: AppendBlock,
         sink: &mpsc::Sender<EventWithFeedback>,
+        conn: &mut AsyncPgConnection,
     ) -> Result<(), Error> {
         //log::trace!("Processing block {} at height {}", block.block_id, block.height);
+        let received_at = Timestamp::now();
         let timestamp = block.timestamp;
+        let height = block.height;
+        self.load_new_pairs_decimals(&block).await?;
+        let last_trades = self.last_trades(&block);
         let block_prices = self.aggregate_prices_from_block(block);
-        Self::send_price_events(block_prices, timestamp, sink).await
+        if block_prices.is_empty() {
+            // The events of the previous blocks are processed by now, see `await_result`
+            return state::Repo {}
+                .set(CHECKPOINT_KEY, &height.to_string(), conn)
+                .await
+                .map_err(Error::CheckpointFailed);
+        }
+        let result_timeout = self.result_timeout;
+        Self::send_price_events(
+            height,
+            block_prices,
+            last_trades,
+            timestamp,
+            received_at,
+            result_timeout,
+            sink,
+        )
+        .await
+    }
+
+    /// Id of the last matcher's trade of each pair in the block
+    fn last_trades(&self, block: &AppendBlock) -> HashMap<AssetPair, String> {
+        block
+            .transactions
+            .iter()
+            .filter(|tx| tx.sender == self.matcher_address)
+            .map(|tx| {
+                let asset_pair = AssetPair {
+                    amount_asset: tx.exchange_tx.amount_asset.clone(),
+                    price_asset: tx.exchange_tx.price_asset.clone(),
+                };
+                (asset_pair, tx.id.clone())
+            })
+            .collect()
+    }
+
+    /// Pairs traded for the first time since the start are not in the initial load
+    async fn load_new_pairs_decimals(&mut self, block: &AppendBlock) -> Result<(), Error> {
+        for tx in &block.transactions {
+            if tx.sender != self.matcher_address {
+                continue;
+            }
+            let asset_pair = AssetPair {
+                amount_asset: tx.exchange_tx.amount_asset.clone(),
+                price_asset: tx.exchange_tx.price_asset.clone(),
+            };
+            if self.price_decimals.contains_key(&asset_pair) {
+                continue;
+            }
+            let decimals = load_price_decimals(&self.assets, &asset_pair)
+                .await
+                .map_err(Error::AssetsUnavailable)?;
+            log::debug!("New pair {}, price decimals {}", asset_pair, decimals);
+            self.price_decimals.insert(asset_pair, decimals);
+        }
+        Ok(())
+    }
+
+    /// Restores the aggregators to their state after the block rolled back to,
+    /// so that prices of the blocks undone are not compared with
+    fn rollback(&mut self, block_id: &str) {
+        let undone = match self.history.rollback(block_id) {
+            Some(undone) => undone,
+            None => {
+                log::warn!(
+                    "Rollback to {} is too deep, prices are not restored",
+                    block_id
+                );
+                return;
+            }
+        };
+        log::debug!("Rollback to {} undid {} blocks", block_id, undone.len());
+        for undo in undone {
+            for (pair, aggregator) in undo {
+                match aggregator {
+                    Some(aggregator) => self.aggregators.insert(pair, aggregator),
+                    None => self.aggregators.remove(&pair),
+                };
+            }
+        }
     }
 
     fn aggregate_prices_from_block(&mut self, block: AppendBlock) -> Vec<(AssetPair, PriceRange)> {
@@ -155,6 +420,8 @@ impl Source {
             .values_mut()
             .for_each(PriceAggregator::reset);
 
+        let mut undo = BlockUndo::new();
+
         for tx in block.transactions {
             if tx.sender == self.matcher_address {
                 let asset_pair = AssetPair {
@@ -163,9 +430,13 @@ impl Source {
                 };
                 let new_price = PriceWithDecimals {
                     price: tx.exchange_tx.price,
-                    decimals: 8, // This is a fixed value, same for all assets
+                    decimals: self.price_decimals[&asset_pair],
                 };
                 let new_price = new_price.value();
+                if !undo.contains_key(&asset_pair) {
+                    let aggregator = self.aggregators.get(&asset_pair).cloned();
+                    undo.insert(asset_pair.clone(), aggregator);
+                }
                 let aggregator = self
                     .aggregators
                     .entry(asset_pair)
@@ -174,9 +445,11 @@ impl Source {
             }
         }
 
+        let unchanged_price = self.unchanged_price;
         self.aggregators
             .values_mut()
-            .for_each(PriceAggregator::finalize);
+            .for_each(|aggregator| aggregator.finalize(unchanged_price));
+        self.history.push(block.block_id, undo);
 
         self.aggregators
             .iter()
@@ -186,13 +459,21 @@ impl Source {
             .collect()
     }
 
+    /// The height is saved along with the last event of the block, once the block is processed
     async fn send_price_events(
+        height: u32,
         block_prices: Vec<(AssetPair, PriceRange)>,
+        mut last_trades: HashMap<AssetPair, String>,
         timestamp: Timestamp,
+        received_at: Timestamp,
+        result_timeout: Option<ResultTimeout>,
         sink: &mpsc::Sender<EventWithFeedback>,
     ) -> Result<(), Error> {
-        for (asset_pair, price_range) in block_prices {
+        let last_index = block_prices.len().saturating_sub(1);
+        for (index, (asset_pair, price_range)) in block_prices.into_iter().enumerate() {
             debug_assert_eq!(price_range.is_empty(), false);
+            let last_trade = last_trades.remove(&asset_pair).unwrap_or_default();
+            let event_id = price_event_id(&last_trade, &asset_pair);
             let event = Event::PriceChanged {
                 asset_pair,
                 price_range,
@@ -201,29 +482,371 @@ impl Source {
             let (tx, rx) = oneshot::channel();
             let evf = EventWithFeedback {
                 event,
+                received_at,
+                checkpoint: (index == last_index).then(|| Checkpoint {
+                    key: CHECKPOINT_KEY,
+                    value: height.to_string(),
+                }),
+                event_id: Some(event_id),
                 result_tx: tx,
             };
             sink.send(evf).await.map_err(|_| Error::StopProcessing)?;
-            let result = rx.await.map_err(|_| Error::StopProcessing)?;
-            result.map_err(|err| Error::EventProcessingFailed(err))?;
+            await_result(rx, result_timeout).await?;
         }
         Ok(())
     }
 }
 
+/// A block (or a microblock) makes at most one price event per pair, identified by the last
+/// trade of the pair in it. Unlike the block id, it stays the same when the block processed
+/// as microblocks before a restart is read as a whole after it: the event of a pair is skipped
+/// then, unless the pair was traded again in the microblocks not processed before the restart.
+/// In that case the event covers the trades of the whole block, so the thresholds crossed
+/// in the microblocks already processed are reported once more.
+fn price_event_id(last_trade: &str, asset_pair: &AssetPair) -> String {
+    format!("price:{}:{}", asset_pair, last_trade)
+}
+
+/// Pairs traded within the Data Service stats window can't be older than that,
+/// so only the other ones need their last trade looked up
+fn recently_traded(txs_count: i64, max_age: Duration) -> bool {
+    txs_count > 0 && max_age >= data_service::STATS_WINDOW
+}
+
+/// Whether the last known price of a pair is too old to report the movements from it.
+/// A pair never traded has no price to compare with at all.
+fn is_stale_seed(last_trade: Option<Timestamp>, now: Timestamp, max_age: Duration) -> bool {
+    match last_trade {
+        Some(last_trade) => {
+            let age = now.unix_timestamp_millis() - last_trade.unix_timestamp_millis();
+            age > max_age.as_millis() as i64
+        }
+        None => true,
+    }
+}
+
+async fn await_result(
+    mut rx: oneshot::Receiver<Result<(), processing::Error>>,
+    result_timeout: Option<ResultTimeout>,
+) -> Result<(), Error> {
+    let ResultTimeout { timeout, action } = match result_timeout {
+        Some(result_timeout) => result_timeout,
+        None => {
+            let result = rx.await.map_err(|_| Error::StopProcessing)?;
+            return result.map_err(Error::EventProcessingFailed);
+        }
+    };
+    loop {
+        match tokio::time::timeout(timeout, &mut rx).await {
+            Ok(result) => {
+                let result = result.map_err(|_| Error::StopProcessing)?;
+                return result.map_err(Error::EventProcessingFailed);
+            }
+            Err(_elapsed) => {
+                metrics::EVENT_RESULT_TIMEOUTS.inc();
+                match action {
+                    OnResultTimeout::Wait => {
+                        log::warn!("No event processing result within {:?}, waiting", timeout);
+                    }
+                    OnResultTimeout::Skip => {
+                        log::warn!("No event processing result within {:?}, skipped", timeout);
+                        return Ok(());
+                    }
+                    OnResultTimeout::Fail => return Err(Error::ResultTimeout(timeout)),
+                }
+            }
+        }
+    }
+}
+
+/// Raw prices of a pair are scaled by `10^(8 + price_asset_decimals - amount_asset_decimals)`
+async fn load_price_decimals(
+    assets: &asset::RemoteGateway,
+    pair: &AssetPair,
+) -> Result<u8, asset::GatewayError> {
+    let amount_asset_decimals = assets.decimals(&pair.amount_asset).await?;
+    let price_asset_decimals = assets.decimals(&pair.price_asset).await?;
+    Ok(price_decimals(amount_asset_decimals, price_asset_decimals))
+}
+
+fn price_decimals(amount_asset_decimals: u8, price_asset_decimals: u8) -> u8 {
+    8 + price_asset_decimals - amount_asset_decimals
+}
+
+#[derive(Debug)]
 enum Error {
     StopProcessing,
     EventProcessingFailed(processing::Error),
+    ResultTimeout(Duration),
+    CheckpointFailed(database::error::Error),
+    AssetsUnavailable(asset::GatewayError),
+}
+
+#[cfg(test)]
+mod test_blocks {
+    use super::{
+        asset, AppendBlock, ConfirmationBuffer, HashMap, PriceAggregator, Source, UnchangedPrice,
+    };
+    use crate::source::{
+        blockchain_updates::{Transaction, TxExchange},
+        history::BlockHistory,
+    };
+    use model::{
+        asset::{Asset, AssetPair},
+        price::RawPrice,
+        time::Timestamp,
+        waves::Address,
+    };
+    use tokio::sync::mpsc;
+
+    pub(super) const MATCHER: &str = "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2";
+
+    /// WAVES (8 decimals) / USDN (6 decimals)
+    pub(super) fn waves_usdn() -> AssetPair {
+        AssetPair {
+            amount_asset: Asset::Waves,
+            price_asset: Asset::from_id("DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p").unwrap(),
+        }
+    }
+
+    /// Source of the pair trading at the last known price, with the given price decimals
+    pub(super) fn source(last_price: f64, price_decimals: u8) -> Source {
+        let (_, stream) = mpsc::channel(1);
+        Source {
+            stream,
+            matcher_address: Address::from_string(MATCHER).unwrap(),
+            assets: asset::RemoteGateway::new("http://localhost"),
+            aggregators: HashMap::from([(waves_usdn(), PriceAggregator::new(last_price))]),
+            price_decimals: HashMap::from([(waves_usdn(), price_decimals)]),
+            result_timeout: None,
+            confirmations: ConfirmationBuffer::new(0),
+            history: BlockHistory::new(10),
+            unchanged_price: UnchangedPrice::Skip,
+        }
+    }
+
+    /// Block with the matcher's trades of the pair at the raw prices
+    pub(super) fn block(block_id: &str, height: u32, prices: &[RawPrice]) -> AppendBlock {
+        let pair = waves_usdn();
+        let transactions = prices
+            .iter()
+            .map(|&price| Transaction {
+                // The same in a microblock and in the whole block
+                id: format!("{}-{}", height, price),
+                height,
+                timestamp: 0,
+                sender: Address::from_string(MATCHER).unwrap(),
+                exchange_tx: TxExchange {
+                    amount_asset: pair.amount_asset.clone(),
+                    price_asset: pair.price_asset.clone(),
+                    amount: 100_000_000,
+                    price,
+                },
+            })
+            .collect();
+        AppendBlock {
+            block_id: block_id.to_string(),
+            height,
+            timestamp: Timestamp::now(),
+            is_microblock: false,
+            transactions,
+        }
+    }
+}
+
+#[test]
+fn test_price_decimals() {
+    use self::test_blocks::{block, source, waves_usdn};
+
+    assert_eq!(price_decimals(8, 6), 6);
+    assert_eq!(price_decimals(8, 8), 8);
+    assert_eq!(price_decimals(0, 8), 16);
+
+    // 2.5 USDN per WAVES, not 0.025 as with 8 decimals
+    let mut source = source(2.0, price_decimals(8, 6));
+    let prices = source.aggregate_prices_from_block(block("a", 1, &[2_500_000]));
+    assert_eq!(prices.len(), 1);
+    let (pair, range) = &prices[0];
+    assert_eq!(pair, &waves_usdn());
+    assert_eq!(range.low_high(), (2.0, 2.5));
+}
+
+#[test]
+fn test_stale_seed() {
+    let hour = Duration::from_secs(3600);
+    let now = Timestamp::from_unix_timestamp_millis(1_700_000_000_000);
+    let ago = |d: Duration| {
+        Timestamp::from_unix_timestamp_millis(1_700_000_000_000 - d.as_millis() as i64)
+    };
+
+    assert!(!is_stale_seed(Some(ago(hour)), now, 2 * hour));
+    assert!(!is_stale_seed(Some(ago(2 * hour)), now, 2 * hour));
+    assert!(is_stale_seed(Some(ago(3 * hour)), now, 2 * hour));
+    assert!(is_stale_seed(Some(ago(30 * 24 * hour)), now, 7 * 24 * hour));
+    assert!(is_stale_seed(None, now, 7 * 24 * hour));
+
+    // The last trade is looked up unless the 24h stats tell it is recent enough
+    assert!(recently_traded(10, 24 * hour));
+    assert!(recently_traded(10, 7 * 24 * hour));
+    assert!(!recently_traded(10, 2 * hour));
+    assert!(!recently_traded(0, 7 * 24 * hour));
+}
+
+#[test]
+#[ignore = "needs Postgres"]
+fn test_load_starting_height() {
+    use database::testing::TestDb;
+
+    let db = TestDb::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut conn = db.connect_async().await;
+        let state = state::Repo {};
+        let assets = asset::RemoteGateway::new("http://localhost");
+        let matcher_address = Address::from_string(test_blocks::MATCHER).unwrap();
+        let factory = |starting_height, last_processed| SourceFactory {
+            // Not to be called, the height is known
+            data_service_url: "http://localhost",
+            assets: &assets,
+            matcher_address: &matcher_address,
+            blockchain_updates_url: "http://localhost",
+            starting_height,
+            last_processed,
+            seed_max_age: None,
+            microblock_timestamp: MicroblockTimestamp::SystemTime,
+            result_timeout: None,
+            min_confirmations: 0,
+            unchanged_price: UnchangedPrice::Skip,
+        };
+
+        state
+            .set(CHECKPOINT_KEY, "3500000", &mut conn)
+            .await
+            .unwrap();
+        let stored = state.get(CHECKPOINT_KEY, &mut conn).await.unwrap();
+        let height = factory(None, stored.clone()).load_starting_height().await;
+        assert_eq!(height.unwrap(), 3_500_000);
+        // Unless overridden in the config
+        let height = factory(Some(3_400_000), stored)
+            .load_starting_height()
+            .await;
+        assert_eq!(height.unwrap(), 3_400_000);
+
+        state.set(CHECKPOINT_KEY, "x", &mut conn).await.unwrap();
+        let stored = state.get(CHECKPOINT_KEY, &mut conn).await.unwrap();
+        let height = factory(None, stored).load_starting_height().await;
+        assert!(height.is_err());
+    });
+}
+
+#[test]
+fn test_price_event_id() {
+    use self::test_blocks::{block, source, waves_usdn};
+
+    let id = price_event_id("tx1", &waves_usdn());
+    assert_eq!(
+        id,
+        "price:WAVES/DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p:tx1"
+    );
+    assert_ne!(price_event_id("tx2", &waves_usdn()), id);
+    let reversed = AssetPair {
+        amount_asset: waves_usdn().price_asset,
+        price_asset: waves_usdn().amount_asset,
+    };
+    assert_ne!(price_event_id("tx1", &reversed), id);
+
+    // The microblocks processed before a restart and the whole block read after it
+    // end with the same trade of the pair
+    let source = source(2.0, 6);
+    let microblock = block("b1", 2, &[3_000_000]);
+    let whole_block = block("b", 2, &[2_500_000, 3_000_000]);
+    let last_trade = |block| source.last_trades(block).remove(&waves_usdn()).unwrap();
+    assert_eq!(last_trade(&whole_block), "2-3000000");
+    assert_eq!(last_trade(&microblock), last_trade(&whole_block));
+}
+
+#[test]
+fn test_rollback() {
+    use self::test_blocks::{block, source, waves_usdn};
+
+    let mut source = source(2.0, 6);
+    source.aggregate_prices_from_block(block("a", 1, &[2_000_000]));
+    source.aggregate_prices_from_block(block("b", 2, &[3_000_000]));
+    source.aggregate_prices_from_block(block("b1", 2, &[4_000_000]));
+
+    // Back to the price as of block `a`, so the forked trades are not compared with
+    source.rollback("a");
+    let prices = source.aggregate_prices_from_block(block("b2", 2, &[2_500_000]));
+    let (_, range) = &prices[0];
+    assert_eq!(range.low_high(), (2.0, 2.5));
+    assert!(!range.contains(3.0));
+
+    // A pair first traded in a rolled back block is forgotten
+    source.aggregators.remove(&waves_usdn());
+    source.aggregate_prices_from_block(block("c", 3, &[5_000_000]));
+    source.rollback("b2");
+    assert!(source.aggregators.get(&waves_usdn()).is_none());
+
+    // Unknown block - nothing to restore
+    source.aggregate_prices_from_block(block("c", 3, &[5_000_000]));
+    source.rollback("x");
+    let prices = source.aggregate_prices_from_block(block("d", 4, &[6_000_000]));
+    let (_, range) = &prices[0];
+    assert_eq!(range.low_high(), (5.0, 6.0));
+}
+
+#[test]
+fn test_await_result() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result_timeout = |action| {
+        Some(ResultTimeout {
+            timeout: Duration::from_millis(10),
+            action,
+        })
+    };
+
+    // The result never arrives
+    let (tx, rx) = oneshot::channel();
+    let result = rt.block_on(await_result(rx, result_timeout(OnResultTimeout::Skip)));
+    assert!(result.is_ok());
+    drop(tx);
+
+    let (tx, rx) = oneshot::channel();
+    let result = rt.block_on(await_result(rx, result_timeout(OnResultTimeout::Fail)));
+    assert!(matches!(result, Err(Error::ResultTimeout(_))));
+    drop(tx);
+
+    // The result arrives late
+    let (tx, rx) = oneshot::channel();
+    let result = rt.block_on(async {
+        tokio::spawn(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send(Ok(())).unwrap();
+        });
+        await_result(rx, result_timeout(OnResultTimeout::Wait)).await
+    });
+    assert!(result.is_ok());
+
+    // The processor is gone
+    let (tx, rx) = oneshot::channel::<Result<(), processing::Error>>();
+    drop(tx);
+    let result = rt.block_on(await_result(rx, result_timeout(OnResultTimeout::Wait)));
+    assert!(matches!(result, Err(Error::StopProcessing)));
 }
 
 mod aggregator {
+    use super::UnchangedPrice;
     use model::price::{Price, PriceRange};
     use std::mem::take;
 
+    #[derive(Clone)]
     pub(super) struct PriceAggregator {
         prev_block_price: Price,
         latest_price: Price,
         current_range: PriceRange,
+        /// The last known price is too old to be compared with,
+        /// so price movements from it are not reported until a fresh price is seen
+        cold: bool,
     }
 
     impl PriceAggregator {
@@ -232,6 +855,14 @@ mod aggregator {
                 prev_block_price: last_known_price,
                 latest_price: last_known_price,
                 current_range: PriceRange::empty(),
+                cold: false,
+            }
+        }
+
+        pub(super) fn new_cold(stale_price: Price) -> Self {
+            PriceAggregator {
+                cold: true,
+                ..Self::new(stale_price)
             }
         }
 
@@ -245,11 +876,29 @@ mod aggregator {
             self.latest_price = new_price;
         }
 
-        pub(super) fn finalize(&mut self) {
-            let current_range = &mut self.current_range;
-            *current_range = take(current_range)
-                .extend(self.prev_block_price)
-                .exclude_bound(self.prev_block_price);
+        pub(super) fn finalize(&mut self, unchanged_price: UnchangedPrice) {
+            if self.cold {
+                // Only the prices within this block are reported, if any
+                self.cold = self.current_range.is_empty();
+                self.prev_block_price = self.latest_price;
+                return;
+            }
+            let prev = self.prev_block_price;
+            let traded = !self.current_range.is_empty();
+            let range = take(&mut self.current_range);
+            self.current_range = match unchanged_price {
+                UnchangedPrice::Report if traded && range.low_high() == (prev, prev) => {
+                    range.moved_from(prev).moved_to(self.latest_price)
+                }
+                UnchangedPrice::SkipRoundTrips if traded && self.latest_price == prev => {
+                    PriceRange::empty()
+                }
+                _ => range
+                    .extend(prev)
+                    .exclude_bound(prev)
+                    .moved_from(prev)
+                    .moved_to(self.latest_price),
+            };
             self.prev_block_price = self.latest_price;
         }
 
@@ -268,7 +917,7 @@ mod aggregator {
         agg.update(4.0);
         agg.update(4.5);
         agg.update(5.0);
-        agg.finalize();
+        agg.finalize(UnchangedPrice::Skip);
         let range = agg.range();
         assert_eq!(range.contains(threshold), true);
 
@@ -276,8 +925,119 @@ mod aggregator {
         agg.reset();
         agg.update(5.5);
         agg.update(6.0);
-        agg.finalize();
+        agg.finalize(UnchangedPrice::Skip);
         let range = agg.range();
         assert_eq!(range.contains(threshold), false);
     }
+
+    #[test]
+    fn test_threshold_direction() {
+        use model::topic::ThresholdDirection::{Above, Any, Below};
+
+        let mut agg = PriceAggregator::new(5.0);
+        let mut block = |prices: &[f64]| {
+            agg.reset();
+            prices.iter().for_each(|&price| agg.update(price));
+            agg.finalize(UnchangedPrice::Skip);
+            agg.range().clone()
+        };
+
+        // Downward crossing of 4 triggers only `Below` and `Any` subscriptions
+        let range = block(&[4.5, 3.0]);
+        assert!(range.crosses(4.0, Below));
+        assert!(range.crosses(4.0, Any));
+        assert!(!range.crosses(4.0, Above));
+
+        // And an upward one, from the previous block close, only `Above` and `Any`
+        let range = block(&[4.0, 6.0]);
+        assert!(range.crosses(4.0, Above));
+        assert!(range.crosses(5.0, Above));
+        assert!(range.crosses(5.0, Any));
+        assert!(!range.crosses(5.0, Below));
+
+        // From 5 down through 4 and back up within a block
+        block(&[5.0]);
+        let range = block(&[3.0, 6.0]);
+        assert!(range.crosses(4.0, Below));
+        assert!(range.crosses(4.0, Above));
+    }
+
+    #[test]
+    fn test_unchanged_price() {
+        let block = |agg: &mut PriceAggregator, prices: &[f64], unchanged_price| {
+            agg.reset();
+            prices.iter().for_each(|&price| agg.update(price));
+            agg.finalize(unchanged_price);
+            agg.range().clone()
+        };
+
+        // The only trade of the block is at exactly the previous close
+        let mut agg = PriceAggregator::new(5.0);
+        assert!(block(&mut agg, &[5.0], UnchangedPrice::Skip).is_empty());
+        assert!(block(&mut agg, &[5.0, 5.0], UnchangedPrice::Skip).is_empty());
+        assert!(block(&mut agg, &[5.0], UnchangedPrice::SkipRoundTrips).is_empty());
+        let range = block(&mut agg, &[5.0], UnchangedPrice::Report);
+        assert!(range.contains(5.0));
+        assert_eq!(range.low_high(), (5.0, 5.0));
+        // No trades at all - nothing to report in any mode
+        assert!(block(&mut agg, &[], UnchangedPrice::Report).is_empty());
+
+        // Round trip: away from the previous close and back
+        let mut agg = PriceAggregator::new(5.0);
+        let range = block(&mut agg, &[6.0, 5.0], UnchangedPrice::Skip);
+        assert!(!range.contains(5.0));
+        assert!(range.contains(6.0));
+        let range = block(&mut agg, &[6.0, 5.0], UnchangedPrice::Report);
+        assert!(!range.contains(5.0));
+        assert!(range.contains(6.0));
+        assert!(block(&mut agg, &[6.0, 5.0], UnchangedPrice::SkipRoundTrips).is_empty());
+
+        // A changed close is reported the same way in any mode
+        for mode in [
+            UnchangedPrice::Skip,
+            UnchangedPrice::Report,
+            UnchangedPrice::SkipRoundTrips,
+        ] {
+            let mut agg = PriceAggregator::new(5.0);
+            let range = block(&mut agg, &[5.0, 6.0], mode);
+            assert!(!range.contains(5.0));
+            assert!(range.contains(5.5));
+            assert!(range.contains(6.0));
+            // The next block starts from the new close
+            assert_eq!(
+                block(&mut agg, &[6.0], mode).contains(6.0),
+                mode == UnchangedPrice::Report
+            );
+        }
+    }
+
+    #[test]
+    fn test_cold_aggregator() {
+        // Stale price 1.0, while the market is at about 5.0 now
+        let mut agg = PriceAggregator::new_cold(1.0);
+
+        // Block without trades: nothing reported
+        agg.reset();
+        agg.finalize(UnchangedPrice::Skip);
+        assert!(agg.range().is_empty());
+
+        // Block 1: only the movement within the block is reported, not the jump from 1.0
+        agg.reset();
+        agg.update(5.0);
+        agg.update(5.5);
+        agg.finalize(UnchangedPrice::Skip);
+        let range = agg.range();
+        assert!(!range.contains(3.0));
+        assert!(range.contains(5.0));
+        assert!(range.contains(5.5));
+
+        // Block 2: warmed up, works as usual
+        agg.reset();
+        agg.update(4.0);
+        agg.finalize(UnchangedPrice::Skip);
+        let range = agg.range();
+        assert!(range.contains(5.0));
+        assert!(!range.contains(5.5));
+        assert!(range.contains(4.0));
+    }
 }
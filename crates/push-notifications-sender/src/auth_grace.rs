@@ -0,0 +1,59 @@
+//! Grace retries on authentication errors.
+//!
+//! An auth error means that our credentials are (maybe temporarily) rejected, so every message
+//! would fail the same way. Instead of spending send attempts of all queued messages
+//! (and giving up on them), the message is retried a few times without counting the attempt.
+
+pub struct AuthGrace {
+    max_retries: u32,
+    retries: u32,
+}
+
+impl AuthGrace {
+    pub fn new(max_retries: u32) -> Self {
+        AuthGrace {
+            max_retries,
+            retries: 0,
+        }
+    }
+
+    /// Called on an auth error, returns whether a grace retry is allowed.
+    /// If not, the error should be handled as a regular send failure.
+    pub fn retry(&mut self) -> bool {
+        if self.retries < self.max_retries {
+            self.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called when a message is sent successfully
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+#[test]
+fn test_auth_grace() {
+    let mut grace = AuthGrace::new(2);
+
+    // Auth error is retried rather than failing the message
+    assert!(grace.retry());
+    assert!(grace.retry());
+    assert_eq!(grace.retries(), 2);
+    // Until grace retries are exhausted
+    assert!(!grace.retry());
+    assert!(!grace.retry());
+
+    // Credentials are fine again
+    grace.reset();
+    assert!(grace.retry());
+
+    let mut grace = AuthGrace::new(0);
+    assert!(!grace.retry());
+}
@@ -1,9 +1,43 @@
 use chrono::Duration;
+use rand::Rng;
+use serde::Deserialize;
 
 pub fn exponential(initial_interval: &Duration, multiplier: f32, attempts_count: u8) -> Duration {
     *initial_interval * multiplier.powf(attempts_count as f32) as i32
 }
 
+/// Randomization of the backoff interval, so that messages failed at the same time
+/// (say, during an FCM outage) are not retried all at once
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Jitter {
+    /// The interval as is
+    None,
+    /// Anywhere from zero to the interval
+    Full,
+    /// Anywhere from half the interval to the interval
+    Equal,
+}
+
+/// Exponential interval capped at `max_interval`, then randomized as per `jitter`
+pub fn exponential_jittered(
+    initial_interval: &Duration,
+    multiplier: f32,
+    attempts_count: u8,
+    max_interval: &Duration,
+    jitter: Jitter,
+    rng: &mut impl Rng,
+) -> Duration {
+    let interval = exponential(initial_interval, multiplier, attempts_count).min(*max_interval);
+    let millis = interval.num_milliseconds();
+    let half = millis / 2;
+    match jitter {
+        Jitter::None => interval,
+        Jitter::Full => Duration::milliseconds(rng.gen_range(0..=millis)),
+        Jitter::Equal => Duration::milliseconds(half + rng.gen_range(0..=millis - half)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(test)]
@@ -61,4 +95,64 @@ mod tests {
             );
         }
     }
+
+    mod jitter {
+        use crate::backoff::{exponential_jittered, Jitter};
+        use chrono::Duration;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        fn intervals(attempts_count: u8, jitter: Jitter) -> Vec<Duration> {
+            let mut rng = StdRng::seed_from_u64(42);
+            let (initial, max) = (Duration::seconds(10), Duration::seconds(100));
+            let mut interval =
+                || exponential_jittered(&initial, 2.0, attempts_count, &max, jitter, &mut rng);
+            (0..1000).map(|_| interval()).collect()
+        }
+
+        #[test]
+        pub fn none() {
+            assert!(intervals(2, Jitter::None)
+                .iter()
+                .all(|i| *i == Duration::seconds(40)));
+            // Capped
+            assert!(intervals(5, Jitter::None)
+                .iter()
+                .all(|i| *i == Duration::seconds(100)));
+        }
+
+        #[test]
+        pub fn full() {
+            let uncapped = intervals(2, Jitter::Full);
+            assert!(uncapped.iter().all(|i| *i >= Duration::zero()));
+            assert!(uncapped.iter().all(|i| *i <= Duration::seconds(40)));
+            // Spread over the whole window
+            assert!(uncapped.iter().any(|i| *i < Duration::seconds(4)));
+            assert!(uncapped.iter().any(|i| *i > Duration::seconds(36)));
+
+            // Randomized within the cap
+            let capped = intervals(5, Jitter::Full);
+            assert!(capped.iter().all(|i| *i <= Duration::seconds(100)));
+            assert!(capped.iter().any(|i| *i > Duration::seconds(90)));
+        }
+
+        #[test]
+        pub fn equal() {
+            // At least half the interval
+            let uncapped = intervals(2, Jitter::Equal);
+            assert!(uncapped.iter().all(|i| *i >= Duration::seconds(20)));
+            assert!(uncapped.iter().all(|i| *i <= Duration::seconds(40)));
+            assert!(uncapped.iter().any(|i| *i < Duration::seconds(22)));
+            assert!(uncapped.iter().any(|i| *i > Duration::seconds(38)));
+
+            let capped = intervals(5, Jitter::Equal);
+            assert!(capped.iter().all(|i| *i >= Duration::seconds(50)));
+            assert!(capped.iter().all(|i| *i <= Duration::seconds(100)));
+        }
+
+        #[test]
+        pub fn seeded() {
+            // Same seed, same intervals
+            assert_eq!(intervals(3, Jitter::Full), intervals(3, Jitter::Full));
+        }
+    }
 }
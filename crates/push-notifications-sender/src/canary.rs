@@ -0,0 +1,120 @@
+//! Startup canary: a single notification sent to a configured device when the service starts,
+//! so that broken FCM credentials or payload changes are noticed right after deploy.
+//!
+//! The service is not ready until the canary is sent. Without a canary it is ready right away.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use chrono::Utc;
+use thiserror::Error;
+
+use crate::{gateway::Gateway, MessageToSend};
+
+#[derive(Debug, Error)]
+#[error("Startup canary notification was not sent")]
+pub struct NotReady;
+
+#[derive(Clone, Default)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn check(&self) -> Result<(), NotReady> {
+        if self.ready.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(NotReady)
+        }
+    }
+}
+
+/// Sends the canary via the same path as regular messages (so `dry_run` applies),
+/// and marks the service ready if it succeeds
+pub async fn send(gateway: &dyn Gateway, fcm_uid: &str, readiness: &Readiness) {
+    match gateway.send(&message(fcm_uid)).await {
+        Ok(_) => {
+            log::info!("Canary notification sent");
+            readiness.set_ready();
+        }
+        Err(err) => {
+            log::error!("Failed to send canary notification, not ready | {:?}", err);
+        }
+    }
+}
+
+fn message(fcm_uid: &str) -> MessageToSend {
+    let now = Utc::now();
+    MessageToSend {
+        uid: 0,
+        created_at: now,
+        updated_at: now,
+        send_error: None,
+        send_attempts_count: 0,
+        notification_title: "Canary".to_string(),
+        notification_body: "Push notifications sender started".to_string(),
+        data: Some(serde_json::json!({"type": "canary"})),
+        collapse_key: None,
+        group_key: None,
+        event_received_at: None,
+        event_timestamp: None,
+        delivery_style: None,
+        time_to_live: None,
+        priority: None,
+        utc_offset_seconds: 0,
+        lang: "en".to_string(),
+        platform: "web".to_string(),
+        fcm_uid: fcm_uid.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{send, Readiness};
+    use crate::{
+        error::SendError,
+        gateway::{Gateway, Sent},
+        MessageToSend,
+    };
+
+    struct MockGateway {
+        result: fn() -> Result<Sent, SendError>,
+    }
+
+    #[async_trait]
+    impl Gateway for MockGateway {
+        async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError> {
+            assert_eq!(message.fcm_uid, "canary_fcm_uid");
+            (self.result)()
+        }
+    }
+
+    fn run_canary(result: fn() -> Result<Sent, SendError>) -> Readiness {
+        let readiness = Readiness::default();
+        let gateway = MockGateway { result };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(send(&gateway, "canary_fcm_uid", &readiness));
+        readiness
+    }
+
+    #[test]
+    fn test_canary_sent() {
+        assert!(run_canary(|| Ok(Sent::default())).check().is_ok());
+    }
+
+    #[test]
+    fn test_failed_canary_not_ready() {
+        let readiness = run_canary(|| Err(fcm::FcmError::Unauthorized.into()));
+        assert!(readiness.check().is_err());
+
+        let readiness = run_canary(|| Err(fcm::FcmError::ServerError(None).into()));
+        assert!(readiness.check().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Circuit breaker around FCM.
+//!
+//! During a provider outage every due message would fail the same way, so after a number
+//! of consecutive provider failures (server or auth errors) the circuit opens and dequeuing
+//! is paused for a cooldown. Then the circuit is half-open: a single message is sent
+//! (rather than a whole batch) to test the recovery, closing the circuit if it succeeds,
+//! or opening it for another cooldown if not.
+//!
+//! Errors about the message itself (like an invalid token) mean that FCM is fine,
+//! so they count as a success here.
+
+use std::time::{Duration, Instant};
+
+use crate::{error::SendError, metrics};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    Closed,
+    Open { since: Instant },
+    HalfOpen,
+}
+
+impl State {
+    /// Value of the `fcm_circuit_breaker_state` metric
+    fn metric_value(&self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::HalfOpen => 1,
+            State::Open { .. } => 2,
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            consecutive_failures: 0,
+            state: State::Closed,
+        }
+    }
+
+    /// Whether a message can be dequeued and sent now.
+    /// An open circuit turns half-open once the cooldown is over.
+    pub fn allows(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Closed | State::HalfOpen => true,
+            State::Open { since } if now.duration_since(since) >= self.cooldown => {
+                log::info!("FCM circuit breaker is half-open, testing with a single message");
+                self.set_state(State::HalfOpen);
+                true
+            }
+            State::Open { .. } => false,
+        }
+    }
+
+    /// Messages to dequeue at once: a single one to test the recovery while half-open
+    pub fn batch_size(&self, batch_size: u32) -> u32 {
+        match self.state {
+            State::HalfOpen => 1,
+            State::Closed | State::Open { .. } => batch_size,
+        }
+    }
+
+    pub fn record<T>(&mut self, result: &Result<T, SendError>, now: Instant) {
+        match result {
+            Err(err) if err.is_server_error() || err.is_auth() => self.record_failure(now),
+            _ => self.record_success(),
+        }
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        if self.state != State::Closed {
+            log::info!("FCM circuit breaker is closed, sending resumed");
+            self.set_state(State::Closed);
+        }
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
+        let trips = match self.state {
+            State::HalfOpen => true,
+            State::Closed => self.consecutive_failures >= self.failure_threshold,
+            State::Open { .. } => false,
+        };
+        if trips {
+            log::warn!(
+                "FCM circuit breaker is open after {} consecutive failures, paused for {:?}",
+                self.consecutive_failures,
+                self.cooldown
+            );
+            self.set_state(State::Open { since: now });
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        metrics::CIRCUIT_BREAKER_STATE.set(state.metric_value());
+    }
+
+    #[cfg(test)]
+    fn state(&self) -> State {
+        self.state
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let failure = || -> Result<(), SendError> { Err(fcm::FcmError::ServerError(None).into()) };
+    let cooldown = Duration::from_secs(60);
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+    let mut breaker = CircuitBreaker::new(3, cooldown);
+
+    // Closed: failures below the threshold, and a success resets the count
+    breaker.record(&failure(), at(0));
+    breaker.record(&failure(), at(0));
+    breaker.record(&Ok(()), at(0));
+    breaker.record(&failure(), at(0));
+    breaker.record(&failure(), at(1));
+    assert_eq!(breaker.state(), State::Closed);
+    assert!(breaker.allows(at(1)));
+
+    // Opened by consecutive failures, nothing is sent during the cooldown
+    breaker.record(&failure(), at(2));
+    assert_eq!(breaker.state(), State::Open { since: at(2) });
+    assert!(!breaker.allows(at(2)));
+    assert!(!breaker.allows(at(61)));
+
+    // Half-open after the cooldown, a failed test send opens it again
+    assert!(breaker.allows(at(62)));
+    assert_eq!(breaker.state(), State::HalfOpen);
+    assert_eq!(breaker.batch_size(10), 1);
+    breaker.record(&failure(), at(63));
+    assert_eq!(breaker.state(), State::Open { since: at(63) });
+    assert!(!breaker.allows(at(100)));
+
+    // A successful test send closes it
+    assert!(breaker.allows(at(123)));
+    assert_eq!(breaker.state(), State::HalfOpen);
+    breaker.record(&Ok(()), at(124));
+    assert_eq!(breaker.state(), State::Closed);
+    assert!(breaker.allows(at(124)));
+    assert_eq!(breaker.batch_size(10), 10);
+
+    // Auth errors count as failures, message errors don't
+    let error = |err: fcm::FcmError| -> Result<(), SendError> { Err(err.into()) };
+    let mut breaker = CircuitBreaker::new(2, cooldown);
+    breaker.record(&error(fcm::FcmError::Unauthorized), at(0));
+    breaker.record(
+        &error(fcm::FcmError::InvalidMessage("bad".to_string())),
+        at(0),
+    );
+    breaker.record(&error(fcm::FcmError::Unauthorized), at(0));
+    assert_eq!(breaker.state(), State::Closed);
+    breaker.record(&error(fcm::FcmError::Unauthorized), at(0));
+    assert!(matches!(breaker.state(), State::Open { .. }));
+}
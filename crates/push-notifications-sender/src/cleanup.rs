@@ -0,0 +1,77 @@
+//! Periodic cleanup of the messages table, which is bloated by the enqueue/dequeue churn.
+//!
+//! Messages which ran out of send attempts are never dequeued again, so they are deleted
+//! after the retention period (they are kept for a while to investigate send errors),
+//! and the table statistics are refreshed with `ANALYZE`.
+//! Stored payloads of sent messages (if any) are deleted after the same retention period.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use diesel::{r2d2::PoolError, PgConnection};
+
+use crate::postgres;
+
+pub struct Cleanup {
+    interval: Duration,
+    retention: chrono::Duration,
+    max_send_attempts: i16,
+}
+
+impl Cleanup {
+    pub fn new(interval: Duration, retention: chrono::Duration, max_send_attempts: i16) -> Self {
+        Cleanup {
+            interval,
+            retention,
+            max_send_attempts,
+        }
+    }
+
+    /// Runs the cleanup every interval on a blocking thread of its own,
+    /// so that it runs under a sustained load as well, not only when the queue is empty.
+    /// Errors are logged only: the cleanup is retried on the next run.
+    pub async fn run_periodically(self, pool: postgres::PgPool) {
+        let interval = self.interval;
+        let cleanup = Arc::new(self);
+        loop {
+            let (cleanup, pool) = (cleanup.clone(), pool.clone());
+            let res = tokio::task::spawn_blocking(move || {
+                let mut conn = pool.get()?;
+                cleanup.run(&mut conn);
+                Ok::<_, PoolError>(())
+            })
+            .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    log::error!("Cleanup: failed to get a database connection: {}", err)
+                }
+                Err(err) => log::error!("Cleanup task failed: {}", err),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn run(&self, conn: &mut PgConnection) {
+        let cutoff = Utc::now() - self.retention;
+        match postgres::delete_dead(conn, self.max_send_attempts, cutoff) {
+            Ok(count) => log::info!("Cleanup: deleted {} undeliverable messages", count),
+            Err(err) => log::error!(
+                "Cleanup: failed to delete undeliverable messages: {:?}",
+                err
+            ),
+        }
+        match postgres::delete_payloads(conn, cutoff) {
+            Ok(0) => {}
+            Ok(count) => log::info!("Cleanup: deleted {} stored payloads", count),
+            Err(err) => log::error!("Cleanup: failed to delete stored payloads: {:?}", err),
+        }
+        if let Err(err) = postgres::analyze_messages(conn) {
+            log::error!("Cleanup: failed to analyze messages table: {:?}", err);
+        }
+        match postgres::count_messages(conn) {
+            Ok(count) => log::info!("Cleanup: {} messages in the queue", count),
+            Err(err) => log::error!("Cleanup: failed to count messages: {:?}", err),
+        }
+    }
+}
@@ -1,24 +1,133 @@
 //! Push notifications Sender config
 
-use std::fmt;
+use std::{fmt, time};
 
 use chrono::Duration;
+use model::{device::Platform, secret::Secret};
 use serde::Deserialize;
 
+use crate::{backoff::Jitter, ordering::Partition};
+
 #[derive(Clone)]
 pub struct Config {
     pub empty_queue_poll_period: Duration,
     pub exponential_backoff_initial_interval: Duration,
     pub exponential_backoff_multiplier: f32,
+    pub exponential_backoff_max_interval: Duration,
+    pub exponential_backoff_jitter: Jitter,
     pub send_max_attempts: u8,
-    pub fcm_api_key: String,
-    pub click_action: String,
+    pub fcm_api_mode: FcmApiMode,
+    /// Server key of the legacy API, set if that's the API used
+    pub fcm_api_key: Option<Secret<String>>,
+    /// Service account key file and Firebase project of the HTTP v1 API,
+    /// set if that's the API used
+    pub fcm_credentials_path: Option<String>,
+    pub fcm_project_id: Option<String>,
+    pub fcm_secondary_api_key: Option<Secret<String>>,
+    pub click_actions: ClickActions,
     pub dry_run: bool,
+    pub omit_empty_data_fields: bool,
+    pub store_payloads: bool,
+    pub store_message_ids: bool,
+    pub audit_removed_devices: bool,
+    pub db_pool_size: u32,
+    pub db_pool_connection_timeout: Duration,
+    pub delete_orphaned_messages: bool,
+    pub auth_grace_retries: u32,
+    pub maintenance: bool,
+    pub canary_fcm_uid: Option<Secret<String>>,
+    pub cleanup_interval: Option<time::Duration>,
+    pub cleanup_retention: Duration,
+    /// How often the queue depth and the oldest message age metrics are sampled
+    pub queue_stats_interval: time::Duration,
+    /// Devices handled by this replica, all of them if not set
+    pub partition: Option<Partition>,
+    pub preserve_device_order: bool,
+    /// Consecutive FCM failures to open the circuit breaker at, disabled if not set
+    pub circuit_breaker_failures: Option<u32>,
+    pub circuit_breaker_cooldown: time::Duration,
+    /// Messages dequeued at once and sent concurrently
+    pub batch_size: u32,
+}
+
+/// FCM API the messages are sent with
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FcmApiMode {
+    /// Legacy HTTP API with a server key, deprecated by Google
+    Legacy,
+    /// HTTP v1 API with OAuth2 access tokens of a service account
+    V1,
+}
+
+/// `click_action` of the notifications, the apps of each platform may expect their own
+#[derive(Clone, Debug)]
+pub struct ClickActions {
+    pub default: String,
+    pub ios: Option<String>,
+    pub android: Option<String>,
+    pub web: Option<String>,
+}
+
+impl ClickActions {
+    pub fn for_platform(&self, platform: Platform) -> &str {
+        let click_action = match platform {
+            Platform::Ios => &self.ios,
+            Platform::Android => &self.android,
+            Platform::Web => &self.web,
+        };
+        click_action.as_deref().unwrap_or(&self.default)
+    }
 }
 
 impl Config {
     pub fn load() -> Result<Self, envy::Error> {
-        Ok(envy::from_env::<ConfigFlat>()?.into())
+        Self::checked(envy::from_env::<ConfigFlat>()?)
+    }
+
+    fn checked(conf: ConfigFlat) -> Result<Self, envy::Error> {
+        if conf.send_partition_index >= conf.send_partition_count {
+            return Err(envy::Error::Custom(
+                "send_partition_index must be less than send_partition_count".to_string(),
+            ));
+        }
+        if conf.send_batch_size == 0 {
+            return Err(envy::Error::Custom(
+                "send_batch_size must be positive".to_string(),
+            ));
+        }
+        if conf.send_db_pool_size == 0 {
+            return Err(envy::Error::Custom(
+                "send_db_pool_size must be positive".to_string(),
+            ));
+        }
+        if conf.send_queue_stats_interval_sec == 0 {
+            return Err(envy::Error::Custom(
+                "send_queue_stats_interval_sec must be positive".to_string(),
+            ));
+        }
+        match conf.fcm_api_mode {
+            FcmApiMode::Legacy if conf.fcm_api_key.is_none() => {
+                return Err(envy::Error::MissingValue("fcm_api_key"));
+            }
+            FcmApiMode::V1 if conf.fcm_credentials_path.is_none() => {
+                return Err(envy::Error::MissingValue("fcm_credentials_path"));
+            }
+            FcmApiMode::V1 if conf.fcm_project_id.is_none() => {
+                return Err(envy::Error::MissingValue("fcm_project_id"));
+            }
+            _ => {}
+        }
+        Ok(conf.into())
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Config with the defaults, as if no variables were set
+    pub fn sample() -> Self {
+        let conf = envy::from_iter::<_, ConfigFlat>(Vec::<(String, String)>::new());
+        conf.expect("default config").into()
     }
 }
 
@@ -32,10 +141,56 @@ impl From<ConfigFlat> for Config {
                 conf.send_exponential_backoff_initial_interval_millis as i64,
             ),
             exponential_backoff_multiplier: conf.send_exponential_backoff_multiplier,
+            exponential_backoff_max_interval: Duration::milliseconds(
+                conf.send_exponential_backoff_max_interval_millis as i64,
+            ),
+            exponential_backoff_jitter: conf.send_exponential_backoff_jitter,
             send_max_attempts: conf.send_max_attempts,
+            fcm_api_mode: conf.fcm_api_mode,
             fcm_api_key: conf.fcm_api_key,
-            click_action: conf.send_click_action,
+            fcm_credentials_path: conf.fcm_credentials_path,
+            fcm_project_id: conf.fcm_project_id,
+            fcm_secondary_api_key: conf.fcm_secondary_api_key,
+            click_actions: ClickActions {
+                default: conf.send_click_action,
+                ios: conf.send_click_action_ios,
+                android: conf.send_click_action_android,
+                web: conf.send_click_action_web,
+            },
             dry_run: conf.send_dry_run,
+            omit_empty_data_fields: conf.send_omit_empty_data_fields,
+            store_payloads: conf.send_store_payloads,
+            store_message_ids: conf.send_store_message_ids,
+            audit_removed_devices: conf.send_audit_removed_devices,
+            db_pool_size: conf.send_db_pool_size,
+            db_pool_connection_timeout: Duration::seconds(
+                conf.send_db_pool_connection_timeout_sec as i64,
+            ),
+            delete_orphaned_messages: conf.send_delete_orphaned_messages,
+            auth_grace_retries: conf.send_auth_grace_retries,
+            maintenance: conf.send_maintenance,
+            canary_fcm_uid: conf.send_canary_fcm_uid,
+            cleanup_interval: conf
+                .send_cleanup_interval_sec
+                .map(|sec| time::Duration::from_secs(sec as u64)),
+            cleanup_retention: Duration::seconds(conf.send_cleanup_retention_sec as i64),
+            queue_stats_interval: time::Duration::from_secs(
+                conf.send_queue_stats_interval_sec as u64,
+            ),
+            partition: if conf.send_partition_count > 1 {
+                Some(Partition {
+                    count: conf.send_partition_count,
+                    index: conf.send_partition_index,
+                })
+            } else {
+                None
+            },
+            preserve_device_order: conf.send_preserve_device_order,
+            circuit_breaker_failures: conf.send_circuit_breaker_failures,
+            circuit_breaker_cooldown: time::Duration::from_secs(
+                conf.send_circuit_breaker_cooldown_sec as u64,
+            ),
+            batch_size: conf.send_batch_size,
         }
     }
 }
@@ -48,13 +203,60 @@ struct ConfigFlat {
     send_exponential_backoff_initial_interval_millis: u32,
     #[serde(default = "default_exponential_backoff_multiplier")]
     send_exponential_backoff_multiplier: f32,
+    #[serde(default = "default_exponential_backoff_max_interval_millis")]
+    send_exponential_backoff_max_interval_millis: u32,
+    #[serde(default = "default_exponential_backoff_jitter")]
+    send_exponential_backoff_jitter: Jitter,
     #[serde(default = "default_send_max_attempts")]
     send_max_attempts: u8,
-    fcm_api_key: String,
+    #[serde(default = "default_fcm_api_mode")]
+    fcm_api_mode: FcmApiMode,
+    fcm_api_key: Option<Secret<String>>,
+    fcm_credentials_path: Option<String>,
+    fcm_project_id: Option<String>,
+    fcm_secondary_api_key: Option<Secret<String>>,
     #[serde(default = "default_send_click_action")]
     send_click_action: String,
+    send_click_action_ios: Option<String>,
+    send_click_action_android: Option<String>,
+    send_click_action_web: Option<String>,
     #[serde(default = "default_send_dry_run")]
     send_dry_run: bool,
+    #[serde(default)]
+    send_omit_empty_data_fields: bool,
+    #[serde(default)]
+    send_store_payloads: bool,
+    #[serde(default)]
+    send_store_message_ids: bool,
+    #[serde(default)]
+    send_audit_removed_devices: bool,
+    #[serde(default = "default_send_db_pool_size")]
+    send_db_pool_size: u32,
+    #[serde(default = "default_send_db_pool_connection_timeout_sec")]
+    send_db_pool_connection_timeout_sec: u32,
+    #[serde(default = "default_send_delete_orphaned_messages")]
+    send_delete_orphaned_messages: bool,
+    #[serde(default = "default_send_auth_grace_retries")]
+    send_auth_grace_retries: u32,
+    #[serde(default)]
+    send_maintenance: bool,
+    send_canary_fcm_uid: Option<Secret<String>>,
+    send_cleanup_interval_sec: Option<u32>,
+    #[serde(default = "default_send_cleanup_retention_sec")]
+    send_cleanup_retention_sec: u32,
+    #[serde(default = "default_send_queue_stats_interval_sec")]
+    send_queue_stats_interval_sec: u32,
+    #[serde(default = "default_send_partition_count")]
+    send_partition_count: u32,
+    #[serde(default)]
+    send_partition_index: u32,
+    #[serde(default)]
+    send_preserve_device_order: bool,
+    send_circuit_breaker_failures: Option<u32>,
+    #[serde(default = "default_send_circuit_breaker_cooldown_sec")]
+    send_circuit_breaker_cooldown_sec: u32,
+    #[serde(default = "default_send_batch_size")]
+    send_batch_size: u32,
 }
 
 fn default_empty_queue_poll_period() -> u32 {
@@ -69,10 +271,22 @@ fn default_exponential_backoff_multiplier() -> f32 {
     3.0
 }
 
+fn default_exponential_backoff_max_interval_millis() -> u32 {
+    60 * 60 * 1000
+}
+
+fn default_exponential_backoff_jitter() -> Jitter {
+    Jitter::None
+}
+
 fn default_send_max_attempts() -> u8 {
     5
 }
 
+fn default_fcm_api_mode() -> FcmApiMode {
+    FcmApiMode::Legacy
+}
+
 fn default_send_dry_run() -> bool {
     false
 }
@@ -81,18 +295,118 @@ fn default_send_click_action() -> String {
     "open".to_owned()
 }
 
+fn default_send_db_pool_size() -> u32 {
+    2
+}
+
+fn default_send_db_pool_connection_timeout_sec() -> u32 {
+    5
+}
+
+fn default_send_delete_orphaned_messages() -> bool {
+    true
+}
+
+fn default_send_auth_grace_retries() -> u32 {
+    3
+}
+
+fn default_send_cleanup_retention_sec() -> u32 {
+    7 * 24 * 60 * 60
+}
+
+fn default_send_queue_stats_interval_sec() -> u32 {
+    60
+}
+
+fn default_send_partition_count() -> u32 {
+    1
+}
+
+fn default_send_circuit_breaker_cooldown_sec() -> u32 {
+    60
+}
+
+fn default_send_batch_size() -> u32 {
+    1
+}
+
 impl fmt::Debug for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Intentionally avoid printing FCM API Key for security reasons
         write!(
             f,
-            "Sender(empty_queue_poll_period={}s; exponential_backoff_initial_interval={}s; exponential_backoff_multiplier={}; send_max_attempts={}; fcm_api_key=***; click_action={}; dry_run={})",
+            "Sender(empty_queue_poll_period={}s; exponential_backoff_initial_interval={}s; exponential_backoff_multiplier={}; exponential_backoff_max_interval={}s; exponential_backoff_jitter={:?}; send_max_attempts={}; fcm_api_mode={:?}; fcm_api_key={:?}; fcm_credentials_path={:?}; fcm_project_id={:?}; fcm_secondary_api_key={:?}; click_actions={:?}; dry_run={}; omit_empty_data_fields={}; store_payloads={}; store_message_ids={}; audit_removed_devices={}; db_pool_size={}; db_pool_connection_timeout={}s; delete_orphaned_messages={}; auth_grace_retries={}; maintenance={}; canary_fcm_uid={:?}; cleanup_interval={:?}; cleanup_retention={}s; partition={:?}; preserve_device_order={}; circuit_breaker_failures={:?}; circuit_breaker_cooldown={:?}; batch_size={})",
             self.empty_queue_poll_period.num_seconds(),
             self.exponential_backoff_initial_interval.num_seconds(),
             self.exponential_backoff_multiplier,
+            self.exponential_backoff_max_interval.num_seconds(),
+            self.exponential_backoff_jitter,
             self.send_max_attempts,
-            self.click_action,
+            self.fcm_api_mode,
+            self.fcm_api_key,
+            self.fcm_credentials_path,
+            self.fcm_project_id,
+            self.fcm_secondary_api_key,
+            self.click_actions,
             self.dry_run,
+            self.omit_empty_data_fields,
+            self.store_payloads,
+            self.store_message_ids,
+            self.audit_removed_devices,
+            self.db_pool_size,
+            self.db_pool_connection_timeout.num_seconds(),
+            self.delete_orphaned_messages,
+            self.auth_grace_retries,
+            self.maintenance,
+            self.canary_fcm_uid,
+            self.cleanup_interval,
+            self.cleanup_retention.num_seconds(),
+            self.partition,
+            self.preserve_device_order,
+            self.circuit_breaker_failures,
+            self.circuit_breaker_cooldown,
+            self.batch_size,
         )
     }
 }
+
+#[test]
+fn test_click_actions() {
+    let click_actions = ClickActions {
+        default: "open".to_string(),
+        ios: Some("OPEN_APP".to_string()),
+        android: None,
+        web: Some("https://waves.exchange".to_string()),
+    };
+    assert_eq!(click_actions.for_platform(Platform::Ios), "OPEN_APP");
+    assert_eq!(click_actions.for_platform(Platform::Android), "open");
+    assert_eq!(
+        click_actions.for_platform(Platform::Web),
+        "https://waves.exchange"
+    );
+}
+
+#[test]
+fn test_checked() {
+    let load = |vars: &[(&str, &str)]| {
+        let vars = vars.iter().map(|&(k, v)| (k.to_string(), v.to_string()));
+        Config::checked(envy::from_iter::<_, ConfigFlat>(vars).unwrap())
+    };
+
+    let config = load(&[("FCM_API_KEY", "key")]).unwrap();
+    assert_eq!(config.queue_stats_interval, time::Duration::from_secs(60));
+    // Sampled back to back otherwise
+    let err = load(&[
+        ("FCM_API_KEY", "key"),
+        ("SEND_QUEUE_STATS_INTERVAL_SEC", "0"),
+    ]);
+    assert!(matches!(err, Err(envy::Error::Custom(msg)) if msg.contains("interval")));
+    let err = load(&[("FCM_API_KEY", "key"), ("SEND_BATCH_SIZE", "0")]);
+    assert!(matches!(err, Err(envy::Error::Custom(_))));
+    let err = load(&[("FCM_API_KEY", "key"), ("SEND_DB_POOL_SIZE", "0")]);
+    assert!(matches!(err, Err(envy::Error::Custom(msg)) if msg.contains("pool")));
+    assert!(matches!(
+        load(&[]),
+        Err(envy::Error::MissingValue("fcm_api_key"))
+    ));
+}
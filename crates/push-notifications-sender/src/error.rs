@@ -0,0 +1,76 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SendError {
+    /// Credentials were rejected, which has nothing to do with the message being sent
+    #[error("FCM authentication failed")]
+    Auth,
+
+    #[error("FCM error: {0}")]
+    Fcm(fcm::FcmError),
+
+    /// Provider-side failure reported by the HTTP v1 API (or its token endpoint)
+    #[error("FCM server error: {0}")]
+    Server(String),
+
+    /// The device token is not valid anymore (like an uninstalled app), so retrying is pointless
+    #[error("FCM device token is invalid: {0}")]
+    TokenInvalid(String),
+
+    /// The message was accepted, but not delivered to the device, with the reason in the response
+    #[error("FCM rejected the message: {0}")]
+    Rejected(String),
+}
+
+impl SendError {
+    pub fn is_auth(&self) -> bool {
+        matches!(self, SendError::Auth)
+    }
+
+    /// Provider-side failure, which has nothing to do with the message either
+    pub fn is_server_error(&self) -> bool {
+        matches!(
+            self,
+            SendError::Fcm(fcm::FcmError::ServerError(_)) | SendError::Server(_)
+        )
+    }
+}
+
+impl From<&fcm::ErrorReason> for SendError {
+    fn from(reason: &fcm::ErrorReason) -> Self {
+        let name = format!("{:?}", reason);
+        match reason {
+            fcm::ErrorReason::NotRegistered | fcm::ErrorReason::InvalidRegistration => {
+                SendError::TokenInvalid(name)
+            }
+            _ => SendError::Rejected(name),
+        }
+    }
+}
+
+impl From<fcm::FcmError> for SendError {
+    fn from(err: fcm::FcmError) -> Self {
+        match err {
+            fcm::FcmError::Unauthorized => SendError::Auth,
+            err => SendError::Fcm(err),
+        }
+    }
+}
+
+#[test]
+fn test_auth_error() {
+    assert!(SendError::from(fcm::FcmError::Unauthorized).is_auth());
+    assert!(!SendError::from(fcm::FcmError::InvalidMessage("bad".to_string())).is_auth());
+    assert!(SendError::from(fcm::FcmError::ServerError(None)).is_server_error());
+    assert!(!SendError::from(fcm::FcmError::Unauthorized).is_server_error());
+}
+
+#[test]
+fn test_token_errors() {
+    let err = SendError::from(&fcm::ErrorReason::NotRegistered);
+    assert!(matches!(err, SendError::TokenInvalid(reason) if reason == "NotRegistered"));
+    let err = SendError::from(&fcm::ErrorReason::InvalidRegistration);
+    assert!(matches!(err, SendError::TokenInvalid(_)));
+    let err = SendError::from(&fcm::ErrorReason::MessageTooBig);
+    assert!(matches!(err, SendError::Rejected(reason) if reason == "MessageTooBig"));
+}
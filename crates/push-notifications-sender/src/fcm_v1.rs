@@ -0,0 +1,616 @@
+//! FCM HTTP v1 API gateway.
+//!
+//! Unlike the legacy API with its static server key, requests are authorized with short-lived
+//! OAuth2 access tokens of a service account. A token is obtained by signing a JWT with
+//! the service account key, cached and refreshed a bit before it expires. FCM may still
+//! reject a cached token (say, if it was revoked), then a new one is fetched once.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use model::{message::MessagePriority, secret::Secret};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::ClickActions,
+    error::SendError,
+    gateway::{Gateway, Sent},
+    metrics, payload_data, MessageToSend,
+};
+
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// Lifetime of the signed JWT, Google doesn't accept longer ones
+const JWT_LIFETIME_SEC: i64 = 3600;
+
+/// A token is refreshed this long before it expires, so that it doesn't expire in flight
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Longest `apns-collapse-id` APNs accepts, in bytes
+const APNS_COLLAPSE_ID_MAX_LEN: usize = 64;
+
+/// The relevant part of the service account JSON key downloaded from the Firebase console
+#[derive(Deserialize)]
+pub struct ServiceAccountKey {
+    client_email: String,
+    private_key: Secret<String>,
+    token_uri: String,
+}
+
+impl ServiceAccountKey {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+pub struct AccessToken {
+    pub value: Secret<String>,
+    pub expires_in: Duration,
+}
+
+#[async_trait]
+pub trait TokenSource: Send + Sync {
+    async fn fetch(&self) -> Result<AccessToken, SendError>;
+}
+
+/// Access tokens of a service account, obtained from the Google OAuth2 token endpoint
+pub struct ServiceAccountTokens {
+    client: reqwest::Client,
+    client_email: String,
+    token_uri: String,
+    signing_key: EncodingKey,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl ServiceAccountTokens {
+    /// Fails if the private key of the service account is malformed
+    pub fn new(key: ServiceAccountKey) -> anyhow::Result<Self> {
+        let signing_key = EncodingKey::from_rsa_pem(key.private_key.expose().as_bytes())?;
+        Ok(ServiceAccountTokens {
+            client: reqwest::Client::new(),
+            client_email: key.client_email,
+            token_uri: key.token_uri,
+            signing_key,
+        })
+    }
+}
+
+#[async_trait]
+impl TokenSource for ServiceAccountTokens {
+    async fn fetch(&self) -> Result<AccessToken, SendError> {
+        let iat = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            iss: &self.client_email,
+            scope: SCOPE,
+            aud: &self.token_uri,
+            iat,
+            exp: iat + JWT_LIFETIME_SEC,
+        };
+        let assertion =
+            jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.signing_key)
+                .map_err(|err| {
+                    log::error!("Failed to sign FCM access token request | {:?}", err);
+                    SendError::Auth
+                })?;
+
+        let response = self
+            .client
+            .post(&self.token_uri)
+            .form(&[("grant_type", GRANT_TYPE), ("assertion", &assertion)])
+            .send()
+            .await
+            .map_err(|err| SendError::Server(err.to_string()))?;
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(SendError::Server(format!("token endpoint: {}", status)));
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            log::error!("FCM access token request rejected: {} {}", status, body);
+            return Err(SendError::Auth);
+        }
+        let token = response
+            .json::<TokenResponse>()
+            .await
+            .map_err(|err| SendError::Server(err.to_string()))?;
+        Ok(AccessToken {
+            value: Secret::new(token.access_token),
+            expires_in: Duration::from_secs(token.expires_in),
+        })
+    }
+}
+
+struct CachedToken {
+    value: Secret<String>,
+    expires_at: Instant,
+}
+
+/// The current access token, fetched anew once it is about to expire
+pub struct TokenCache {
+    source: Box<dyn TokenSource>,
+    refresh_margin: Duration,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl TokenCache {
+    pub fn new(source: Box<dyn TokenSource>, refresh_margin: Duration) -> Self {
+        TokenCache {
+            source,
+            refresh_margin,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn token(&self, now: Instant) -> Result<String, SendError> {
+        let mut cached = self.cached.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if now + self.refresh_margin < token.expires_at {
+                return Ok(token.value.expose().clone());
+            }
+        }
+        let AccessToken { value, expires_in } = self.source.fetch().await?;
+        log::debug!("Fetched FCM access token, expires in {:?}", expires_in);
+        let token = value.expose().clone();
+        *cached = Some(CachedToken {
+            value,
+            expires_at: now + expires_in,
+        });
+        Ok(token)
+    }
+
+    async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+}
+
+/// The collapse key as is if APNs accepts it, otherwise its SHA-256 in hex, which fits exactly.
+/// Price alert keys of a pair of two tokens (not WAVES) are too long for APNs.
+fn apns_collapse_id(collapse_key: &str) -> String {
+    if collapse_key.len() <= APNS_COLLAPSE_ID_MAX_LEN {
+        return collapse_key.to_string();
+    }
+    Sha256::digest(collapse_key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Makes the request with the cached access token. If it is rejected anyway,
+/// makes it once again with a new token, so that a revoked token doesn't fail
+/// all the messages up to its expiry.
+async fn with_token_refresh<F, Fut>(tokens: &TokenCache, mut request: F) -> Result<Sent, SendError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<Sent, SendError>>,
+{
+    let token = tokens.token(Instant::now()).await?;
+    match request(token).await {
+        Err(SendError::Auth) => {
+            log::warn!("FCM rejected the access token, retrying with a new one");
+            tokens.invalidate().await;
+            let token = tokens.token(Instant::now()).await?;
+            request(token).await
+        }
+        res => res,
+    }
+}
+
+pub struct FcmV1Gateway {
+    client: reqwest::Client,
+    send_url: String,
+    tokens: TokenCache,
+    click_actions: ClickActions,
+    dry_run: bool,
+    omit_empty_data_fields: bool,
+}
+
+impl FcmV1Gateway {
+    pub fn new(
+        project_id: &str,
+        tokens: Box<dyn TokenSource>,
+        click_actions: ClickActions,
+        dry_run: bool,
+        omit_empty_data_fields: bool,
+    ) -> Self {
+        FcmV1Gateway {
+            client: reqwest::Client::new(),
+            send_url: format!(
+                "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+                project_id
+            ),
+            tokens: TokenCache::new(tokens, TOKEN_REFRESH_MARGIN),
+            click_actions,
+            dry_run,
+            omit_empty_data_fields,
+        }
+    }
+
+    /// Request body of the `messages:send` method. Options which are specific
+    /// to a platform in the v1 API (like the TTL) are set for both Android and iOS.
+    fn request_body(&self, message: &MessageToSend) -> serde_json::Value {
+        let delivery_style = message.delivery_style();
+        let priority = message.priority();
+        let mut fcm_msg = json!({ "token": message.fcm_uid });
+        let mut android = json!({
+            "priority": match priority {
+                MessagePriority::High => "high",
+                MessagePriority::Normal => "normal",
+            },
+        });
+        let mut apns_headers = json!({
+            "apns-priority": match priority {
+                MessagePriority::High => "10",
+                MessagePriority::Normal => "5",
+            },
+        });
+
+        if delivery_style.has_notification() {
+            fcm_msg["notification"] = json!({
+                "title": message.notification_title,
+                "body": message.notification_body,
+            });
+            let click_action = self.click_actions.for_platform(message.platform());
+            android["notification"] = json!({ "click_action": click_action });
+        }
+
+        if delivery_style.has_data() {
+            let data = payload_data(message, self.omit_empty_data_fields);
+            metrics::PAYLOAD_DATA_SIZE.observe(data.to_string().len() as f64);
+            fcm_msg["data"] = string_values(data);
+        }
+
+        if let Some(collapse_key) = &message.collapse_key {
+            android["collapse_key"] = collapse_key.as_str().into();
+            apns_headers["apns-collapse-id"] = apns_collapse_id(collapse_key).into();
+        }
+
+        if let Some(ttl) = message.remaining_time_to_live() {
+            android["ttl"] = format!("{}s", ttl).into();
+            let expiration = chrono::Utc::now().timestamp() + ttl as i64;
+            apns_headers["apns-expiration"] = expiration.to_string().into();
+        }
+
+        fcm_msg["android"] = android;
+        let mut apns = json!({ "headers": apns_headers });
+        if let Some(group_key) = &message.group_key {
+            apns["payload"] = json!({ "aps": { "thread-id": group_key } });
+        }
+        fcm_msg["apns"] = apns;
+        json!({ "message": fcm_msg })
+    }
+
+    async fn post(&self, body: &serde_json::Value, token: String) -> Result<Sent, SendError> {
+        let response = self
+            .client
+            .post(&self.send_url)
+            .bearer_auth(token)
+            .json(body)
+            .send()
+            .await
+            .map_err(|err| SendError::Server(err.to_string()))?;
+        let status = response.status().as_u16();
+        let body = response
+            .text()
+            .await
+            .map_err(|err| SendError::Server(err.to_string()))?;
+        check_response(status, &body)
+    }
+}
+
+#[async_trait]
+impl Gateway for FcmV1Gateway {
+    async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError> {
+        let body = self.request_body(message);
+        if self.dry_run {
+            // The message is acked as usual, so it is only the log telling it wasn't really sent
+            log::info!(
+                "DRY RUN: message #{} not sent, payload: {}",
+                message.uid,
+                body
+            );
+            metrics::DRY_RUN_SENDS.inc();
+            return Ok(Sent::default());
+        }
+        let sent = with_token_refresh(&self.tokens, |token| self.post(&body, token)).await?;
+        log::debug!("Message #{} {:?}", message.uid, sent);
+        Ok(sent)
+    }
+
+    fn audit_payload(&self, message: &MessageToSend) -> Option<serde_json::Value> {
+        let mut payload = self.request_body(message);
+        // Redacted just like in the `Debug` impl of `MessageToSend`
+        payload["message"]["token"] = "***".into();
+        Some(payload)
+    }
+}
+
+/// The v1 API only takes strings as data values, other values are passed JSON-encoded
+fn string_values(data: serde_json::Value) -> serde_json::Value {
+    match data {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value.into()),
+                value => (key, value.to_string().into()),
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        data => data,
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorStatus,
+}
+
+#[derive(Deserialize)]
+struct ErrorStatus {
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    details: Vec<ErrorDetails>,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+/// Maps the `messages:send` response to the outcome, the same way as the legacy API responses:
+/// a server error fails over, a dead token removes the device, the rest are retried
+fn check_response(status: u16, body: &str) -> Result<Sent, SendError> {
+    if status == 200 {
+        #[derive(Deserialize)]
+        struct SendResponse {
+            name: String,
+        }
+        let name = serde_json::from_str::<SendResponse>(body)
+            .map(|response| response.name)
+            .ok();
+        return Ok(Sent {
+            canonical_fcm_uid: None,
+            message_id: name,
+        });
+    }
+    if status == 401 {
+        return Err(SendError::Auth);
+    }
+    if status == 429 || status >= 500 {
+        return Err(SendError::Server(format!("{} {}", status, body)));
+    }
+    let error_code = serde_json::from_str::<ErrorResponse>(body)
+        .ok()
+        .map(|response| {
+            let code = response
+                .error
+                .details
+                .into_iter()
+                .find_map(|d| d.error_code);
+            code.unwrap_or(response.error.status)
+        })
+        .unwrap_or_else(|| status.to_string());
+    match error_code.as_str() {
+        "UNREGISTERED" => Err(SendError::TokenInvalid(error_code)),
+        _ => Err(SendError::Rejected(error_code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apns_collapse_id, check_response, string_values, with_token_refresh, AccessToken,
+        FcmV1Gateway, TokenCache, TokenSource,
+    };
+    use crate::{config::ClickActions, error::SendError, gateway::Sent, MessageToSend};
+    use model::secret::Secret;
+    use serde_json::json;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// Issues tokens "token1", "token2", ... valid for an hour
+    struct MockTokenSource {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl TokenSource for MockTokenSource {
+        async fn fetch(&self) -> Result<AccessToken, SendError> {
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(AccessToken {
+                value: Secret::new(format!("token{}", n)),
+                expires_in: Duration::from_secs(3600),
+            })
+        }
+    }
+
+    fn token_cache() -> (TokenCache, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let source = MockTokenSource {
+            fetches: fetches.clone(),
+        };
+        let cache = TokenCache::new(Box::new(source), Duration::from_secs(300));
+        (cache, fetches)
+    }
+
+    #[test]
+    fn test_token_cache_reuse() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (cache, fetches) = token_cache();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Reused until the refresh margin before the expiry
+        assert_eq!(rt.block_on(cache.token(at(0))).unwrap(), "token1");
+        assert_eq!(rt.block_on(cache.token(at(1))).unwrap(), "token1");
+        assert_eq!(rt.block_on(cache.token(at(3299))).unwrap(), "token1");
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Refreshed ahead of the expiry
+        assert_eq!(rt.block_on(cache.token(at(3300))).unwrap(), "token2");
+        assert_eq!(rt.block_on(cache.token(at(3301))).unwrap(), "token2");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_token_refresh_on_401() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (cache, fetches) = token_cache();
+
+        // Revoked token: rejected, a new one is fetched and the request is made once again
+        let mut used_tokens = Vec::new();
+        let res = rt.block_on(with_token_refresh(&cache, |token| {
+            let res = if token == "token1" {
+                Err(SendError::Auth)
+            } else {
+                Ok(Sent::default())
+            };
+            used_tokens.push(token);
+            async move { res }
+        }));
+        assert!(res.is_ok());
+        assert_eq!(used_tokens, vec!["token1", "token2"]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // The new token is cached
+        let res = rt.block_on(with_token_refresh(&cache, |token| {
+            assert_eq!(token, "token2");
+            async { Ok(Sent::default()) }
+        }));
+        assert!(res.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // Rejected again - an auth error, no endless retries
+        let mut requests = 0;
+        let res = rt.block_on(with_token_refresh(&cache, |_| {
+            requests += 1;
+            async { Err(SendError::Auth) }
+        }));
+        assert!(res.unwrap_err().is_auth());
+        assert_eq!(requests, 2);
+    }
+
+    #[test]
+    fn test_check_response() {
+        let sent = check_response(200, r#"{"name": "projects/p/messages/0:1516"}"#).unwrap();
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("projects/p/messages/0:1516")
+        );
+
+        let error = |status: u16, code: &str| {
+            let body = json!({
+                "error": {
+                    "code": status,
+                    "message": "error",
+                    "status": "NOT_FOUND",
+                    "details": [{
+                        "@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                        "errorCode": code,
+                    }],
+                }
+            });
+            check_response(status, &body.to_string())
+        };
+        assert!(matches!(
+            error(404, "UNREGISTERED"),
+            Err(SendError::TokenInvalid(_))
+        ));
+        assert!(matches!(
+            error(400, "INVALID_ARGUMENT"),
+            Err(SendError::Rejected(_))
+        ));
+        assert!(error(503, "UNAVAILABLE").unwrap_err().is_server_error());
+        assert!(error(429, "QUOTA_EXCEEDED").unwrap_err().is_server_error());
+        assert!(error(401, "THIRD_PARTY_AUTH_ERROR").unwrap_err().is_auth());
+
+        // Not even JSON
+        let res = check_response(404, "Not Found");
+        assert!(matches!(res, Err(SendError::Rejected(code)) if code == "404"));
+    }
+
+    #[test]
+    fn test_string_values() {
+        assert_eq!(
+            string_values(json!({"type": "digest", "count": 2, "flags": [], "label": null})),
+            json!({"type": "digest", "count": "2", "flags": "[]", "label": "null"})
+        );
+    }
+
+    #[test]
+    fn test_apns_options() {
+        const USDN: &str = "DG2xFkPdDwKUoBkzGAhQtLpSGzfXLiCYPEzeKH2Ad24p";
+        const BTC: &str = "8LQW8f7P5d5PZM7GtZEBgaqRPGSzS3DfPuiXrURJ4AJS";
+
+        let source = MockTokenSource {
+            fetches: Arc::new(AtomicUsize::new(0)),
+        };
+        let click_actions = ClickActions {
+            default: "open".to_string(),
+            ios: None,
+            android: None,
+            web: None,
+        };
+        let gateway = FcmV1Gateway::new("project", Box::new(source), click_actions, false, true);
+        let collapse_key = format!("price:{}/{}", BTC, USDN);
+        let message = MessageToSend {
+            collapse_key: Some(collapse_key.clone()),
+            group_key: Some("price_alerts".to_string()),
+            platform: "ios".to_string(),
+            ..MessageToSend::sample()
+        };
+
+        let body = gateway.request_body(&message);
+        let apns = &body["message"]["apns"];
+        let collapse_id = apns["headers"]["apns-collapse-id"].as_str().unwrap();
+        assert!(collapse_key.len() > 64);
+        assert_eq!(collapse_id.len(), 64);
+        assert_eq!(collapse_id, apns_collapse_id(&collapse_key));
+        assert_ne!(
+            collapse_id,
+            apns_collapse_id(&format!("price:{}/{}", USDN, BTC))
+        );
+        // Android has no such limit
+        assert_eq!(body["message"]["android"]["collapse_key"], collapse_key);
+        assert_eq!(apns["payload"]["aps"]["thread-id"], "price_alerts");
+
+        // Short keys are kept as they are, no group - no thread
+        let collapse_key = format!("price:WAVES/{}", USDN);
+        let message = MessageToSend {
+            collapse_key: Some(collapse_key.clone()),
+            ..MessageToSend::sample()
+        };
+        let body = gateway.request_body(&message);
+        let apns = &body["message"]["apns"];
+        assert_eq!(apns["headers"]["apns-collapse-id"], collapse_key);
+        assert!(apns.get("payload").is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Delivery gateways, with failover to secondary ones during a provider-side outage
+
+use crate::{error::SendError, metrics, MessageToSend};
+
+/// Successfully sent message
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Sent {
+    /// Token the device is to be addressed by from now on, if FCM reports the one used
+    /// as outdated (the canonical registration id of the legacy API)
+    pub canonical_fcm_uid: Option<String>,
+    /// Id FCM assigned to the message, to look it up in FCM diagnostics
+    pub message_id: Option<String>,
+}
+
+#[async_trait]
+pub trait Gateway: Send + Sync {
+    async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError>;
+
+    /// The payload sent for the message, with the device token redacted, if known
+    fn audit_payload(&self, _message: &MessageToSend) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// Tries the gateways in order, moving on to the next one only on a server error,
+/// because other errors (bad message, bad credentials) would most likely repeat there.
+pub struct Failover {
+    gateways: Vec<Box<dyn Gateway>>,
+}
+
+impl Failover {
+    pub fn new(gateways: Vec<Box<dyn Gateway>>) -> Self {
+        assert!(!gateways.is_empty(), "at least one gateway is required");
+        Failover { gateways }
+    }
+}
+
+#[async_trait]
+impl Gateway for Failover {
+    async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError> {
+        let (last, rest) = self.gateways.split_last().expect("gateways");
+        for (i, gateway) in rest.iter().enumerate() {
+            match gateway.send(message).await {
+                Err(err) if err.is_server_error() => {
+                    log::warn!(
+                        "Gateway #{} failed to send message {}, failing over | {:?}",
+                        i,
+                        message.uid,
+                        err
+                    );
+                    metrics::GATEWAY_FAILOVERS.inc();
+                }
+                res => return res,
+            }
+        }
+        last.send(message).await
+    }
+
+    /// The payload is the same whichever gateway sent the message (only the API key differs)
+    fn audit_payload(&self, message: &MessageToSend) -> Option<serde_json::Value> {
+        self.gateways[0].audit_payload(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failover, Gateway, Sent};
+    use crate::{error::SendError, MessageToSend};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    struct MockGateway {
+        result: fn() -> Result<Sent, SendError>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Gateway for MockGateway {
+        async fn send(&self, _message: &MessageToSend) -> Result<Sent, SendError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            (self.result)()
+        }
+    }
+
+    fn server_error() -> Result<Sent, SendError> {
+        Err(fcm::FcmError::ServerError(None).into())
+    }
+
+    fn bad_message() -> Result<Sent, SendError> {
+        Err(fcm::FcmError::InvalidMessage("bad".to_string()).into())
+    }
+
+    fn ok() -> Result<Sent, SendError> {
+        Ok(Sent::default())
+    }
+
+    /// Sends a message via primary & secondary gateways, returns the result and numbers of calls
+    fn send(
+        primary: fn() -> Result<Sent, SendError>,
+        secondary: fn() -> Result<Sent, SendError>,
+    ) -> (Result<Sent, SendError>, usize, usize) {
+        let primary_calls = Arc::new(AtomicUsize::new(0));
+        let secondary_calls = Arc::new(AtomicUsize::new(0));
+        let failover = Failover::new(vec![
+            Box::new(MockGateway {
+                result: primary,
+                calls: primary_calls.clone(),
+            }),
+            Box::new(MockGateway {
+                result: secondary,
+                calls: secondary_calls.clone(),
+            }),
+        ]);
+        let message = MessageToSend::sample();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let res = rt.block_on(failover.send(&message));
+        (
+            res,
+            primary_calls.load(Ordering::SeqCst),
+            secondary_calls.load(Ordering::SeqCst),
+        )
+    }
+
+    #[test]
+    fn test_failover() {
+        // Primary is fine - secondary is not used
+        let (res, primary, secondary) = send(ok, ok);
+        assert!(res.is_ok());
+        assert_eq!((primary, secondary), (1, 0));
+
+        // Primary outage - the message is sent via secondary
+        let (res, primary, secondary) = send(server_error, ok);
+        assert!(res.is_ok());
+        assert_eq!((primary, secondary), (1, 1));
+
+        // Both are down - the error is reported
+        let (res, _, secondary) = send(server_error, server_error);
+        assert!(res.unwrap_err().is_server_error());
+        assert_eq!(secondary, 1);
+
+        // Bad message is not retried via secondary
+        let (res, primary, secondary) = send(bad_message, ok);
+        assert!(res.is_err());
+        assert_eq!((primary, secondary), (1, 0));
+    }
+}
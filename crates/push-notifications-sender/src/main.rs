@@ -1,13 +1,44 @@
 //! Push notifications Sender service executable
 
+#[macro_use]
+extern crate async_trait;
+
 extern crate wavesexchange_log as log;
 
+mod auth_grace;
 mod backoff;
+mod canary;
+mod circuit_breaker;
+mod cleanup;
 mod config;
+mod error;
+mod fcm_v1;
+mod gateway;
+mod maintenance;
+mod metrics;
+mod ordering;
+mod queue_stats;
 
+use auth_grace::AuthGrace;
+use canary::Readiness;
 use chrono::{DateTime, Utc};
-use diesel::{prelude::*, Connection, PgConnection};
-use std::fmt;
+use circuit_breaker::CircuitBreaker;
+use cleanup::Cleanup;
+use config::{ClickActions, FcmApiMode};
+use diesel::prelude::*;
+use error::SendError;
+use fcm_v1::{FcmV1Gateway, ServiceAccountKey, ServiceAccountTokens};
+use futures::stream::{self, StreamExt};
+use gateway::{Failover, Gateway, Sent};
+use maintenance::Maintenance;
+use model::{
+    device::Platform,
+    message::{fill_date_time, DeliveryStyle, MessagePriority},
+    secret::Secret,
+    time::Timestamp,
+};
+use postgres::Outcome;
+use std::{fmt, time::Instant};
 use tokio::task;
 use wavesexchange_warp::MetricsWarpBuilder;
 
@@ -22,68 +53,418 @@ async fn main() -> anyhow::Result<()> {
     );
 
     log::info!("Connecting to postgres database: {:?}", pg_config);
-    let mut conn = PgConnection::establish(&pg_config.database_url())?;
+    let pool = postgres::pool(
+        &pg_config.database_url(),
+        config.db_pool_size,
+        config.db_pool_connection_timeout.to_std()?,
+    )?;
 
-    let fcm = FcmRemoteGateway {
-        client: fcm::Client::new(),
-        api_key: config.fcm_api_key,
-        click_action: config.click_action,
-        dry_run: config.dry_run,
+    // .expect() is safe, the settings of the API used are validated on config load
+    let primary_gateway: Box<dyn Gateway> = match config.fcm_api_mode {
+        FcmApiMode::Legacy => Box::new(FcmRemoteGateway {
+            client: fcm::Client::new(),
+            api_key: config.fcm_api_key.clone().expect("fcm_api_key"),
+            click_actions: config.click_actions.clone(),
+            dry_run: config.dry_run,
+            omit_empty_data_fields: config.omit_empty_data_fields,
+        }),
+        FcmApiMode::V1 => {
+            let credentials_path = config.fcm_credentials_path.as_deref();
+            let key = ServiceAccountKey::load(credentials_path.expect("fcm_credentials_path"))?;
+            Box::new(FcmV1Gateway::new(
+                config.fcm_project_id.as_deref().expect("fcm_project_id"),
+                Box::new(ServiceAccountTokens::new(key)?),
+                config.click_actions.clone(),
+                config.dry_run,
+                config.omit_empty_data_fields,
+            ))
+        }
     };
+    let mut gateways = vec![primary_gateway];
+    if let Some(api_key) = config.fcm_secondary_api_key.clone() {
+        gateways.push(Box::new(FcmRemoteGateway {
+            client: fcm::Client::new(),
+            api_key,
+            click_actions: config.click_actions.clone(),
+            dry_run: config.dry_run,
+            omit_empty_data_fields: config.omit_empty_data_fields,
+        }));
+    }
+    let gateway = Failover::new(gateways);
+
+    let readiness = Readiness::default();
 
     // Stats & liveness endpoints
+    let readyz = readiness.clone();
     task::spawn(
         MetricsWarpBuilder::new()
             .with_metrics_port_from_env()
+            .with_metric(&*metrics::GATEWAY_FAILOVERS)
+            .with_metric(&*metrics::NOTIFICATION_LATENCY)
+            .with_metric(&*metrics::DRY_RUN_SENDS)
+            .with_metric(&*metrics::PAYLOAD_DATA_SIZE)
+            .with_metric(&*metrics::CIRCUIT_BREAKER_STATE)
+            .with_metric(&*metrics::INVALID_TOKEN_DEVICES_REMOVED)
+            .with_metric(&*metrics::LAST_DEVICES_REMOVED)
+            .with_metric(&*metrics::DEVICE_TOKENS_MIGRATED)
+            .with_metric(&*metrics::QUEUE_DEPTH)
+            .with_metric(&*metrics::QUEUE_OLDEST_SECONDS)
+            .with_readyz_checker(move || {
+                let readiness = readyz.clone();
+                async move { readiness.check() }
+            })
             .run_async(),
     );
 
+    task::spawn(queue_stats::sample_periodically(
+        pool.clone(),
+        config.queue_stats_interval,
+        config.send_max_attempts as i16,
+    ));
+
+    if let Some(interval) = config.cleanup_interval {
+        let cleanup = Cleanup::new(
+            interval,
+            config.cleanup_retention,
+            config.send_max_attempts as i16,
+        );
+        task::spawn(cleanup.run_periodically(pool.clone()));
+    }
+
+    match &config.canary_fcm_uid {
+        Some(fcm_uid) => canary::send(&gateway, fcm_uid.expose(), &readiness).await,
+        None => readiness.set_ready(),
+    }
+
+    // .unwrap() is safe, non-negativity is validated on config load (u32)
+    let empty_queue_poll_period = config.empty_queue_poll_period.to_std().unwrap();
+
+    let mut auth_grace = AuthGrace::new(config.auth_grace_retries);
+
+    let mut maintenance = Maintenance::new(config.maintenance, empty_queue_poll_period);
+
+    let mut circuit_breaker = config
+        .circuit_breaker_failures
+        .map(|failures| CircuitBreaker::new(failures, config.circuit_breaker_cooldown));
+
     loop {
-        let message_to_send = postgres::dequeue(&mut conn, config.send_max_attempts as i16)?;
-
-        match message_to_send {
-            None => {
-                tokio::time::sleep(config.empty_queue_poll_period.to_std().unwrap()).await;
-                // .unwrap() is safe, non-negativity is validated on config load (u32)
-            }
-            Some(message) => {
-                // todo ttl
-                match fcm.send(&message).await {
-                    Ok(()) => {
-                        log::info!("SENT message #{}", message.uid);
-                        log::debug!("BODY: {:?}", message);
-                        postgres::ack(&mut conn, message.uid)?;
-                        log::debug!("DB DELETE message #{}", message.uid);
-                    }
-                    Err(err) => {
-                        log::error!("Failed to send message {} | {:?}", message.uid, err);
-
-                        let backoff_interval = backoff::exponential(
-                            &config.exponential_backoff_initial_interval,
-                            config.exponential_backoff_multiplier,
-                            message.send_attempts_count,
-                        );
-
-                        let scheduled_for = Utc::now() + backoff_interval;
-
-                        postgres::nack(
-                            &mut conn,
-                            message.uid,
-                            message.send_attempts_count as i16 + 1,
-                            format!("{:?}", err),
-                            scheduled_for,
-                        )?;
-
-                        log::debug!(
-                            "Message {} rescheduled for {:?} folowing backoff of {}s",
-                            message.uid,
-                            scheduled_for,
-                            backoff_interval.num_seconds(),
-                        );
-                    }
-                };
+        // A connection is taken from the pool for every cycle, so that a broken connection
+        // is replaced with a new one instead of terminating the service.
+        let mut conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::error!("Failed to get a database connection: {}", err);
+                tokio::time::sleep(empty_queue_poll_period).await;
+                continue;
+            }
+        };
+
+        if maintenance.is_on(&mut conn) {
+            // Messages stay in the queue until maintenance is over
+            tokio::time::sleep(empty_queue_poll_period).await;
+            continue;
+        }
+
+        let circuit_open = circuit_breaker
+            .as_mut()
+            .map_or(false, |breaker| !breaker.allows(Instant::now()));
+        if circuit_open {
+            // Messages stay in the queue until the cooldown is over
+            tokio::time::sleep(empty_queue_poll_period).await;
+            continue;
+        }
+
+        let batch_size = circuit_breaker
+            .as_ref()
+            .map_or(config.batch_size, |breaker| {
+                breaker.batch_size(config.batch_size)
+            });
+
+        let messages = match postgres::dequeue(
+            &mut conn,
+            config.send_max_attempts as i16,
+            config.partition,
+            config.preserve_device_order,
+            batch_size as i64,
+        ) {
+            Ok(messages) => messages,
+            Err(err) => {
+                log::error!("Failed to dequeue messages: {:?}", err);
+                tokio::time::sleep(empty_queue_poll_period).await;
+                continue;
+            }
+        };
+
+        if messages.is_empty() {
+            // Normally impossible because of `on delete cascade`, but such messages
+            // would be stuck in the queue forever, as `dequeue` can't see them
+            if config.delete_orphaned_messages {
+                match postgres::delete_orphaned(&mut conn) {
+                    Ok(0) => {}
+                    Ok(count) => log::warn!("Deleted {} orphaned messages", count),
+                    Err(err) => log::error!("Failed to delete orphaned messages: {:?}", err),
+                }
+            }
+            tokio::time::sleep(empty_queue_poll_period).await;
+            continue;
+        }
+
+        // The batch is sent concurrently, and the results are written to the database
+        // afterwards, one by one over the same connection
+        let messages = messages.into_iter().map(MessageToSend::with_date_time);
+        let results = send_batch(&gateway, messages, batch_size as usize).await;
+
+        // A single grace retry is spent per batch, as all of its messages fail the same way
+        let auth_failed = results
+            .iter()
+            .any(|(_, res)| matches!(res, Err(err) if err.is_auth()));
+        let auth_retry = auth_failed && auth_grace.retry();
+
+        for (message, res) in results {
+            if let Some(breaker) = &mut circuit_breaker {
+                breaker.record(&res, Instant::now());
+            }
+            match completion(res, auth_retry) {
+                Completion::Ack(sent) => {
+                    auth_grace.reset();
+                    acknowledge(&mut conn, &config, &gateway, &message, &sent);
+                }
+                Completion::RemoveDevice(err) => {
+                    remove_device(&mut conn, &config, &message, &err);
+                }
+                Completion::RetryAuth => {
+                    // The message stays in the queue as is, its attempts are not spent
+                    log::warn!(
+                        "Auth error sending message {}, grace retry {} of {}",
+                        message.uid,
+                        auth_grace.retries(),
+                        config.auth_grace_retries,
+                    );
+                }
+                Completion::Nack(err) => {
+                    log::error!("Failed to send message {} | {:?}", message.uid, err);
+                    reschedule(&mut conn, &config, &message, &err);
+                }
+            }
+        }
+        if auth_retry {
+            tokio::time::sleep(empty_queue_poll_period).await;
+        }
+    }
+}
+
+/// Sends the messages concurrently, up to `concurrency` at a time.
+/// Results come in the order the sends complete.
+async fn send_batch(
+    gateway: &dyn Gateway,
+    messages: impl IntoIterator<Item = MessageToSend>,
+    concurrency: usize,
+) -> Vec<(MessageToSend, Result<Sent, SendError>)> {
+    stream::iter(messages)
+        .map(|message| async move {
+            let res = gateway.send(&message).await;
+            (message, res)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await
+}
+
+/// What is done with a message after a send attempt
+#[derive(Debug)]
+enum Completion {
+    /// Sent, removed from the queue
+    Ack(Sent),
+    /// Dead token, the device is removed along with its messages
+    RemoveDevice(SendError),
+    /// Auth error within the grace retries, the message stays in the queue as is
+    RetryAuth,
+    /// Rescheduled after a backoff
+    Nack(SendError),
+}
+
+fn completion(res: Result<Sent, SendError>, auth_retry: bool) -> Completion {
+    match res {
+        Ok(sent) => Completion::Ack(sent),
+        Err(err @ SendError::TokenInvalid(_)) => Completion::RemoveDevice(err),
+        Err(err) if err.is_auth() && auth_retry => Completion::RetryAuth,
+        Err(err) => Completion::Nack(err),
+    }
+}
+
+#[test]
+fn test_send_batch() {
+    struct MockGateway;
+
+    #[async_trait]
+    impl Gateway for MockGateway {
+        async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError> {
+            match message.uid {
+                2 => Err(fcm::FcmError::ServerError(None).into()),
+                4 => Err(SendError::TokenInvalid("NotRegistered".to_string())),
+                _ => Ok(Sent::default()),
+            }
+        }
+    }
+
+    let message = |uid| MessageToSend {
+        uid,
+        fcm_uid: format!("fcm_uid_{}", uid),
+        ..MessageToSend::sample()
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let results = rt.block_on(send_batch(&MockGateway, (1..=5).map(message), 3));
+    let mut completions = results
+        .into_iter()
+        .map(|(message, res)| (message.uid, completion(res, false)))
+        .collect::<Vec<_>>();
+    completions.sort_by_key(|(uid, _)| *uid);
+
+    // Every message of the batch is handled independently of the failed ones
+    assert_eq!(completions.len(), 5);
+    assert!(matches!(completions[0], (1, Completion::Ack(_))));
+    assert!(matches!(completions[1], (2, Completion::Nack(_))));
+    assert!(matches!(completions[2], (3, Completion::Ack(_))));
+    assert!(matches!(completions[3], (4, Completion::RemoveDevice(_))));
+    assert!(matches!(completions[4], (5, Completion::Ack(_))));
+
+    // Auth errors are nacked once the grace retries are exhausted
+    let auth_error = || Err(fcm::FcmError::Unauthorized.into());
+    assert!(matches!(
+        completion(auth_error(), true),
+        Completion::RetryAuth
+    ));
+    assert!(matches!(
+        completion(auth_error(), false),
+        Completion::Nack(_)
+    ));
+}
+
+fn acknowledge(
+    conn: &mut PgConnection,
+    config: &config::Config,
+    gateway: &dyn Gateway,
+    message: &MessageToSend,
+    sent: &Sent,
+) {
+    log::info!("SENT message #{}", message.uid);
+    let latency = delivery_latency(message, Utc::now());
+    metrics::NOTIFICATION_LATENCY.observe(latency.as_secs_f64());
+    log::debug!("BODY: {:?}", message);
+    let attempts = message.send_attempts_count as i16;
+    match postgres::ack(conn, message.uid, attempts) {
+        Ok(Outcome::Done) => {
+            log::debug!("DB DELETE message #{}", message.uid);
+            if config.store_payloads || config.store_message_ids {
+                store_audit_record(conn, gateway, message, sent, config);
             }
         }
+        Ok(Outcome::AlreadyHandled) => {
+            log::info!("Message #{} was already handled", message.uid);
+        }
+        Err(err) => {
+            // The message will be sent once again, unfortunately
+            log::error!("Failed to ack message {} | {:?}", message.uid, err);
+        }
+    }
+    if let Some(canonical_fcm_uid) = &sent.canonical_fcm_uid {
+        migrate_token(conn, message, canonical_fcm_uid);
+    }
+}
+
+/// The device is gone along with its messages (`on delete cascade`), this one included,
+/// so there is nothing to nack, unless the device could not be removed
+fn remove_device(
+    conn: &mut PgConnection,
+    config: &config::Config,
+    message: &MessageToSend,
+    err: &SendError,
+) {
+    log::warn!("Failed to send message {} | {:?}", message.uid, err);
+    let removed = if config.audit_removed_devices {
+        postgres::delete_device_audited(conn, &message.fcm_uid, &err.to_string())
+    } else {
+        postgres::delete_device_by_fcm_uid(conn, &message.fcm_uid).map(|count| (count, 0))
+    };
+    match removed {
+        Ok((count, last_devices)) => {
+            log::info!("DB DELETE {} devices of message #{}", count, message.uid);
+            metrics::INVALID_TOKEN_DEVICES_REMOVED.inc_by(count as u64);
+            metrics::LAST_DEVICES_REMOVED.inc_by(last_devices as u64);
+        }
+        Err(db_err) => {
+            log::error!(
+                "Failed to remove device of message {} | {:?}",
+                message.uid,
+                db_err
+            );
+            reschedule(conn, config, message, err);
+        }
+    }
+}
+
+/// Nacks the message, scheduling the next attempt after a backoff
+fn reschedule(
+    conn: &mut PgConnection,
+    config: &config::Config,
+    message: &MessageToSend,
+    err: &SendError,
+) {
+    let backoff_interval = backoff::exponential_jittered(
+        &config.exponential_backoff_initial_interval,
+        config.exponential_backoff_multiplier,
+        message.send_attempts_count,
+        &config.exponential_backoff_max_interval,
+        config.exponential_backoff_jitter,
+        &mut rand::thread_rng(),
+    );
+
+    let scheduled_for = Utc::now() + backoff_interval;
+
+    let res = postgres::nack(
+        conn,
+        message.uid,
+        message.send_attempts_count as i16,
+        format!("{:?}", err),
+        scheduled_for,
+    );
+    match res {
+        Ok(Outcome::Done) => {}
+        Ok(Outcome::AlreadyHandled) => {
+            log::info!("Message #{} was already handled", message.uid);
+            return;
+        }
+        Err(err) => {
+            log::error!("Failed to nack message {} | {:?}", message.uid, err);
+            return;
+        }
+    }
+
+    log::debug!(
+        "Message {} rescheduled for {:?} folowing backoff of {}s",
+        message.uid,
+        scheduled_for,
+        backoff_interval.num_seconds(),
+    );
+}
+
+/// Errors are logged only: the old token keeps working for a while, and FCM reports
+/// the canonical one again with the next message
+fn migrate_token(conn: &mut PgConnection, message: &MessageToSend, canonical_fcm_uid: &str) {
+    log::info!(
+        "Device token of message #{} is migrated to the canonical one",
+        message.uid
+    );
+    match postgres::update_fcm_uid(conn, &message.fcm_uid, canonical_fcm_uid) {
+        Ok(count) => metrics::DEVICE_TOKENS_MIGRATED.inc_by(count as u64),
+        Err(err) => {
+            log::error!(
+                "Failed to migrate device token of message {} | {:?}",
+                message.uid,
+                err
+            )
+        }
     }
 }
 
@@ -99,6 +480,17 @@ pub struct MessageToSend {
     pub notification_body: String,
     pub data: Option<serde_json::Value>,
     pub collapse_key: Option<String>,
+    pub group_key: Option<String>,
+    pub event_received_at: Option<DateTime<Utc>>,
+    pub event_timestamp: Option<DateTime<Utc>>,
+    pub delivery_style: Option<String>,
+    pub time_to_live: Option<i32>,
+    pub priority: Option<String>,
+    pub utc_offset_seconds: i32,
+    /// Language of the device, which the date and time are formatted as per
+    pub lang: String,
+    /// Platform of the device, see `MessageToSend::platform`
+    pub platform: String,
     pub fcm_uid: String,
 }
 
@@ -107,7 +499,7 @@ impl fmt::Debug for MessageToSend {
         // Intentionally avoid printing fcm_uid for security reasons
         write!(
             f,
-            "MessageToSend {{ uid: {}, created_at: {:?}, updated_at: {:?}, send_error: {:?}, send_attempts_count: {}, notification_title: {}, notification_body: {}, data: {:?}, collapse_key: {:?}, fcm_uid: *** }}",
+            "MessageToSend {{ uid: {}, created_at: {:?}, updated_at: {:?}, send_error: {:?}, send_attempts_count: {}, notification_title: {}, notification_body: {}, data: {:?}, collapse_key: {:?}, group_key: {:?}, event_received_at: {:?}, event_timestamp: {:?}, delivery_style: {:?}, time_to_live: {:?}, priority: {:?}, utc_offset_seconds: {}, lang: {}, platform: {}, fcm_uid: *** }}",
             self.uid,
             self.created_at,
             self.updated_at,
@@ -117,92 +509,904 @@ impl fmt::Debug for MessageToSend {
             self.notification_body,
             self.data,
             self.collapse_key,
+            self.group_key,
+            self.event_received_at,
+            self.event_timestamp,
+            self.delivery_style,
+            self.time_to_live,
+            self.priority,
+            self.utc_offset_seconds,
+            self.lang,
+            self.platform,
         )
     }
 }
 
+impl MessageToSend {
+    /// Fills in the date and time left to be formatted at send time (if any),
+    /// in the current timezone of the device
+    fn with_date_time(mut self) -> Self {
+        if let Some(event_timestamp) = self.event_timestamp {
+            let timestamp =
+                Timestamp::from_unix_timestamp_millis(event_timestamp.timestamp_millis());
+            let offset = self.utc_offset_seconds;
+            let fill = |text: &str| fill_date_time(text, timestamp, offset, &self.lang);
+            self.notification_title = fill(&self.notification_title);
+            self.notification_body = fill(&self.notification_body);
+        }
+        self
+    }
+
+    /// Messages queued before the delivery style was stored carry both notification and data
+    fn delivery_style(&self) -> DeliveryStyle {
+        match self.delivery_style.as_deref() {
+            Some(style) => DeliveryStyle::parse(style).unwrap_or_else(|| {
+                log::warn!("Unknown delivery style {} of message #{}", style, self.uid);
+                DeliveryStyle::default()
+            }),
+            None => DeliveryStyle::default(),
+        }
+    }
+
+    /// Messages queued before the priority was stored are sent as high priority
+    fn priority(&self) -> MessagePriority {
+        match self.priority.as_deref() {
+            Some(priority) => MessagePriority::parse(priority).unwrap_or_else(|| {
+                log::warn!("Unknown priority {} of message #{}", priority, self.uid);
+                MessagePriority::default()
+            }),
+            None => MessagePriority::default(),
+        }
+    }
+
+    /// Time to live left out of the configured one after the time spent in the queue, seconds,
+    /// so that a message delayed by retries or quiet hours doesn't outlive its purpose
+    fn remaining_time_to_live(&self) -> Option<i32> {
+        self.time_to_live.map(|ttl| {
+            let queued_secs = (Utc::now() - self.created_at).num_seconds();
+            (ttl as i64 - queued_secs.max(0)).clamp(0, MAX_TIME_TO_LIVE as i64) as i32
+        })
+    }
+
+    /// Unknown platforms are stored as web on registration,
+    /// so an unknown one here means the database was changed by hand
+    fn platform(&self) -> Platform {
+        Platform::parse(&self.platform).unwrap_or_else(|| {
+            log::warn!(
+                "Unknown platform {} of message #{}",
+                self.platform,
+                self.uid
+            );
+            Platform::default()
+        })
+    }
+}
+
 struct FcmRemoteGateway {
     client: fcm::Client,
-    api_key: String,
-    click_action: String,
+    api_key: Secret<String>,
+    click_actions: ClickActions,
     dry_run: bool,
+    omit_empty_data_fields: bool,
 }
 
-impl FcmRemoteGateway {
-    pub async fn send(&self, message: &MessageToSend) -> anyhow::Result<()> {
-        if !self.dry_run {
-            let fcm_msg = self.fcm_message(&message);
-            let fcm_response = self.client.send(fcm_msg).await?; // todo handle errors from FcmResponse body
-            log::debug!("Message #{} {:?}", message.uid, fcm_response);
+#[async_trait]
+impl Gateway for FcmRemoteGateway {
+    async fn send(&self, message: &MessageToSend) -> Result<Sent, SendError> {
+        let fcm_msg = self.fcm_message(&message);
+        if self.dry_run {
+            // The message is acked as usual, so it is only the log telling it wasn't really sent
+            log::info!(
+                "DRY RUN: message #{} not sent, payload: {}",
+                message.uid,
+                dry_run_payload(&fcm_msg)
+            );
+            metrics::DRY_RUN_SENDS.inc();
+            return Ok(Sent::default());
         }
-        Ok(())
+        let fcm_response = self.client.send(fcm_msg).await?;
+        log::debug!("Message #{} {:?}", message.uid, fcm_response);
+        check_response(&fcm_response)
+    }
+
+    fn audit_payload(&self, message: &MessageToSend) -> Option<serde_json::Value> {
+        let fcm_msg = self.fcm_message(message);
+        let mut payload = serde_json::to_value(&fcm_msg.body).expect("serialize json");
+        if let Some(fields) = payload.as_object_mut() {
+            // Redacted just like in the `Debug` impl of `MessageToSend`
+            for field in ["to", "registration_ids"] {
+                if let Some(value) = fields.get_mut(field) {
+                    *value = "***".into();
+                }
+            }
+        }
+        Some(payload)
+    }
+}
+
+/// The legacy API replies with 200 OK even if the message is not delivered,
+/// with the error (or the canonical token) in the result for the device
+fn check_response(response: &fcm::FcmResponse) -> Result<Sent, SendError> {
+    if let Some(reason) = &response.error {
+        return Err(reason.into());
     }
+    let result = match response
+        .results
+        .as_ref()
+        .and_then(|results| results.first())
+    {
+        Some(result) => result,
+        // Topic message
+        None => {
+            return Ok(Sent {
+                canonical_fcm_uid: None,
+                message_id: response.message_id.map(|id| id.to_string()),
+            })
+        }
+    };
+    if let Some(reason) = &result.error {
+        return Err(reason.into());
+    }
+    Ok(Sent {
+        canonical_fcm_uid: result.registration_id.clone(),
+        message_id: result.message_id.clone(),
+    })
+}
+
+/// FCM rejects messages with a longer TTL, seconds (4 weeks, which is also the default)
+const MAX_TIME_TO_LIVE: i32 = 2_419_200;
+
+/// The request body which would be sent to FCM (without the API key, which is a header)
+fn dry_run_payload(fcm_msg: &fcm::Message) -> String {
+    serde_json::to_string(&fcm_msg.body).expect("serialize json")
+}
 
+impl FcmRemoteGateway {
     fn fcm_message<'a: 'b, 'b>(&'a self, message: &'b MessageToSend) -> fcm::Message<'b> {
-        let notification = {
-            let mut builder = fcm::NotificationBuilder::new();
-            builder.title(&message.notification_title);
-            builder.body(&message.notification_body);
-            builder.click_action(&self.click_action);
-            builder.finalize()
-        };
+        let delivery_style = message.delivery_style();
+        let mut builder = fcm::MessageBuilder::new(self.api_key.expose(), &message.fcm_uid);
 
-        let mut builder = fcm::MessageBuilder::new(&self.api_key, &message.fcm_uid);
-        builder.notification(notification);
+        if delivery_style.has_notification() {
+            let notification = {
+                let mut builder = fcm::NotificationBuilder::new();
+                builder.title(&message.notification_title);
+                builder.body(&message.notification_body);
+                builder.click_action(self.click_actions.for_platform(message.platform()));
+                builder.finalize()
+            };
+            builder.notification(notification);
+        }
 
-        // message must have `data` field from DB or at least an empty object
-        builder
-            .data(message.data.as_ref().unwrap_or(&serde_json::json!("{}")))
-            .unwrap(); // serde_json::Value guarantees success
+        if delivery_style.has_data() {
+            let data = payload_data(message, self.omit_empty_data_fields);
+            metrics::PAYLOAD_DATA_SIZE.observe(data.to_string().len() as f64);
+            builder.data(&data).unwrap(); // serde_json::Value guarantees success
+        }
 
-        // todo collapse key
-        // if let Some(k) = collapse_key {
-        // builder.collapse_key(&k);
-        // }
+        if let Some(collapse_key) = &message.collapse_key {
+            builder.collapse_key(collapse_key);
+        }
 
-        // todo ttl
-        // todo priority
+        if let Some(ttl) = message.remaining_time_to_live() {
+            builder.time_to_live(ttl);
+        }
+
+        builder.priority(match message.priority() {
+            MessagePriority::High => fcm::Priority::High,
+            MessagePriority::Normal => fcm::Priority::Normal,
+        });
 
         builder.finalize()
     }
 }
 
+/// Message must have `data` field from DB or at least an empty object.
+///
+/// The group key is passed in `data` rather than as the Android notification `tag`,
+/// because notifications with the same tag replace each other (just like with a collapse key),
+/// and the legacy FCM API has no equivalent of the APNs `thread-id`.
+/// The apps group notifications in the tray by this field. The v1 API sets the APNs `thread-id`
+/// as well, see `FcmV1Gateway`.
+///
+/// FCM limits the payload size (4KB), so the fields which are null or empty strings
+/// can be left out - the apps treat a missing field the same way.
+fn payload_data(message: &MessageToSend, omit_empty_fields: bool) -> serde_json::Value {
+    let mut data = message
+        .data
+        .clone()
+        .unwrap_or_else(|| serde_json::json!({}));
+    if let Some(fields) = data.as_object_mut() {
+        if let Some(group_key) = &message.group_key {
+            fields.insert("group".to_string(), group_key.clone().into());
+        }
+        if omit_empty_fields {
+            fields.retain(|_, value| !value.is_null() && value.as_str() != Some(""));
+        }
+    }
+    data
+}
+
+/// Audit record of a sent message: its payload and/or FCM message id, as configured
+#[derive(PartialEq, Debug)]
+struct AuditRecord {
+    payload: Option<serde_json::Value>,
+    fcm_message_id: Option<String>,
+}
+
+/// `None` if there is nothing to store (like the message id of a dry run)
+fn audit_record(
+    gateway: &dyn Gateway,
+    message: &MessageToSend,
+    sent: &Sent,
+    store_payloads: bool,
+    store_message_ids: bool,
+) -> Option<AuditRecord> {
+    let payload = store_payloads
+        .then(|| gateway.audit_payload(message))
+        .flatten();
+    let fcm_message_id = store_message_ids.then(|| sent.message_id.clone()).flatten();
+    (payload.is_some() || fcm_message_id.is_some()).then_some(AuditRecord {
+        payload,
+        fcm_message_id,
+    })
+}
+
+/// Errors are logged only: the message is sent already, so it's only the audit record missing
+fn store_audit_record(
+    conn: &mut PgConnection,
+    gateway: &dyn Gateway,
+    message: &MessageToSend,
+    sent: &Sent,
+    config: &config::Config,
+) {
+    let (store_payloads, store_message_ids) = (config.store_payloads, config.store_message_ids);
+    let record = match audit_record(gateway, message, sent, store_payloads, store_message_ids) {
+        Some(record) => record,
+        None => return,
+    };
+    let res =
+        postgres::store_audit_record(conn, message.uid, record.payload, record.fcm_message_id);
+    if let Err(err) = res {
+        log::error!(
+            "Failed to store audit record of message {} | {:?}",
+            message.uid,
+            err
+        );
+    }
+}
+
+/// Time from the event receipt by a processor to sending the message.
+/// Messages enqueued before the receipt time was recorded are measured from their creation.
+fn delivery_latency(message: &MessageToSend, now: DateTime<Utc>) -> std::time::Duration {
+    let since = message.event_received_at.unwrap_or(message.created_at);
+    // Clocks of the processor and the sender may be slightly off
+    (now - since).to_std().unwrap_or_default()
+}
+
+#[test]
+fn test_delivery_latency() {
+    use chrono::Duration;
+
+    let now = Utc::now();
+    let message = |created_at, event_received_at| MessageToSend {
+        created_at,
+        updated_at: created_at,
+        event_received_at,
+        ..MessageToSend::sample()
+    };
+
+    let created_at = now - Duration::seconds(3);
+    let received_at = now - Duration::seconds(5);
+    assert_eq!(
+        delivery_latency(&message(created_at, Some(received_at)), now),
+        std::time::Duration::from_secs(5)
+    );
+    assert_eq!(
+        delivery_latency(&message(created_at, None), now),
+        std::time::Duration::from_secs(3)
+    );
+    let received_at = now + Duration::milliseconds(100);
+    assert_eq!(
+        delivery_latency(&message(created_at, Some(received_at)), now),
+        std::time::Duration::ZERO
+    );
+}
+
+#[test]
+fn test_with_date_time() {
+    use chrono::TimeZone;
+
+    let message = |body: &str, event_timestamp, utc_offset_seconds| MessageToSend {
+        notification_title: "Order filled".to_string(),
+        notification_body: body.to_string(),
+        event_timestamp,
+        utc_offset_seconds,
+        lang: "ru".to_string(),
+        ..MessageToSend::sample()
+    };
+    let event_timestamp = Some(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+
+    // Timezone of the device at send time
+    let deferred = "Filled at [%s:time] on [%s:date]";
+    let msg = message(deferred, event_timestamp, 3 * 3600).with_date_time();
+    assert_eq!(msg.notification_body, "Filled at 01:13:20 on 15.11.2023");
+    assert_eq!(msg.notification_title, "Order filled");
+
+    // Formatted at enqueue time, or enqueued before the event timestamp was stored
+    let formatted = "Filled at 22:13:20 on 14.11.2023";
+    let msg = message(formatted, event_timestamp, 3 * 3600).with_date_time();
+    assert_eq!(msg.notification_body, formatted);
+    let msg = message(deferred, None, 3 * 3600).with_date_time();
+    assert_eq!(msg.notification_body, deferred);
+}
+
+#[cfg(test)]
+impl MessageToSend {
+    /// A message to a web device, with the fields tests don't care about left empty
+    fn sample() -> Self {
+        MessageToSend {
+            uid: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            send_error: None,
+            send_attempts_count: 0,
+            notification_title: "title".to_string(),
+            notification_body: "body".to_string(),
+            data: None,
+            collapse_key: None,
+            group_key: None,
+            event_received_at: None,
+            event_timestamp: None,
+            delivery_style: None,
+            time_to_live: None,
+            priority: None,
+            utc_offset_seconds: 0,
+            lang: "en".to_string(),
+            platform: "web".to_string(),
+            fcm_uid: "fcm_uid".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl FcmRemoteGateway {
+    /// With a fake API key, so only a dry run one can send
+    fn sample(dry_run: bool) -> Self {
+        FcmRemoteGateway {
+            client: fcm::Client::new(),
+            api_key: Secret::new("api_key".to_string()),
+            click_actions: ClickActions {
+                default: "open".to_string(),
+                ios: None,
+                android: None,
+                web: None,
+            },
+            dry_run,
+            omit_empty_data_fields: true,
+        }
+    }
+}
+
+#[test]
+fn test_dry_run() {
+    let gateway = FcmRemoteGateway::sample(true);
+    let message = MessageToSend {
+        data: Some(serde_json::json!({"type": "order_executed"})),
+        ..MessageToSend::sample()
+    };
+
+    let payload = dry_run_payload(&gateway.fcm_message(&message));
+    let mut payload = serde_json::from_str::<serde_json::Value>(&payload).unwrap();
+    assert_eq!(payload["to"], "fcm_uid");
+
+    // Stored for audit with the device token masked, otherwise the same
+    let audit_payload = gateway.audit_payload(&message).unwrap();
+    assert!(!audit_payload.to_string().contains("fcm_uid"));
+    payload["to"] = "***".into();
+    assert_eq!(audit_payload, payload);
+
+    assert_eq!(payload["notification"]["title"], "title");
+    assert_eq!(payload["notification"]["click_action"], "open");
+    assert_eq!(payload["data"]["type"], "order_executed");
+    assert!(!payload.to_string().contains("api_key"));
+
+    // No network call is made (it would fail with this API key)
+    let sends_before = metrics::DRY_RUN_SENDS.get();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    assert!(rt.block_on(gateway.send(&message)).is_ok());
+    assert!(metrics::DRY_RUN_SENDS.get() > sends_before);
+}
+
+#[test]
+fn test_check_response() {
+    use serde_json::json;
+
+    let check = |response: serde_json::Value| {
+        check_response(&serde_json::from_value::<fcm::FcmResponse>(response).unwrap())
+    };
+    let result = |result: serde_json::Value| {
+        check(json!({
+            "multicast_id": 1,
+            "success": 1,
+            "failure": 0,
+            "canonical_ids": 0,
+            "results": [result],
+        }))
+    };
+
+    // Delivered: the message is acked
+    let res = result(json!({"message_id": "0:1"}));
+    assert_eq!(res.unwrap().message_id.as_deref(), Some("0:1"));
+    let res = check(json!({"message_id": 1}));
+    assert_eq!(res.unwrap().message_id.as_deref(), Some("1"));
+
+    // Delivered, but the device token is outdated: it is replaced with the canonical one
+    let res = result(json!({"message_id": "0:1", "registration_id": "new_fcm_uid"}));
+    assert_eq!(
+        res.unwrap().canonical_fcm_uid.as_deref(),
+        Some("new_fcm_uid")
+    );
+
+    // Dead token: the device is removed instead of a nack
+    for reason in ["NotRegistered", "InvalidRegistration"] {
+        let res = result(json!({ "error": reason }));
+        assert!(matches!(res, Err(SendError::TokenInvalid(r)) if r == reason));
+    }
+    let res = check(json!({"error": "NotRegistered"}));
+    assert!(matches!(res, Err(SendError::TokenInvalid(_))));
+
+    // Other errors: the message is nacked and retried as usual
+    let res = result(json!({"error": "Unavailable"}));
+    assert!(matches!(res, Err(SendError::Rejected(r)) if r == "Unavailable"));
+}
+
+#[test]
+fn test_audit_record() {
+    use serde_json::json;
+
+    let gateway = FcmRemoteGateway::sample(false);
+    let message = MessageToSend::sample();
+    let response = json!({"success": 1, "results": [{"message_id": "0:1516"}]});
+    let sent = check_response(&serde_json::from_value(response).unwrap()).unwrap();
+
+    // The id returned by FCM is recorded for the successfully sent message
+    let record = audit_record(&gateway, &message, &sent, false, true).unwrap();
+    assert_eq!(record.fcm_message_id.as_deref(), Some("0:1516"));
+    assert_eq!(record.payload, None);
+
+    let record = audit_record(&gateway, &message, &sent, true, true).unwrap();
+    assert_eq!(record.fcm_message_id.as_deref(), Some("0:1516"));
+    assert_eq!(record.payload.unwrap()["to"], "***");
+
+    let record = audit_record(&gateway, &message, &sent, true, false).unwrap();
+    assert_eq!(record.fcm_message_id, None);
+
+    // Nothing to store without an id (dry run)
+    assert_eq!(
+        audit_record(&gateway, &message, &Sent::default(), false, true),
+        None
+    );
+}
+
+#[test]
+fn test_time_to_live() {
+    let gateway = FcmRemoteGateway::sample(true);
+    let payload_queued = |time_to_live: Option<i32>, queued: chrono::Duration| {
+        let message = MessageToSend {
+            data: Some(serde_json::json!({"type": "price_threshold_reached"})),
+            time_to_live,
+            created_at: Utc::now() - queued,
+            ..MessageToSend::sample()
+        };
+        let payload = dry_run_payload(&gateway.fcm_message(&message));
+        serde_json::from_str::<serde_json::Value>(&payload).unwrap()
+    };
+    let payload =
+        |time_to_live: Option<i32>| payload_queued(time_to_live, chrono::Duration::zero());
+
+    assert_eq!(payload(Some(3600))["time_to_live"], 3600);
+    // Messages queued before the TTL was stored are kept by FCM for the default period
+    assert!(payload(None).get("time_to_live").is_none());
+    // Beyond the FCM limit
+    assert_eq!(payload(Some(i32::MAX))["time_to_live"], MAX_TIME_TO_LIVE);
+    // The time spent in the queue is taken off
+    let queued = chrono::Duration::minutes(10);
+    assert_eq!(payload_queued(Some(3600), queued)["time_to_live"], 3000);
+    let queued = chrono::Duration::hours(2);
+    assert_eq!(payload_queued(Some(3600), queued)["time_to_live"], 0);
+}
+
+#[test]
+fn test_priority() {
+    let gateway = FcmRemoteGateway::sample(true);
+    let payload = |priority: Option<&str>| {
+        let message = MessageToSend {
+            data: Some(serde_json::json!({"type": "digest"})),
+            priority: priority.map(ToString::to_string),
+            ..MessageToSend::sample()
+        };
+        let payload = dry_run_payload(&gateway.fcm_message(&message));
+        serde_json::from_str::<serde_json::Value>(&payload).unwrap()
+    };
+
+    // As stored on enqueue
+    for priority in [MessagePriority::High, MessagePriority::Normal] {
+        assert_eq!(
+            payload(Some(priority.as_str()))["priority"],
+            priority.as_str()
+        );
+    }
+    // Queued before the priority was stored, or unknown
+    assert_eq!(payload(None)["priority"], "high");
+    assert_eq!(payload(Some("urgent"))["priority"], "high");
+}
+
+#[test]
+fn test_delivery_style() {
+    let gateway = FcmRemoteGateway::sample(true);
+    let message = |delivery_style: Option<&str>| MessageToSend {
+        data: Some(serde_json::json!({"type": "price_threshold_reached"})),
+        delivery_style: delivery_style.map(ToString::to_string),
+        ..MessageToSend::sample()
+    };
+    let payload_of = |delivery_style| {
+        let payload = dry_run_payload(&gateway.fcm_message(&message(delivery_style)));
+        serde_json::from_str::<serde_json::Value>(&payload).unwrap()
+    };
+
+    // Both, also for messages queued before the style was stored, or with an unknown one
+    for style in [Some("notification_and_data"), None, Some("unknown")] {
+        let payload = payload_of(style);
+        assert_eq!(payload["notification"]["title"], "title");
+        assert_eq!(payload["data"]["type"], "price_threshold_reached");
+    }
+
+    let payload = payload_of(Some("data_only"));
+    assert!(payload.get("notification").is_none());
+    assert_eq!(payload["data"]["type"], "price_threshold_reached");
+    assert_eq!(payload["to"], "fcm_uid");
+
+    let payload = payload_of(Some("notification_only"));
+    assert_eq!(payload["notification"]["body"], "body");
+    assert!(payload.get("data").is_none());
+}
+
+#[test]
+fn test_payload_data() {
+    use serde_json::json;
+
+    let message = |data: Option<serde_json::Value>, group_key: Option<&str>| MessageToSend {
+        data,
+        group_key: group_key.map(ToString::to_string),
+        ..MessageToSend::sample()
+    };
+
+    let data = json!({"type": "price_threshold_reached", "address": "1234567890"});
+    assert_eq!(
+        payload_data(&message(Some(data.clone()), Some("price_alerts")), true),
+        json!({"type": "price_threshold_reached", "address": "1234567890", "group": "price_alerts"})
+    );
+    assert_eq!(payload_data(&message(Some(data.clone()), None), true), data);
+    assert_eq!(
+        payload_data(&message(None, Some("orders")), true),
+        json!({"group": "orders"})
+    );
+    assert_eq!(payload_data(&message(None, None), true), json!({}));
+
+    // Empty fields
+    let data = json!({
+        "type": "digest",
+        "count": 0,
+        "address": "1234567890",
+        "deep_link": null,
+        "label": "",
+        "flags": [],
+    });
+    assert_eq!(
+        payload_data(&message(Some(data.clone()), None), true),
+        json!({"type": "digest", "count": 0, "address": "1234567890", "flags": []})
+    );
+    assert_eq!(
+        payload_data(&message(Some(data.clone()), None), false),
+        data
+    );
+}
+
 // todo db transactions
 mod postgres {
-    use crate::MessageToSend;
+    use crate::{
+        ordering::{no_older_pending_sql_filter, Partition},
+        queue_stats::QueueStats,
+        MessageToSend,
+    };
     use chrono::{DateTime, Utc};
-    use database::schema::{devices, messages};
-    use diesel::{prelude::*, PgConnection};
+    use database::{
+        schema::{
+            devices, message_payloads, messages, removed_devices, service_state, subscribers,
+        },
+        state::SENDER_MAINTENANCE_KEY,
+    };
+    use diesel::{
+        dsl::{count_star, min, sql},
+        pg::Pg,
+        prelude::*,
+        r2d2::{ConnectionManager, ManageConnection, Pool, PoolError},
+        sql_types::Bool,
+        PgConnection,
+    };
+    use std::time::Duration;
+
+    pub type PgPool = Pool<ConnectionManager<PgConnection>>;
+
+    pub fn pool(
+        database_url: &str,
+        max_size: u32,
+        connection_timeout: Duration,
+    ) -> anyhow::Result<PgPool> {
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        Ok(build_pool(manager, max_size, connection_timeout)?)
+    }
+
+    // Connections are validated on checkout, broken ones are dropped and replaced
+    fn build_pool<M: ManageConnection>(
+        manager: M,
+        max_size: u32,
+        connection_timeout: Duration,
+    ) -> Result<Pool<M>, PoolError> {
+        Pool::builder()
+            .max_size(max_size)
+            .min_idle(Some(0))
+            .connection_timeout(connection_timeout)
+            .test_on_check_out(true)
+            .build(manager)
+    }
+
+    /// Result of an ack/nack.
+    ///
+    /// Both are guarded by the number of send attempts seen at dequeue time,
+    /// so if the message was meanwhile handled by another replica (or a retry),
+    /// nothing is changed and this is not considered an error.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Outcome {
+        Done,
+        AlreadyHandled,
+    }
+
+    impl Outcome {
+        fn from_affected_rows(count: usize) -> Self {
+            debug_assert!(count <= 1, "message uid is a primary key");
+            if count == 0 {
+                Outcome::AlreadyHandled
+            } else {
+                Outcome::Done
+            }
+        }
+    }
 
     // todo separate business logic from DB I/O
     pub fn nack(
         conn: &mut PgConnection,
         message_uid: i32,
-        new_send_attempts_count: i16,
+        send_attempts_count: i16,
         new_send_error: String,
         new_scheduled_for: DateTime<Utc>,
-    ) -> anyhow::Result<()> {
-        diesel::update(messages::table)
+    ) -> anyhow::Result<Outcome> {
+        let count = diesel::update(messages::table)
             .filter(messages::uid.eq(message_uid))
+            .filter(messages::send_attempts_count.eq(send_attempts_count))
             .set((
                 messages::scheduled_for.eq(new_scheduled_for),
-                messages::send_attempts_count.eq(new_send_attempts_count),
+                messages::send_attempts_count.eq(send_attempts_count + 1),
                 messages::send_error.eq(new_send_error),
             ))
             .execute(conn)?;
+        Ok(Outcome::from_affected_rows(count))
+    }
+
+    pub fn ack(
+        conn: &mut PgConnection,
+        message_uid: i32,
+        send_attempts_count: i16,
+    ) -> anyhow::Result<Outcome> {
+        let count = diesel::delete(
+            messages::table
+                .filter(messages::uid.eq(message_uid))
+                .filter(messages::send_attempts_count.eq(send_attempts_count)),
+        )
+        .execute(conn)?;
+        Ok(Outcome::from_affected_rows(count))
+    }
+
+    /// Devices with the token, which FCM reported as not registered or invalid,
+    /// along with their messages (`on delete cascade`)
+    pub fn delete_device_by_fcm_uid(
+        conn: &mut PgConnection,
+        fcm_uid: &str,
+    ) -> anyhow::Result<usize> {
+        let count = diesel::delete(devices::table)
+            .filter(devices::fcm_uid.eq(fcm_uid))
+            .execute(conn)?;
+        Ok(count)
+    }
+
+    /// Deletes the devices with the token, like `delete_device_by_fcm_uid`, and records them
+    /// in `removed_devices`. Subscribers left without a device are marked with
+    /// `last_device_removed_at`. Returns the number of devices removed,
+    /// and how many of them were the last device of their subscriber.
+    pub fn delete_device_audited(
+        conn: &mut PgConnection,
+        fcm_uid: &str,
+        reason: &str,
+    ) -> anyhow::Result<(usize, usize)> {
+        conn.transaction(|conn| {
+            let removed = diesel::delete(devices::table)
+                .filter(devices::fcm_uid.eq(fcm_uid))
+                .returning((devices::uid, devices::subscriber_address))
+                .get_results::<(i32, String)>(conn)?;
+            let addresses = removed.iter().map(|(_, address)| address);
+            let with_devices = devices::table
+                .select(devices::subscriber_address)
+                .filter(devices::subscriber_address.eq_any(addresses))
+                .load::<String>(conn)?;
+            let records = removed
+                .iter()
+                .map(|(device_uid, address)| {
+                    (
+                        removed_devices::device_uid.eq(device_uid),
+                        removed_devices::subscriber_address.eq(address),
+                        removed_devices::reason.eq(reason),
+                        removed_devices::last_device.eq(!with_devices.contains(address)),
+                    )
+                })
+                .collect::<Vec<_>>();
+            diesel::insert_into(removed_devices::table)
+                .values(records)
+                .execute(conn)?;
+            let last_devices = diesel::update(subscribers::table)
+                .filter(subscribers::address.eq_any(removed.iter().map(|(_, address)| address)))
+                .filter(subscribers::address.ne_all(&with_devices))
+                .set(subscribers::last_device_removed_at.eq(Utc::now()))
+                .execute(conn)?;
+            Ok((removed.len(), last_devices))
+        })
+    }
+
+    /// Replaces the token of devices with the canonical one reported by FCM.
+    /// The devices of subscribers who have registered the canonical token already
+    /// are removed instead, as they would be duplicates.
+    pub fn update_fcm_uid(
+        conn: &mut PgConnection,
+        fcm_uid: &str,
+        canonical_fcm_uid: &str,
+    ) -> anyhow::Result<usize> {
+        conn.transaction(|conn| {
+            let registered = devices::table
+                .select(devices::subscriber_address)
+                .filter(devices::fcm_uid.eq(canonical_fcm_uid))
+                .load::<String>(conn)?;
+            diesel::delete(devices::table)
+                .filter(devices::fcm_uid.eq(fcm_uid))
+                .filter(devices::subscriber_address.eq_any(registered))
+                .execute(conn)?;
+            let count = diesel::update(devices::table)
+                .filter(devices::fcm_uid.eq(fcm_uid))
+                .set((
+                    devices::fcm_uid.eq(canonical_fcm_uid),
+                    devices::updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)?;
+            Ok(count)
+        })
+    }
+
+    /// Delete messages whose device no longer exists
+    pub fn delete_orphaned(conn: &mut PgConnection) -> anyhow::Result<usize> {
+        let device_exists = devices::table.filter(devices::uid.eq(messages::device_uid));
+        let count = diesel::delete(messages::table)
+            .filter(diesel::dsl::not(diesel::dsl::exists(device_exists)))
+            .execute(conn)?;
+        Ok(count)
+    }
+
+    pub fn store_audit_record(
+        conn: &mut PgConnection,
+        message_uid: i32,
+        payload: Option<serde_json::Value>,
+        fcm_message_id: Option<String>,
+    ) -> anyhow::Result<()> {
+        diesel::insert_into(message_payloads::table)
+            .values((
+                message_payloads::message_uid.eq(message_uid),
+                message_payloads::payload.eq(payload),
+                message_payloads::fcm_message_id.eq(fcm_message_id),
+            ))
+            .execute(conn)?;
         Ok(())
     }
 
-    pub fn ack(conn: &mut PgConnection, message_uid: i32) -> anyhow::Result<()> {
-        diesel::delete(messages::table.filter(messages::uid.eq(message_uid))).execute(conn)?;
+    /// Audit records of messages sent before the cutoff
+    pub fn delete_payloads(
+        conn: &mut PgConnection,
+        cutoff: DateTime<Utc>,
+    ) -> anyhow::Result<usize> {
+        let count = diesel::delete(message_payloads::table)
+            .filter(message_payloads::sent_at.lt(cutoff))
+            .execute(conn)?;
+        Ok(count)
+    }
+
+    /// Messages which ran out of send attempts before the cutoff
+    fn dead_messages(
+        max_send_attempts: i16,
+        cutoff: DateTime<Utc>,
+    ) -> messages::BoxedQuery<'static, Pg> {
+        messages::table
+            .filter(messages::send_attempts_count.ge(max_send_attempts))
+            .filter(messages::scheduled_for.lt(cutoff))
+            .into_boxed()
+    }
+
+    pub fn delete_dead(
+        conn: &mut PgConnection,
+        max_send_attempts: i16,
+        cutoff: DateTime<Utc>,
+    ) -> anyhow::Result<usize> {
+        let dead = dead_messages(max_send_attempts, cutoff).select(messages::uid);
+        let count = diesel::delete(messages::table)
+            .filter(messages::uid.eq_any(dead))
+            .execute(conn)?;
+        Ok(count)
+    }
+
+    /// Refresh the planner statistics, which go stale quickly under the queue churn
+    pub fn analyze_messages(conn: &mut PgConnection) -> anyhow::Result<()> {
+        diesel::sql_query("ANALYZE messages").execute(conn)?;
         Ok(())
     }
 
+    pub fn count_messages(conn: &mut PgConnection) -> anyhow::Result<i64> {
+        Ok(messages::table.count().get_result(conn)?)
+    }
+
+    /// Messages still to be sent, as opposed to the dead ones
+    fn queued_messages(max_send_attempts: i16) -> messages::BoxedQuery<'static, Pg> {
+        messages::table
+            .filter(messages::send_attempts_count.lt(max_send_attempts))
+            .into_boxed()
+    }
+
+    pub fn queue_stats(
+        conn: &mut PgConnection,
+        max_send_attempts: i16,
+    ) -> anyhow::Result<QueueStats> {
+        let (depth, oldest_scheduled_for) = queued_messages(max_send_attempts)
+            .select((count_star(), min(messages::scheduled_for)))
+            .get_result::<(i64, Option<DateTime<Utc>>)>(conn)?;
+        Ok(QueueStats {
+            depth,
+            oldest_scheduled_for,
+        })
+    }
+
+    /// Maintenance mode toggled at runtime, if any
+    pub fn maintenance_toggle(conn: &mut PgConnection) -> anyhow::Result<Option<bool>> {
+        let value = service_state::table
+            .select(service_state::value)
+            .filter(service_state::key.eq(SENDER_MAINTENANCE_KEY))
+            .first::<String>(conn)
+            .optional()?;
+        Ok(value.map(|value| value == "true"))
+    }
+
+    /// Messages due to be sent, up to `batch_size`, the earliest scheduled first
     pub fn dequeue(
         conn: &mut PgConnection,
         max_send_attempts: i16,
-    ) -> anyhow::Result<Option<MessageToSend>> {
-        Ok(messages::table
+        partition: Option<Partition>,
+        preserve_device_order: bool,
+        batch_size: i64,
+    ) -> anyhow::Result<Vec<MessageToSend>> {
+        let mut query = messages::table
             .inner_join(devices::table.on(messages::device_uid.eq(devices::uid)))
             .select((
                 messages::uid,
@@ -214,13 +1418,465 @@ mod postgres {
                 messages::notification_body,
                 messages::data,
                 messages::collapse_key,
+                messages::group_key,
+                messages::event_received_at,
+                messages::event_timestamp,
+                messages::delivery_style,
+                messages::time_to_live,
+                messages::priority,
+                devices::utc_offset_seconds,
+                devices::language,
+                devices::platform,
                 devices::fcm_uid,
             ))
             .filter(messages::send_attempts_count.lt(max_send_attempts))
             .filter(messages::scheduled_for.lt(Utc::now()))
             .order(messages::scheduled_for)
-            .first(conn)
-            .optional()?)
+            .into_boxed();
+        if let Some(partition) = partition {
+            query = query.filter(sql::<Bool>(&partition.sql_filter()));
+        }
+        if preserve_device_order {
+            query = query.filter(sql::<Bool>(&no_older_pending_sql_filter(max_send_attempts)));
+        }
+        Ok(query.limit(batch_size).load(conn)?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{
+            ack, build_pool, dead_messages, delete_device_by_fcm_uid, delete_orphaned, nack,
+            queued_messages, Outcome,
+        };
+        use chrono::Utc;
+        use database::{
+            schema::{devices, messages, subscribers},
+            testing::TestDb,
+        };
+        use diesel::{prelude::*, r2d2::ManageConnection, PgConnection};
+        use std::{
+            sync::{
+                atomic::{AtomicBool, AtomicU32, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        struct TestManager {
+            next_id: AtomicU32,
+            database_down: Arc<AtomicBool>,
+        }
+
+        struct TestConnection {
+            id: u32,
+            dropped: bool,
+        }
+
+        impl ManageConnection for TestManager {
+            type Connection = TestConnection;
+            type Error = std::io::Error;
+
+            fn connect(&self) -> Result<TestConnection, Self::Error> {
+                if self.database_down.load(Ordering::SeqCst) {
+                    return Err(std::io::ErrorKind::ConnectionRefused.into());
+                }
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                Ok(TestConnection { id, dropped: false })
+            }
+
+            fn is_valid(&self, conn: &mut TestConnection) -> Result<(), Self::Error> {
+                if conn.dropped {
+                    Err(std::io::ErrorKind::BrokenPipe.into())
+                } else {
+                    Ok(())
+                }
+            }
+
+            fn has_broken(&self, conn: &mut TestConnection) -> bool {
+                conn.dropped
+            }
+        }
+
+        #[test]
+        fn test_outcome_from_affected_rows() {
+            // Zero affected rows is a successful no-op, not an error
+            assert_eq!(Outcome::from_affected_rows(0), Outcome::AlreadyHandled);
+            assert_eq!(Outcome::from_affected_rows(1), Outcome::Done);
+        }
+
+        #[test]
+        fn test_dead_messages() {
+            use chrono::{TimeZone, Utc};
+            use database::schema::messages;
+            use diesel::{debug_query, pg::Pg, QueryDsl};
+
+            let cutoff = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+            let query = dead_messages(5, cutoff).select(messages::uid);
+            let sql = debug_query::<Pg, _>(&query).to_string();
+            // Only messages out of send attempts, and only after the retention period
+            assert!(sql.contains(r#""messages"."send_attempts_count" >= $1"#));
+            assert!(sql.contains(r#""messages"."scheduled_for" < $2"#));
+            assert!(sql.contains("binds: [5, 2023-11-14T22:13:20Z]"));
+        }
+
+        #[test]
+        fn test_queued_messages() {
+            use database::schema::messages;
+            use diesel::{
+                debug_query,
+                dsl::{count_star, min},
+                pg::Pg,
+                QueryDsl,
+            };
+
+            let query = queued_messages(5).select((count_star(), min(messages::scheduled_for)));
+            let sql = debug_query::<Pg, _>(&query).to_string();
+            // Depth and the oldest message of a single scan, dead messages aside
+            assert!(sql.contains(r#"SELECT COUNT(*), min("messages"."scheduled_for")"#));
+            assert!(sql.contains(r#""messages"."send_attempts_count" < $1"#));
+            assert!(sql.contains("binds: [5]"));
+        }
+
+        /// Queues a message for a new device with the token, returns the message uid
+        fn seed_message(conn: &mut PgConnection, fcm_uid: &str) -> i32 {
+            seed_message_of(conn, "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2", fcm_uid)
+        }
+
+        /// Queues a message for the device of the address with the token
+        fn seed_message_of(conn: &mut PgConnection, address: &str, fcm_uid: &str) -> i32 {
+            diesel::insert_into(subscribers::table)
+                .values(subscribers::address.eq(address))
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .unwrap();
+            let device_uid = diesel::insert_into(devices::table)
+                .values((
+                    devices::fcm_uid.eq(fcm_uid),
+                    devices::subscriber_address.eq(address),
+                    devices::language.eq("en"),
+                    devices::utc_offset_seconds.eq(0),
+                ))
+                .on_conflict((devices::subscriber_address, devices::fcm_uid))
+                .do_update()
+                .set(devices::language.eq("en"))
+                .returning(devices::uid)
+                .get_result::<i32>(conn)
+                .unwrap();
+            diesel::insert_into(messages::table)
+                .values((
+                    messages::device_uid.eq(device_uid),
+                    messages::notification_title.eq("title"),
+                    messages::notification_body.eq("body"),
+                ))
+                .returning(messages::uid)
+                .get_result(conn)
+                .unwrap()
+        }
+
+        fn send_attempts(conn: &mut PgConnection, message_uid: i32) -> Option<i16> {
+            messages::table
+                .select(messages::send_attempts_count)
+                .filter(messages::uid.eq(message_uid))
+                .first(conn)
+                .optional()
+                .unwrap()
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_failover_acks() {
+            use crate::{
+                acknowledge, completion, config::Config, error::SendError, gateway::Failover,
+                send_batch, Completion, Gateway, MessageToSend, Sent,
+            };
+
+            struct MockGateway(fn() -> Result<Sent, SendError>);
+
+            #[async_trait]
+            impl Gateway for MockGateway {
+                async fn send(&self, _message: &MessageToSend) -> Result<Sent, SendError> {
+                    (self.0)()
+                }
+            }
+
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let uid = seed_message(conn, "fcm_uid");
+
+            // The primary is down, the secondary sends the message
+            let gateway = Failover::new(vec![
+                Box::new(MockGateway(|| Err(fcm::FcmError::ServerError(None).into()))),
+                Box::new(MockGateway(|| Ok(Sent::default()))),
+            ]);
+            let config = Config::sample();
+            let messages = super::dequeue(conn, 5, None, false, 10).unwrap();
+            assert_eq!(messages.len(), 1);
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let results = rt.block_on(send_batch(&gateway, messages, 10));
+            for (message, res) in results {
+                match completion(res, false) {
+                    Completion::Ack(sent) => acknowledge(conn, &config, &gateway, &message, &sent),
+                    other => panic!("message {} not sent: {:?}", message.uid, other),
+                }
+            }
+
+            // Acked, so it is gone from the queue
+            assert_eq!(send_attempts(conn, uid), None);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_dequeue_partitions() {
+            use crate::ordering::Partition;
+            use std::collections::HashSet;
+
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            // A phone shared by two addresses, so it has a device row for each
+            let phone = [
+                seed_message_of(conn, "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2", "phone"),
+                seed_message_of(conn, "3PAs2qSeUAfgqSKS8LpZPKGYEjJKcud9Djr", "phone"),
+                seed_message_of(conn, "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2", "phone"),
+            ];
+            let others = (0..10)
+                .map(|n| seed_message(conn, &format!("fcm_uid_{}", n)))
+                .collect::<HashSet<_>>();
+            let partitions = [0, 1].map(|index| Some(Partition { count: 2, index }));
+            let dequeue = |conn: &mut _, partition, preserve_order| {
+                super::dequeue(conn, 5, partition, preserve_order, 100)
+                    .unwrap()
+                    .into_iter()
+                    .map(|message| message.uid)
+                    .collect::<HashSet<_>>()
+            };
+
+            // Every message is in exactly one partition, the phone's ones in the same
+            let [first, second] = partitions.map(|partition| dequeue(conn, partition, false));
+            assert!(first.is_disjoint(&second));
+            assert_eq!(first.len() + second.len(), phone.len() + others.len());
+            assert!(
+                phone.iter().all(|uid| first.contains(uid))
+                    || phone.iter().all(|uid| second.contains(uid))
+            );
+
+            // Both replicas sending at once deliver to the phone one message at a time,
+            // in the order they were queued, whichever address they are for
+            let mut delivered = Vec::<i32>::new();
+            while delivered.len() < phone.len() {
+                let dequeued = partitions
+                    .into_iter()
+                    .flat_map(|partition| dequeue(conn, partition, true))
+                    .collect::<Vec<_>>();
+                let to_phone = dequeued.iter().filter(|uid| phone.contains(uid));
+                assert_eq!(to_phone.clone().count(), 1);
+                delivered.extend(to_phone);
+                for uid in dequeued {
+                    ack(conn, uid, 0).unwrap();
+                }
+            }
+            assert_eq!(delivered, phone);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_queue_stats() {
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let minutes_ago = |minutes| Utc::now() - chrono::Duration::minutes(minutes);
+            let seed = |conn: &mut PgConnection, n, scheduled_for, send_attempts: i16| {
+                let uid = seed_message(conn, &format!("fcm_uid_{}", n));
+                diesel::update(messages::table.filter(messages::uid.eq(uid)))
+                    .set((
+                        messages::scheduled_for.eq(scheduled_for),
+                        messages::send_attempts_count.eq(send_attempts),
+                    ))
+                    .execute(conn)
+                    .unwrap();
+            };
+            assert_eq!(super::queue_stats(conn, 5).unwrap().depth, 0);
+
+            let oldest = minutes_ago(10);
+            seed(conn, 1, oldest, 2);
+            seed(conn, 2, minutes_ago(1), 0);
+            // Retried later
+            seed(conn, 3, minutes_ago(-5), 1);
+            // Out of send attempts, waiting for the cleanup
+            seed(conn, 4, minutes_ago(60), 5);
+
+            let stats = super::queue_stats(conn, 5).unwrap();
+            assert_eq!(stats.depth, 3);
+            // Microseconds are what Postgres keeps
+            let oldest_scheduled_for = stats.oldest_scheduled_for.unwrap();
+            assert!((oldest_scheduled_for - oldest).num_milliseconds().abs() < 1);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_dequeue_priority() {
+            use model::message::MessagePriority;
+
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let stored = [Some("normal"), Some("high"), None];
+            for (n, priority) in stored.iter().enumerate() {
+                let uid = seed_message(conn, &format!("fcm_uid_{}", n));
+                diesel::update(messages::table.filter(messages::uid.eq(uid)))
+                    .set(messages::priority.eq(priority))
+                    .execute(conn)
+                    .unwrap();
+            }
+
+            let mut dequeued = super::dequeue(conn, 5, None, false, 10).unwrap();
+            dequeued.sort_by_key(|message| message.uid);
+            let priorities = dequeued
+                .iter()
+                .map(|message| message.priority())
+                .collect::<Vec<_>>();
+            // Queued before the priority was stored, so high
+            let expected = [
+                MessagePriority::Normal,
+                MessagePriority::High,
+                MessagePriority::High,
+            ];
+            assert_eq!(priorities, expected);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_removed_devices_audit() {
+            use crate::{config::Config, error::SendError, remove_device};
+            use database::schema::removed_devices;
+
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let (first, second) = (
+                "3PPKDQ3G67gekeN8VdKFiE1mGXGS6t2mKu2",
+                "3PAs2qSeUAfgqSKS8LpZPKGYEjJKcud9Djr",
+            );
+            // A phone shared by two addresses, the first one has a tablet as well
+            seed_message_of(conn, first, "phone");
+            seed_message_of(conn, second, "phone");
+            seed_message_of(conn, first, "tablet");
+            let config = Config {
+                audit_removed_devices: true,
+                ..Config::sample()
+            };
+            let message = super::dequeue(conn, 5, None, false, 10)
+                .unwrap()
+                .into_iter()
+                .find(|message| message.fcm_uid == "phone")
+                .unwrap();
+            let err = SendError::TokenInvalid("NotRegistered".to_string());
+            remove_device(conn, &config, &message, &err);
+
+            let mut audit = removed_devices::table
+                .select((
+                    removed_devices::subscriber_address,
+                    removed_devices::reason,
+                    removed_devices::last_device,
+                ))
+                .load::<(String, String, bool)>(conn)
+                .unwrap();
+            audit.sort();
+            let reason = err.to_string();
+            assert_eq!(
+                audit,
+                vec![
+                    (second.to_string(), reason.clone(), true),
+                    (first.to_string(), reason, false),
+                ]
+            );
+
+            // Only the subscriber left without a device is to be re-engaged
+            let marked = subscribers::table
+                .select(subscribers::address)
+                .filter(subscribers::last_device_removed_at.is_not_null())
+                .load::<String>(conn)
+                .unwrap();
+            assert_eq!(marked, vec![second.to_string()]);
+            let devices = devices::table
+                .select(devices::fcm_uid)
+                .load::<String>(conn)
+                .unwrap();
+            assert_eq!(devices, vec!["tablet".to_string()]);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_ack_nack_already_handled() {
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let uid = seed_message(conn, "fcm_uid");
+            let retry_at = Utc::now();
+
+            // Nacked by another replica after both had dequeued the message
+            let nack = |conn: &mut _, attempts| nack(conn, uid, attempts, "err".into(), retry_at);
+            assert_eq!(nack(conn, 0).unwrap(), Outcome::Done);
+            assert_eq!(nack(conn, 0).unwrap(), Outcome::AlreadyHandled);
+            assert_eq!(send_attempts(conn, uid), Some(1));
+
+            // Acked with the attempts seen at dequeue time, a stale ack changes nothing
+            assert_eq!(ack(conn, uid, 0).unwrap(), Outcome::AlreadyHandled);
+            assert_eq!(send_attempts(conn, uid), Some(1));
+            assert_eq!(ack(conn, uid, 1).unwrap(), Outcome::Done);
+            assert_eq!(send_attempts(conn, uid), None);
+            assert_eq!(ack(conn, uid, 1).unwrap(), Outcome::AlreadyHandled);
+            assert_eq!(nack(conn, 1).unwrap(), Outcome::AlreadyHandled);
+        }
+
+        #[test]
+        #[ignore = "needs Postgres"]
+        fn test_messages_of_deleted_devices() {
+            let db = TestDb::new();
+            let conn = &mut db.connect();
+            let kept = seed_message(conn, "fcm_uid_1");
+            let cascaded = seed_message(conn, "fcm_uid_2");
+
+            // Removed along with the device, rather than stuck in the queue
+            assert_eq!(delete_device_by_fcm_uid(conn, "fcm_uid_2").unwrap(), 1);
+            assert_eq!(send_attempts(conn, cascaded), None);
+
+            // Left behind while the foreign key was not in place, purged by the cleanup
+            diesel::sql_query("ALTER TABLE messages DROP CONSTRAINT messages_device_uid_fkey")
+                .execute(conn)
+                .unwrap();
+            let orphaned = seed_message(conn, "fcm_uid_3");
+            diesel::delete(devices::table.filter(devices::fcm_uid.eq("fcm_uid_3")))
+                .execute(conn)
+                .unwrap();
+            assert_eq!(delete_orphaned(conn).unwrap(), 1);
+            assert_eq!(send_attempts(conn, orphaned), None);
+            assert_eq!(send_attempts(conn, kept), Some(0));
+        }
+
+        #[test]
+        fn test_dropped_connection_is_replaced() {
+            let database_down = Arc::new(AtomicBool::new(false));
+            let manager = TestManager {
+                next_id: AtomicU32::new(0),
+                database_down: database_down.clone(),
+            };
+            let pool = build_pool(manager, 1, Duration::from_millis(100)).expect("pool");
+
+            let first_id = {
+                let mut conn = pool.get().expect("connection");
+                // Connection dropped by the server while in use
+                conn.dropped = true;
+                conn.id
+            };
+
+            let conn = pool.get().expect("replacement connection");
+            assert_ne!(conn.id, first_id);
+            drop(conn);
+
+            // Database is unavailable for a while - an error, not a panic,
+            // and the pool recovers once the database is back
+            pool.get().expect("connection").dropped = true;
+            database_down.store(true, Ordering::SeqCst);
+            assert!(pool.get().is_err());
+            database_down.store(false, Ordering::SeqCst);
+            assert!(pool.get().is_ok());
+        }
     }
 }
 
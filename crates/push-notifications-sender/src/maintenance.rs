@@ -0,0 +1,77 @@
+//! Maintenance mode: no messages are sent, while they keep being queued by the processors.
+//!
+//! Enabled with the `SEND_MAINTENANCE` config flag, or toggled at runtime via the API
+//! (`PUT /maintenance`), which overrides the config flag until toggled back.
+
+use std::time::{Duration, Instant};
+
+use diesel::PgConnection;
+
+use crate::postgres;
+
+pub struct Maintenance {
+    configured: bool,
+    toggled: Option<bool>,
+    last_check: Option<Instant>,
+    check_interval: Duration,
+}
+
+impl Maintenance {
+    pub fn new(configured: bool, check_interval: Duration) -> Self {
+        Maintenance {
+            configured,
+            toggled: None,
+            last_check: None,
+            check_interval,
+        }
+    }
+
+    /// The runtime toggle is re-read from the database at most once per `check_interval`
+    pub fn is_on(&mut self, conn: &mut PgConnection) -> bool {
+        let check_due = self.last_check.map_or(true, |last_check| {
+            last_check.elapsed() >= self.check_interval
+        });
+        if check_due {
+            match postgres::maintenance_toggle(conn) {
+                Ok(toggled) => self.update(toggled),
+                Err(err) => log::error!("Failed to check maintenance mode: {:?}", err),
+            }
+            self.last_check = Some(Instant::now());
+        }
+        self.is_active()
+    }
+
+    fn update(&mut self, toggled: Option<bool>) {
+        let was_active = self.is_active();
+        self.toggled = toggled;
+        match (was_active, self.is_active()) {
+            (false, true) => log::info!("Maintenance mode is on, sending paused"),
+            (true, false) => log::info!("Maintenance mode is off, sending resumed"),
+            _ => {}
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.toggled.unwrap_or(self.configured)
+    }
+}
+
+#[test]
+fn test_maintenance() {
+    let mut maintenance = Maintenance::new(false, Duration::from_secs(5));
+    assert!(!maintenance.is_active());
+
+    // Messages are held back while maintenance is on, and flow again afterwards
+    maintenance.update(Some(true));
+    assert!(maintenance.is_active());
+    maintenance.update(Some(false));
+    assert!(!maintenance.is_active());
+
+    // Configured maintenance can be ended at runtime
+    let mut maintenance = Maintenance::new(true, Duration::from_secs(5));
+    assert!(maintenance.is_active());
+    maintenance.update(None);
+    assert!(maintenance.is_active());
+    maintenance.update(Some(false));
+    assert!(!maintenance.is_active());
+}
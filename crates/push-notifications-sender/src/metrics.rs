@@ -0,0 +1,67 @@
+//! Sender metrics
+
+use lazy_static::lazy_static;
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge};
+
+lazy_static! {
+    pub static ref GATEWAY_FAILOVERS: IntCounter = IntCounter::new(
+        "gateway_failovers",
+        "Messages retried via a secondary gateway because of a server error"
+    )
+    .unwrap();
+    pub static ref DRY_RUN_SENDS: IntCounter = IntCounter::new(
+        "dry_run_sends",
+        "Messages acked without sending because of the dry run mode"
+    )
+    .unwrap();
+    pub static ref INVALID_TOKEN_DEVICES_REMOVED: IntCounter = IntCounter::new(
+        "invalid_token_devices_removed",
+        "Devices removed because FCM reported their token as not registered or invalid"
+    )
+    .unwrap();
+    pub static ref LAST_DEVICES_REMOVED: IntCounter = IntCounter::new(
+        "last_devices_removed",
+        "Devices removed because of an invalid token which were the last ones of their subscriber"
+    )
+    .unwrap();
+    pub static ref DEVICE_TOKENS_MIGRATED: IntCounter = IntCounter::new(
+        "device_tokens_migrated",
+        "Device tokens replaced with the canonical ones reported by FCM"
+    )
+    .unwrap();
+    pub static ref CIRCUIT_BREAKER_STATE: IntGauge = IntGauge::new(
+        "fcm_circuit_breaker_state",
+        "State of the FCM circuit breaker: 0 - closed, 1 - half-open, 2 - open (sending paused)"
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGauge = IntGauge::new(
+        "push_queue_depth",
+        "Messages in the queue which are still to be sent"
+    )
+    .unwrap();
+    pub static ref QUEUE_OLDEST_SECONDS: IntGauge = IntGauge::new(
+        "push_queue_oldest_seconds",
+        "How long the oldest message in the queue is overdue"
+    )
+    .unwrap();
+    pub static ref NOTIFICATION_LATENCY: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "notification_latency_seconds",
+            "Time from the event receipt by a processor to sending the notification"
+        )
+        .buckets(vec![
+            0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0
+        ])
+    )
+    .unwrap();
+    pub static ref PAYLOAD_DATA_SIZE: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "payload_data_size_bytes",
+            "Size of the serialized data payload of sent notifications (FCM allows up to 4096)"
+        )
+        .buckets(vec![
+            64.0, 128.0, 256.0, 512.0, 1024.0, 2048.0, 3072.0, 4096.0
+        ])
+    )
+    .unwrap();
+}
@@ -0,0 +1,48 @@
+//! Per-device ordering of sent messages.
+//!
+//! Several sender replicas can run in parallel, each one handling its own partition of devices
+//! (by a hash of the device token, `fcm_uid`, which several addresses may share),
+//! so that messages for the same device are never sent concurrently.
+//! Within a partition, a message can be held back while an older one for the same device
+//! is still pending (say, waiting for a retry), so that at most one message per device
+//! is in flight and the messages are delivered in the order they were enqueued.
+
+/// Part of the devices handled by this sender replica
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Partition {
+    pub count: u32,
+    pub index: u32,
+}
+
+impl Partition {
+    /// SQL condition on the dequeued message (joined with its device).
+    /// `hashtext` is stable across Postgres versions, so are the partitions.
+    pub fn sql_filter(&self) -> String {
+        format!(
+            "(mod(abs(hashtext(devices.fcm_uid)::bigint), {}) = {})",
+            self.count, self.index
+        )
+    }
+}
+
+/// SQL condition on the dequeued message: no older message for the same device is pending,
+/// unless it ran out of send attempts
+pub fn no_older_pending_sql_filter(max_send_attempts: i16) -> String {
+    format!(
+        "(not exists (select 1 from messages older \
+         join devices older_device on older_device.uid = older.device_uid \
+         where older_device.fcm_uid = devices.fcm_uid \
+         and older.uid < messages.uid and older.send_attempts_count < {}))",
+        max_send_attempts
+    )
+}
+
+#[test]
+fn test_sql_filters() {
+    let partition = Partition { count: 4, index: 1 };
+    assert_eq!(
+        partition.sql_filter(),
+        "(mod(abs(hashtext(devices.fcm_uid)::bigint), 4) = 1)"
+    );
+    assert!(no_older_pending_sql_filter(5).ends_with("older.send_attempts_count < 5))"));
+}
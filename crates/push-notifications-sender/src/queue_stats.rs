@@ -0,0 +1,76 @@
+//! Depth of the messages queue and the age of its oldest message, sampled periodically
+//! and exposed as metrics, to see how backed up the queue is.
+//!
+//! Only messages which are still to be sent count: undeliverable ones (out of send attempts)
+//! are not in the queue anymore, they are just waiting for the cleanup.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{metrics, postgres};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QueueStats {
+    pub depth: i64,
+    /// Due time of the oldest message, `None` if the queue is empty
+    pub oldest_scheduled_for: Option<DateTime<Utc>>,
+}
+
+impl QueueStats {
+    /// How long the oldest message is overdue, zero if it is not due yet (being retried later)
+    pub fn oldest_age(&self, now: DateTime<Utc>) -> Duration {
+        self.oldest_scheduled_for
+            .and_then(|oldest| (now - oldest).to_std().ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Samples the queue on a blocking thread of its own, so that sending is not held up.
+/// Errors are logged only: the metrics keep the last sampled values.
+pub async fn sample_periodically(
+    pool: postgres::PgPool,
+    interval: Duration,
+    max_send_attempts: i16,
+) {
+    loop {
+        let pool = pool.clone();
+        let stats = tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            postgres::queue_stats(&mut conn, max_send_attempts)
+        })
+        .await;
+        match stats {
+            Ok(Ok(stats)) => {
+                metrics::QUEUE_DEPTH.set(stats.depth);
+                let oldest_age = stats.oldest_age(Utc::now());
+                metrics::QUEUE_OLDEST_SECONDS.set(oldest_age.as_secs() as i64);
+            }
+            Ok(Err(err)) => log::error!("Failed to sample the queue stats: {:?}", err),
+            Err(err) => log::error!("Queue stats task failed: {}", err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[test]
+fn test_oldest_age() {
+    use chrono::TimeZone;
+
+    let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    let stats = |oldest_scheduled_for| QueueStats {
+        depth: 3,
+        oldest_scheduled_for,
+    };
+    let minutes_ago = |minutes| Some(now - chrono::Duration::minutes(minutes));
+
+    assert_eq!(
+        stats(minutes_ago(5)).oldest_age(now),
+        Duration::from_secs(300)
+    );
+    assert_eq!(stats(minutes_ago(0)).oldest_age(now), Duration::ZERO);
+    // Scheduled for a retry later
+    assert_eq!(stats(minutes_ago(-5)).oldest_age(now), Duration::ZERO);
+    // Empty queue
+    assert_eq!(stats(None).oldest_age(now), Duration::ZERO);
+}